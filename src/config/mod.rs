@@ -1,44 +1,348 @@
 pub mod parser;
 
+use glob::Glob;
+
+use mime::Mime;
+use regex::bytes::Regex;
+
+use std::collections::HashMap;
+use std::fmt;
 use std::net::{SocketAddr, IpAddr, Ipv4Addr};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 /// A holder for app configuration
 #[derive(Debug, Clone)]
 pub struct Config {
-    /// Port number to listen on
-    pub port: u16,
-    pub stat: StaticFilesConfig,
-    pub fcgi: FastCgiConfig
+    /// Addresses to take connections on
+    pub listeners: Vec<ListenConfig>,
+    /// Where to write the process ID of whichever server is running
+    pub pidfile: Option<PathBuf>,
+    pub timeouts: Timeouts,
+    pub limits: Limits,
+    /// Sites to serve, chosen between by the host a request is for
+    pub sites: Vec<SiteConfig>
 }
 
 impl Default for Config {
     fn default() -> Config {
         Config {
-            port: 8000,
-            stat: Default::default(),
-            fcgi: Default::default()
+            listeners: vec![Default::default()],
+            pidfile: None,
+            timeouts: Default::default(),
+            limits: Default::default(),
+            sites: vec![Default::default()]
         }
     }
 }
 
+/// One address to take connections on, and how
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListenConfig {
+    pub address: ListenAddress,
+    /// How many connections may wait to be accepted
+    pub backlog: u32,
+    /// Lets other sockets bind the same address with the same option, for the
+    /// kernel to share connections between
+    pub reuse_port: bool,
+    /// Whether an IPv6 address takes IPv6 connections only, rather than IPv4
+    /// ones too; the system's default if not given
+    pub ipv6_only: Option<bool>,
+    /// Sends small writes to clients without waiting to fill a packet
+    pub nodelay: bool,
+    /// How many TCP Fast Open requests may be pending; 0 turns it off
+    pub fastopen: u32,
+    /// Permissions for a Unix socket's file, like `0o660`
+    pub mode: Option<u32>,
+    /// User, by name or ID, to own a Unix socket's file
+    pub owner: Option<String>,
+    /// Group, by name or ID, to own a Unix socket's file
+    pub group: Option<String>,
+    /// Speak HTTPS with this certificate, unless a site has its own for the
+    /// name the client asks for
+    pub tls: Option<TlsConfig>
+}
+
+impl Default for ListenConfig {
+    fn default() -> ListenConfig {
+        ListenConfig {
+            address: ListenAddress::Tcp(
+                SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 8000)
+            ),
+            backlog: 128,
+            reuse_port: false,
+            ipv6_only: None,
+            nodelay: false,
+            fastopen: 0,
+            mode: None,
+            owner: None,
+            group: None,
+            tls: None
+        }
+    }
+}
+
+/// Where a listener takes connections
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenAddress {
+    /// An IPv4 or IPv6 address and port
+    Tcp(SocketAddr),
+    /// A Unix socket, written `unix:/path`
+    Unix(PathBuf)
+}
+
+impl fmt::Display for ListenAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &ListenAddress::Tcp(ref addr) => write!(f, "{}", addr),
+            &ListenAddress::Unix(ref path) => write!(f, "unix:{}",
+                                                     path.display())
+        }
+    }
+}
+
+/// A certificate chain and its private key, both PEM files
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsConfig {
+    /// The server's certificate, then any intermediates
+    pub certificate: PathBuf,
+    pub key: PathBuf
+}
+
+/// How long clients get to send requests and take responses
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    /// Seconds from a request's first byte, or the connection opening, until
+    /// its headers must all be in
+    pub header: u64,
+    /// Slowest a request body may arrive, in bytes per second on average;
+    /// 0 for no limit
+    pub body_min_rate: u64,
+    /// Seconds a write to the client may block
+    pub write: u64,
+    /// Seconds a connection may sit idle waiting for its next request; 0
+    /// closes connections after one
+    pub keep_alive: u64,
+    /// Seconds requests in flight get to finish once the server's asked to
+    /// stop
+    pub shutdown: u64
+}
+
+impl Default for Timeouts {
+    fn default() -> Timeouts {
+        Timeouts {
+            header: 10,
+            body_min_rate: 1024,
+            write: 30,
+            keep_alive: 5,
+            shutdown: 30
+        }
+    }
+}
+
+/// How big requests may be
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// Longest request line, in bytes, not counting its line break
+    pub request_line: u64,
+    /// Most bytes of headers, not counting the request line
+    pub header_bytes: u64,
+    /// Most header fields
+    pub headers: u64,
+    /// Largest request body, in bytes
+    pub body: u64
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            request_line: 8 * 1024,
+            header_bytes: 32 * 1024,
+            headers: 100,
+            body: 100 * 1024 * 1024
+        }
+    }
+}
+
+/// Configuration for one name-based virtual host
+#[derive(Debug, Clone)]
+pub struct SiteConfig {
+    /// Host names this site answers to
+    pub server_names: Vec<ServerName>,
+    /// Whether this site takes requests for hosts no site is named for
+    pub default: bool,
+    /// Static file mounts
+    pub statics: Vec<StaticFilesConfig>,
+    pub fcgi: FastCgiConfig,
+    /// Response header rules, applied in order
+    pub header_rules: Vec<HeaderRule>,
+    /// Redirects and rewrites, tried in order before routing
+    pub rewrites: Vec<RewriteRule>,
+    /// Pages to send in place of the built-in ones, by status
+    pub error_pages: HashMap<u16, ErrorPage>,
+    /// The certificate to present to HTTPS clients asking for this site
+    pub tls: Option<TlsConfig>
+}
+
+impl SiteConfig {
+    /// The directory FastCGI applications are told request paths map onto
+    ///
+    /// This is the first static mount's webroot.
+    pub fn document_root(&self) -> &Path {
+        self.statics.first()
+            .map_or(Path::new("/"), |mount| mount.webroot.as_path())
+    }
+}
+
+impl Default for SiteConfig {
+    fn default() -> SiteConfig {
+        SiteConfig {
+            server_names: Vec::new(),
+            default: true,
+            statics: vec![Default::default()],
+            fcgi: Default::default(),
+            header_rules: Vec::new(),
+            rewrites: Vec::new(),
+            error_pages: HashMap::new(),
+            tls: None
+        }
+    }
+}
+
+/// A host name a site answers to, lowercase and without a trailing `.`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServerName {
+    /// Just this name, like `example.com`
+    Exact(String),
+    /// Any name ending in this, written `*.example.com` and stored as
+    /// `.example.com`
+    Suffix(String),
+    /// Any name starting with this, written `www.example.*` and stored as
+    /// `www.example.`
+    Prefix(String)
+}
+
+impl fmt::Display for ServerName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &ServerName::Exact(ref name) => write!(f, "{}", name),
+            &ServerName::Suffix(ref suffix) => write!(f, "*{}", suffix),
+            &ServerName::Prefix(ref prefix) => write!(f, "{}*", prefix)
+        }
+    }
+}
+
+/// Configuration for one static mount
 #[derive(Debug, Clone)]
 pub struct StaticFilesConfig {
     /// Where the files are located on disk
     pub webroot: PathBuf,
     /// Public URI prefix that gets mapped onto `webroot`
-    pub public_prefix: PathBuf
+    pub public_prefix: PathBuf,
+    /// Extra MIME types by (lowercase) file extension, taking precedence over
+    /// the built-in guesses
+    pub mime_types: HashMap<String, Mime>,
+    /// Charset appended to textual types that don't already name one
+    pub default_charset: Option<String>,
+    /// Refuse paths with a segment starting with `.`, other than `.well-known`
+    pub deny_dotfiles: bool,
+    /// Refuse paths matching any of these patterns
+    pub deny: Vec<Glob>,
+    /// What to do on meeting a symbolic link inside the webroot
+    pub symlinks: SymlinkPolicy,
+    /// Candidate paths to look for, in order, with `$uri` standing for the
+    /// request path
+    pub try_files: Vec<String>,
+    /// What to do when none of `try_files` exist
+    pub fallback: Fallback,
+    /// Files to look for when a directory is requested
+    pub index: Vec<String>,
+    /// Whether to list directories without an index file
+    pub listing: bool,
+    /// Header rules for this mount only, applied after the global ones
+    pub header_rules: Vec<HeaderRule>,
+    pub cache: CacheConfig,
+    /// Accept uploads and deletions, if set
+    pub writable: Option<WritableConfig>
 }
 
 impl Default for StaticFilesConfig {
     fn default() -> StaticFilesConfig {
         StaticFilesConfig {
             webroot: PathBuf::from("/etc/http-server/site"),
-            public_prefix: PathBuf::from("/html")
+            public_prefix: PathBuf::from("/html"),
+            mime_types: HashMap::new(),
+            default_charset: Some(String::from("utf-8")),
+            deny_dotfiles: true,
+            deny: Vec::new(),
+            symlinks: SymlinkPolicy::Follow,
+            try_files: vec![String::from("$uri")],
+            fallback: Fallback::Status(404),
+            index: vec![String::from("index.html")],
+            listing: false,
+            header_rules: Vec::new(),
+            cache: Default::default(),
+            writable: None
+        }
+    }
+}
+
+/// Settings for a static mount that accepts `PUT`, `DELETE` and `MKCOL`
+#[derive(Debug, Clone)]
+pub struct WritableConfig {
+    /// Largest body a `PUT` may have
+    pub max_size: u64,
+    /// User names and passwords allowed to write, with Basic authentication
+    pub users: Vec<(String, String)>
+}
+
+/// Limits for a static mount's file cache
+#[derive(Debug, Clone)]
+pub struct CacheConfig {
+    /// Most paths to remember; 0 turns the cache off
+    pub entries: usize,
+    /// Most bytes of file contents to hold
+    pub bytes: usize,
+    /// Largest file whose contents are kept in memory
+    pub max_file_size: u64,
+    /// Seconds before an entry is checked against the disk again
+    pub revalidate: u64
+}
+
+impl Default for CacheConfig {
+    fn default() -> CacheConfig {
+        CacheConfig {
+            entries: 0,
+            bytes: 16 * 1024 * 1024,
+            max_file_size: 64 * 1024,
+            revalidate: 2
         }
     }
 }
 
+/// The last resort of a `try_files` list
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Fallback {
+    /// Respond with an error page, like `=404`
+    Status(u16),
+    /// Serve this path (after `$uri` substitution), like `/html/index.html`
+    Uri(String),
+    /// Hand the request to the FastCGI application, written `@fastcgi`
+    FastCgi
+}
+
+/// How `Statics` treats symbolic links below the webroot
+///
+/// Whatever the policy, the final file must still be inside the webroot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    /// Follow any link
+    Follow,
+    /// Follow a link only if it's owned by the same user as its target
+    FollowIfOwnerMatches,
+    /// Never follow links
+    Never
+}
+
 #[derive(Debug, Clone)]
 pub struct FastCgiConfig {
     /// Socket addresses suitable for passing to `TcpStream::connect`.
@@ -54,3 +358,42 @@ impl Default for FastCgiConfig {
     }
 }
 
+/// Response header edits for requests matching a path pattern
+///
+/// A rule matches when the request path matches `path` (if given) and has one
+/// of the `extensions` (if any are given). Matching rules first `remove`, then
+/// `set`, then `append` headers.
+#[derive(Debug, Clone)]
+pub struct HeaderRule {
+    pub path: Option<Glob>,
+    /// Lowercase extensions, without the leading `.`
+    pub extensions: Vec<String>,
+    /// Whether this rule also applies to FastCGI responses
+    pub fastcgi: bool,
+    pub set: Vec<(String, Vec<u8>)>,
+    pub append: Vec<(String, Vec<u8>)>,
+    pub remove: Vec<String>
+}
+
+/// A redirect or internal rewrite for request paths matching a pattern
+#[derive(Debug, Clone)]
+pub struct RewriteRule {
+    /// Matched against the request path, without the query string
+    pub pattern: Regex,
+    /// The new path or URL, with `$1` or `$name` standing for captures
+    pub to: String,
+    /// The status to redirect with, or `None` to rewrite internally
+    pub redirect: Option<u16>,
+    /// Whether the rules are tried again on the path this one rewrites to
+    pub chain: bool
+}
+
+/// A custom error page, in which `{status}`, `{reason}` and `{request_id}` are
+/// filled in
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ErrorPage {
+    /// An HTML file, read when the server starts
+    File(PathBuf),
+    /// HTML given in the config itself
+    Template(String)
+}
@@ -1,10 +1,17 @@
 use super::*;
+use glob::Glob;
 use toml::{Parser, ParserError, Table, Value};
 
+use mime::Mime;
+use regex::bytes::Regex;
+
+use std::ascii::AsciiExt;
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::{self, Read};
-use std::net::ToSocketAddrs;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs};
 use std::path::{Path, PathBuf};
+use std::i32;
 use std::u16;
 
 pub fn parse_file<P: AsRef<Path>>(conf: P)
@@ -33,35 +40,179 @@ fn config_from_table(table: Table) -> Result<Config, Error> {
     let table = Value::Table(table);
     let mut config: Config = Default::default();
 
-    match table.lookup("listen.port") {
-        Some(&Value::Integer(p))
-            if p <= u16::MAX as i64 &&
-            p > 0 => config.port = p as u16,
-        Some(&Value::Integer(p)) => return Err(Error::Validation(
-            format!("The given port {} is out of range", p)
-        )),
+    match table.lookup("listen") {
+        Some(&Value::Array(ref listeners)) => {
+            config.listeners = Vec::with_capacity(listeners.len());
+            for val in listeners {
+                match val {
+                    &Value::Table(ref listener) =>
+                        config.listeners.push(try!(listen_entry(listener))),
+                    _ => return Err(Error::Validation(format!(
+                        "Expected a [[listen]] entry to be a table, got a {}",
+                        val.type_str()
+                    )))
+                }
+            }
+
+            if config.listeners.is_empty() {
+                return Err(Error::Validation(String::from(
+                    "There has to be somewhere to listen"
+                )));
+            }
+        },
+        Some(&Value::Table(ref listener)) =>
+            config.listeners = vec![try!(listen_entry(listener))],
         Some(val) => return Err(Error::Validation(
-            format!("Expected the port to be an integer, got a {}",
+            format!("Expected listen to be a table, got a {}",
                     val.type_str())
         )),
         None => ()
     }
 
-    match table.lookup("static.webroot") {
+    match table.lookup("pidfile") {
         Some(&Value::String(ref path)) =>
-            config.stat.webroot = PathBuf::from(path),
+            config.pidfile = Some(PathBuf::from(path)),
         Some(val) => return Err(Error::Validation(
-            format!("Expected the webroot to be a string, got a {}",
+            format!("Expected the pidfile to be a string, got a {}",
                     val.type_str())
         )),
         None => ()
     }
 
-    match table.lookup("static.public_prefix") {
-        Some(&Value::String(ref path)) =>
-            config.stat.public_prefix = PathBuf::from(path),
+    match table.lookup("timeouts") {
+        Some(&Value::Table(ref timeouts)) =>
+            config.timeouts = try!(timeout_settings(timeouts)),
         Some(val) => return Err(Error::Validation(
-            format!("Expected the webroot to be a string, got a {}",
+            format!("Expected the timeouts to be a table, got a {}",
+                    val.type_str())
+        )),
+        None => ()
+    }
+
+    match table.lookup("limits") {
+        Some(&Value::Table(ref limits)) =>
+            config.limits = try!(limit_settings(limits)),
+        Some(val) => return Err(Error::Validation(
+            format!("Expected the limits to be a table, got a {}",
+                    val.type_str())
+        )),
+        None => ()
+    }
+
+    match table.lookup("site") {
+        Some(&Value::Array(ref sites)) => {
+            for key in &["static", "fastcgi", "tls"] {
+                if table.lookup(key).is_some() {
+                    return Err(Error::Validation(
+                        format!("With [[site]] blocks, {} settings belong in \
+                                 a site", key)
+                    ));
+                }
+            }
+
+            let global_rules = match table.lookup("headers") {
+                Some(val) => try!(header_rules(val, "the header rules")),
+                None => Vec::new()
+            };
+            let global_rewrites = match table.lookup("rewrite") {
+                Some(val) => try!(rewrite_rules(val)),
+                None => Vec::new()
+            };
+            let global_pages = match table.lookup("error_pages") {
+                Some(val) => try!(error_pages(val)),
+                None => HashMap::new()
+            };
+
+            config.sites = Vec::with_capacity(sites.len());
+            for val in sites {
+                if let &Value::Table(_) = val {
+                    let mut site = try!(named_site(val));
+                    site.header_rules = global_rules.iter().cloned()
+                        .chain(site.header_rules.into_iter())
+                        .collect();
+                    site.rewrites = global_rewrites.iter().cloned()
+                        .chain(site.rewrites.into_iter())
+                        .collect();
+                    for (code, page) in &global_pages {
+                        site.error_pages.entry(*code)
+                            .or_insert_with(|| page.clone());
+                    }
+                    config.sites.push(site);
+                }
+                else {
+                    return Err(Error::Validation(
+                        format!("Expected each site to be a table, got a {}",
+                                val.type_str())
+                    ));
+                }
+            }
+
+            try!(choose_default_site(&mut config.sites));
+        },
+        Some(val) => return Err(Error::Validation(
+            format!("Expected the sites to be an array of tables, got a {}",
+                    val.type_str())
+        )),
+        None => config.sites = vec![try!(site(&table))]
+    }
+
+    Ok(config)
+}
+
+/// Reads the settings a site has, either from the top level or a `[[site]]`
+fn site(table: &Value) -> Result<SiteConfig, Error> {
+    let mut site: SiteConfig = Default::default();
+
+    match table.lookup("static") {
+        Some(&Value::Table(ref mount)) =>
+            site.statics = vec![try!(static_mount(mount))],
+        Some(&Value::Array(ref mounts)) => {
+            site.statics = Vec::with_capacity(mounts.len());
+            for mount in mounts {
+                match mount {
+                    &Value::Table(ref mount) =>
+                        site.statics.push(try!(static_mount(mount))),
+                    val => return Err(Error::Validation(
+                        format!("Expected each static mount to be a table, \
+                                 got a {}", val.type_str())
+                    ))
+                }
+            }
+        },
+        Some(val) => return Err(Error::Validation(
+            format!("Expected the static config to be a table or an array of \
+                     tables, got a {}", val.type_str())
+        )),
+        None => ()
+    }
+
+    for (i, mount) in site.statics.iter().enumerate() {
+        if site.statics[.. i].iter()
+            .any(|other| other.public_prefix == mount.public_prefix) {
+                return Err(Error::Validation(
+                    format!("More than one static mount has the prefix {}",
+                            mount.public_prefix.display())
+                ));
+            }
+    }
+
+    if let Some(val) = table.lookup("headers") {
+        site.header_rules = try!(header_rules(val, "the header rules"));
+    }
+
+    if let Some(val) = table.lookup("rewrite") {
+        site.rewrites = try!(rewrite_rules(val));
+    }
+
+    if let Some(val) = table.lookup("error_pages") {
+        site.error_pages = try!(error_pages(val));
+    }
+
+    match table.lookup("tls") {
+        Some(&Value::Table(ref tls)) =>
+            site.tls = try!(tls_files(tls, "a site's TLS settings")),
+        Some(val) => return Err(Error::Validation(
+            format!("Expected the TLS settings to be a table, got a {}",
                     val.type_str())
         )),
         None => ()
@@ -90,11 +241,851 @@ fn config_from_table(table: Table) -> Result<Config, Error> {
         None => 9000
     };
 
-    config.fcgi.address =
+    site.fcgi.address =
         ToSocketAddrs::to_socket_addrs(&(fcgi_host, fcgi_port)).unwrap()
         .next().unwrap();
 
-    Ok(config)
+    Ok(site)
+}
+
+/// Reads one `[[site]]`, which also names the hosts it answers to
+fn named_site(table: &Value) -> Result<SiteConfig, Error> {
+    let mut site = try!(site(table));
+
+    if let Some(val) = table.lookup("server_names") {
+        for name in try!(string_array(val, "a site's server_names")) {
+            site.server_names.push(try!(server_name(&name)));
+        }
+    }
+
+    site.default = match table.lookup("default") {
+        Some(&Value::Boolean(b)) => b,
+        Some(val) => return Err(Error::Validation(
+            format!("Expected a site's default flag to be a boolean, got a {}",
+                    val.type_str())
+        )),
+        None => false
+    };
+
+    if site.server_names.is_empty() && !site.default {
+        return Err(Error::Validation(String::from(
+            "Every site but the default needs some server_names"
+        )));
+    }
+
+    Ok(site)
+}
+
+/// Reads a server name, which may have a wildcard at one end
+fn server_name(name: &str) -> Result<ServerName, Error> {
+    let lower = name.trim_right_matches('.').to_ascii_lowercase();
+
+    let parsed = if lower.starts_with("*.") {
+        ServerName::Suffix(String::from(&lower[1..]))
+    }
+    else if lower.ends_with(".*") {
+        ServerName::Prefix(String::from(&lower[.. lower.len() - 1]))
+    }
+    else {
+        ServerName::Exact(lower.clone())
+    };
+
+    let valid = match parsed {
+        ServerName::Exact(ref fixed) |
+        ServerName::Suffix(ref fixed) |
+        ServerName::Prefix(ref fixed) =>
+            fixed.trim_matches('.').len() > 0 && !fixed.contains('*')
+    };
+
+    if valid {
+        Ok(parsed)
+    }
+    else {
+        Err(Error::Validation(
+            format!("Server name \"{}\" may only have a wildcard at the \
+                     start, like *.example.com, or the end, like \
+                     www.example.*", name)
+        ))
+    }
+}
+
+/// Makes sure exactly one site is the default, the first if none says so,
+/// and that no two sites share a name
+fn choose_default_site(sites: &mut [SiteConfig]) -> Result<(), Error> {
+    if sites.is_empty() {
+        return Err(Error::Validation(String::from(
+            "Expected at least one site"
+        )));
+    }
+
+    match sites.iter().filter(|site| site.default).count() {
+        0 => sites[0].default = true,
+        1 => (),
+        _ => return Err(Error::Validation(String::from(
+            "Only one site can be the default"
+        )))
+    }
+
+    for (i, site) in sites.iter().enumerate() {
+        for name in &site.server_names {
+            if sites[.. i].iter()
+                .any(|other| other.server_names.contains(name)) {
+                return Err(Error::Validation(
+                    format!("More than one site is named {}", name)
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads one static mount, either `[static]` or an entry of `[[static]]`
+fn static_mount(table: &Table) -> Result<StaticFilesConfig, Error> {
+    let mut mount: StaticFilesConfig = Default::default();
+
+    match table.get("webroot") {
+        Some(&Value::String(ref path)) =>
+            mount.webroot = PathBuf::from(path),
+        Some(val) => return Err(Error::Validation(
+            format!("Expected the webroot to be a string, got a {}",
+                    val.type_str())
+        )),
+        None => ()
+    }
+
+    match table.get("public_prefix") {
+        Some(&Value::String(ref path)) =>
+            mount.public_prefix = PathBuf::from(path),
+        Some(val) => return Err(Error::Validation(
+            format!("Expected the webroot to be a string, got a {}",
+                    val.type_str())
+        )),
+        None => ()
+    }
+
+    match table.get("mime_types") {
+        Some(&Value::Table(ref types)) => {
+            for (ext, mime) in types {
+                let mime = match mime {
+                    &Value::String(ref mime) => mime,
+                    val => return Err(Error::Validation(
+                        format!("Expected the MIME type for .{} to be a \
+                                 string, got a {}", ext, val.type_str())
+                    ))
+                };
+
+                match mime.parse::<Mime>() {
+                    Ok(mime) => {
+                        mount.mime_types.insert(
+                            ext.trim_left_matches('.').to_ascii_lowercase(),
+                            mime
+                        );
+                    },
+                    Err(()) => return Err(Error::Validation(
+                        format!("\"{}\" is not a valid MIME type", mime)
+                    ))
+                }
+            }
+        },
+        Some(val) => return Err(Error::Validation(
+            format!("Expected the MIME types to be a table, got a {}",
+                    val.type_str())
+        )),
+        None => ()
+    }
+
+    match table.get("default_charset") {
+        Some(&Value::String(ref charset)) if charset.is_empty() =>
+            mount.default_charset = None,
+        Some(&Value::String(ref charset)) =>
+            mount.default_charset = Some(charset.clone()),
+        Some(val) => return Err(Error::Validation(
+            format!("Expected the default charset to be a string, got a {}",
+                    val.type_str())
+        )),
+        None => ()
+    }
+
+    match table.get("deny_dotfiles") {
+        Some(&Value::Boolean(b)) => mount.deny_dotfiles = b,
+        Some(val) => return Err(Error::Validation(
+            format!("Expected deny_dotfiles to be a boolean, got a {}",
+                    val.type_str())
+        )),
+        None => ()
+    }
+
+    if let Some(val) = table.get("deny") {
+        mount.deny = try!(string_array(val, "the static deny list"))
+            .iter()
+            .map(|pattern| Glob::new(pattern))
+            .collect();
+    }
+
+    match table.get("symlinks") {
+        Some(&Value::String(ref policy)) => {
+            mount.symlinks = match &policy[..] {
+                "follow" => SymlinkPolicy::Follow,
+                "follow-if-owner-matches" =>
+                    SymlinkPolicy::FollowIfOwnerMatches,
+                "never" => SymlinkPolicy::Never,
+                other => return Err(Error::Validation(
+                    format!("Unknown symlink policy \"{}\"; expected \
+                             \"follow\", \"follow-if-owner-matches\" or \
+                             \"never\"", other)
+                ))
+            }
+        },
+        Some(val) => return Err(Error::Validation(
+            format!("Expected the symlink policy to be a string, got a {}",
+                    val.type_str())
+        )),
+        None => ()
+    }
+
+    if let Some(val) = table.get("try_files") {
+        let mut candidates = try!(string_array(val, "try_files"));
+
+        let last = match candidates.pop() {
+            Some(last) if !candidates.is_empty() => last,
+            _ => return Err(Error::Validation(String::from(
+                "try_files needs at least one candidate and a fallback"
+            )))
+        };
+
+        for candidate in &candidates {
+            try!(validate_try_path(candidate));
+        }
+
+        mount.fallback = if last == "@fastcgi" {
+            Fallback::FastCgi
+        }
+        else if last.starts_with('=') {
+            match last[1..].parse() {
+                Ok(code) if code >= 400 && code <= 599 =>
+                    Fallback::Status(code),
+                _ => return Err(Error::Validation(
+                    format!("try_files can't fall back to status {}",
+                            &last[1..])
+                ))
+            }
+        }
+        else {
+            try!(validate_try_path(&last));
+            Fallback::Uri(last)
+        };
+        mount.try_files = candidates;
+    }
+
+    if let Some(val) = table.get("index") {
+        mount.index = try!(string_array(val, "the index files"));
+    }
+
+    match table.get("listing") {
+        Some(&Value::Boolean(b)) => mount.listing = b,
+        Some(val) => return Err(Error::Validation(
+            format!("Expected listing to be a boolean, got a {}",
+                    val.type_str())
+        )),
+        None => ()
+    }
+
+    if let Some(val) = table.get("headers") {
+        mount.header_rules =
+            try!(header_rules(val, "a static mount's header rules"));
+    }
+
+    match table.get("cache") {
+        Some(&Value::Table(ref cache)) => {
+            if let Some(n) = try!(count(cache, "entries")) {
+                mount.cache.entries = n as usize;
+            }
+            if let Some(n) = try!(count(cache, "bytes")) {
+                mount.cache.bytes = n as usize;
+            }
+            if let Some(n) = try!(count(cache, "max_file_size")) {
+                mount.cache.max_file_size = n;
+            }
+            if let Some(n) = try!(count(cache, "revalidate")) {
+                mount.cache.revalidate = n;
+            }
+        },
+        Some(val) => return Err(Error::Validation(
+            format!("Expected the cache settings to be a table, got a {}",
+                    val.type_str())
+        )),
+        None => ()
+    }
+
+    match table.get("writable") {
+        Some(&Value::Table(ref writable)) => {
+            let users = match writable.get("users") {
+                Some(&Value::Table(ref users)) => users,
+                _ => return Err(Error::Validation(String::from(
+                    "A writable mount needs a table of users and passwords"
+                )))
+            };
+
+            let mut credentials = Vec::with_capacity(users.len());
+            for (user, password) in users {
+                match password {
+                    &Value::String(ref password) =>
+                        credentials.push((user.clone(), password.clone())),
+                    val => return Err(Error::Validation(
+                        format!("Expected the password for {} to be a \
+                                 string, got a {}", user, val.type_str())
+                    ))
+                }
+            }
+
+            if credentials.is_empty() {
+                return Err(Error::Validation(String::from(
+                    "A writable mount needs at least one user"
+                )));
+            }
+
+            mount.writable = Some(WritableConfig {
+                max_size: try!(count(writable, "max_size"))
+                    .unwrap_or(100 * 1024 * 1024),
+                users: credentials
+            });
+        },
+        Some(val) => return Err(Error::Validation(
+            format!("Expected the writable settings to be a table, got a {}",
+                    val.type_str())
+        )),
+        None => ()
+    }
+
+    Ok(mount)
+}
+
+/// Reads an optional non-negative integer out of a table
+fn count(table: &Table, key: &str) -> Result<Option<u64>, Error> {
+    match table.get(key) {
+        Some(&Value::Integer(n)) if n >= 0 => Ok(Some(n as u64)),
+        Some(&Value::Integer(n)) => Err(Error::Validation(
+            format!("{} can't be negative, but it's {}", key, n)
+        )),
+        Some(val) => Err(Error::Validation(
+            format!("Expected {} to be an integer, got a {}",
+                    key, val.type_str())
+        )),
+        None => Ok(None)
+    }
+}
+
+/// Reads one `[[listen]]` entry, or the older `[listen]` with just a port
+fn listen_entry(table: &Table) -> Result<ListenConfig, Error> {
+    let mut listener: ListenConfig = Default::default();
+
+    match (table.get("address"), table.get("port")) {
+        (Some(&Value::String(ref address)), None) =>
+            listener.address = try!(listen_address(address)),
+        (Some(&Value::String(_)), Some(_)) => return Err(Error::Validation(
+            String::from("A listener's port goes in its address")
+        )),
+        (Some(val), _) => return Err(Error::Validation(
+            format!("Expected a listener's address to be a string, got a {}",
+                    val.type_str())
+        )),
+        (None, Some(&Value::Integer(p))) if p <= u16::MAX as i64 && p > 0 =>
+            listener.address = ListenAddress::Tcp(
+                SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
+                                p as u16)
+            ),
+        (None, Some(&Value::Integer(p))) => return Err(Error::Validation(
+            format!("The given port {} is out of range", p)
+        )),
+        (None, Some(val)) => return Err(Error::Validation(
+            format!("Expected the port to be an integer, got a {}",
+                    val.type_str())
+        )),
+        (None, None) => ()
+    }
+
+    if let Some(n) = try!(count(table, "backlog")) {
+        if n == 0 || n > i32::MAX as u64 {
+            return Err(Error::Validation(
+                format!("The backlog {} is out of range", n)
+            ));
+        }
+        listener.backlog = n as u32;
+    }
+    if let Some(n) = try!(count(table, "fastopen")) {
+        if n > i32::MAX as u64 {
+            return Err(Error::Validation(
+                format!("The fastopen queue {} is out of range", n)
+            ));
+        }
+        listener.fastopen = n as u32;
+    }
+
+    listener.reuse_port = try!(listen_flag(table, "reuse_port"))
+        .unwrap_or(false);
+    listener.nodelay = try!(listen_flag(table, "nodelay")).unwrap_or(false);
+    listener.ipv6_only = try!(listen_flag(table, "ipv6_only"));
+
+    match table.get("mode") {
+        Some(&Value::String(ref mode)) => match u32::from_str_radix(mode, 8) {
+            Ok(mode) if mode <= 0o7777 => listener.mode = Some(mode),
+            _ => return Err(Error::Validation(format!(
+                "A socket's mode should be in octal, like \"0660\", not {:?}",
+                mode
+            )))
+        },
+        Some(val) => return Err(Error::Validation(
+            format!("Expected a socket's mode to be a string, got a {}",
+                    val.type_str())
+        )),
+        None => ()
+    }
+    listener.owner = try!(listen_name(table, "owner"));
+    listener.group = try!(listen_name(table, "group"));
+    listener.tls = try!(tls_files(table, "a listener"));
+
+    match listener.address {
+        ListenAddress::Tcp(_) if listener.mode.is_some() ||
+            listener.owner.is_some() || listener.group.is_some() =>
+            return Err(Error::Validation(format!(
+                "{} isn't a Unix socket, to have a mode or owner",
+                listener.address
+            ))),
+        ListenAddress::Tcp(SocketAddr::V4(_))
+            if listener.ipv6_only.is_some() =>
+            return Err(Error::Validation(format!(
+                "{} isn't an IPv6 address, to be ipv6_only",
+                listener.address
+            ))),
+        ListenAddress::Unix(_) if listener.tls.is_some() =>
+            return Err(Error::Validation(format!(
+                "{} is a Unix socket, so it can't speak HTTPS",
+                listener.address
+            ))),
+        ListenAddress::Unix(_) if listener.reuse_port ||
+            listener.nodelay || listener.fastopen > 0 ||
+            listener.ipv6_only.is_some() =>
+            return Err(Error::Validation(format!(
+                "{} is a Unix socket, so the TCP options don't apply",
+                listener.address
+            ))),
+        _ => ()
+    }
+
+    Ok(listener)
+}
+
+/// Parses an address like `127.0.0.1:80`, `[::1]:80` or `unix:/path`
+fn listen_address(address: &str) -> Result<ListenAddress, Error> {
+    if address.starts_with("unix:") {
+        let path = PathBuf::from(&address["unix:".len() ..]);
+        if !path.is_absolute() {
+            return Err(Error::Validation(format!(
+                "A Unix socket's path has to be absolute, unlike {:?}", path
+            )));
+        }
+        return Ok(ListenAddress::Unix(path));
+    }
+
+    address.parse().map(ListenAddress::Tcp).map_err(|_| Error::Validation(
+        format!("Expected an address like 0.0.0.0:80, [::]:80 or \
+                 unix:/path, got {:?}", address)
+    ))
+}
+
+fn listen_flag(table: &Table, key: &str) -> Result<Option<bool>, Error> {
+    match table.get(key) {
+        Some(&Value::Boolean(b)) => Ok(Some(b)),
+        Some(val) => Err(Error::Validation(
+            format!("Expected a listener's {} to be a boolean, got a {}",
+                    key, val.type_str())
+        )),
+        None => Ok(None)
+    }
+}
+
+fn listen_name(table: &Table, key: &str) -> Result<Option<String>, Error> {
+    match table.get(key) {
+        Some(&Value::String(ref name)) => Ok(Some(name.clone())),
+        Some(&Value::Integer(id)) if id >= 0 => Ok(Some(format!("{}", id))),
+        Some(val) => Err(Error::Validation(
+            format!("Expected a socket's {} to be a name or ID, got a {}",
+                    key, val.type_str())
+        )),
+        None => Ok(None)
+    }
+}
+
+/// Reads the paths of a certificate and its key, which come as a pair
+fn tls_files(table: &Table, what: &str) -> Result<Option<TlsConfig>, Error> {
+    let path = |key: &str| match table.get(key) {
+        Some(&Value::String(ref path)) => Ok(Some(PathBuf::from(path))),
+        Some(val) => Err(Error::Validation(
+            format!("Expected the {} for {} to be a path, got a {}",
+                    key, what, val.type_str())
+        )),
+        None => Ok(None)
+    };
+
+    match (try!(path("certificate")), try!(path("key"))) {
+        (Some(certificate), Some(key)) =>
+            Ok(Some(TlsConfig { certificate: certificate, key: key })),
+        (None, None) => Ok(None),
+        _ => Err(Error::Validation(
+            format!("Both a certificate and a key are needed for {}", what)
+        ))
+    }
+}
+
+fn timeout_settings(table: &Table) -> Result<Timeouts, Error> {
+    let mut timeouts: Timeouts = Default::default();
+
+    if let Some(n) = try!(count(table, "header")) {
+        timeouts.header = n;
+    }
+    if let Some(n) = try!(count(table, "body_min_rate")) {
+        timeouts.body_min_rate = n;
+    }
+    if let Some(n) = try!(count(table, "write")) {
+        timeouts.write = n;
+    }
+    if let Some(n) = try!(count(table, "keep_alive")) {
+        timeouts.keep_alive = n;
+    }
+    if let Some(n) = try!(count(table, "shutdown")) {
+        timeouts.shutdown = n;
+    }
+
+    if timeouts.header == 0 || timeouts.write == 0 {
+        return Err(Error::Validation(String::from(
+            "The header and write timeouts must be at least a second"
+        )));
+    }
+
+    Ok(timeouts)
+}
+
+fn limit_settings(table: &Table) -> Result<Limits, Error> {
+    let mut limits: Limits = Default::default();
+
+    if let Some(n) = try!(count(table, "request_line")) {
+        limits.request_line = n;
+    }
+    if let Some(n) = try!(count(table, "header_bytes")) {
+        limits.header_bytes = n;
+    }
+    if let Some(n) = try!(count(table, "headers")) {
+        limits.headers = n;
+    }
+    if let Some(n) = try!(count(table, "body")) {
+        limits.body = n;
+    }
+
+    if limits.request_line == 0 || limits.header_bytes == 0 {
+        return Err(Error::Validation(String::from(
+            "The request line and header limits must be at least a byte"
+        )));
+    }
+
+    Ok(limits)
+}
+
+/// Reads an array of header rules, like `[[headers]]`
+fn header_rules(val: &Value, what: &str) -> Result<Vec<HeaderRule>, Error> {
+    let rules = match val {
+        &Value::Array(ref rules) => rules,
+        val => return Err(Error::Validation(
+            format!("Expected {} to be an array of tables, got a {}",
+                    what, val.type_str())
+        ))
+    };
+
+    let mut parsed = Vec::with_capacity(rules.len());
+    for rule in rules {
+        match rule {
+            &Value::Table(ref rule) => parsed.push(try!(header_rule(rule))),
+            val => return Err(Error::Validation(
+                format!("Expected each of {} to be a table, got a {}",
+                        what, val.type_str())
+            ))
+        }
+    }
+
+    Ok(parsed)
+}
+
+/// Reads one `[[headers]]` entry
+fn header_rule(table: &Table) -> Result<HeaderRule, Error> {
+    let path = match table.get("path") {
+        Some(&Value::String(ref path)) => Some(Glob::new(path)),
+        Some(val) => return Err(Error::Validation(
+            format!("Expected a header rule's path to be a string, got a {}",
+                    val.type_str())
+        )),
+        None => None
+    };
+
+    let extensions = match table.get("extensions") {
+        Some(val) => try!(string_array(val, "a header rule's extensions"))
+            .into_iter()
+            .map(|ext| ext.trim_left_matches('.').to_ascii_lowercase())
+            .collect(),
+        None => Vec::new()
+    };
+
+    if path.is_none() && extensions.is_empty() {
+        return Err(Error::Validation(
+            String::from("A header rule needs a path or some extensions")
+        ));
+    }
+
+    let fastcgi = match table.get("fastcgi") {
+        Some(&Value::Boolean(b)) => b,
+        Some(val) => return Err(Error::Validation(
+            format!("Expected a header rule's fastcgi flag to be a boolean, \
+                     got a {}", val.type_str())
+        )),
+        None => false
+    };
+
+    let set = match table.get("set") {
+        Some(val) => try!(header_table(val, "a header rule's set")),
+        None => Vec::new()
+    };
+
+    let append = match table.get("append") {
+        Some(val) => try!(header_table(val, "a header rule's append")),
+        None => Vec::new()
+    };
+
+    let remove = match table.get("remove") {
+        Some(val) => try!(string_array(val, "a header rule's remove")),
+        None => Vec::new()
+    };
+
+    Ok(HeaderRule {
+        path: path,
+        extensions: extensions,
+        fastcgi: fastcgi,
+        set: set,
+        append: append,
+        remove: remove
+    })
+}
+
+/// Reads an array of `[[rewrite]]` rules
+fn rewrite_rules(val: &Value) -> Result<Vec<RewriteRule>, Error> {
+    let rules = match val {
+        &Value::Array(ref rules) => rules,
+        val => return Err(Error::Validation(
+            format!("Expected the rewrite rules to be an array of tables, got \
+                     a {}", val.type_str())
+        ))
+    };
+
+    let mut parsed = Vec::with_capacity(rules.len());
+    for rule in rules {
+        match rule {
+            &Value::Table(ref rule) => parsed.push(try!(rewrite_rule(rule))),
+            val => return Err(Error::Validation(
+                format!("Expected each rewrite rule to be a table, got a {}",
+                        val.type_str())
+            ))
+        }
+    }
+
+    Ok(parsed)
+}
+
+/// Reads one `[[rewrite]]` entry
+fn rewrite_rule(table: &Table) -> Result<RewriteRule, Error> {
+    let pattern = match (table.get("regex"), table.get("glob")) {
+        (Some(&Value::String(ref regex)), None) => String::from(&regex[..]),
+        (None, Some(&Value::String(ref glob))) => Glob::new(glob).to_regex(),
+        (Some(_), Some(_)) | (None, None) => return Err(Error::Validation(
+            String::from("A rewrite rule needs either a regex or a glob")
+        )),
+        (Some(val), _) | (_, Some(val)) => return Err(Error::Validation(
+            format!("Expected a rewrite rule's pattern to be a string, got a \
+                     {}", val.type_str())
+        ))
+    };
+
+    let pattern = match Regex::new(&pattern) {
+        Ok(regex) => regex,
+        Err(e) => return Err(Error::Validation(
+            format!("The rewrite pattern {:?} is invalid: {}", pattern, e)
+        ))
+    };
+
+    let to = match table.get("to") {
+        Some(&Value::String(ref to)) => to.clone(),
+        Some(val) => return Err(Error::Validation(
+            format!("Expected a rewrite rule's target to be a string, got a {}",
+                    val.type_str())
+        )),
+        None => return Err(Error::Validation(
+            String::from("A rewrite rule needs a target, given as to")
+        ))
+    };
+
+    let redirect = match table.get("redirect") {
+        Some(&Value::Integer(code @ 301)) |
+        Some(&Value::Integer(code @ 302)) |
+        Some(&Value::Integer(code @ 307)) |
+        Some(&Value::Integer(code @ 308)) => Some(code as u16),
+        Some(&Value::Integer(code)) => return Err(Error::Validation(
+            format!("Can't redirect with status {}; use 301, 302, 307 or 308",
+                    code)
+        )),
+        Some(val) => return Err(Error::Validation(
+            format!("Expected a rewrite rule's redirect status to be an \
+                     integer, got a {}", val.type_str())
+        )),
+        None => None
+    };
+
+    if redirect.is_none() && !to.starts_with('/') {
+        return Err(Error::Validation(
+            format!("The rewrite target \"{}\" must start with /, or the \
+                     rule must redirect", to)
+        ));
+    }
+
+    let chain = match table.get("chain") {
+        Some(&Value::Boolean(chain)) => chain,
+        Some(val) => return Err(Error::Validation(
+            format!("Expected a rewrite rule's chain to be a boolean, got a {}",
+                    val.type_str())
+        )),
+        None => false
+    };
+
+    if chain && redirect.is_some() {
+        return Err(Error::Validation(
+            format!("The rewrite to \"{}\" redirects, so it can't chain", to)
+        ));
+    }
+
+    Ok(RewriteRule {
+        pattern: pattern,
+        to: to,
+        redirect: redirect,
+        chain: chain
+    })
+}
+
+/// Reads the `[error_pages]` table, keyed by status
+fn error_pages(val: &Value) -> Result<HashMap<u16, ErrorPage>, Error> {
+    let table = match val {
+        &Value::Table(ref table) => table,
+        val => return Err(Error::Validation(
+            format!("Expected the error pages to be a table, got a {}",
+                    val.type_str())
+        ))
+    };
+
+    let mut pages = HashMap::with_capacity(table.len());
+    for (code, page) in table {
+        let code = match code.parse::<u16>() {
+            Ok(code) if code >= 400 && code <= 599 => code,
+            _ => return Err(Error::Validation(
+                format!("Error pages are for statuses from 400 to 599, not {}",
+                        code)
+            ))
+        };
+
+        let page = match page {
+            &Value::Table(ref page) => page,
+            val => return Err(Error::Validation(
+                format!("Expected the error page for {} to be a table, got a \
+                         {}", code, val.type_str())
+            ))
+        };
+
+        let page = match (page.get("file"), page.get("template")) {
+            (Some(&Value::String(ref path)), None) =>
+                ErrorPage::File(PathBuf::from(path)),
+            (None, Some(&Value::String(ref template))) =>
+                ErrorPage::Template(template.clone()),
+            (Some(_), Some(_)) | (None, None) => return Err(Error::Validation(
+                format!("The error page for {} needs either a file or a \
+                         template", code)
+            )),
+            (Some(val), _) | (_, Some(val)) => return Err(Error::Validation(
+                format!("Expected the error page for {} to be a string, got a \
+                         {}", code, val.type_str())
+            ))
+        };
+
+        pages.insert(code, page);
+    }
+
+    Ok(pages)
+}
+
+/// Checks that a `try_files` entry will expand to an absolute path
+fn validate_try_path(path: &str) -> Result<(), Error> {
+    if path.starts_with('/') || path.starts_with("$uri") {
+        Ok(())
+    }
+    else {
+        Err(Error::Validation(
+            format!("try_files entry \"{}\" must start with / or $uri", path)
+        ))
+    }
+}
+
+/// Reads an array of strings; `what` names it for error messages
+fn string_array(val: &Value, what: &str) -> Result<Vec<String>, Error> {
+    let array = match val {
+        &Value::Array(ref array) => array,
+        val => return Err(Error::Validation(
+            format!("Expected {} to be an array, got a {}",
+                    what, val.type_str())
+        ))
+    };
+
+    let mut strings = Vec::with_capacity(array.len());
+    for item in array {
+        match item {
+            &Value::String(ref s) => strings.push(s.clone()),
+            val => return Err(Error::Validation(
+                format!("Expected {} to hold strings, got a {}",
+                        what, val.type_str())
+            ))
+        }
+    }
+
+    Ok(strings)
+}
+
+/// Reads a table of header names to header values
+fn header_table(val: &Value, what: &str)
+                -> Result<Vec<(String, Vec<u8>)>, Error>
+{
+    let table = match val {
+        &Value::Table(ref table) => table,
+        val => return Err(Error::Validation(
+            format!("Expected {} to be a table, got a {}",
+                    what, val.type_str())
+        ))
+    };
+
+    let mut headers = Vec::with_capacity(table.len());
+    for (name, value) in table {
+        match value {
+            &Value::String(ref value) =>
+                headers.push((name.clone(), value.clone().into_bytes())),
+            val => return Err(Error::Validation(
+                format!("Expected header {} in {} to be a string, got a {}",
+                        name, what, val.type_str())
+            ))
+        }
+    }
+
+    Ok(headers)
 }
 
 #[derive(Debug)]
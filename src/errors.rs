@@ -1,7 +1,10 @@
 //! Error handling for the http server
 
 use httparse;
+use xml;
 
+use std::error;
+use std::fmt;
 use std::io;
 use std::num::ParseIntError;
 use std::str::Utf8Error;
@@ -25,7 +28,113 @@ pub enum Error {
     PathNotInOriginForm,
     IllegalPercentEncoding,
     PermissionDenied,
-    RequestIncomplete
+    RequestIncomplete,
+    /// A request body was bigger than the server will take
+    TooLarge,
+    /// The request line was longer than the server will take
+    UriTooLong,
+    /// A request had more headers, or more bytes of them, than the server
+    /// will take
+    HeadersTooLarge,
+    /// A request body came in chunks, or some other way than with a
+    /// `Content-Length`
+    LengthRequired,
+    Xml(xml::reader::Error),
+    /// A WebDAV request body was well-formed XML, but not what the method
+    /// calls for
+    MalformedDavBody,
+    /// Two routes claimed the same pattern and method
+    RouteConflict(String)
+}
+
+impl Error {
+    /// The HTTP status a request failing with this error should get
+    ///
+    /// Errors the client caused are 4xx; trouble with the FastCGI application
+    /// is a 502, and anything else is the server's own fault.
+    pub fn status(&self) -> u16 {
+        match *self {
+            Error::Parse(_) | Error::PathNotInOriginForm |
+            Error::IllegalPercentEncoding | Error::RequestIncomplete |
+            Error::Xml(_) | Error::MalformedDavBody => 400,
+            Error::PermissionDenied => 403,
+            Error::LengthRequired => 411,
+            Error::TooLarge => 413,
+            Error::UriTooLong => 414,
+            Error::HeadersTooLarge => 431,
+            Error::Io(ref e) => match e.kind() {
+                io::ErrorKind::PermissionDenied => 403,
+                io::ErrorKind::NotFound => 404,
+                io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => 408,
+                _ => 500
+            },
+            Error::ApplicationServerDisappeared |
+            Error::FastCgiProtocolViolation => 502,
+            Error::Serialization(_) | Error::ParseInt(_) |
+            Error::FromUtf8(_) | Error::FromUtf8Alt(_) | Error::Poison |
+            Error::RouteConflict(_) => 500
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Parse(ref e) => write!(f, "Malformed request: {:?}", e),
+            Error::Io(ref e) => write!(f, "I/O error: {}", e),
+            Error::Serialization(SerializationError::TooLong) =>
+                f.write_str("A FastCGI name or value was too long to send"),
+            Error::ParseInt(ref e) => write!(f, "Bad number: {}", e),
+            Error::FromUtf8(ref e) => write!(f, "Bad UTF-8: {}", e),
+            Error::FromUtf8Alt(ref e) => write!(f, "Bad UTF-8: {}", e),
+            Error::Xml(ref e) => write!(f, "Malformed XML: {}", e),
+            Error::RouteConflict(ref e) =>
+                write!(f, "Conflicting routes: {}", e),
+            _ => f.write_str(error::Error::description(self))
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::Parse(_) => "Malformed request",
+            Error::Io(_) => "I/O error",
+            Error::Serialization(_) => "Couldn't serialize a FastCGI message",
+            Error::ParseInt(_) => "Bad number",
+            Error::FromUtf8(_) | Error::FromUtf8Alt(_) => "Bad UTF-8",
+            Error::Poison => "A lock was poisoned by a panicking thread",
+            Error::ApplicationServerDisappeared =>
+                "The FastCGI application went away",
+            Error::FastCgiProtocolViolation =>
+                "The FastCGI application broke the protocol",
+            Error::PathNotInOriginForm =>
+                "The request target wasn't an absolute path",
+            Error::IllegalPercentEncoding =>
+                "The request path had a bad percent-encoding",
+            Error::PermissionDenied => "Permission denied",
+            Error::RequestIncomplete => "The request ended before its headers",
+            Error::TooLarge => "The request body was too large",
+            Error::UriTooLong => "The request line was too long",
+            Error::HeadersTooLarge => "The request headers were too large",
+            Error::LengthRequired => "The request body had no Content-Length",
+            Error::Xml(_) => "Malformed XML",
+            Error::MalformedDavBody => "The WebDAV request body didn't make \
+                                        sense for the method",
+            Error::RouteConflict(_) => "Conflicting routes"
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            Error::Io(ref e) => Some(e),
+            Error::ParseInt(ref e) => Some(e),
+            Error::FromUtf8(ref e) => Some(e),
+            Error::FromUtf8Alt(ref e) => Some(e),
+            Error::Xml(ref e) => Some(e),
+            _ => None
+        }
+    }
 }
 
 /// Things that can go wrong when serializing FastCGI messages
@@ -43,10 +152,21 @@ impl From<httparse::Error> for Error {
 
 impl From<io::Error> for Error {
     fn from(e: io::Error) -> Error {
+        // One of ours, passed through an `io::Read`
+        if e.get_ref().map_or(false, |inner| inner.is::<Error>()) {
+            return *e.into_inner().unwrap().downcast::<Error>().unwrap();
+        }
+
         Error::Io(e)
     }
 }
 
+impl From<xml::reader::Error> for Error {
+    fn from(e: xml::reader::Error) -> Error {
+        Error::Xml(e)
+    }
+}
+
 impl From<ParseIntError> for Error {
     fn from(e: ParseIntError) -> Error {
         Error::ParseInt(e)
@@ -64,3 +184,38 @@ impl From<FromUtf8Error> for Error {
         Error::FromUtf8Alt(e)
     }
 }
+
+#[test]
+fn client_errors_are_4xx() {
+    assert_eq!(Error::Parse(httparse::Error::Token).status(), 400);
+    assert_eq!(Error::IllegalPercentEncoding.status(), 400);
+    assert_eq!(Error::PermissionDenied.status(), 403);
+    assert_eq!(Error::TooLarge.status(), 413);
+    assert_eq!(Error::UriTooLong.status(), 414);
+    assert_eq!(Error::HeadersTooLarge.status(), 431);
+}
+
+#[test]
+fn errors_come_back_out_of_io_errors() {
+    let passed = io::Error::new(io::ErrorKind::Other, Error::TooLarge);
+
+    assert_eq!(Error::from(passed).status(), 413);
+}
+
+#[test]
+fn io_errors_map_by_kind() {
+    let io = |kind| Error::Io(io::Error::new(kind, "test"));
+
+    assert_eq!(io(io::ErrorKind::NotFound).status(), 404);
+    assert_eq!(io(io::ErrorKind::PermissionDenied).status(), 403);
+    assert_eq!(io(io::ErrorKind::WouldBlock).status(), 408);
+    assert_eq!(io(io::ErrorKind::BrokenPipe).status(), 500);
+}
+
+#[test]
+fn application_errors_are_502() {
+    assert_eq!(Error::ApplicationServerDisappeared.status(), 502);
+    assert_eq!(Error::FastCgiProtocolViolation.status(), 502);
+    assert_eq!(format!("{}", Error::FastCgiProtocolViolation),
+               "The FastCGI application broke the protocol");
+}
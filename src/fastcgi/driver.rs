@@ -2,20 +2,22 @@
 
 use cgi;
 use cgi::parser::doc_headers;
-use config::Config;
+use config::SiteConfig;
 use errors::{Result, Error};
 use fastcgi::{Record, Content, EndRequest, protocol_status};
 use fastcgi::parser::record;
 use fastcgi::serializer::*;
 use log_util::*;
 use server::{Handler, Request, Response, Fresh};
+use server::error_messages::{fail, log_failure};
+use server::header_rules;
 
 use nom::IResult;
 
 use std::ascii::AsciiExt;
 use std::ffi::OsStr;
 use std::io::{self, Write, Read, BufWriter, BufReader, BufRead};
-use std::net::{ToSocketAddrs, TcpStream};
+use std::net::{self, ToSocketAddrs, TcpStream};
 use std::os::unix::ffi::OsStrExt;
 use std::str;
 use std::sync::Mutex;
@@ -25,11 +27,11 @@ use std::sync::atomic::{AtomicUsize, Ordering};
 pub struct Connection {
     conn: Mutex<TcpStream>,
     request_id: AtomicUsize,
-    config: Config
+    config: SiteConfig
 }
 
 impl Connection {
-    pub fn establish<A: ToSocketAddrs>(addr: A, config: &Config)
+    pub fn establish<A: ToSocketAddrs>(addr: A, config: &SiteConfig)
                                        -> Result<Connection>
     {
         // I'd originally planned to configure the FCGI server to adapt to the
@@ -43,6 +45,18 @@ impl Connection {
         })
     }
 
+    /// Closes the connection to the responder, once no request is using it
+    pub fn close(&self) {
+        let conn = self.conn.lock()
+            .unwrap_or_else(|poison| poison.into_inner());
+
+        match conn.shutdown(net::Shutdown::Both) {
+            Ok(()) => (),
+            Err(ref e) if e.kind() == io::ErrorKind::NotConnected => (),
+            Err(e) => warn!("Couldn't close the FastCGI connection: {}", e)
+        }
+    }
+
     /// Like `Handler::serve` but with access to `try!`
     fn serve_inner(&self, mut req: Request, mut res: Response<Fresh>)
                    -> Result<()> {
@@ -52,10 +66,104 @@ impl Connection {
 
         let mut conn = match self.conn.lock() {
             Ok(guard) => guard,
-            Err(_poison) => return Err(Error::Poison)
+            Err(_poison) => return Ok(try!(fail(&Error::Poison, res)))
         };
 
-        try!(self.initialize_request(&mut *conn, request_number as u16, &req));
+        if let Err(e) = self.send_request(&mut *conn, request_number,
+                                          &mut req) {
+            return Ok(try!(fail(&e, res)));
+        }
+
+        // Parse CGI headers from the responder, translating them into HTTP
+        // headers
+        let mut reader = BufReader::new(&mut *conn);
+        let mut buffer = Vec::with_capacity(4096);
+        let unconsumed_buffer_index =
+            match self.read_headers(&mut reader, request_number, &mut res,
+                                    &mut buffer) {
+                Ok(index) => index,
+                Err(e) => return Ok(try!(fail(&e, res)))
+            };
+
+        header_rules::apply(&self.config.header_rules,
+                            req.request_uri().as_bytes(),
+                            res.headers_mut(), true);
+        let mut res = try!(res.start());
+
+        // Send responder output to the client, error to a log, until we get
+        // an END_REQUEST message
+        try!(res.write_all(&buffer[unconsumed_buffer_index ..]));
+        
+        let mut last_buffer_length = 0;
+        loop {
+            let consume = {
+                let buffer = try!(reader.fill_buf()
+                                  .map_err(|e| from_backend(e.into())));
+                if last_buffer_length == buffer.len() {
+                    warn!("Out of responder input before end of headers");
+                    return Err(Error::FastCgiProtocolViolation);
+                }
+                last_buffer_length = buffer.len();
+
+                match record(buffer) {
+                    IResult::Done(rest, Record { id, content }) => {
+                        last_buffer_length = rest.len();
+
+                        if id as usize != request_number {
+                            warn!("Found a message for request {}, this is request {}",
+                                  id, request_number);
+                            return Err(Error::FastCgiProtocolViolation);
+                        }
+
+                        match content {
+                            Content::Stdout(data) => try!(res.write_all(&data[..])),
+                            Content::Stderr(msg) =>
+                                warn!("Error from responder: \"{}\"",
+                                      ascii_escape(&msg[..])),
+                            Content::EndRequest(EndRequest {
+                                app_status, protocol_status
+                            }) => {
+                                if protocol_status != protocol_status::REQUEST_COMPLETE {
+                                    warn!("Got protocol status {}, expected 0",
+                                          protocol_status);
+                                }
+
+                                if app_status != 0 {
+                                    warn!("Responder closed unsuccesfully with code {}",
+                                          app_status);
+                                }
+
+                                break;
+                            },
+                            other => {
+                                warn!("Saw unexpected record kind {}",
+                                      other.kind());
+                                return Err(Error::FastCgiProtocolViolation);
+                            }
+                        };
+
+                        buffer.len() - rest.len()
+                    },
+                    IResult::Error(_) =>
+                        return Err(Error::FastCgiProtocolViolation),
+                    IResult::Incomplete(_) => 0
+                }
+            };
+
+            reader.consume(consume);
+        }
+        
+
+        Ok(())
+    }
+
+    /// Sends the request, and any body it has, to the responder
+    fn send_request(&self, conn: &mut TcpStream, request_number: usize,
+                    req: &mut Request) -> Result<()> {
+        let request_number = request_number as u16;
+
+        try!(self.initialize_request(&mut *conn, request_number, req)
+             .map_err(from_backend));
 
         // Send any request body there might be
         let mut client_buffer = [0; 4096];
@@ -73,24 +181,27 @@ impl Connection {
                 break;
             }
 
-            try!(stdin(&mut *conn, request_number as u16, &client_buffer[.. read]));
+            try!(stdin(&mut *conn, request_number, &client_buffer[.. read])
+                 .map_err(from_backend));
         }
         // Write the stream's sentinel marker
-        try!(stdin(&mut *conn, request_number as u16, &[][..]));
+        stdin(conn, request_number, &[][..]).map_err(from_backend)
+    }
 
-        // Parse CGI headers from the responder, translating them into HTTP
-        // headers
-        let mut reader = BufReader::new(&mut *conn);
-        let mut buffer = Vec::with_capacity(4096);
+    /// Reads the responder's CGI headers into `res`, returning where the body
+    /// starts in `buffer`
+    fn read_headers<R: BufRead>(&self, reader: &mut R, request_number: usize,
+                                res: &mut Response<Fresh>,
+                                buffer: &mut Vec<u8>) -> Result<usize> {
         let mut unconsumed_buffer_index = 0;
         let mut last_buffer_length = 0;
         let mut headers_finished = false;
         while !headers_finished {
             let consumed = {
-                let read_buffer = try!(reader.fill_buf());
+                let read_buffer = try!(reader.fill_buf()
+                                       .map_err(|e| from_backend(e.into())));
                 if last_buffer_length == read_buffer.len() {
-                    // deal with unexpected eof
-                    unimplemented!();
+                    return Err(Error::ApplicationServerDisappeared);
                 }
                 last_buffer_length = read_buffer.len();
                 
@@ -135,7 +246,8 @@ impl Connection {
                                     headers_finished = true;
                             },
                             IResult::Incomplete(_) => (),
-                            IResult::Error(_) => unimplemented!()
+                            IResult::Error(_) =>
+                                return Err(Error::FastCgiProtocolViolation)
                         }
 
                         read_buffer.len() - rest.len()
@@ -154,78 +266,14 @@ impl Connection {
                         return Err(Error::FastCgiProtocolViolation);
                     },
                     IResult::Incomplete(_) => 0,
-                    IResult::Error(_) => unimplemented!()
+                    IResult::Error(_) =>
+                        return Err(Error::FastCgiProtocolViolation)
                 }
             };
             reader.consume(consumed);
         }
-        let mut res = try!(res.start());
-
-        // Send responder output to the client, error to a log, until we get
-        // an END_REQUEST message
-        try!(res.write_all(&buffer[unconsumed_buffer_index ..]));
-        
-        last_buffer_length = 0;
-        loop {
-            let consume = {
-                let buffer = try!(reader.fill_buf());
-                if last_buffer_length == buffer.len() {
-                    warn!("Out of responder input before end of headers");
-                    return Err(Error::FastCgiProtocolViolation);
-                }
-                last_buffer_length = buffer.len();
 
-                match record(buffer) {
-                    IResult::Done(rest, Record { id, content }) => {
-                        last_buffer_length = rest.len();
-
-                        if id as usize != request_number {
-                            warn!("Found a message for request {}, this is request {}",
-                                  id, request_number);
-                            return Err(Error::FastCgiProtocolViolation);
-                        }
-
-                        match content {
-                            Content::Stdout(data) => try!(res.write_all(&data[..])),
-                            Content::Stderr(msg) =>
-                                warn!("Error from responder: \"{}\"",
-                                      ascii_escape(&msg[..])),
-                            Content::EndRequest(EndRequest {
-                                app_status, protocol_status
-                            }) => {
-                                if protocol_status != protocol_status::REQUEST_COMPLETE {
-                                    warn!("Got protocol status {}, expected 0",
-                                          protocol_status);
-                                }
-
-                                if app_status != 0 {
-                                    warn!("Responder closed unsuccesfully with code {}",
-                                          app_status);
-                                }
-
-                                break;
-                            },
-                            other => {
-                                warn!("Saw unexpected record kind {}",
-                                      other.kind());
-                                return Err(Error::FastCgiProtocolViolation);
-                            }
-                        };
-
-                        buffer.len() - rest.len()
-                    },
-                    IResult::Error(_e) => {
-                        unimplemented!()
-                    },
-                    IResult::Incomplete(_) => 0
-                }
-            };
-
-            reader.consume(consume);
-        }
-        
-
-        Ok(())
+        Ok(unconsumed_buffer_index)
     }
 
     /// Initializes the request to the responder
@@ -244,7 +292,7 @@ impl Connection {
                           name.replace("-", "_").to_ascii_uppercase()),
                   value))
             .collect();
-        let translated_path = self.config.stat.webroot
+        let translated_path = self.config.document_root()
             .join(OsStr::from_bytes(&req.request_uri().as_bytes()[1..]));
 
         let mut metavars = Vec::new();
@@ -253,9 +301,7 @@ impl Connection {
         metavars.push((&b"PATH_TRANSLATED"[..],
                        translated_path.as_os_str().as_bytes()));
 
-        let query_string = req.request_uri().as_bytes().iter()
-            .position(|&b| b == b'?')
-            .map_or(&b""[..], |i| req.request_uri().as_bytes().split_at(i).1);
+        let query_string = req.query().map_or(&b""[..], OsStrExt::as_bytes);
         metavars.push((&b"QUERY_STRING"[..], query_string));
 
         metavars.push((&b"REMOTE_ADDR"[..], remote_addr.as_bytes()));
@@ -268,6 +314,9 @@ impl Connection {
         metavars.push((&b"SERVER_PROTOCOL"[..], &b"HTTP/1.1"[..]));
         metavars.push((&b"SERVER_SOFTWARE"[..],
                        &b"toy http-server 0.2 (hella unstable)"[..]));
+        if req.secure {
+            metavars.push((&b"HTTPS"[..], &b"on"[..]));
+        }
 
         for &(ref name, value) in &headers {
             metavars.push((name.as_bytes(), value));
@@ -283,8 +332,38 @@ impl Connection {
 
 impl Handler for Connection {
     fn serve(&self, req: Request, res: Response<Fresh>) {
+        let id = req.id().to_owned();
+
         if let Err(e) = self.serve_inner(req, res) {
-            warn!("Error serving FastCGI: {:?}", e);
+            log_failure(&e, &id);
         }
     }
 }
+
+/// Puts an I/O failure talking to the responder down to the responder
+///
+/// Left as an `Error::Io`, a responder that timed out would look like a slow
+/// client, and one that hung up like the server's own fault.
+fn from_backend(e: Error) -> Error {
+    match e {
+        Error::Io(e) => {
+            warn!("Lost the FastCGI responder: {}", e);
+            Error::ApplicationServerDisappeared
+        },
+        e => e
+    }
+}
+
+#[test]
+fn responder_io_errors_are_502s() {
+    for &kind in &[io::ErrorKind::TimedOut, io::ErrorKind::WouldBlock,
+                   io::ErrorKind::BrokenPipe, io::ErrorKind::ConnectionReset] {
+        assert_eq!(from_backend(Error::Io(io::Error::new(kind, "gone")))
+                   .status(), 502);
+    }
+
+    match from_backend(Error::TooLarge) {
+        Error::TooLarge => (),
+        other => panic!("{:?} came back as {:?}", Error::TooLarge, other)
+    }
+}
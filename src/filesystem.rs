@@ -6,17 +6,18 @@ use errors::{Result, Error};
 ///
 /// The following operations are performed:
 ///
-/// 1. Sequences of multiple `'/'` characters are collapsed into a single `'/'`.
-/// 2. Percent-encoded bytes are decoded. Bogus percent-encoding, like `b"%bo"`,
-///    will return `Err`.
+/// 1. Sequences of multiple `'/'` characters are collapsed into a single `'/'`,
+///    including the leading one, which is kept.
+/// 2. Percent-encoded bytes are decoded. Bogus percent-encoding, like `b"%bo"`
+///    or a `b"%2"` cut short, will return `Err`.
 pub fn normalize_path(path: &[u8]) -> Result<Vec<u8>> {
-    let mut buffer = Vec::with_capacity(path.len() - 1);
-
     // Check for a leading `'/'`
-    if path[0] != 0x2F {
+    if path.first() != Some(&0x2F) {
         return Err(Error::PathNotInOriginForm);
     }
 
+    let mut buffer = Vec::with_capacity(path.len());
+
     let mut i = 1;
 
     // skip leading '/' characters
@@ -36,7 +37,7 @@ pub fn normalize_path(path: &[u8]) -> Result<Vec<u8>> {
             },
             // '%'
             0x25 => {
-                if !(path.len() >= i + 2) {
+                if !(path.len() >= i + 3) {
                     return Err(Error::IllegalPercentEncoding);
                 }
 
@@ -62,29 +63,76 @@ pub fn normalize_path(path: &[u8]) -> Result<Vec<u8>> {
     Ok(buffer)
 }
 
+/// Splits a request target into its path and query string
+///
+/// The query string comes back without its `'?'`, and still
+/// percent-encoded; only the path is normalized.
+pub fn split_query(target: &[u8]) -> (&[u8], Option<&[u8]>) {
+    match target.iter().position(|&b| b == b'?') {
+        Some(i) => (&target[.. i], Some(&target[i + 1 ..])),
+        None => (target, None)
+    }
+}
+
+/// Whether the request path `path` is `root` or somewhere below it
+///
+/// Trailing `'/'` characters don't matter, so `/a/` is within `/a`, but
+/// `/ab` is not.
+pub fn is_within(path: &[u8], root: &[u8]) -> bool {
+    let root = trim_trailing_slashes(root);
+    let path = trim_trailing_slashes(path);
+
+    root.is_empty() ||
+        (path.starts_with(root) &&
+         (path.len() == root.len() || path[root.len()] == b'/'))
+}
+
+fn trim_trailing_slashes(path: &[u8]) -> &[u8] {
+    let end = path.iter().rposition(|&b| b != b'/').map_or(0, |i| i + 1);
+    &path[..end]
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
 
     #[test]
-    fn normalize_strips_leading_slashes() {
-        assert_eq!(normalize_path(b"/blah").unwrap(), b"blah");
-        assert_eq!(normalize_path(b"//bleh").unwrap(), b"bleh");
+    fn queries_are_split_at_the_first_question_mark() {
+        assert_eq!(split_query(b"/a?b=c?d"), (&b"/a"[..], Some(&b"b=c?d"[..])));
+        assert_eq!(split_query(b"/a?"), (&b"/a"[..], Some(&b""[..])));
+        assert_eq!(split_query(b"/a%3Fb"), (&b"/a%3Fb"[..], None));
+    }
+
+    #[test]
+    fn is_within_respects_segments() {
+        assert!(is_within(b"/a/b", b"/a"));
+        assert!(is_within(b"/a/", b"/a"));
+        assert!(is_within(b"/a", b"/"));
+        assert!(!is_within(b"/ab", b"/a"));
+        assert!(!is_within(b"/", b"/a"));
+    }
+
+    #[test]
+    fn normalize_collapses_leading_slashes() {
+        assert_eq!(normalize_path(b"/blah").unwrap(), b"/blah");
+        assert_eq!(normalize_path(b"//bleh").unwrap(), b"/bleh");
     }
 
     #[test]
     fn normalize_collapses_embedded_slash_sequences() {
-        assert_eq!(normalize_path(b"/foo//bar").unwrap(), b"foo/bar");
+        assert_eq!(normalize_path(b"/foo//bar").unwrap(), b"/foo/bar");
     }
 
     #[test]
     fn normalize_decodes_percents() {
-        assert_eq!(normalize_path(b"/foo%20bar").unwrap(), b"foo bar");
+        assert_eq!(normalize_path(b"/foo%20bar").unwrap(), b"/foo bar");
     }
 
     #[test]
     fn normalize_handles_trailing_percents_correctly() {
-        assert_eq!(normalize_path(b"/trail%20").unwrap(), b"trail ");
+        assert_eq!(normalize_path(b"/trail%20").unwrap(), b"/trail ");
+        assert!(normalize_path(b"/trail%2").is_err());
+        assert!(normalize_path(b"/trail%").is_err());
     }
 
     #[test]
@@ -95,6 +143,7 @@ mod test {
     #[test]
     fn normalize_errors_without_leading_slash() {
         assert!(normalize_path(b"bogus").is_err());
+        assert!(normalize_path(b"").is_err());
     }
 }
 
@@ -0,0 +1,166 @@
+//! Shell-style glob patterns over request paths
+//!
+//! The syntax is deliberately small:
+//!
+//! - `?` matches any single byte other than `'/'`
+//! - `*` matches any run of bytes not containing `'/'`
+//! - `**` matches any run of bytes at all, so `/assets/**` is everything below
+//!   `/assets/`, and `/a/**/b` also matches `/a/b`
+//! - everything else matches itself
+//!
+//! A pattern with no `'/'` in it is matched against the last segment of the
+//! path only, so `*.html` means "any HTML file, wherever it is".
+
+use std::fmt;
+
+/// A compiled glob pattern
+#[derive(Clone, PartialEq, Eq)]
+pub struct Glob {
+    pattern: Vec<u8>,
+    basename_only: bool
+}
+
+impl Glob {
+    pub fn new(pattern: &str) -> Glob {
+        Glob {
+            pattern: Vec::from(pattern.as_bytes()),
+            basename_only: !pattern.contains('/')
+        }
+    }
+
+    /// Returns `true` iff `path` matches this pattern
+    pub fn matches(&self, path: &[u8]) -> bool {
+        if self.basename_only {
+            let name = path.rsplit(|&b| b == b'/').next().unwrap_or(path);
+            glob_match(&self.pattern, name)
+        }
+        else {
+            glob_match(&self.pattern, path)
+        }
+    }
+
+    /// Translates this pattern into an equivalent byte regex, in which each
+    /// wildcard is a capture group, numbered from `1` in order
+    pub fn to_regex(&self) -> String {
+        let mut regex = String::from(if self.basename_only {
+            "(?s-u)^(?:.*/)?"
+        }
+        else {
+            "(?s-u)^"
+        });
+
+        let mut i = 0;
+        while i < self.pattern.len() {
+            match self.pattern[i] {
+                b'*' if self.pattern.get(i + 1) == Some(&b'*') => {
+                    // `/**/` may stand for a single slash
+                    if self.pattern.get(i + 2) == Some(&b'/') {
+                        regex.push_str("(?:(.*)/)?");
+                        i += 3;
+                    }
+                    else {
+                        regex.push_str("(.*)");
+                        i += 2;
+                    }
+                    continue;
+                },
+                b'*' => regex.push_str("([^/]*)"),
+                b'?' => regex.push_str("([^/])"),
+                b if b < 0x80 && (b as char).is_alphanumeric() =>
+                    regex.push(b as char),
+                b => regex.push_str(&format!("\\x{:02X}", b))
+            }
+            i += 1;
+        }
+
+        regex.push('$');
+        regex
+    }
+}
+
+impl fmt::Debug for Glob {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Glob({:?})", String::from_utf8_lossy(&self.pattern))
+    }
+}
+
+fn glob_match(pattern: &[u8], subject: &[u8]) -> bool {
+    match pattern.first() {
+        None => subject.is_empty(),
+        Some(&b'*') if pattern.get(1) == Some(&b'*') => {
+            let rest = &pattern[2..];
+
+            // `/**/` may stand for a single slash
+            if rest.first() == Some(&b'/') && glob_match(&rest[1..], subject) {
+                return true;
+            }
+
+            (0 .. subject.len() + 1).any(|i| glob_match(rest, &subject[i..]))
+        },
+        Some(&b'*') => {
+            (0 .. subject.len() + 1)
+                .take_while(|&i| i == 0 || subject[i - 1] != b'/')
+                .any(|i| glob_match(&pattern[1..], &subject[i..]))
+        },
+        Some(&b'?') => {
+            !subject.is_empty() && subject[0] != b'/' &&
+                glob_match(&pattern[1..], &subject[1..])
+        },
+        Some(&b) => {
+            !subject.is_empty() && subject[0] == b &&
+                glob_match(&pattern[1..], &subject[1..])
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn literal_patterns_match_exactly() {
+        assert!(Glob::new("/index.html").matches(b"/index.html"));
+        assert!(!Glob::new("/index.html").matches(b"/index.htm"));
+    }
+
+    #[test]
+    fn single_star_stays_in_segment() {
+        let glob = Glob::new("/html/*.css");
+        assert!(glob.matches(b"/html/site.css"));
+        assert!(!glob.matches(b"/html/assets/site.css"));
+    }
+
+    #[test]
+    fn double_star_crosses_segments() {
+        let glob = Glob::new("/html/assets/**");
+        assert!(glob.matches(b"/html/assets/app.js"));
+        assert!(glob.matches(b"/html/assets/img/logo.png"));
+        assert!(!glob.matches(b"/html/index.html"));
+
+        assert!(Glob::new("/a/**/b").matches(b"/a/b"));
+        assert!(Glob::new("/a/**/b").matches(b"/a/x/y/b"));
+    }
+
+    #[test]
+    fn question_mark_matches_one_byte() {
+        assert!(Glob::new("/v?/").matches(b"/v1/"));
+        assert!(!Glob::new("/v?/").matches(b"/v10/"));
+        assert!(!Glob::new("/a?b").matches(b"/a/b"));
+    }
+
+    #[test]
+    fn regexes_capture_wildcards() {
+        assert_eq!(Glob::new("/a/**/b-?.*").to_regex(),
+                   "(?s-u)^\\x2Fa\\x2F(?:(.*)/)?b\\x2D([^/])\\x2E([^/]*)$");
+        assert_eq!(Glob::new("*.html").to_regex(),
+                   "(?s-u)^(?:.*/)?([^/]*)\\x2Ehtml$");
+    }
+
+    #[test]
+    fn slashless_patterns_match_basename() {
+        let glob = Glob::new("*.html");
+        assert!(glob.matches(b"/index.html"));
+        assert!(glob.matches(b"/deep/down/page.html"));
+        assert!(!glob.matches(b"/page.html/data.json"));
+    }
+}
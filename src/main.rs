@@ -8,12 +8,47 @@
 //! the Rust ecosystem. Here is an example:
 //!
 //! ```toml
-//! [listen]
-//! port = 8000
+//! # pidfile = "/run/http-server.pid"   # none unless given
+//!
+//! [[listen]]
+//! address = "0.0.0.0:8000"
+//! backlog = 128
+//! reuse_port = false
+//! nodelay = false
+//! fastopen = 0                # queue length; 0 for off
+//!
+//! [timeouts]
+//! header = 10                 # seconds
+//! body_min_rate = 1024        # bytes per second
+//! write = 30                  # seconds
+//! keep_alive = 5              # seconds
+//! shutdown = 30               # seconds
+//!
+//! [limits]
+//! request_line = 8192         # bytes
+//! header_bytes = 32768
+//! headers = 100
+//! body = 104857600
 //!
 //! [static]
 //! webroot = "/etc/http-server/site"
 //! public_prefix = "/html"
+//! default_charset = "utf-8"
+//! deny_dotfiles = true
+//! deny = []
+//! symlinks = "follow"
+//! try_files = ["$uri", "=404"]
+//! index = ["index.html"]
+//! listing = false
+//!
+//! [static.mime_types]
+//! # Extensions mime_guess doesn't know, or that it gets wrong
+//!
+//! [static.cache]
+//! entries = 0                 # off; try 1024
+//! bytes = 16777216
+//! max_file_size = 65536
+//! revalidate = 2              # seconds
 //!
 //! [fastcgi]
 //! host = "localhost"
@@ -24,9 +59,213 @@
 //! or any given key is not present. If a key is of the wrong type, the server
 //! will bail, so don’t do that.
 //!
-//! `http-server` will listen for connections from any IP address, and
-//! understands only GET requests. It speaks only the bare minimum of HTTP to
-//! perform that task, and doesn’t care about things like Accept headers.
+//! Each connection gets a thread of its own. A client has `header` seconds to
+//! send a request's line and headers, counted from connecting, or from the
+//! first byte of a later request on a kept-alive connection; past that it gets
+//! a 408. Request bodies have to arrive at `body_min_rate` bytes per second on
+//! average, after five seconds' grace (0 turns that off), and a write to the
+//! client may block for `write` seconds. HTTP/1.1 connections wait up to
+//! `keep_alive` seconds for another request; 0 closes them after one.
+//! HTTP/1.x request bodies need a `Content-Length`: chunked ones aren't
+//! decoded, and get a 411.
+//!
+//! Clients may speak HTTP/2 instead, by asking for `h2` with ALPN over TLS,
+//! or by starting a plain connection with HTTP/2's preface, as clients that
+//! know the server speaks it do. Each of a connection's streams is served on
+//! a thread of its own, up to 100 at once, by the same statics and FastCGI
+//! as HTTP/1.1. An HTTP/2 connection with no streams open is closed after
+//! `keep_alive` seconds, or after `header` seconds if it's yet to make a
+//! request. The server never pushes.
+//!
+//! Write `[[listen]]` once per address to listen on; the server accepts on
+//! all of them at once. An address is an IPv4 or IPv6 address with a port,
+//! like `"[::]:8000"`, or a Unix socket, like `"unix:/run/http-server.sock"`.
+//! IPv6 listeners take IPv4 connections too unless `ipv6_only = true`, going
+//! by the system's default if it isn't given. Unix sockets don't take the TCP
+//! options, but may have a `mode`, like `"0660"`, and an `owner` and `group`,
+//! by name or ID. A lone `[listen]` with just a `port` still works, and
+//! listens on every IPv4 address.
+//!
+//! A listener given a PEM `certificate` chain and its `key` speaks HTTPS:
+//!
+//! ```toml
+//! [[listen]]
+//! address = "[::]:443"
+//! certificate = "/etc/http-server/example.com.pem"
+//! key = "/etc/http-server/example.com.key"
+//! ```
+//!
+//! It offers HTTP/2 and HTTP/1.1 with ALPN, lets clients resume sessions by
+//! ID or ticket, and tells FastCGI applications `HTTPS=on`. A site (see below)
+//! can have its own certificate, with `certificate` and `key` in
+//! `[site.tls]`, for clients that ask for one of its names with SNI; clients
+//! that don't name a host get the default site's. Sites without one get the
+//! listener's. Unix sockets don't speak HTTPS.
+//!
+//! SIGTERM or SIGINT stops the server taking connections, and gives requests
+//! already being served, FastCGI ones included, `shutdown` seconds to finish
+//! before it exits; idle connections are closed straight away. The exit status
+//! is 0 if everything finished in time, and 2 if something had to be cut off.
+//! SIGQUIT cuts everything off at once.
+//!
+//! SIGHUP rereads the config file. Connections made after that are served
+//! under the new configuration, while those already open carry on under the
+//! old one; if the file has errors, they're logged and the old configuration
+//! stays. Only the listeners need a restart to change, though certificates
+//! are read afresh; connections already open keep the ones they started with.
+//!
+//! To upgrade without turning anyone away, replace the binary and send
+//! SIGUSR2. The server starts the new binary with the same arguments, passing
+//! it the listening sockets in `HTTP_SERVER_LISTEN_FD`; once the new server is
+//! accepting, it sends the old one SIGTERM, so the old one drains and exits
+//! as above. If the new one fails to start, the old one keeps serving. The
+//! `pidfile`, if given, always holds the process ID of the server that's
+//! taking connections. Listeners added to the config file since are opened
+//! by the new server.
+//!
+//! Under systemd, the server takes its listening sockets from socket
+//! activation (`LISTEN_FDS`) if there are any, instead of opening the ones
+//! configured; `[[listen]]` entries with the same addresses still say which
+//! get `nodelay`. With `Type=notify` it reports when it's ready, reloading and
+//! stopping, and feeds the watchdog if `WatchdogSec=` is set. For upgrades by
+//! SIGUSR2 to be tracked, the unit also needs `NotifyAccess=all`.
+//!
+//! Requests whose request line is longer than `request_line` get a 414, those
+//! with more than `headers` header fields, or `header_bytes` of them, a 431,
+//! and those with a body bigger than `body` a 413.
+//!
+//! Entries in `[static.mime_types]` look like `wasm = "application/wasm"`.
+//! The `default_charset` is added to `text/*`, JavaScript and JSON responses
+//! that don't name their own; set it to `""` to turn that off.
+//!
+//! The cache remembers up to `entries` resolved paths, keeping the contents of
+//! files up to `max_file_size` bytes in memory, `bytes` in all. An entry older
+//! than `revalidate` seconds is checked against the file's size and mtime
+//! before it's used again. On SIGUSR1, each mount's hit and miss counts are
+//! logged, along with how many entries and bytes its cache holds.
+//!
+//! To serve several directories, write `[[static]]` once per mount instead of
+//! `[static]`; each takes all the keys above, and its own `[[static.headers]]`
+//! rules (see below), applied after the global ones. Where prefixes overlap,
+//! the longest one takes the request, whatever order they're written in.
+//!
+//! With `deny_dotfiles` set, any path with a segment starting in `.` is
+//! refused, except under `.well-known`. Paths matching one of the `deny` globs,
+//! like `"**/*.bak"`, are refused too. `symlinks` may be `"follow"`,
+//! `"follow-if-owner-matches"` or `"never"`; links out of the webroot are
+//! never followed regardless.
+//!
+//! `try_files` works like nginx’s: each entry but the last is a path to look
+//! for, with `$uri` replaced by the request path and a trailing `/` meaning
+//! that directory’s first `index` file, or its listing if `listing` is on. The
+//! first that exists is served. The last entry is the fallback: `=404` (or
+//! another error status), `@fastcgi` to hand the request to the FastCGI
+//! application, or a path to serve regardless, like
+//! `["$uri", "/html/index.html"]` for a single-page app.
+//!
+//! Response headers can be adjusted per path with `[[headers]]` rules, which
+//! match a glob `path`, a list of `extensions`, or both:
+//!
+//! ```toml
+//! [[headers]]
+//! path = "/html/assets/**"
+//! set = { Cache-Control = "public, max-age=31536000, immutable" }
+//!
+//! [[headers]]
+//! extensions = ["html"]
+//! fastcgi = true              # also apply to FastCGI responses
+//! set = { Cache-Control = "no-cache" }
+//! append = { Vary = "Cookie" }
+//! remove = ["X-Powered-By"]
+//! ```
+//!
+//! Old paths can be redirected, or quietly rewritten to new ones, with
+//! `[[rewrite]]` rules. Each matches a `regex` or a `glob` against the request
+//! path, without the query string, and substitutes captures into `to` as `$1`,
+//! or `$name` for named groups; each wildcard in a glob is a capture. A rule
+//! with a `redirect` status (301, 302, 307 or 308) answers with a `Location`;
+//! one without rewrites the path and routes the request again.
+//!
+//! ```toml
+//! [[rewrite]]
+//! regex = "^/blog/(?P<year>[0-9]{4})/(.*)\\.php$"
+//! to = "/posts/$year/$2"
+//! redirect = 301
+//!
+//! [[rewrite]]
+//! glob = "/app/**"
+//! to = "/index.html"
+//! ```
+//!
+//! Rules are tried in order and the first match wins, once: a rewritten path
+//! isn't matched again, so a rule's target may match its own pattern, like a
+//! front controller's `"^/(.*)$"` to `"/index.php?q=$1"`. A rule with
+//! `chain = true` has the rules tried again from the top on its target
+//! instead, but a request rewritten more than ten times, or back to a path it
+//! had before, fails with a 500. The query string is kept unless `to` has its
+//! own.
+//!
+//! Error pages can be replaced per status, from a file or with HTML written in
+//! the config. In either, `{status}`, `{reason}` and `{request_id}` are filled
+//! in; the request ID also appears in the log.
+//!
+//! ```toml
+//! [error_pages.404]
+//! file = "/etc/http-server/404.html"
+//!
+//! [error_pages.500]
+//! template = "<h1>{status} {reason}</h1><p>Quote {request_id} to us.</p>"
+//! ```
+//!
+//! Clients whose `Accept` header asks for JSON and not HTML get an
+//! `application/problem+json` document instead of any page.
+//!
+//! A mount with a `[static.writable]` table speaks WebDAV (classes 1 and 2)
+//! to clients that send one of its users' credentials with HTTP Basic
+//! authentication, so it can be mounted by file managers or used as an upload
+//! target:
+//!
+//! ```toml
+//! [static.writable]
+//! max_size = 104857600        # bytes per upload
+//! users = { ci = "secret" }
+//! ```
+//!
+//! Uploads are written beside their target and renamed into place once
+//! complete, so readers never see half a file. Locks and dead properties are
+//! kept in memory, so a restart drops them. `PROPFIND` with `Depth: infinity`
+//! is refused, and `COPY` skips symbolic links. Basic authentication sends
+//! passwords in the clear; only use it behind TLS or on a trusted network.
+//!
+//! Several sites can share the server, each picked by the host a request is
+//! for. Write a `[[site]]` block per site, holding the `static`, `fastcgi`,
+//! `headers`, `rewrite` and `error_pages` settings above. `[[headers]]` and
+//! `[[rewrite]]` rules outside any site apply to all of them first, and
+//! `[error_pages]` outside any site fill in for those a site doesn't have.
+//!
+//! ```toml
+//! [[site]]
+//! server_names = ["example.com", "www.example.*"]
+//! default = true
+//!
+//! [[site.static]]
+//! webroot = "/srv/example"
+//! public_prefix = "/"
+//!
+//! [[site]]
+//! server_names = ["*.example.org"]
+//!
+//! [site.fastcgi]
+//! port = 9001
+//! ```
+//!
+//! A site matches by exact name first, then by the longest `*.` wildcard, then
+//! by the longest `.*` one. Requests for any other host, or with no `Host`
+//! header, go to the `default` site, or the first if none is marked.
+//!
+//! Static files answer `GET`, `HEAD` and `OPTIONS`; everything else goes to
+//! FastCGI. It speaks only the bare minimum of HTTP to perform that task, and
+//! doesn’t care about things like Accept headers.
 //!
 //! [toml]: https://github.com/toml-lang/toml
 
@@ -34,22 +273,29 @@ extern crate byteorder;
 extern crate clap;
 extern crate env_logger;
 extern crate httparse;
+extern crate libc;
 #[macro_use] extern crate log;
 #[macro_use] extern crate mime;
 extern crate mime_guess;
 #[macro_use] extern crate nom;
+extern crate regex;
+extern crate rustls;
+extern crate rustls_pemfile;
 extern crate toml;
+extern crate xml;
 
 mod cgi;
 mod config;
 mod errors;
 mod fastcgi;
 mod filesystem;
+mod glob;
 mod log_util;
 mod server;
 
+use config::Config;
 use config::parser::{self, parse_file};
-use server::serve;
+use server::{serve, Shutdown};
 
 use clap::{Arg, App};
 
@@ -59,6 +305,34 @@ use std::io::{stderr, Write};
 use std::os::unix::ffi::OsStrExt;
 use std::process::exit;
 
+/// Reads the config file, logging what's wrong with it if it can't be used
+fn load_config(config_file: &OsStr) -> Option<Config> {
+    match parse_file(config_file) {
+        Ok(c) => Some(c),
+        Err(parser::Error::Io(e)) => {
+            log!(log::LogLevel::Error, "Error opening config file {:?}: {}",
+                 config_file, e);
+            None
+        },
+        Err(parser::Error::Parse(e)) => {
+            log!(log::LogLevel::Error,
+                 "Errors parsing config file {:?}", config_file);
+            for error in e {
+                log!(
+                    log::LogLevel::Error,
+                    "Config file error at line {} column {}: {}",
+                    error.line, error.column, error.desc);
+            }
+            None
+        },
+        Err(parser::Error::Validation(message)) => {
+            log!(log::LogLevel::Error,
+                 "Error in config file: {}", message);
+            None
+        }
+    }
+}
+
 fn main() {
     let mut log_builder = env_logger::LogBuilder::new();
     log_builder.filter(None, log::LogLevelFilter::Info);
@@ -90,33 +364,18 @@ fn main() {
     let config_file = matches.value_of_os("config_file")
         .unwrap_or(OsStr::from_bytes(b"/etc/http-server/config.toml"));
 
-    let config = match parse_file(config_file) {
-        Ok(c) => c,
-        Err(parser::Error::Io(e)) => {
-            log!(log::LogLevel::Error, "Error opening config file {:?}: {}",
-                 config_file, e);
-            exit(1);
-        },
-        Err(parser::Error::Parse(e)) => {
-            log!(log::LogLevel::Error,
-                 "Errors parsing config file {:?}", config_file);
-            for error in e {
-                log!(
-                    log::LogLevel::Error,
-                    "Config file error at line {} column {}: {}",
-                    error.line, error.column, error.desc);
-            }
-            exit(1);
-        },
-        Err(parser::Error::Validation(message)) => {
-            log!(log::LogLevel::Error,
-                 "Error in config file: {}", message);
-            exit(1);
-        }
+    let config = match load_config(config_file) {
+        Some(c) => c,
+        None => exit(1)
     };
 
-    log!(log::LogLevel::Info, "Starting server on port {}", config.port);
-    if let Err(_) = serve(config) {
-        exit(1);
+    log!(log::LogLevel::Info, "Starting server");
+    match serve(config, || load_config(config_file)) {
+        Ok(Shutdown::Graceful) => info!("Stopped"),
+        Ok(Shutdown::Forced) => exit(2),
+        Err(e) => {
+            log!(log::LogLevel::Error, "Server failed: {}", e);
+            exit(1);
+        }
     }
 }
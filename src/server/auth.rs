@@ -0,0 +1,80 @@
+//! HTTP Basic authentication
+
+use server::Request;
+
+/// Builds the `Authorization` header value a client would send for these
+/// credentials
+pub fn basic_credentials(user: &str, password: &str) -> Vec<u8> {
+    let mut value = Vec::from(&b"Basic "[..]);
+    value.extend_from_slice(&base64(format!("{}:{}", user, password)
+                                    .as_bytes()));
+    value
+}
+
+/// Whether the request carries one of the `accepted` credentials, as built by
+/// `basic_credentials`
+pub fn authorized(req: &Request, accepted: &[Vec<u8>]) -> bool {
+    match req.headers().get("Authorization") {
+        Some(given) => accepted.iter().any(|a| constant_time_eq(a, given)),
+        None => false
+    }
+}
+
+/// Compares without bailing at the first difference, so response times don't
+/// give away how much of a guess was right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b.iter()).fold(0, |acc, (&x, &y)| acc | (x ^ y)) == 0
+}
+
+fn base64(input: &[u8]) -> Vec<u8> {
+    const ALPHABET: &'static [u8] =
+        b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut output = Vec::with_capacity((input.len() + 2) / 3 * 4);
+
+    for chunk in input.chunks(3) {
+        let b0 = chunk[0] as u32;
+        let b1 = chunk.get(1).map_or(0, |&b| b as u32);
+        let b2 = chunk.get(2).map_or(0, |&b| b as u32);
+        let triple = b0 << 16 | b1 << 8 | b2;
+
+        output.push(ALPHABET[(triple >> 18 & 0x3F) as usize]);
+        output.push(ALPHABET[(triple >> 12 & 0x3F) as usize]);
+
+        if chunk.len() > 1 {
+            output.push(ALPHABET[(triple >> 6 & 0x3F) as usize]);
+        }
+        else {
+            output.push(b'=');
+        }
+
+        if chunk.len() > 2 {
+            output.push(ALPHABET[(triple & 0x3F) as usize]);
+        }
+        else {
+            output.push(b'=');
+        }
+    }
+
+    output
+}
+
+#[test]
+fn base64_pads_correctly() {
+    assert_eq!(base64(b""), b"");
+    assert_eq!(base64(b"f"), b"Zg==");
+    assert_eq!(base64(b"fo"), b"Zm8=");
+    assert_eq!(base64(b"foo"), b"Zm9v");
+    assert_eq!(base64(b"foob"), b"Zm9vYg==");
+}
+
+#[test]
+fn basic_credentials_match_rfc_example() {
+    // From RFC 7617
+    assert_eq!(basic_credentials("Aladdin", "open sesame"),
+               &b"Basic QWxhZGRpbjpvcGVuIHNlc2FtZQ=="[..]);
+}
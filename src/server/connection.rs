@@ -0,0 +1,528 @@
+//! Client connections, and the timeouts that keep slow clients from holding
+//! them
+//!
+//! Each connection is served on its own thread, one request after another for
+//! as long as it's kept alive. A client gets `Timeouts::header` seconds from
+//! connecting, or from the first byte of a later request, to send the request
+//! line and headers. After that, its body has to keep up `body_min_rate`
+//! bytes per second on average, once `BODY_GRACE` seconds have passed.
+//!
+//! Once the server starts shutting down, connections are drained: each
+//! finishes the request it's serving, if any, and closes.
+//!
+//! Clients that ask for HTTP/2 by ALPN, or open with its preface, are handed
+//! over to `http2` once the TLS handshake's done.
+
+use config::{Limits, Timeouts};
+use server::{Handler, make_request_pair};
+use server::http2;
+use server::listener::Stream;
+
+use std::cmp;
+use std::io::{self, Read, ErrorKind};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Seconds a request body has before its rate is held against it
+const BODY_GRACE: u64 = 5;
+
+/// Whether connections should close instead of waiting for more requests
+static DRAINING: AtomicBool = AtomicBool::new(false);
+
+/// Closes every connection once it's done with its current request
+pub fn drain() {
+    DRAINING.store(true, Ordering::SeqCst);
+}
+
+pub fn draining() -> bool {
+    DRAINING.load(Ordering::SeqCst)
+}
+
+/// Serves requests from `stream` until it's closed, times out, or something
+/// rules out keeping it alive
+pub fn serve(stream: Stream, handler: &Handler, timeouts: &Timeouts,
+             limits: &Limits) {
+    let write_timeout = Some(Duration::from_secs(timeouts.write));
+    if let Err(e) = stream.set_write_timeout(write_timeout) {
+        warn!("Failed connection: {}", e);
+        return;
+    }
+
+    // A TLS handshake gets as long for each read as the headers do in all
+    let connected = Instant::now();
+    let header_timeout = Some(Duration::from_secs(timeouts.header));
+    if let Err(e) = stream.set_read_timeout(header_timeout)
+        .and_then(|_| stream.handshake()) {
+            debug!("TLS handshake failed: {}", e);
+            return;
+        }
+
+    if speaks_http2(&stream, connected + Duration::from_secs(timeouts.header)) {
+        return http2::serve(stream, handler, timeouts, limits);
+    }
+
+    let mut leftover = Vec::new();
+    let mut first = true;
+
+    loop {
+        if !first && leftover.is_empty() &&
+            !waiting(&stream, timeouts.keep_alive) {
+                return;
+            }
+
+        // The first request's time started with the connection
+        let start = if first { connected } else { Instant::now() };
+        first = false;
+
+        let keep_alive = KeepAlive::new(timeouts.keep_alive > 0);
+        let reader = TimedStream {
+            stream: match stream.try_clone() {
+                Ok(stream) => stream,
+                Err(_) => return
+            },
+            pending: leftover,
+            limit: Limit::Deadline(start + Duration::from_secs(timeouts.header))
+        };
+
+        // Requests that can't be read have been answered already
+        match make_request_pair(reader, &stream, keep_alive.clone(),
+                                timeouts, limits) {
+            Ok((req, res)) => handler.serve(req, res),
+            Err(_) => return
+        }
+
+        if !keep_alive.reusable() || draining() {
+            return;
+        }
+        leftover = keep_alive.take_leftover();
+    }
+}
+
+/// Whether the client asked for HTTP/2 by ALPN, or without it opened with the
+/// HTTP/2 preface, as it would knowing the server speaks it
+///
+/// HTTP/1.1 methods can start like the preface does, so it takes three bytes
+/// to tell, waiting until `deadline` for them.
+fn speaks_http2(stream: &Stream, deadline: Instant) -> bool {
+    if stream.protocol().map_or(false, |protocol| protocol == b"h2") {
+        return true;
+    }
+
+    let mut start = [0; 3];
+    loop {
+        match stream.peek(&mut start) {
+            Ok(n) if start[.. n] != http2::PREFACE[.. n] => return false,
+            Ok(n) if n == start.len() => return true,
+            Ok(n) if n > 0 && Instant::now() < deadline =>
+                thread::sleep(Duration::from_millis(10)),
+            _ => return false
+        }
+    }
+}
+
+/// Waits up to `idle` seconds for the client to start another request,
+/// saying whether it did
+///
+/// The wait is cut short, within a second, if the server starts draining.
+fn waiting(stream: &Stream, idle: u64) -> bool {
+    let deadline = Instant::now() + Duration::from_secs(idle);
+
+    loop {
+        let now = Instant::now();
+        if draining() || deadline <= now {
+            return false;
+        }
+
+        let slice = cmp::min(deadline - now, Duration::from_secs(1));
+        if stream.set_read_timeout(Some(slice)).is_err() {
+            return false;
+        }
+
+        match stream.peek(&mut [0]) {
+            Ok(n) => return n > 0,
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock ||
+                e.kind() == ErrorKind::TimedOut => continue,
+            Err(_) => return false
+        }
+    }
+}
+
+/// The number of connections being served, for shutdown to wait on
+#[derive(Debug)]
+pub struct Active {
+    count: Mutex<usize>,
+    changed: Condvar
+}
+
+/// Counts as an active connection until dropped
+pub struct Serving {
+    active: Arc<Active>
+}
+
+impl Active {
+    pub fn new() -> Arc<Active> {
+        Arc::new(Active {
+            count: Mutex::new(0),
+            changed: Condvar::new()
+        })
+    }
+
+    pub fn serving(active: &Arc<Active>) -> Serving {
+        *active.lock_count() += 1;
+        Serving { active: active.clone() }
+    }
+
+    /// Waits up to `timeout` for every connection to finish, returning how
+    /// many are still going
+    pub fn wait(&self, timeout: Duration) -> usize {
+        let deadline = Instant::now() + timeout;
+        let mut count = self.lock_count();
+
+        loop {
+            let now = Instant::now();
+            if *count == 0 || deadline <= now {
+                return *count;
+            }
+
+            count = match self.changed.wait_timeout(count, deadline - now) {
+                Ok((count, _)) => count,
+                Err(poison) => poison.into_inner().0
+            };
+        }
+    }
+
+    fn lock_count<'a>(&'a self) -> ::std::sync::MutexGuard<'a, usize> {
+        self.count.lock().unwrap_or_else(|poison| poison.into_inner())
+    }
+}
+
+impl Drop for Serving {
+    fn drop(&mut self) {
+        *self.active.lock_count() -= 1;
+        self.active.changed.notify_all();
+    }
+}
+
+/// A client's stream, whose reads fail with `TimedOut` once its limit is up
+#[derive(Debug)]
+pub struct TimedStream {
+    stream: Stream,
+    /// Bytes that came in with the previous request, to be read first
+    pending: Vec<u8>,
+    limit: Limit
+}
+
+#[derive(Debug)]
+enum Limit {
+    /// Everything has to be in by then
+    Deadline(Instant),
+    /// The body has to arrive at an average of `rate` bytes per second
+    /// from `start`, after the grace period
+    Rate { start: Instant, rate: u64, read: u64 },
+    /// Nothing more will be read; reads give what's pending, then nothing
+    Stopped
+}
+
+impl TimedStream {
+    /// A stream with nothing to it but a request body, held to `rate` from
+    /// the start
+    pub fn body(stream: Stream, rate: u64) -> TimedStream {
+        let mut body = TimedStream {
+            stream: stream,
+            pending: Vec::new(),
+            limit: Limit::Stopped
+        };
+        body.start_body(rate);
+        body
+    }
+
+    /// Holds the rest of the request to the body rate
+    pub fn start_body(&mut self, rate: u64) {
+        self.limit = Limit::Rate {
+            start: Instant::now(),
+            rate: rate,
+            read: 0
+        };
+    }
+
+    /// Stops reading from the client, so whatever was buffered can be taken
+    /// without waiting for more
+    pub fn stop(&mut self) {
+        self.limit = Limit::Stopped;
+    }
+
+    /// When the next read has to be done by, if ever
+    fn deadline(&self) -> Option<Instant> {
+        match self.limit {
+            Limit::Deadline(at) => Some(at),
+            Limit::Rate { rate: 0, .. } | Limit::Stopped => None,
+            Limit::Rate { start, rate, read } => {
+                let allowed = Duration::from_secs(BODY_GRACE + read / rate) +
+                    Duration::from_millis(read % rate * 1000 / rate);
+                Some(start + allowed)
+            }
+        }
+    }
+}
+
+impl Read for TimedStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.pending.is_empty() {
+            let n = cmp::min(buf.len(), self.pending.len());
+            buf[.. n].copy_from_slice(&self.pending[.. n]);
+            self.pending.drain(.. n);
+            return Ok(n);
+        }
+
+        if let Limit::Stopped = self.limit {
+            return Ok(0);
+        }
+
+        let timeout = match self.deadline() {
+            Some(deadline) => {
+                let now = Instant::now();
+                if deadline <= now {
+                    return Err(timed_out());
+                }
+                Some(deadline - now)
+            },
+            None => None
+        };
+        try!(self.stream.set_read_timeout(timeout));
+
+        match self.stream.read(buf) {
+            Ok(n) => {
+                if let Limit::Rate { ref mut read, .. } = self.limit {
+                    *read += n as u64;
+                }
+                Ok(n)
+            },
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock ||
+                e.kind() == ErrorKind::TimedOut => Err(timed_out()),
+            Err(e) => Err(e)
+        }
+    }
+}
+
+fn timed_out() -> io::Error {
+    io::Error::new(ErrorKind::TimedOut, "The client was too slow")
+}
+
+/// What a request and its response tell their connection about serving
+/// another request on it
+///
+/// Either can rule that out: the request by having a body nobody read to the
+/// end, the response by having no length for the client to go by.
+#[derive(Debug, Clone)]
+pub struct KeepAlive {
+    inner: Arc<State>
+}
+
+#[derive(Debug)]
+struct State {
+    reusable: AtomicBool,
+    /// The start of the next request, read along with this one
+    leftover: Mutex<Vec<u8>>
+}
+
+impl KeepAlive {
+    pub fn new(reusable: bool) -> KeepAlive {
+        KeepAlive {
+            inner: Arc::new(State {
+                reusable: AtomicBool::new(reusable),
+                leftover: Mutex::new(Vec::new())
+            })
+        }
+    }
+
+    /// Closes the connection after this request
+    pub fn end(&self) {
+        self.inner.reusable.store(false, Ordering::Release);
+    }
+
+    pub fn reusable(&self) -> bool {
+        self.inner.reusable.load(Ordering::Acquire)
+    }
+
+    pub fn keep_leftover(&self, bytes: &[u8]) {
+        self.lock_leftover().extend_from_slice(bytes);
+    }
+
+    fn take_leftover(&self) -> Vec<u8> {
+        let mut leftover = self.lock_leftover();
+        ::std::mem::replace(&mut *leftover, Vec::new())
+    }
+
+    fn lock_leftover<'a>(&'a self) -> ::std::sync::MutexGuard<'a, Vec<u8>> {
+        self.inner.leftover.lock()
+            .unwrap_or_else(|poison| poison.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::Limit;
+    use config::Timeouts;
+    use server::{Request, Response, Fresh};
+
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    /// A connected pair of streams: the client's end, and the server's
+    fn pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (client, server)
+    }
+
+    #[test]
+    fn trickling_headers_hit_the_deadline() {
+        let (mut client, server) = pair();
+        let mut timed = TimedStream {
+            stream: server.into(),
+            pending: Vec::new(),
+            limit: Limit::Deadline(Instant::now() +
+                                   Duration::from_millis(300))
+        };
+
+        let trickle = thread::spawn(move || {
+            for &b in b"GET / HTTP/1.1\r\n" {
+                if client.write_all(&[b]).is_err() {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(100));
+            }
+        });
+
+        let mut buf = [0; 64];
+        let mut outcome = Ok(0);
+        for _ in 0 .. 16 {
+            outcome = timed.read(&mut buf);
+            if outcome.is_err() {
+                break;
+            }
+        }
+
+        assert_eq!(outcome.unwrap_err().kind(), ErrorKind::TimedOut);
+        drop(timed);
+        trickle.join().unwrap();
+    }
+
+    #[test]
+    fn pending_bytes_come_first() {
+        let (mut client, server) = pair();
+        let mut timed = TimedStream {
+            stream: server.into(),
+            pending: Vec::from(&b"GET"[..]),
+            limit: Limit::Deadline(Instant::now() + Duration::from_secs(5))
+        };
+        client.write_all(b" /").unwrap();
+
+        let mut buf = [0; 2];
+        timed.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"GE");
+        timed.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"T ");
+
+        timed.stop();
+        assert_eq!(timed.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn body_rate_extends_the_deadline() {
+        let (_client, server) = pair();
+        let mut timed = TimedStream {
+            stream: server.into(),
+            pending: Vec::new(),
+            limit: Limit::Stopped
+        };
+
+        timed.start_body(1000);
+        let start = match timed.limit {
+            Limit::Rate { start, .. } => start,
+            _ => unreachable!()
+        };
+        assert_eq!(timed.deadline(), Some(start + Duration::from_secs(5)));
+
+        if let Limit::Rate { ref mut read, .. } = timed.limit {
+            *read = 2500;
+        }
+        assert_eq!(timed.deadline(),
+                   Some(start + Duration::from_millis(7500)));
+
+        timed.start_body(0);
+        assert_eq!(timed.deadline(), None);
+    }
+
+    /// Serves whatever the client sends, answering with each request's path
+    fn exchange(raw: &'static [u8], timeouts: Timeouts) -> String {
+        let (mut client, server) = pair();
+
+        let writer = thread::spawn(move || {
+            client.write_all(raw).unwrap();
+
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            response
+        });
+
+        serve(server.into(), &|req: Request, mut res: Response<Fresh>| {
+            let path = req.request_uri().to_string_lossy().into_owned();
+            res.headers_mut().insert("Content-Length",
+                                     format!("{}", path.len()).into_bytes());
+            let _ = res.of_stream(path.as_bytes());
+        }, &timeouts, &Default::default());
+
+        writer.join().unwrap()
+    }
+
+    #[test]
+    fn kept_alive_connections_take_pipelined_requests() {
+        let response = exchange(b"POST /a HTTP/1.1\r\nContent-Length: 3\r\n\r\n\
+                                  abcGET /b HTTP/1.1\r\nConnection: close\r\n\
+                                  \r\n",
+                                Default::default());
+
+        let (first, second) = response.split_at(response.rfind("HTTP/1.1")
+                                                .unwrap());
+        assert!(first.starts_with("HTTP/1.1 200 "));
+        assert!(first.ends_with("\r\n\r\n/a"));
+        assert!(!first.contains("Connection: close"));
+        assert!(second.contains("Connection: close\r\n"));
+        assert!(second.ends_with("\r\n\r\n/b"));
+    }
+
+    #[test]
+    fn waiting_on_active_connections() {
+        let active = Active::new();
+        assert_eq!(active.wait(Duration::from_secs(1)), 0);
+
+        let serving = Active::serving(&active);
+        assert_eq!(active.wait(Duration::from_millis(10)), 1);
+
+        let finish = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            drop(serving);
+        });
+        assert_eq!(active.wait(Duration::from_secs(5)), 0);
+        finish.join().unwrap();
+    }
+
+    #[test]
+    fn slow_headers_get_a_408() {
+        let response = exchange(b"GET / HTTP/1.1\r\nHost: slow", Timeouts {
+            header: 1,
+            ..Default::default()
+        });
+
+        assert!(response.starts_with("HTTP/1.1 408 "));
+        assert!(response.contains("Connection: close\r\n"));
+    }
+}
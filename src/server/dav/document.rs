@@ -0,0 +1,430 @@
+//! WebDAV request bodies and multistatus responses
+//!
+//! Request bodies are small, so they're read into a bare element tree and
+//! picked apart from there. Responses are simple enough to write as text.
+
+use errors::*;
+use server::listing::percent_encode;
+use server::reason;
+
+use xml::escape::{escape_str_attribute, escape_str_pcdata};
+use xml::name::OwnedName;
+use xml::reader::{ParserConfig, XmlEvent};
+
+use std::io::Read;
+
+/// The WebDAV namespace
+pub const DAV: &'static str = "DAV:";
+
+/// A namespaced element or property name
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Name {
+    /// The namespace URI, empty for none
+    pub ns: String,
+    pub local: String
+}
+
+impl Name {
+    pub fn dav(local: &str) -> Name {
+        Name { ns: String::from(DAV), local: String::from(local) }
+    }
+
+    #[inline]
+    pub fn is_dav(&self, local: &str) -> bool {
+        self.ns == DAV && self.local == local
+    }
+}
+
+impl From<OwnedName> for Name {
+    fn from(name: OwnedName) -> Name {
+        Name {
+            ns: name.namespace.unwrap_or_default(),
+            local: name.local_name
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Element {
+    pub name: Name,
+    pub attributes: Vec<(Name, String)>,
+    pub children: Vec<Node>
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Node {
+    Element(Element),
+    Text(String)
+}
+
+impl Element {
+    /// Child elements, skipping text
+    pub fn elements(&self) -> Vec<&Element> {
+        self.children.iter()
+            .filter_map(|child| match child {
+                &Node::Element(ref el) => Some(el),
+                &Node::Text(_) => None
+            })
+            .collect()
+    }
+
+    /// The first child element in the `DAV:` namespace called `local`
+    pub fn child(&self, local: &str) -> Option<&Element> {
+        self.elements().into_iter().find(|el| el.name.is_dav(local))
+    }
+
+    /// Serializes what's inside this element
+    ///
+    /// Every element written declares its own namespace, so the result means
+    /// the same wherever it's pasted.
+    pub fn inner_xml(&self) -> String {
+        let mut xml = String::new();
+        for child in &self.children {
+            write_node(child, &mut xml);
+        }
+        xml
+    }
+}
+
+fn write_node(node: &Node, xml: &mut String) {
+    let el = match node {
+        &Node::Element(ref el) => el,
+        &Node::Text(ref text) => {
+            xml.push_str(&escape_str_pcdata(text));
+            return;
+        }
+    };
+
+    xml.push('<');
+    xml.push_str(&el.name.local);
+    xml.push_str(" xmlns=\"");
+    xml.push_str(&escape_str_attribute(&el.name.ns));
+    xml.push('"');
+
+    for (i, &(ref name, ref value)) in el.attributes.iter().enumerate() {
+        if name.ns.is_empty() {
+            xml.push_str(&format!(" {}=\"{}\"", name.local,
+                                  escape_str_attribute(value)));
+        }
+        else {
+            xml.push_str(&format!(" xmlns:a{}=\"{}\" a{}:{}=\"{}\"",
+                                  i, escape_str_attribute(&name.ns),
+                                  i, name.local, escape_str_attribute(value)));
+        }
+    }
+
+    if el.children.is_empty() {
+        xml.push_str("/>");
+        return;
+    }
+
+    xml.push('>');
+    for child in &el.children {
+        write_node(child, xml);
+    }
+    xml.push_str("</");
+    xml.push_str(&el.name.local);
+    xml.push('>');
+}
+
+/// Reads a request body into its root element
+pub fn parse<R: Read>(body: R) -> Result<Element> {
+    let reader = ParserConfig::new()
+        .cdata_to_characters(true)
+        .create_reader(body);
+
+    let mut open: Vec<Element> = Vec::new();
+
+    for event in reader {
+        match try!(event) {
+            XmlEvent::StartElement { name, attributes, .. } => {
+                open.push(Element {
+                    name: Name::from(name),
+                    attributes: attributes.into_iter()
+                        .map(|attr| (Name::from(attr.name), attr.value))
+                        .collect(),
+                    children: Vec::new()
+                });
+            },
+            XmlEvent::EndElement { .. } => {
+                let done = match open.pop() {
+                    Some(done) => done,
+                    None => break
+                };
+
+                match open.last_mut() {
+                    Some(parent) => parent.children.push(Node::Element(done)),
+                    None => return Ok(done)
+                }
+            },
+            XmlEvent::Characters(text) => {
+                if let Some(parent) = open.last_mut() {
+                    parent.children.push(Node::Text(text));
+                }
+            },
+            _ => ()
+        }
+    }
+
+    Err(Error::MalformedDavBody)
+}
+
+/// What a `PROPFIND` asks for
+#[derive(Debug, PartialEq)]
+pub enum PropFind {
+    /// Every property, with values
+    AllProp,
+    /// Every property's name, without values
+    PropName,
+    /// These properties, with values
+    Prop(Vec<Name>)
+}
+
+/// Interprets a `DAV:propfind` element
+pub fn propfind(root: &Element) -> Result<PropFind> {
+    if !root.name.is_dav("propfind") {
+        return Err(Error::MalformedDavBody);
+    }
+
+    for el in root.elements() {
+        if el.name.is_dav("allprop") {
+            return Ok(PropFind::AllProp);
+        }
+        if el.name.is_dav("propname") {
+            return Ok(PropFind::PropName);
+        }
+        if el.name.is_dav("prop") {
+            return Ok(PropFind::Prop(
+                el.elements().into_iter().map(|p| p.name.clone()).collect()
+            ));
+        }
+    }
+
+    Err(Error::MalformedDavBody)
+}
+
+/// One change from a `PROPPATCH`: a new value, as XML, or `None` to remove
+pub type PropUpdate = (Name, Option<String>);
+
+/// Interprets a `DAV:propertyupdate` element, keeping document order
+pub fn propertyupdate(root: &Element) -> Result<Vec<PropUpdate>> {
+    if !root.name.is_dav("propertyupdate") {
+        return Err(Error::MalformedDavBody);
+    }
+
+    let mut updates = Vec::new();
+
+    for action in root.elements() {
+        let set = if action.name.is_dav("set") {
+            true
+        }
+        else if action.name.is_dav("remove") {
+            false
+        }
+        else {
+            continue;
+        };
+
+        let props = match action.child("prop") {
+            Some(props) => props,
+            None => return Err(Error::MalformedDavBody)
+        };
+
+        for prop in props.elements() {
+            let value = if set { Some(prop.inner_xml()) } else { None };
+            updates.push((prop.name.clone(), value));
+        }
+    }
+
+    if updates.is_empty() {
+        return Err(Error::MalformedDavBody);
+    }
+
+    Ok(updates)
+}
+
+/// What a `LOCK` asks for
+#[derive(Debug, PartialEq)]
+pub struct LockInfo {
+    pub exclusive: bool,
+    /// The contents of `DAV:owner`, as XML
+    pub owner: Option<String>
+}
+
+/// Interprets a `DAV:lockinfo` element
+///
+/// Only write locks exist, so anything else is refused.
+pub fn lockinfo(root: &Element) -> Result<LockInfo> {
+    if !root.name.is_dav("lockinfo") {
+        return Err(Error::MalformedDavBody);
+    }
+
+    let exclusive = match root.child("lockscope")
+        .and_then(|scope| scope.elements().into_iter().next())
+    {
+        Some(scope) if scope.name.is_dav("exclusive") => true,
+        Some(scope) if scope.name.is_dav("shared") => false,
+        _ => return Err(Error::MalformedDavBody)
+    };
+
+    match root.child("locktype")
+        .and_then(|kind| kind.elements().into_iter().next())
+    {
+        Some(kind) if kind.name.is_dav("write") => (),
+        _ => return Err(Error::MalformedDavBody)
+    }
+
+    Ok(LockInfo {
+        exclusive: exclusive,
+        owner: root.child("owner").map(Element::inner_xml)
+    })
+}
+
+/// Renders a property element holding `value`, which is already XML
+///
+/// With no value, the element is empty, as in a `DAV:propname` response.
+pub fn property(name: &Name, value: Option<&str>) -> String {
+    let open = if name.ns == DAV {
+        format!("D:{}", name.local)
+    }
+    else {
+        format!("{} xmlns=\"{}\"", name.local, escape_str_attribute(&name.ns))
+    };
+    let close = if name.ns == DAV {
+        format!("D:{}", name.local)
+    }
+    else {
+        name.local.clone()
+    };
+
+    match value {
+        Some(value) if !value.is_empty() =>
+            format!("<{}>{}</{}>", open, value, close),
+        _ => format!("<{}/>", open)
+    }
+}
+
+/// Escapes text for use as a property value
+pub fn text(value: &str) -> String {
+    escape_str_pcdata(value).into_owned()
+}
+
+/// Renders a request path as a `DAV:href`
+pub fn href(path: &[u8]) -> String {
+    let encoded: Vec<Vec<u8>> = path.split(|&b| b == b'/')
+        .map(percent_encode)
+        .collect();
+
+    // Percent-encoding leaves nothing but ASCII
+    format!("<D:href>{}</D:href>", String::from_utf8(encoded.join(&b'/'))
+            .unwrap())
+}
+
+/// A `DAV:error` body naming a failed precondition, like `lock-token-submitted`
+pub fn error(condition: &str) -> Vec<u8> {
+    format!("<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+             <D:error xmlns:D=\"DAV:\"><D:{}/></D:error>", condition)
+        .into_bytes()
+}
+
+/// Properties sharing a status within one response
+pub struct PropStat {
+    pub status: u16,
+    /// Rendered by `property`
+    pub props: Vec<String>
+}
+
+/// Builds a `207 Multi-Status` body
+pub struct Multistatus {
+    body: String
+}
+
+impl Multistatus {
+    pub fn new() -> Multistatus {
+        Multistatus {
+            body: String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+                                <D:multistatus xmlns:D=\"DAV:\">")
+        }
+    }
+
+    /// Adds a response for `path` with its properties grouped by status
+    pub fn props(&mut self, path: &[u8], propstats: &[PropStat]) {
+        self.body.push_str("<D:response>");
+        self.body.push_str(&href(path));
+
+        for propstat in propstats {
+            if propstat.props.is_empty() {
+                continue;
+            }
+
+            self.body.push_str("<D:propstat><D:prop>");
+            for prop in &propstat.props {
+                self.body.push_str(prop);
+            }
+            self.body.push_str("</D:prop>");
+            self.push_status(propstat.status);
+            self.body.push_str("</D:propstat>");
+        }
+
+        self.body.push_str("</D:response>");
+    }
+
+    fn push_status(&mut self, status: u16) {
+        self.body.push_str(&format!("<D:status>HTTP/1.1 {} {}</D:status>",
+                                    status, reason(status)));
+    }
+
+    pub fn into_bytes(mut self) -> Vec<u8> {
+        self.body.push_str("</D:multistatus>");
+        self.body.into_bytes()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn propfind_bodies_are_understood() {
+        let body: &[u8] = b"<?xml version=\"1.0\"?>\
+            <propfind xmlns=\"DAV:\"><prop>\
+            <getcontentlength/><x:color xmlns:x=\"urn:example\"/>\
+            </prop></propfind>";
+
+        assert_eq!(propfind(&parse(body).unwrap()).unwrap(),
+                   PropFind::Prop(vec![
+                       Name::dav("getcontentlength"),
+                       Name {
+                           ns: String::from("urn:example"),
+                           local: String::from("color")
+                       }
+                   ]));
+    }
+
+    #[test]
+    fn property_values_keep_their_namespaces() {
+        let body: &[u8] = b"<D:propertyupdate xmlns:D=\"DAV:\" \
+            xmlns:Z=\"urn:z\"><D:set><D:prop>\
+            <Z:author><Z:name>Ann &amp; Bo</Z:name></Z:author>\
+            </D:prop></D:set><D:remove><D:prop><Z:old/></D:prop></D:remove>\
+            </D:propertyupdate>";
+
+        let updates = propertyupdate(&parse(body).unwrap()).unwrap();
+
+        assert_eq!(updates.len(), 2);
+        assert_eq!(updates[0].1.as_ref().unwrap(),
+                   "<name xmlns=\"urn:z\">Ann &amp; Bo</name>");
+        assert_eq!(updates[1].1, None);
+    }
+
+    #[test]
+    fn malformed_bodies_are_errors() {
+        assert!(parse(&b"<propfind xmlns=\"DAV:\">"[..]).is_err());
+        assert!(propfind(&parse(&b"<nope/>"[..]).unwrap()).is_err());
+    }
+
+    #[test]
+    fn hrefs_are_percent_encoded() {
+        assert_eq!(href(b"/a b/c&d/"), "<D:href>/a%20b/c%26d/</D:href>");
+    }
+}
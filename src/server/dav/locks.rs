@@ -0,0 +1,388 @@
+//! WebDAV write locks, and the `If` headers that present their tokens
+
+use errors::*;
+use filesystem::is_within;
+
+use std::ascii::AsciiExt;
+use std::fs::File;
+use std::io::Read;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone)]
+pub struct Lock {
+    pub token: String,
+    /// The request path the lock was taken on
+    pub root: Vec<u8>,
+    pub exclusive: bool,
+    /// Whether the lock covers everything below `root` too
+    pub infinite: bool,
+    /// The `DAV:owner` the client gave, as XML
+    pub owner: Option<String>,
+    pub timeout: Duration,
+    expires: Instant
+}
+
+impl Lock {
+    /// Whether this lock covers the resource at `uri`
+    pub fn applies_to(&self, uri: &[u8]) -> bool {
+        if self.infinite {
+            is_within(uri, &self.root)
+        }
+        else {
+            trim_slash(uri) == trim_slash(&self.root)
+        }
+    }
+}
+
+/// The locks held on one mount
+///
+/// Locks are kept in memory only, so they're all released when the server
+/// restarts.
+pub struct Locks {
+    locks: Mutex<Vec<Lock>>
+}
+
+impl Locks {
+    pub fn new() -> Locks {
+        Locks { locks: Mutex::new(Vec::new()) }
+    }
+
+    /// The current locks, with expired ones swept away
+    fn current<'a>(&'a self) -> MutexGuard<'a, Vec<Lock>> {
+        let mut locks = self.locks.lock()
+            .unwrap_or_else(|poison| poison.into_inner());
+
+        let now = Instant::now();
+        locks.retain(|lock| lock.expires > now);
+        locks
+    }
+
+    /// Takes a new lock on `root`, unless one already held conflicts with it
+    pub fn acquire(&self, root: &[u8], exclusive: bool, infinite: bool,
+                   owner: Option<String>, timeout: Duration)
+                   -> Result<Option<Lock>> {
+        let token = try!(new_token());
+        let mut locks = self.current();
+
+        let conflicts = locks.iter().any(|held| {
+            let overlaps = held.applies_to(root) ||
+                (infinite && is_within(&held.root, root));
+
+            overlaps && (exclusive || held.exclusive)
+        });
+
+        if conflicts {
+            return Ok(None);
+        }
+
+        let lock = Lock {
+            token: token,
+            root: trim_slash(root).to_owned(),
+            exclusive: exclusive,
+            infinite: infinite,
+            owner: owner,
+            timeout: timeout,
+            expires: Instant::now() + timeout
+        };
+
+        locks.push(lock.clone());
+        Ok(Some(lock))
+    }
+
+    /// Extends the lock with `token`, if it covers `uri`
+    pub fn refresh(&self, uri: &[u8], token: &str, timeout: Duration)
+                   -> Option<Lock> {
+        let mut locks = self.current();
+
+        locks.iter_mut()
+            .find(|lock| lock.token == token && lock.applies_to(uri))
+            .map(|lock| {
+                lock.timeout = timeout;
+                lock.expires = Instant::now() + timeout;
+                lock.clone()
+            })
+    }
+
+    /// Releases the lock with `token`, if it covers `uri`
+    pub fn release(&self, uri: &[u8], token: &str) -> bool {
+        let mut locks = self.current();
+        let before = locks.len();
+
+        locks.retain(|lock| !(lock.token == token && lock.applies_to(uri)));
+        locks.len() < before
+    }
+
+    /// The locks covering `uri`
+    pub fn applying_to(&self, uri: &[u8]) -> Vec<Lock> {
+        self.current().iter()
+            .filter(|lock| lock.applies_to(uri))
+            .cloned()
+            .collect()
+    }
+
+    /// Whether a lock whose token wasn't submitted stands in the way of
+    /// changing `uri`
+    ///
+    /// With `members` set, the change adds or removes `uri` itself, so locks
+    /// on its parent collection and on anything below it count too.
+    pub fn blocks(&self, uri: &[u8], tokens: &[String], members: bool)
+                  -> bool {
+        let parent = parent(uri);
+
+        self.current().iter()
+            .filter(|lock| !tokens.contains(&lock.token))
+            .any(|lock| {
+                lock.applies_to(uri) ||
+                    (members && (is_within(&lock.root, uri) ||
+                                 trim_slash(&lock.root) == parent))
+            })
+    }
+
+    /// Drops the locks on anything in the tree at `uri`, after it's gone
+    pub fn remove_tree(&self, uri: &[u8]) {
+        self.current().retain(|lock| !is_within(&lock.root, uri));
+    }
+}
+
+fn trim_slash(uri: &[u8]) -> &[u8] {
+    if uri.len() > 1 && uri.last() == Some(&b'/') {
+        &uri[.. uri.len() - 1]
+    }
+    else {
+        uri
+    }
+}
+
+fn parent(uri: &[u8]) -> &[u8] {
+    let uri = trim_slash(uri);
+
+    match uri.iter().rposition(|&b| b == b'/') {
+        Some(0) | None => b"/",
+        Some(i) => &uri[.. i]
+    }
+}
+
+/// Makes up a lock token from a random (version 4) UUID
+fn new_token() -> Result<String> {
+    let mut bytes = [0; 16];
+    try!(try!(File::open("/dev/urandom")).read_exact(&mut bytes));
+
+    bytes[6] = bytes[6] & 0x0F | 0x40;
+    bytes[8] = bytes[8] & 0x3F | 0x80;
+
+    let hex: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b))
+        .collect();
+
+    Ok(format!("urn:uuid:{}-{}-{}-{}-{}", hex[0..4].concat(),
+               hex[4..6].concat(), hex[6..8].concat(), hex[8..10].concat(),
+               hex[10..16].concat()))
+}
+
+/// One parenthesized list of conditions from an `If` header
+#[derive(Debug, PartialEq)]
+pub struct IfList {
+    /// The resource the list is tagged with, as the client wrote it
+    pub resource: Option<Vec<u8>>,
+    pub conditions: Vec<Condition>
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Condition {
+    pub not: bool,
+    pub test: Test
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Test {
+    /// The resource is locked with this token
+    Token(String),
+    /// The resource has this entity tag
+    ETag(String)
+}
+
+/// Parses an `If` header, or returns `None` if it's malformed
+pub fn parse_if(header: &[u8]) -> Option<Vec<IfList>> {
+    let mut lists = Vec::new();
+    let mut resource = None;
+    let mut rest = header;
+
+    loop {
+        rest = skip_spaces(rest);
+
+        match rest.first() {
+            None => break,
+            Some(&b'<') => {
+                let (tag, after) = match delimited(rest, b'>') {
+                    Some(split) => split,
+                    None => return None
+                };
+
+                resource = Some(tag.to_owned());
+                rest = after;
+            },
+            Some(&b'(') => {
+                let (conditions, after) = match parse_list(&rest[1..]) {
+                    Some(split) => split,
+                    None => return None
+                };
+
+                lists.push(IfList {
+                    resource: resource.clone(),
+                    conditions: conditions
+                });
+                rest = after;
+            },
+            Some(_) => return None
+        }
+    }
+
+    if lists.is_empty() {
+        None
+    }
+    else {
+        Some(lists)
+    }
+}
+
+/// Parses conditions up to the `)` closing a list
+fn parse_list(mut rest: &[u8]) -> Option<(Vec<Condition>, &[u8])> {
+    let mut conditions = Vec::new();
+    let mut not = false;
+
+    loop {
+        rest = skip_spaces(rest);
+
+        let (test, after) = match rest.first() {
+            Some(&b')') if !not && !conditions.is_empty() =>
+                return Some((conditions, &rest[1..])),
+            Some(&b'N') | Some(&b'n') if !not && rest.len() >= 3 &&
+                rest[..3].eq_ignore_ascii_case(b"not") => {
+                    not = true;
+                    rest = &rest[3..];
+                    continue;
+                },
+            Some(&b'<') => match delimited(rest, b'>') {
+                Some((token, after)) => (Test::Token(lossy(token)), after),
+                None => return None
+            },
+            Some(&b'[') => match delimited(rest, b']') {
+                Some((etag, after)) => (Test::ETag(lossy(etag)), after),
+                None => return None
+            },
+            _ => return None
+        };
+
+        conditions.push(Condition { not: not, test: test });
+        not = false;
+        rest = after;
+    }
+}
+
+/// Splits off what's between the opening byte of `input` and `close`
+fn delimited(input: &[u8], close: u8) -> Option<(&[u8], &[u8])> {
+    input[1..].iter()
+        .position(|&b| b == close)
+        .map(|end| (&input[1 .. end + 1], &input[end + 2 ..]))
+}
+
+fn skip_spaces(input: &[u8]) -> &[u8] {
+    let start = input.iter()
+        .position(|&b| b != b' ' && b != b'\t')
+        .unwrap_or(input.len());
+    &input[start..]
+}
+
+fn lossy(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).into_owned()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::time::Duration;
+
+    #[test]
+    fn if_headers_are_parsed() {
+        let lists = parse_if(b"</a/b> (<urn:uuid:1> [\"e1\"]) \
+                               (Not <DAV:no-lock>)").unwrap();
+
+        assert_eq!(lists, vec![
+            IfList {
+                resource: Some(Vec::from(&b"/a/b"[..])),
+                conditions: vec![
+                    Condition {
+                        not: false,
+                        test: Test::Token(String::from("urn:uuid:1"))
+                    },
+                    Condition {
+                        not: false,
+                        test: Test::ETag(String::from("\"e1\""))
+                    }
+                ]
+            },
+            IfList {
+                resource: Some(Vec::from(&b"/a/b"[..])),
+                conditions: vec![Condition {
+                    not: true,
+                    test: Test::Token(String::from("DAV:no-lock"))
+                }]
+            }
+        ]);
+    }
+
+    #[test]
+    fn malformed_if_headers_are_refused() {
+        assert!(parse_if(b"").is_none());
+        assert!(parse_if(b"()").is_none());
+        assert!(parse_if(b"(<urn:uuid:1>").is_none());
+        assert!(parse_if(b"(Not)").is_none());
+        assert!(parse_if(b"urn:uuid:1").is_none());
+    }
+
+    #[test]
+    fn exclusive_locks_conflict() {
+        let locks = Locks::new();
+        let hour = Duration::from_secs(3600);
+
+        let dir = locks.acquire(b"/dir/", true, true, None, hour)
+            .unwrap().unwrap();
+        assert!(locks.acquire(b"/dir/file", false, false, None, hour)
+                .unwrap().is_none());
+        assert!(locks.acquire(b"/other", true, false, None, hour)
+                .unwrap().is_some());
+
+        assert!(locks.blocks(b"/dir/file", &[], false));
+        assert!(!locks.blocks(b"/dir/file", &[dir.token.clone()], false));
+        assert!(locks.blocks(b"/dir", &[], true));
+
+        assert!(locks.release(b"/dir/file", &dir.token));
+        assert!(!locks.blocks(b"/dir/file", &[], true));
+    }
+
+    #[test]
+    fn shared_locks_coexist() {
+        let locks = Locks::new();
+        let hour = Duration::from_secs(3600);
+
+        assert!(locks.acquire(b"/f", false, false, None, hour)
+                .unwrap().is_some());
+        assert!(locks.acquire(b"/f", false, false, None, hour)
+                .unwrap().is_some());
+        assert!(locks.acquire(b"/f", true, false, None, hour)
+                .unwrap().is_none());
+        assert_eq!(locks.applying_to(b"/f").len(), 2);
+    }
+
+    #[test]
+    fn depth_zero_locks_guard_membership() {
+        let locks = Locks::new();
+        let hour = Duration::from_secs(3600);
+
+        locks.acquire(b"/dir", true, false, None, hour).unwrap().unwrap();
+
+        assert!(!locks.blocks(b"/dir/file", &[], false));
+        assert!(locks.blocks(b"/dir/file", &[], true));
+    }
+}
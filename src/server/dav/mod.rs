@@ -0,0 +1,1607 @@
+//! WebDAV on writable static mounts
+//!
+//! A writable mount speaks WebDAV classes 1 and 2: enough for file managers to
+//! mount it, and for scripts to use it as an upload target. Paths are
+//! resolved by the mount's `Statics`, so the same webroot containment, deny
+//! and symlink rules apply to everything here as to reads.
+//!
+//! Locks, and properties set with `PROPPATCH`, are kept in memory and don't
+//! survive a restart.
+
+mod document;
+mod locks;
+mod props;
+
+use self::document::{DAV, Element, Multistatus, Name, PropFind, PropStat};
+use self::locks::{IfList, Lock, Locks, Test};
+use self::props::DeadProps;
+use super::{Handler, Request, Response, Fresh, reason};
+use super::auth;
+use super::error_messages::*;
+use super::static_files::Statics;
+use config::WritableConfig;
+use errors::*;
+use filesystem::{is_within, normalize_path, split_query};
+
+use libc;
+
+use std::ascii::AsciiExt;
+use std::cmp;
+use std::ffi::OsStr;
+use std::fs::{self, Metadata, OpenOptions};
+use std::io::{self, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::str;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// Methods `Dav` answers, for the router
+pub const METHODS: &'static [&'static str] = &[
+    "COPY", "DELETE", "LOCK", "MKCOL", "MOVE", "OPTIONS", "PROPFIND",
+    "PROPPATCH", "PUT", "UNLOCK"
+];
+
+/// Methods a writable mount answers, `GET` included
+const ALLOWED: &'static str = "COPY, DELETE, GET, HEAD, LOCK, MKCOL, MOVE, \
+                               OPTIONS, PROPFIND, PROPPATCH, PUT, UNLOCK";
+
+/// Largest XML request body accepted
+const MAX_DOCUMENT: u64 = 1024 * 1024;
+
+/// Seconds a lock lasts if the client doesn't say
+const DEFAULT_LOCK_TIMEOUT: u64 = 10 * 60;
+
+/// Most seconds a lock may last before it must be refreshed
+const MAX_LOCK_TIMEOUT: u64 = 24 * 60 * 60;
+
+const SUPPORTED_LOCK: &'static str =
+    "<D:lockentry><D:lockscope><D:exclusive/></D:lockscope>\
+     <D:locktype><D:write/></D:locktype></D:lockentry>\
+     <D:lockentry><D:lockscope><D:shared/></D:lockscope>\
+     <D:locktype><D:write/></D:locktype></D:lockentry>";
+
+/// A handler for WebDAV requests to a static mount
+pub struct Dav {
+    statics: Arc<Statics>,
+    max_size: u64,
+    credentials: Vec<Vec<u8>>,
+    locks: Locks,
+    props: DeadProps,
+    uploads: AtomicUsize
+}
+
+/// What to send back
+enum Reply {
+    Status(u16),
+    /// A status with an XML body
+    Xml(u16, Vec<u8>),
+    /// A granted lock: the status, lock token and body
+    Locked(u16, String, Vec<u8>)
+}
+
+/// How far into a collection a request reaches
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Depth {
+    Zero,
+    One,
+    Infinity
+}
+
+impl Dav {
+    pub fn new(statics: Arc<Statics>, conf: &WritableConfig) -> Dav {
+        Dav {
+            statics: statics,
+            max_size: conf.max_size,
+            credentials: conf.users.iter()
+                .map(|&(ref user, ref password)| {
+                    auth::basic_credentials(user, password)
+                })
+                .collect(),
+            locks: Locks::new(),
+            props: DeadProps::new(),
+            uploads: AtomicUsize::new(0)
+        }
+    }
+
+    fn serve_inner(&self, mut req: Request, mut res: Response<Fresh>)
+                   -> Result<()> {
+        // Clients ask before they've been challenged for credentials
+        if req.method() == "OPTIONS" {
+            return Ok(try!(options(res)));
+        }
+
+        if !auth::authorized(&req, &self.credentials) {
+            return Ok(try!(unauthorized(res)));
+        }
+
+        let wants_document = match req.method() {
+            "PROPFIND" | "PROPPATCH" | "LOCK" => true,
+            _ => false
+        };
+
+        if wants_document &&
+            content_length(&req).map_or(false, |len| len > MAX_DOCUMENT) {
+                return Ok(try!(fail(&Error::TooLarge, res)));
+            }
+
+        let uri = req.request_uri().as_bytes().to_owned();
+
+        let reply = match req.method() {
+            "PUT" => self.put(req, &uri),
+            "DELETE" => self.delete(&req, &uri),
+            "MKCOL" => self.mkcol(&req, &uri),
+            "COPY" => self.transfer(&req, &uri, false),
+            "MOVE" => self.transfer(&req, &uri, true),
+            "PROPFIND" => self.propfind(&mut req, &uri),
+            "PROPPATCH" => self.proppatch(&mut req, &uri),
+            "LOCK" => self.lock(&mut req, &uri),
+            "UNLOCK" => self.unlock(&req, &uri),
+            _ => Ok(Reply::Status(405))
+        };
+
+        match reply {
+            Ok(Reply::Status(403)) => try!(error_403(res)),
+            Ok(Reply::Status(404)) => try!(error_404(res)),
+            Ok(Reply::Status(405)) => try!(error_405(res, ALLOWED)),
+            Ok(Reply::Status(code)) => try!(status(res, code)),
+            Ok(Reply::Xml(code, body)) => try!(xml(res, code, body)),
+            Ok(Reply::Locked(code, token, body)) => {
+                res.headers_mut().insert("Lock-Token",
+                                         format!("<{}>", token).into_bytes());
+                try!(xml(res, code, body));
+            },
+            Err(e) => try!(fail(&e, res))
+        }
+
+        Ok(())
+    }
+
+    /// Stores the request body at `uri`, atomically replacing any file
+    /// already there
+    fn put(&self, mut req: Request, uri: &[u8]) -> Result<Reply> {
+        let len = match content_length(&req) {
+            Some(len) => len,
+            None => return Ok(Reply::Status(411))
+        };
+
+        if len > self.max_size {
+            return Err(Error::TooLarge);
+        }
+
+        let target = match try!(self.new_entry(uri)) {
+            Some(target) => target,
+            None => return Ok(Reply::Status(409))
+        };
+
+        let existed = match fs::metadata(&target) {
+            Ok(ref meta) if meta.is_dir() => return Ok(Reply::Status(409)),
+            Ok(_) => true,
+            Err(_) => false
+        };
+
+        if let Some(code) = try!(self.check_locks(&req, &[(uri, !existed)])) {
+            return Ok(Reply::Status(code));
+        }
+
+        // Write beside the target so the rename can't cross filesystems. The
+        // leading dot keeps half-written files out of sight.
+        let temp = target.with_file_name(format!(
+            ".{}.{}-{}.upload",
+            target.file_name().unwrap().to_string_lossy(),
+            unsafe { libc::getpid() },
+            self.uploads.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        let copied = {
+            let mut file = try!(OpenOptions::new().write(true)
+                                .create_new(true).open(&temp));
+            io::copy(&mut (&mut req).take(len), &mut file)
+                .and_then(|copied| file.sync_all().map(|_| copied))
+        };
+
+        match copied {
+            Ok(copied) if copied == len => (),
+            Ok(_) => {
+                // The client hung up early
+                let _ = fs::remove_file(&temp);
+                return Ok(Reply::Status(400));
+            },
+            Err(e) => {
+                let _ = fs::remove_file(&temp);
+                return Err(Error::from(e));
+            }
+        }
+
+        if let Err(e) = fs::rename(&temp, &target) {
+            let _ = fs::remove_file(&temp);
+            return Err(Error::from(e));
+        }
+
+        self.statics.forget(uri);
+        info!("Stored {} bytes at {:?}", len, target);
+
+        Ok(Reply::Status(if existed { 204 } else { 201 }))
+    }
+
+    /// Removes the file or directory tree at `uri`
+    ///
+    /// A symbolic link is removed itself, leaving what it points to alone.
+    fn delete(&self, req: &Request, uri: &[u8]) -> Result<Reply> {
+        if self.is_mount_root(uri) {
+            return Ok(Reply::Status(403));
+        }
+
+        let target = match try!(self.existing_entry(uri)) {
+            Some(target) => target,
+            None => return Ok(Reply::Status(404))
+        };
+
+        if let Some(code) = try!(self.check_locks(req, &[(uri, true)])) {
+            return Ok(Reply::Status(code));
+        }
+
+        try!(remove(&target));
+
+        self.props.remove(&target);
+        self.locks.remove_tree(uri);
+        self.statics.forget(uri);
+        info!("Deleted {:?}", target);
+
+        Ok(Reply::Status(204))
+    }
+
+    /// Creates a directory at `uri`
+    fn mkcol(&self, req: &Request, uri: &[u8]) -> Result<Reply> {
+        // There's no body format for MKCOL that we understand
+        if content_length(req).map_or(false, |len| len > 0) {
+            return Ok(Reply::Status(415));
+        }
+
+        let target = match try!(self.new_entry(uri)) {
+            Some(target) => target,
+            None => return Ok(Reply::Status(409))
+        };
+
+        if fs::symlink_metadata(&target).is_ok() {
+            return Ok(Reply::Status(405));
+        }
+
+        if let Some(code) = try!(self.check_locks(req, &[(uri, true)])) {
+            return Ok(Reply::Status(code));
+        }
+
+        try!(fs::create_dir(&target));
+        info!("Created directory {:?}", target);
+
+        Ok(Reply::Status(201))
+    }
+
+    /// Copies or moves the resource at `uri` to the request's `Destination`
+    fn transfer(&self, req: &Request, uri: &[u8], moving: bool)
+                -> Result<Reply> {
+        let destination = match req.headers().get("Destination")
+            .and_then(|dest| request_path(dest))
+        {
+            Some(destination) => destination,
+            None => return Ok(Reply::Status(400))
+        };
+
+        let prefix = self.statics.mount().public_prefix.as_os_str().as_bytes();
+        if !is_within(&destination, prefix) {
+            // Somewhere another handler, or another server, looks after
+            return Ok(Reply::Status(502));
+        }
+
+        let overwrite = match req.headers().get("Overwrite") {
+            None => true,
+            Some(value) if value.eq_ignore_ascii_case(b"T") => true,
+            Some(value) if value.eq_ignore_ascii_case(b"F") => false,
+            Some(_) => return Ok(Reply::Status(400))
+        };
+
+        let recursive = match depth(req, Depth::Infinity) {
+            Some(Depth::Infinity) => true,
+            Some(Depth::Zero) if !moving => false,
+            _ => return Ok(Reply::Status(400))
+        };
+
+        if self.is_mount_root(uri) || self.is_mount_root(&destination) {
+            return Ok(Reply::Status(403));
+        }
+
+        let source = match try!(self.existing_entry(uri)) {
+            Some(source) => source,
+            None => return Ok(Reply::Status(404))
+        };
+
+        // A link would be moved itself, but `copy_tree` won't copy one
+        if !moving && try!(fs::symlink_metadata(&source)).file_type()
+            .is_symlink() {
+            return Ok(Reply::Status(403));
+        }
+
+        let target = match try!(self.new_entry(&destination)) {
+            Some(target) => target,
+            None => return Ok(Reply::Status(409))
+        };
+
+        // Onto itself, into itself, or over its own parent
+        if target.starts_with(&source) || source.starts_with(&target) {
+            return Ok(Reply::Status(403));
+        }
+
+        let existed = fs::symlink_metadata(&target).is_ok();
+
+        let mut changing = vec![(&destination[..], true)];
+        if moving {
+            changing.push((uri, true));
+        }
+
+        if let Some(code) = try!(self.check_locks(req, &changing)) {
+            return Ok(Reply::Status(code));
+        }
+
+        if existed {
+            if !overwrite {
+                return Ok(Reply::Status(412));
+            }
+
+            try!(remove(&target));
+            self.props.remove(&target);
+            self.locks.remove_tree(&destination);
+        }
+
+        if moving {
+            try!(fs::rename(&source, &target));
+            self.props.rename(&source, &target);
+            self.locks.remove_tree(uri);
+            self.statics.forget(uri);
+            info!("Moved {:?} to {:?}", source, target);
+        }
+        else {
+            try!(copy_tree(&source, &target, recursive));
+            self.props.copy(&source, &target, recursive);
+            info!("Copied {:?} to {:?}", source, target);
+        }
+
+        self.statics.forget(&destination);
+
+        Ok(Reply::Status(if existed { 204 } else { 201 }))
+    }
+
+    /// Reports properties of the resource at `uri`, and its members if asked
+    fn propfind(&self, req: &mut Request, uri: &[u8]) -> Result<Reply> {
+        let depth = match depth(req, Depth::Infinity) {
+            Some(Depth::Infinity) => return Ok(Reply::Xml(
+                403, document::error("propfind-finite-depth")
+            )),
+            Some(depth) => depth,
+            None => return Ok(Reply::Status(400))
+        };
+
+        let wanted = match try!(read_document(req)) {
+            Some(root) => try!(document::propfind(&root)),
+            None => PropFind::AllProp
+        };
+
+        let path = match try!(self.statics.locate(uri)) {
+            Some(path) => path,
+            None => return Ok(Reply::Status(404))
+        };
+        let meta = try!(fs::metadata(&path));
+
+        let mut href = uri.to_owned();
+        if meta.is_dir() && href.last() != Some(&b'/') {
+            href.push(b'/');
+        }
+
+        let mut multistatus = Multistatus::new();
+        self.describe(&mut multistatus, &href, &path, &meta, &wanted);
+
+        if depth == Depth::One && meta.is_dir() {
+            for entry in try!(fs::read_dir(&path)) {
+                let entry = try!(entry);
+
+                let mut member = href.clone();
+                member.extend_from_slice(entry.file_name().as_bytes());
+
+                // Whatever reads would refuse stays out of sight
+                let member_path = match self.statics.locate(&member) {
+                    Ok(Some(member_path)) => member_path,
+                    _ => continue
+                };
+                let member_meta = try!(fs::metadata(&member_path));
+
+                if member_meta.is_dir() {
+                    member.push(b'/');
+                }
+
+                self.describe(&mut multistatus, &member, &member_path,
+                              &member_meta, &wanted);
+            }
+        }
+
+        Ok(Reply::Xml(207, multistatus.into_bytes()))
+    }
+
+    /// Adds the `wanted` properties of the resource at `path` to a
+    /// multistatus response
+    fn describe(&self, multistatus: &mut Multistatus, uri: &[u8], path: &Path,
+                meta: &Metadata, wanted: &PropFind) {
+        let dead = self.props.get(path);
+        let mut found = Vec::new();
+        let mut missing = Vec::new();
+
+        match wanted {
+            &PropFind::PropName => {
+                for local in props::LIVE {
+                    let name = Name::dav(local);
+                    if self.live(&name, uri, path, meta).is_some() {
+                        found.push(document::property(&name, None));
+                    }
+                }
+
+                for name in dead.keys() {
+                    found.push(document::property(name, None));
+                }
+            },
+            &PropFind::AllProp => {
+                for local in props::LIVE {
+                    let name = Name::dav(local);
+                    if let Some(value) = self.live(&name, uri, path, meta) {
+                        found.push(document::property(&name, Some(&value)));
+                    }
+                }
+
+                for (name, value) in &dead {
+                    found.push(document::property(name, Some(value)));
+                }
+            },
+            &PropFind::Prop(ref names) => {
+                for name in names {
+                    match self.live(name, uri, path, meta)
+                        .or_else(|| dead.get(name).cloned())
+                    {
+                        Some(value) =>
+                            found.push(document::property(name, Some(&value))),
+                        None => missing.push(document::property(name, None))
+                    }
+                }
+            }
+        }
+
+        multistatus.props(uri, &[
+            PropStat { status: 200, props: found },
+            PropStat { status: 404, props: missing }
+        ]);
+    }
+
+    /// The value of a live property of the resource at `path`, as XML
+    fn live(&self, name: &Name, uri: &[u8], path: &Path, meta: &Metadata)
+            -> Option<String> {
+        if name.ns != DAV {
+            return None;
+        }
+
+        let file = !meta.is_dir();
+
+        match &name.local[..] {
+            "creationdate" => meta.created().or_else(|_| meta.modified()).ok()
+                .map(props::rfc3339),
+            "displayname" => path.file_name()
+                .map(|name| document::text(&name.to_string_lossy())),
+            "getcontentlength" if file => Some(format!("{}", meta.len())),
+            "getcontenttype" if file =>
+                Some(document::text(&self.statics.content_type(path))),
+            "getetag" if file => Some(document::text(&props::etag(meta))),
+            "getlastmodified" => meta.modified().ok().map(props::http_date),
+            "lockdiscovery" => Some(self.locks.applying_to(uri).iter()
+                                    .map(activelock).collect()),
+            "resourcetype" if file => Some(String::new()),
+            "resourcetype" => Some(String::from("<D:collection/>")),
+            "supportedlock" => Some(String::from(SUPPORTED_LOCK)),
+            _ => None
+        }
+    }
+
+    /// Sets and removes dead properties of the resource at `uri`
+    ///
+    /// The changes are all made, or none are; live properties can't be
+    /// changed at all.
+    fn proppatch(&self, req: &mut Request, uri: &[u8]) -> Result<Reply> {
+        let path = match try!(self.statics.locate(uri)) {
+            Some(path) => path,
+            None => return Ok(Reply::Status(404))
+        };
+
+        if let Some(code) = try!(self.check_locks(req, &[(uri, false)])) {
+            return Ok(Reply::Status(code));
+        }
+
+        let updates = match try!(read_document(req)) {
+            Some(root) => try!(document::propertyupdate(&root)),
+            None => return Err(Error::MalformedDavBody)
+        };
+
+        let (protected, allowed): (Vec<_>, Vec<_>) = updates.iter()
+            .map(|&(ref name, _)| name)
+            .partition(|name| props::is_live(name));
+
+        let render = |names: Vec<&Name>| -> Vec<String> {
+            names.into_iter()
+                .map(|name| document::property(name, None))
+                .collect()
+        };
+
+        let mut multistatus = Multistatus::new();
+
+        if protected.is_empty() {
+            let changed = render(allowed);
+            self.props.update(&path, updates);
+            multistatus.props(uri, &[PropStat { status: 200, props: changed }]);
+        }
+        else {
+            multistatus.props(uri, &[
+                PropStat { status: 403, props: render(protected) },
+                PropStat { status: 424, props: render(allowed) }
+            ]);
+        }
+
+        Ok(Reply::Xml(207, multistatus.into_bytes()))
+    }
+
+    /// Locks the resource at `uri`, creating an empty file there if there's
+    /// nothing yet, or refreshes a lock if there's no body
+    fn lock(&self, req: &mut Request, uri: &[u8]) -> Result<Reply> {
+        let timeout = lock_timeout(req);
+
+        let info = match try!(read_document(req)) {
+            Some(root) => try!(document::lockinfo(&root)),
+            None => return self.refresh(req, uri, timeout)
+        };
+
+        let infinite = match depth(req, Depth::Infinity) {
+            Some(Depth::Zero) => false,
+            Some(Depth::Infinity) => true,
+            _ => return Ok(Reply::Status(400))
+        };
+
+        let (path, created) = match try!(self.statics.locate(uri)) {
+            Some(path) => (path, false),
+            None => match try!(self.new_entry(uri)) {
+                Some(path) => (path, true),
+                None => return Ok(Reply::Status(409))
+            }
+        };
+
+        // Locks on `uri` are the ones being negotiated, so only the If header
+        // itself is checked here
+        if let Some(code) = try!(self.check_locks(req, &[])) {
+            return Ok(Reply::Status(code));
+        }
+
+        let lock = match try!(self.locks.acquire(uri, info.exclusive, infinite,
+                                                 info.owner, timeout)) {
+            Some(lock) => lock,
+            None => return Ok(Reply::Xml(
+                423, document::error("no-conflicting-lock")
+            ))
+        };
+
+        if created {
+            if let Err(e) = OpenOptions::new().write(true).create_new(true)
+                .open(&path)
+            {
+                self.locks.release(uri, &lock.token);
+                return Err(Error::from(e));
+            }
+
+            self.statics.forget(uri);
+        }
+
+        Ok(Reply::Locked(if created { 201 } else { 200 }, lock.token.clone(),
+                         lock_body(&lock)))
+    }
+
+    /// Extends a lock whose token is in the `If` header
+    fn refresh(&self, req: &Request, uri: &[u8], timeout: Duration)
+               -> Result<Reply> {
+        let lists = match req.headers().get("If")
+            .and_then(|header| locks::parse_if(header))
+        {
+            Some(lists) => lists,
+            None => return Ok(Reply::Status(400))
+        };
+
+        for token in submitted_tokens(&lists) {
+            if let Some(lock) = self.locks.refresh(uri, &token, timeout) {
+                return Ok(Reply::Xml(200, lock_body(&lock)));
+            }
+        }
+
+        Ok(Reply::Xml(412, document::error("lock-token-matches-request-uri")))
+    }
+
+    /// Releases the lock named by the `Lock-Token` header
+    fn unlock(&self, req: &Request, uri: &[u8]) -> Result<Reply> {
+        let token = match req.headers().get("Lock-Token") {
+            Some(header) => String::from_utf8_lossy(header)
+                .trim()
+                .trim_matches(|c| c == '<' || c == '>')
+                .to_owned(),
+            None => return Ok(Reply::Status(400))
+        };
+
+        if self.locks.release(uri, &token) {
+            Ok(Reply::Status(204))
+        }
+        else {
+            Ok(Reply::Xml(409,
+                          document::error("lock-token-matches-request-uri")))
+        }
+    }
+
+    /// Evaluates the request's `If` header, then checks it submits the
+    /// tokens for any locks on the `changing` paths
+    ///
+    /// Each path comes with whether the request adds or removes it, as for
+    /// `Locks::blocks`. Returns the status to fail with, if the request
+    /// shouldn't go ahead.
+    fn check_locks(&self, req: &Request, changing: &[(&[u8], bool)])
+                   -> Result<Option<u16>> {
+        let lists = match req.headers().get("If") {
+            Some(header) => match locks::parse_if(header) {
+                Some(lists) => lists,
+                None => return Ok(Some(400))
+            },
+            None => Vec::new()
+        };
+
+        let uri = req.request_uri().as_bytes();
+        if !lists.is_empty() && !try!(self.if_holds(&lists, uri)) {
+            return Ok(Some(412));
+        }
+
+        let tokens = submitted_tokens(&lists);
+        let blocked = changing.iter().any(|&(path, members)| {
+            self.locks.blocks(path, &tokens, members)
+        });
+
+        Ok(if blocked { Some(423) } else { None })
+    }
+
+    /// Whether any list in an `If` header holds
+    ///
+    /// Untagged lists are about `uri`, the request path.
+    fn if_holds(&self, lists: &[IfList], uri: &[u8]) -> Result<bool> {
+        for list in lists {
+            let resource = match list.resource {
+                Some(ref tag) => match request_path(tag) {
+                    Some(resource) => resource,
+                    None => continue
+                },
+                None => uri.to_owned()
+            };
+
+            let mut holds = true;
+
+            for condition in &list.conditions {
+                let met = match condition.test {
+                    Test::Token(ref token) => self.locks
+                        .applying_to(&resource).iter()
+                        .any(|lock| &lock.token == token),
+                    Test::ETag(ref etag) =>
+                        try!(self.etag(&resource)).as_ref() == Some(etag)
+                };
+
+                if met == condition.not {
+                    holds = false;
+                    break;
+                }
+            }
+
+            if holds {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// The entity tag of the resource at `uri`, if there is one
+    fn etag(&self, uri: &[u8]) -> Result<Option<String>> {
+        match try!(self.statics.locate(uri)) {
+            Some(path) => Ok(Some(props::etag(&try!(fs::metadata(path))))),
+            None => Ok(None)
+        }
+    }
+
+    /// Whether `uri` is the mount's `public_prefix`, or above it
+    fn is_mount_root(&self, uri: &[u8]) -> bool {
+        is_within(self.statics.mount().public_prefix.as_os_str().as_bytes(),
+                  uri)
+    }
+
+    /// Where on disk the file, directory or link at `uri` is, without
+    /// following a link there
+    ///
+    /// That's `Ok(None)` if nothing is there.
+    fn existing_entry(&self, uri: &[u8]) -> Result<Option<PathBuf>> {
+        let entry = match try!(self.new_entry(uri)) {
+            Some(entry) => entry,
+            None => return Ok(None)
+        };
+
+        match fs::symlink_metadata(&entry) {
+            Ok(_) => Ok(Some(entry)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(Error::from(e))
+        }
+    }
+
+    /// Works out where on disk a new file or directory at `uri` would go
+    ///
+    /// Its parent must already exist, which is `Ok(None)` if not.
+    fn new_entry(&self, uri: &[u8]) -> Result<Option<PathBuf>> {
+        let trimmed = if uri.len() > 1 && uri.last() == Some(&b'/') {
+            &uri[.. uri.len() - 1]
+        }
+        else {
+            uri
+        };
+
+        let split = trimmed.iter().rposition(|&b| b == b'/').unwrap_or(0);
+        let (parent, name) = (&trimmed[.. split + 1], &trimmed[split + 1 ..]);
+
+        if name.is_empty() || name == b"." || name == b".." ||
+            self.statics.is_denied(trimmed) {
+                return Err(Error::PermissionDenied);
+            }
+
+        Ok(try!(self.statics.locate(parent))
+           .map(|dir| dir.join(OsStr::from_bytes(name))))
+    }
+}
+
+impl Handler for Dav {
+    fn serve(&self, req: Request, res: Response<Fresh>) {
+        let id = req.id().to_owned();
+
+        if let Err(e) = self.serve_inner(req, res) {
+            log_failure(&e, &id);
+        }
+    }
+}
+
+fn content_length(req: &Request) -> Option<u64> {
+    req.headers().get("Content-Length")
+        .and_then(|len| str::from_utf8(len).ok())
+        .and_then(|len| len.trim().parse().ok())
+}
+
+fn depth(req: &Request, default: Depth) -> Option<Depth> {
+    match req.headers().get("Depth").map(|depth| &depth[..]) {
+        None => Some(default),
+        Some(b"0") => Some(Depth::Zero),
+        Some(b"1") => Some(Depth::One),
+        Some(depth) if depth.eq_ignore_ascii_case(b"infinity") =>
+            Some(Depth::Infinity),
+        Some(_) => None
+    }
+}
+
+/// The lock duration a request asks for, within our limits
+fn lock_timeout(req: &Request) -> Duration {
+    let requested = req.headers().get("Timeout")
+        .and_then(|value| str::from_utf8(value).ok())
+        .and_then(|value| {
+            value.split(',')
+                .filter_map(|choice| {
+                    let choice = choice.trim();
+
+                    if choice.eq_ignore_ascii_case("infinite") {
+                        Some(MAX_LOCK_TIMEOUT)
+                    }
+                    else if choice.len() > 7 &&
+                        choice.as_bytes()[..7].eq_ignore_ascii_case(b"second-")
+                    {
+                        choice[7..].parse().ok()
+                    }
+                    else {
+                        None
+                    }
+                })
+                .next()
+        });
+
+    Duration::from_secs(cmp::min(requested.unwrap_or(DEFAULT_LOCK_TIMEOUT),
+                                 MAX_LOCK_TIMEOUT))
+}
+
+/// The lock tokens an `If` header submits
+fn submitted_tokens(lists: &[IfList]) -> Vec<String> {
+    lists.iter()
+        .flat_map(|list| list.conditions.iter())
+        .filter_map(|condition| match condition.test {
+            Test::Token(ref token) if !condition.not => Some(token.clone()),
+            _ => None
+        })
+        .collect()
+}
+
+/// Reads the request's XML body, if it has one
+fn read_document(req: &mut Request) -> Result<Option<Element>> {
+    match content_length(req) {
+        None | Some(0) => Ok(None),
+        Some(len) => document::parse(req.by_ref().take(len)).map(Some)
+    }
+}
+
+/// Takes the path out of a URI that may be absolute, like a `Destination`
+fn request_path(uri: &[u8]) -> Option<Vec<u8>> {
+    let after_scheme = ["http://", "https://"].iter()
+        .find(|scheme| {
+            uri.len() >= scheme.len() &&
+                uri[.. scheme.len()].eq_ignore_ascii_case(scheme.as_bytes())
+        })
+        .map(|scheme| &uri[scheme.len() ..]);
+
+    let path = match after_scheme {
+        Some(rest) => match rest.iter().position(|&b| b == b'/') {
+            Some(start) => &rest[start ..],
+            None => &b"/"[..]
+        },
+        None => uri
+    };
+
+    let (path, _) = split_query(path);
+    let end = path.iter().position(|&b| b == b'#').unwrap_or(path.len());
+
+    if path.first() != Some(&b'/') {
+        return None;
+    }
+
+    normalize_path(&path[.. end]).ok()
+}
+
+/// Removes a file, or a directory and everything in it
+fn remove(path: &Path) -> io::Result<()> {
+    if try!(fs::symlink_metadata(path)).is_dir() {
+        fs::remove_dir_all(path)
+    }
+    else {
+        fs::remove_file(path)
+    }
+}
+
+/// Copies the file or directory at `from` to `to`, with the directory's
+/// contents too if `recursive` is set
+///
+/// Symbolic links aren't copied, so nothing from outside the webroot can be
+/// pulled into it.
+fn copy_tree(from: &Path, to: &Path, recursive: bool) -> io::Result<()> {
+    let meta = try!(fs::symlink_metadata(from));
+
+    if meta.file_type().is_symlink() {
+        return Ok(());
+    }
+
+    if !meta.is_dir() {
+        return fs::copy(from, to).map(|_| ());
+    }
+
+    try!(fs::create_dir(to));
+
+    if recursive {
+        for entry in try!(fs::read_dir(from)) {
+            let entry = try!(entry);
+            try!(copy_tree(&entry.path(), &to.join(entry.file_name()), true));
+        }
+    }
+
+    Ok(())
+}
+
+fn activelock(lock: &Lock) -> String {
+    format!("<D:activelock><D:locktype><D:write/></D:locktype>\
+             <D:lockscope><D:{}/></D:lockscope><D:depth>{}</D:depth>{}\
+             <D:timeout>Second-{}</D:timeout>\
+             <D:locktoken><D:href>{}</D:href></D:locktoken>\
+             <D:lockroot>{}</D:lockroot></D:activelock>",
+            if lock.exclusive { "exclusive" } else { "shared" },
+            if lock.infinite { "infinity" } else { "0" },
+            lock.owner.as_ref().map_or(String::new(), |owner| {
+                format!("<D:owner>{}</D:owner>", owner)
+            }),
+            lock.timeout.as_secs(),
+            document::text(&lock.token),
+            document::href(&lock.root))
+}
+
+/// The body of a `LOCK` response
+fn lock_body(lock: &Lock) -> Vec<u8> {
+    format!("<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+             <D:prop xmlns:D=\"DAV:\"><D:lockdiscovery>{}</D:lockdiscovery>\
+             </D:prop>", activelock(lock))
+        .into_bytes()
+}
+
+/// Sends a bodiless response
+fn status(mut res: Response<Fresh>, code: u16) -> io::Result<()> {
+    res.set_status(code, String::from(reason(code)));
+    res.headers_mut().insert("Content-Length", Vec::from(&b"0"[..]));
+    res.of_stream(&b""[..])
+}
+
+fn xml(mut res: Response<Fresh>, code: u16, body: Vec<u8>) -> io::Result<()> {
+    res.set_status(code, String::from(reason(code)));
+    {
+        let headers = res.headers_mut();
+        headers.insert("Content-Type",
+                       Vec::from(&b"application/xml; charset=utf-8"[..]));
+        headers.insert("Content-Length",
+                       format!("{}", body.len()).into_bytes());
+    }
+
+    res.of_stream(&body[..])
+}
+
+fn options(mut res: Response<Fresh>) -> io::Result<()> {
+    {
+        let headers = res.headers_mut();
+        headers.insert("Allow", Vec::from(ALLOWED.as_bytes()));
+        headers.insert("DAV", Vec::from(&b"1, 2"[..]));
+        // Without this, Microsoft's clients won't try WebDAV at all
+        headers.insert("MS-Author-Via", Vec::from(&b"DAV"[..]));
+    }
+
+    status(res, 200)
+}
+
+fn unauthorized(mut res: Response<Fresh>) -> io::Result<()> {
+    res.headers_mut().insert("WWW-Authenticate",
+                             Vec::from(&b"Basic realm=\"uploads\""[..]));
+    status(res, 401)
+}
+
+/// End-to-end checks in the spirit of the litmus WebDAV test suite, each
+/// against a scratch webroot through a real socket
+#[cfg(test)]
+mod test {
+    use super::*;
+    use config::{StaticFilesConfig, Timeouts, WritableConfig};
+    use server::auth::basic_credentials;
+    use server::connection;
+
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::os::unix::fs::symlink;
+    use std::path::{Path, PathBuf};
+    use std::process;
+    use std::sync::Arc;
+    use std::thread;
+
+    struct Answer {
+        status: u16,
+        head: String,
+        body: String
+    }
+
+    impl Answer {
+        fn header(&self, name: &str) -> Option<&str> {
+            self.head.lines()
+                .find(|line| {
+                    line.to_ascii_lowercase()
+                        .starts_with(&format!("{}:", name.to_ascii_lowercase()))
+                })
+                .map(|line| line[name.len() + 1 ..].trim())
+        }
+    }
+
+    /// Sends `raw` to `dav` through a real socket
+    fn send(dav: &Dav, raw: String) -> Answer {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(raw.as_bytes()).unwrap();
+
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        connection::serve(stream.into(), dav, &Timeouts {
+            keep_alive: 0,
+            ..Default::default()
+        }, &Default::default());
+
+        let response = client.join().unwrap();
+        let split = response.find("\r\n\r\n").unwrap();
+
+        Answer {
+            status: response[9..12].parse().unwrap(),
+            head: String::from(&response[.. split]),
+            body: String::from(&response[split + 4 ..])
+        }
+    }
+
+    /// Sends a request as `ci`, the user the tests' mounts let write
+    fn request(dav: &Dav, method: &str, path: &str, headers: &[(&str, &str)],
+               body: &str) -> Answer {
+        let credentials = String::from_utf8(
+            basic_credentials("ci", "secret")
+        ).unwrap();
+
+        let mut raw = format!("{} {} HTTP/1.1\r\nHost: localhost\r\n\
+                               Authorization: {}\r\n\
+                               Content-Length: {}\r\n",
+                              method, path, credentials, body.len());
+        for &(name, value) in headers {
+            raw.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        raw.push_str("\r\n");
+        raw.push_str(body);
+
+        send(dav, raw)
+    }
+
+    fn read(path: &Path) -> String {
+        let mut contents = String::new();
+        File::open(path).unwrap().read_to_string(&mut contents).unwrap();
+        contents
+    }
+
+    const PROPFIND_COLOR: &'static str =
+        "<?xml version=\"1.0\"?><D:propfind xmlns:D=\"DAV:\" \
+         xmlns:Z=\"urn:z\"><D:prop><Z:color/></D:prop></D:propfind>";
+
+    const LOCK_EXCLUSIVE: &'static str =
+        "<?xml version=\"1.0\"?><D:lockinfo xmlns:D=\"DAV:\">\
+         <D:lockscope><D:exclusive/></D:lockscope>\
+         <D:locktype><D:write/></D:locktype>\
+         <D:owner><D:href>mailto:ci@example.com</D:href></D:owner>\
+         </D:lockinfo>";
+
+    #[test]
+    fn options_advertises_classes_1_and_2() {
+        let webroot = env::temp_dir()
+            .join(format!("http-server-dav-{}-options", process::id()));
+        let _ = fs::remove_dir_all(&webroot);
+        fs::create_dir_all(&webroot).unwrap();
+        let webroot = fs::canonicalize(&webroot).unwrap();
+        let dav = Dav::new(Arc::new(Statics::new(StaticFilesConfig {
+            webroot: webroot.clone(),
+            public_prefix: PathBuf::from("/"),
+            ..Default::default()
+        }, Vec::new(), None)), &WritableConfig {
+            max_size: 1024,
+            users: vec![(String::from("ci"), String::from("secret"))]
+        });
+
+        let answer = send(&dav, String::from("OPTIONS / HTTP/1.1\r\n\r\n"));
+
+        assert_eq!(answer.status, 200);
+        assert_eq!(answer.header("DAV"), Some("1, 2"));
+        assert!(answer.header("Allow").unwrap().contains("PROPFIND"));
+
+        fs::remove_dir_all(&webroot).unwrap();
+    }
+
+    #[test]
+    fn writes_need_credentials() {
+        let webroot = env::temp_dir()
+            .join(format!("http-server-dav-{}-credentials", process::id()));
+        let _ = fs::remove_dir_all(&webroot);
+        fs::create_dir_all(&webroot).unwrap();
+        let webroot = fs::canonicalize(&webroot).unwrap();
+        let dav = Dav::new(Arc::new(Statics::new(StaticFilesConfig {
+            webroot: webroot.clone(),
+            public_prefix: PathBuf::from("/"),
+            ..Default::default()
+        }, Vec::new(), None)), &WritableConfig {
+            max_size: 1024,
+            users: vec![(String::from("ci"), String::from("secret"))]
+        });
+
+        let answer = send(&dav, String::from(
+            "PUT /a.txt HTTP/1.1\r\nContent-Length: 0\r\n\r\n"
+        ));
+
+        assert_eq!(answer.status, 401);
+        assert!(answer.header("WWW-Authenticate").is_some());
+
+        fs::remove_dir_all(&webroot).unwrap();
+    }
+
+    #[test]
+    fn put_then_delete() {
+        let webroot = env::temp_dir()
+            .join(format!("http-server-dav-{}-put", process::id()));
+        let _ = fs::remove_dir_all(&webroot);
+        fs::create_dir_all(&webroot).unwrap();
+        let webroot = fs::canonicalize(&webroot).unwrap();
+        let dav = Dav::new(Arc::new(Statics::new(StaticFilesConfig {
+            webroot: webroot.clone(),
+            public_prefix: PathBuf::from("/"),
+            ..Default::default()
+        }, Vec::new(), None)), &WritableConfig {
+            max_size: 1024,
+            users: vec![(String::from("ci"), String::from("secret"))]
+        });
+
+        assert_eq!(request(&dav, "PUT", "/a.txt", &[], "hello").status, 201);
+        assert_eq!(request(&dav, "PUT", "/a.txt", &[], "again").status, 204);
+        assert_eq!(read(&webroot.join("a.txt")), "again");
+
+        assert_eq!(request(&dav, "DELETE", "/a.txt", &[], "").status, 204);
+        assert_eq!(request(&dav, "DELETE", "/a.txt", &[], "").status, 404);
+
+        fs::remove_dir_all(&webroot).unwrap();
+    }
+
+    #[test]
+    fn delete_spares_the_mount_and_link_targets() {
+        let webroot = env::temp_dir()
+            .join(format!("http-server-dav-{}-delete", process::id()));
+        let outside = env::temp_dir()
+            .join(format!("http-server-dav-{}-delete-outside", process::id()));
+        let _ = fs::remove_dir_all(&webroot);
+        let _ = fs::remove_dir_all(&outside);
+        fs::create_dir_all(&webroot).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        File::create(outside.join("keep.txt")).unwrap();
+        symlink(&outside, webroot.join("link")).unwrap();
+        fs::create_dir(webroot.join("dir")).unwrap();
+        File::create(webroot.join("dir/keep.txt")).unwrap();
+        symlink("dir", webroot.join("dirlink")).unwrap();
+        let webroot = fs::canonicalize(&webroot).unwrap();
+        let dav = Dav::new(Arc::new(Statics::new(StaticFilesConfig {
+            webroot: webroot.clone(),
+            public_prefix: PathBuf::from("/files"),
+            ..Default::default()
+        }, Vec::new(), None)), &WritableConfig {
+            max_size: 1024,
+            users: vec![(String::from("ci"), String::from("secret"))]
+        });
+
+        assert_eq!(request(&dav, "DELETE", "/files/", &[], "").status, 403);
+        assert_eq!(request(&dav, "DELETE", "/files", &[], "").status, 403);
+        assert_eq!(request(&dav, "DELETE", "/", &[], "").status, 403);
+        assert!(webroot.is_dir());
+
+        assert_eq!(request(&dav, "DELETE", "/files/link/", &[], "").status,
+                   204);
+        assert!(fs::symlink_metadata(webroot.join("link")).is_err());
+        assert!(outside.join("keep.txt").exists());
+
+        assert_eq!(request(&dav, "DELETE", "/files/dirlink/", &[], "").status,
+                   204);
+        assert!(fs::symlink_metadata(webroot.join("dirlink")).is_err());
+        assert!(webroot.join("dir/keep.txt").exists());
+
+        fs::remove_dir_all(&webroot).unwrap();
+        fs::remove_dir_all(&outside).unwrap();
+    }
+
+    #[test]
+    fn mkcol_cases() {
+        let webroot = env::temp_dir()
+            .join(format!("http-server-dav-{}-mkcol", process::id()));
+        let _ = fs::remove_dir_all(&webroot);
+        fs::create_dir_all(&webroot).unwrap();
+        let webroot = fs::canonicalize(&webroot).unwrap();
+        let dav = Dav::new(Arc::new(Statics::new(StaticFilesConfig {
+            webroot: webroot.clone(),
+            public_prefix: PathBuf::from("/"),
+            ..Default::default()
+        }, Vec::new(), None)), &WritableConfig {
+            max_size: 1024,
+            users: vec![(String::from("ci"), String::from("secret"))]
+        });
+
+        assert_eq!(request(&dav, "MKCOL", "/d", &[], "").status, 201);
+        assert_eq!(request(&dav, "MKCOL", "/d", &[], "").status, 405);
+        assert_eq!(request(&dav, "MKCOL", "/x/y", &[], "").status, 409);
+        assert_eq!(request(&dav, "MKCOL", "/e", &[], "<x/>").status, 415);
+
+        fs::remove_dir_all(&webroot).unwrap();
+    }
+
+    #[test]
+    fn copy_and_move_files() {
+        let webroot = env::temp_dir()
+            .join(format!("http-server-dav-{}-copymove", process::id()));
+        let _ = fs::remove_dir_all(&webroot);
+        fs::create_dir_all(&webroot).unwrap();
+        let webroot = fs::canonicalize(&webroot).unwrap();
+        let dav = Dav::new(Arc::new(Statics::new(StaticFilesConfig {
+            webroot: webroot.clone(),
+            public_prefix: PathBuf::from("/"),
+            ..Default::default()
+        }, Vec::new(), None)), &WritableConfig {
+            max_size: 1024,
+            users: vec![(String::from("ci"), String::from("secret"))]
+        });
+
+        request(&dav, "PUT", "/src.txt", &[], "data");
+
+        let to = |dest: &'static str| ("Destination", dest);
+
+        assert_eq!(request(&dav, "COPY", "/src.txt",
+                           &[to("http://localhost/dst.txt")], "")
+                   .status, 201);
+        assert_eq!(request(&dav, "COPY", "/src.txt",
+                           &[to("/dst.txt"), ("Overwrite", "F")], "")
+                   .status, 412);
+        assert_eq!(request(&dav, "MOVE", "/src.txt",
+                           &[to("/dst.txt"), ("Overwrite", "T")], "")
+                   .status, 204);
+
+        assert_eq!(read(&webroot.join("dst.txt")), "data");
+        assert!(!webroot.join("src.txt").exists());
+        assert_eq!(request(&dav, "MOVE", "/dst.txt", &[to("/dst.txt")], "")
+                   .status, 403);
+
+        fs::remove_dir_all(&webroot).unwrap();
+    }
+
+    #[test]
+    fn collections_copy_with_members() {
+        let webroot = env::temp_dir()
+            .join(format!("http-server-dav-{}-copycol", process::id()));
+        let _ = fs::remove_dir_all(&webroot);
+        fs::create_dir_all(&webroot).unwrap();
+        let webroot = fs::canonicalize(&webroot).unwrap();
+        let dav = Dav::new(Arc::new(Statics::new(StaticFilesConfig {
+            webroot: webroot.clone(),
+            public_prefix: PathBuf::from("/"),
+            ..Default::default()
+        }, Vec::new(), None)), &WritableConfig {
+            max_size: 1024,
+            users: vec![(String::from("ci"), String::from("secret"))]
+        });
+
+        request(&dav, "MKCOL", "/c", &[], "");
+        request(&dav, "PUT", "/c/f.txt", &[], "member");
+
+        assert_eq!(request(&dav, "COPY", "/c/", &[("Destination", "/c2/")],
+                           "").status, 201);
+        assert_eq!(read(&webroot.join("c2/f.txt")), "member");
+
+        assert_eq!(request(&dav, "COPY", "/c/",
+                           &[("Destination", "/c/inner/")], "")
+                   .status, 403);
+
+        fs::remove_dir_all(&webroot).unwrap();
+    }
+
+    #[test]
+    fn transfers_spare_the_mount_and_move_links_themselves() {
+        let webroot = env::temp_dir()
+            .join(format!("http-server-dav-{}-transfer", process::id()));
+        let _ = fs::remove_dir_all(&webroot);
+        fs::create_dir_all(webroot.join("dir")).unwrap();
+        File::create(webroot.join("dir/keep.txt")).unwrap();
+        symlink("dir", webroot.join("dirlink")).unwrap();
+        let webroot = fs::canonicalize(&webroot).unwrap();
+        let dav = Dav::new(Arc::new(Statics::new(StaticFilesConfig {
+            webroot: webroot.clone(),
+            public_prefix: PathBuf::from("/files"),
+            ..Default::default()
+        }, Vec::new(), None)), &WritableConfig {
+            max_size: 1024,
+            users: vec![(String::from("ci"), String::from("secret"))]
+        });
+
+        request(&dav, "PUT", "/files/a.txt", &[], "data");
+        assert_eq!(request(&dav, "COPY", "/files/a.txt",
+                           &[("Destination", "/files/b.txt")], "").status,
+                   201);
+        assert_eq!(read(&webroot.join("b.txt")), "data");
+
+        assert_eq!(request(&dav, "MOVE", "/files/",
+                           &[("Destination", "/files/moved/")], "").status,
+                   403);
+        assert_eq!(request(&dav, "COPY", "/files",
+                           &[("Destination", "/files/copy/")], "").status,
+                   403);
+        assert_eq!(request(&dav, "MOVE", "/files/a.txt",
+                           &[("Destination", "/files/")], "").status,
+                   403);
+        assert!(webroot.join("a.txt").exists());
+
+        assert_eq!(request(&dav, "COPY", "/files/dirlink/",
+                           &[("Destination", "/files/copied/")], "").status,
+                   403);
+        assert_eq!(request(&dav, "MOVE", "/files/dirlink/",
+                           &[("Destination", "/files/moved")], "").status,
+                   201);
+        assert!(fs::symlink_metadata(webroot.join("moved")).unwrap()
+                .file_type().is_symlink());
+        assert!(webroot.join("dir/keep.txt").exists());
+
+        fs::remove_dir_all(&webroot).unwrap();
+    }
+
+    #[test]
+    fn propfind_reports_live_properties() {
+        let webroot = env::temp_dir()
+            .join(format!("http-server-dav-{}-propfind", process::id()));
+        let _ = fs::remove_dir_all(&webroot);
+        fs::create_dir_all(&webroot).unwrap();
+        let webroot = fs::canonicalize(&webroot).unwrap();
+        let dav = Dav::new(Arc::new(Statics::new(StaticFilesConfig {
+            webroot: webroot.clone(),
+            public_prefix: PathBuf::from("/"),
+            ..Default::default()
+        }, Vec::new(), None)), &WritableConfig {
+            max_size: 1024,
+            users: vec![(String::from("ci"), String::from("secret"))]
+        });
+
+        request(&dav, "PUT", "/p.txt", &[], "abc");
+
+        let answer = request(
+            &dav, "PROPFIND", "/p.txt", &[("Depth", "0")],
+            "<?xml version=\"1.0\"?><propfind xmlns=\"DAV:\"><prop>\
+             <getcontentlength/><resourcetype/><nothing/></prop></propfind>"
+        );
+
+        assert_eq!(answer.status, 207);
+        assert!(answer.body.contains(
+            "<D:getcontentlength>3</D:getcontentlength>"
+        ));
+        assert!(answer.body.contains("<D:resourcetype/>"));
+        assert!(answer.body.contains(
+            "<D:nothing/></D:prop><D:status>HTTP/1.1 404 Not Found"
+        ));
+
+        fs::remove_dir_all(&webroot).unwrap();
+    }
+
+    #[test]
+    fn propfind_lists_members_but_not_dotfiles() {
+        let webroot = env::temp_dir()
+            .join(format!("http-server-dav-{}-members", process::id()));
+        let _ = fs::remove_dir_all(&webroot);
+        fs::create_dir_all(&webroot).unwrap();
+        let webroot = fs::canonicalize(&webroot).unwrap();
+        let dav = Dav::new(Arc::new(Statics::new(StaticFilesConfig {
+            webroot: webroot.clone(),
+            public_prefix: PathBuf::from("/"),
+            ..Default::default()
+        }, Vec::new(), None)), &WritableConfig {
+            max_size: 1024,
+            users: vec![(String::from("ci"), String::from("secret"))]
+        });
+
+        request(&dav, "MKCOL", "/sub", &[], "");
+        request(&dav, "PUT", "/a.txt", &[], "");
+        File::create(webroot.join(".secret")).unwrap();
+
+        let answer = request(&dav, "PROPFIND", "/", &[("Depth", "1")], "");
+
+        assert_eq!(answer.status, 207);
+        assert!(answer.body.contains("<D:href>/a.txt</D:href>"));
+        assert!(answer.body.contains("<D:href>/sub/</D:href>"));
+        assert!(answer.body.contains("<D:collection/>"));
+        assert!(!answer.body.contains("secret"));
+
+        fs::remove_dir_all(&webroot).unwrap();
+    }
+
+    #[test]
+    fn propfind_of_infinite_depth_is_refused() {
+        let webroot = env::temp_dir()
+            .join(format!("http-server-dav-{}-infinity", process::id()));
+        let _ = fs::remove_dir_all(&webroot);
+        fs::create_dir_all(&webroot).unwrap();
+        let webroot = fs::canonicalize(&webroot).unwrap();
+        let dav = Dav::new(Arc::new(Statics::new(StaticFilesConfig {
+            webroot: webroot.clone(),
+            public_prefix: PathBuf::from("/"),
+            ..Default::default()
+        }, Vec::new(), None)), &WritableConfig {
+            max_size: 1024,
+            users: vec![(String::from("ci"), String::from("secret"))]
+        });
+
+        let answer = request(&dav, "PROPFIND", "/", &[], "");
+
+        assert_eq!(answer.status, 403);
+        assert!(answer.body.contains("propfind-finite-depth"));
+
+        fs::remove_dir_all(&webroot).unwrap();
+    }
+
+    #[test]
+    fn proppatch_sets_and_removes_dead_properties() {
+        let webroot = env::temp_dir()
+            .join(format!("http-server-dav-{}-proppatch", process::id()));
+        let _ = fs::remove_dir_all(&webroot);
+        fs::create_dir_all(&webroot).unwrap();
+        let webroot = fs::canonicalize(&webroot).unwrap();
+        let dav = Dav::new(Arc::new(Statics::new(StaticFilesConfig {
+            webroot: webroot.clone(),
+            public_prefix: PathBuf::from("/"),
+            ..Default::default()
+        }, Vec::new(), None)), &WritableConfig {
+            max_size: 1024,
+            users: vec![(String::from("ci"), String::from("secret"))]
+        });
+
+        request(&dav, "PUT", "/p.txt", &[], "");
+
+        let answer = request(
+            &dav, "PROPPATCH", "/p.txt", &[],
+            "<?xml version=\"1.0\"?><D:propertyupdate xmlns:D=\"DAV:\" \
+             xmlns:Z=\"urn:z\"><D:set><D:prop><Z:color>red &amp; \
+             blue</Z:color></D:prop></D:set></D:propertyupdate>"
+        );
+        assert_eq!(answer.status, 207);
+        assert!(answer.body.contains("HTTP/1.1 200 OK"));
+
+        let answer = request(&dav, "PROPFIND", "/p.txt", &[("Depth", "0")],
+                             PROPFIND_COLOR);
+        assert!(answer.body.contains(
+            "<color xmlns=\"urn:z\">red &amp; blue</color>"
+        ));
+
+        request(
+            &dav, "PROPPATCH", "/p.txt", &[],
+            "<?xml version=\"1.0\"?><D:propertyupdate xmlns:D=\"DAV:\" \
+             xmlns:Z=\"urn:z\"><D:remove><D:prop><Z:color/></D:prop>\
+             </D:remove></D:propertyupdate>"
+        );
+
+        let answer = request(&dav, "PROPFIND", "/p.txt", &[("Depth", "0")],
+                             PROPFIND_COLOR);
+        assert!(answer.body.contains("404 Not Found"));
+
+        fs::remove_dir_all(&webroot).unwrap();
+    }
+
+    #[test]
+    fn proppatch_of_live_properties_changes_nothing() {
+        let webroot = env::temp_dir()
+            .join(format!("http-server-dav-{}-protected", process::id()));
+        let _ = fs::remove_dir_all(&webroot);
+        fs::create_dir_all(&webroot).unwrap();
+        let webroot = fs::canonicalize(&webroot).unwrap();
+        let dav = Dav::new(Arc::new(Statics::new(StaticFilesConfig {
+            webroot: webroot.clone(),
+            public_prefix: PathBuf::from("/"),
+            ..Default::default()
+        }, Vec::new(), None)), &WritableConfig {
+            max_size: 1024,
+            users: vec![(String::from("ci"), String::from("secret"))]
+        });
+
+        request(&dav, "PUT", "/p.txt", &[], "");
+
+        let answer = request(
+            &dav, "PROPPATCH", "/p.txt", &[],
+            "<?xml version=\"1.0\"?><D:propertyupdate xmlns:D=\"DAV:\" \
+             xmlns:Z=\"urn:z\"><D:set><D:prop><D:getetag>x</D:getetag>\
+             <Z:color>red</Z:color></D:prop></D:set></D:propertyupdate>"
+        );
+        assert!(answer.body.contains("403 Forbidden"));
+        assert!(answer.body.contains("424 Failed Dependency"));
+
+        let answer = request(&dav, "PROPFIND", "/p.txt", &[("Depth", "0")],
+                             PROPFIND_COLOR);
+        assert!(answer.body.contains("404 Not Found"));
+
+        fs::remove_dir_all(&webroot).unwrap();
+    }
+
+    #[test]
+    fn locks_guard_writes() {
+        let webroot = env::temp_dir()
+            .join(format!("http-server-dav-{}-lock", process::id()));
+        let _ = fs::remove_dir_all(&webroot);
+        fs::create_dir_all(&webroot).unwrap();
+        let webroot = fs::canonicalize(&webroot).unwrap();
+        let dav = Dav::new(Arc::new(Statics::new(StaticFilesConfig {
+            webroot: webroot.clone(),
+            public_prefix: PathBuf::from("/"),
+            ..Default::default()
+        }, Vec::new(), None)), &WritableConfig {
+            max_size: 1024,
+            users: vec![(String::from("ci"), String::from("secret"))]
+        });
+
+        let answer = request(&dav, "LOCK", "/l.txt", &[], LOCK_EXCLUSIVE);
+        assert_eq!(answer.status, 201);
+        assert!(answer.body.contains("mailto:ci@example.com"));
+
+        let token = String::from(answer.header("Lock-Token").unwrap());
+        let condition = format!("({})", token);
+
+        assert_eq!(request(&dav, "PUT", "/l.txt", &[], "x").status, 423);
+        assert_eq!(request(&dav, "LOCK", "/l.txt", &[], LOCK_EXCLUSIVE)
+                   .status, 423);
+        assert_eq!(request(&dav, "PUT", "/l.txt", &[("If", &condition)], "x")
+                   .status, 204);
+
+        let refreshed = request(&dav, "LOCK", "/l.txt",
+                                &[("If", &condition),
+                                  ("Timeout", "Second-100")], "");
+        assert_eq!(refreshed.status, 200);
+        assert!(refreshed.body.contains("Second-100"));
+
+        assert_eq!(request(&dav, "UNLOCK", "/l.txt",
+                           &[("Lock-Token", &token)], "").status, 204);
+        assert_eq!(request(&dav, "PUT", "/l.txt", &[], "y").status, 204);
+
+        fs::remove_dir_all(&webroot).unwrap();
+    }
+
+    #[test]
+    fn collection_locks_cover_new_members() {
+        let webroot = env::temp_dir()
+            .join(format!("http-server-dav-{}-lockcol", process::id()));
+        let _ = fs::remove_dir_all(&webroot);
+        fs::create_dir_all(&webroot).unwrap();
+        let webroot = fs::canonicalize(&webroot).unwrap();
+        let dav = Dav::new(Arc::new(Statics::new(StaticFilesConfig {
+            webroot: webroot.clone(),
+            public_prefix: PathBuf::from("/"),
+            ..Default::default()
+        }, Vec::new(), None)), &WritableConfig {
+            max_size: 1024,
+            users: vec![(String::from("ci"), String::from("secret"))]
+        });
+
+        request(&dav, "MKCOL", "/dir", &[], "");
+
+        let answer = request(&dav, "LOCK", "/dir/", &[], LOCK_EXCLUSIVE);
+        assert_eq!(answer.status, 200);
+        let condition = format!("({})",
+                                answer.header("Lock-Token").unwrap());
+
+        assert_eq!(request(&dav, "PUT", "/dir/new.txt", &[], "").status, 423);
+        assert_eq!(request(&dav, "DELETE", "/dir", &[], "").status, 423);
+        assert_eq!(request(&dav, "PUT", "/dir/new.txt",
+                           &[("If", &condition)], "").status, 201);
+
+        fs::remove_dir_all(&webroot).unwrap();
+    }
+
+    #[test]
+    fn if_header_etags_are_checked() {
+        let webroot = env::temp_dir()
+            .join(format!("http-server-dav-{}-etag", process::id()));
+        let _ = fs::remove_dir_all(&webroot);
+        fs::create_dir_all(&webroot).unwrap();
+        let webroot = fs::canonicalize(&webroot).unwrap();
+        let dav = Dav::new(Arc::new(Statics::new(StaticFilesConfig {
+            webroot: webroot.clone(),
+            public_prefix: PathBuf::from("/"),
+            ..Default::default()
+        }, Vec::new(), None)), &WritableConfig {
+            max_size: 1024,
+            users: vec![(String::from("ci"), String::from("secret"))]
+        });
+
+        request(&dav, "PUT", "/e.txt", &[], "v1");
+
+        assert_eq!(request(&dav, "PUT", "/e.txt",
+                           &[("If", "([\"nope\"])")], "v2").status,
+                   412);
+        assert_eq!(request(&dav, "PUT", "/e.txt",
+                           &[("If", "(Not [\"nope\"])")], "v2").status,
+                   204);
+        assert_eq!(request(&dav, "PUT", "/e.txt", &[("If", "(bogus)")], "")
+                   .status, 400);
+
+        fs::remove_dir_all(&webroot).unwrap();
+    }
+}
@@ -0,0 +1,207 @@
+//! Property bookkeeping: dead properties set by clients, and the formats live
+//! properties are reported in
+
+use super::document::{Name, DAV};
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Properties the server computes, in the order `allprop` reports them
+pub const LIVE: &'static [&'static str] = &[
+    "creationdate",
+    "displayname",
+    "getcontentlength",
+    "getcontenttype",
+    "getetag",
+    "getlastmodified",
+    "lockdiscovery",
+    "resourcetype",
+    "supportedlock"
+];
+
+/// Whether `name` is a live property, which clients may not change
+pub fn is_live(name: &Name) -> bool {
+    name.ns == DAV && LIVE.contains(&&name.local[..])
+}
+
+/// Properties set with `PROPPATCH`, by file path
+///
+/// These are kept in memory only, so they're lost when the server restarts.
+pub struct DeadProps {
+    props: Mutex<HashMap<PathBuf, BTreeMap<Name, String>>>
+}
+
+impl DeadProps {
+    pub fn new() -> DeadProps {
+        DeadProps { props: Mutex::new(HashMap::new()) }
+    }
+
+    fn lock<'a>(&'a self)
+                -> MutexGuard<'a, HashMap<PathBuf, BTreeMap<Name, String>>> {
+        // Every update leaves the map consistent, so a panic elsewhere can't
+        // have left it half-changed
+        self.props.lock().unwrap_or_else(|poison| poison.into_inner())
+    }
+
+    /// The dead properties of the file at `path`, with their values as XML
+    pub fn get(&self, path: &Path) -> BTreeMap<Name, String> {
+        self.lock().get(path).cloned().unwrap_or_default()
+    }
+
+    /// Sets or removes properties of the file at `path`
+    pub fn update(&self, path: &Path, updates: Vec<(Name, Option<String>)>) {
+        let mut props = self.lock();
+        let entry = props.entry(path.to_owned()).or_insert_with(BTreeMap::new);
+
+        for (name, value) in updates {
+            match value {
+                Some(value) => { entry.insert(name, value); },
+                None => { entry.remove(&name); }
+            }
+        }
+    }
+
+    /// Gives `to` a copy of the properties of `from`, and with `recursive`
+    /// set, everything below `to` copies of what's below `from`
+    pub fn copy(&self, from: &Path, to: &Path, recursive: bool) {
+        let mut props = self.lock();
+        props.retain(|path, _| !path.starts_with(to));
+
+        let copies: Vec<_> = props.iter()
+            .filter(|&(path, _)| recursive || path == from)
+            .filter_map(|(path, values)| {
+                path.strip_prefix(from).ok()
+                    .map(|rest| (to.join(rest), values.clone()))
+            })
+            .collect();
+
+        props.extend(copies);
+    }
+
+    /// Moves the properties in the tree at `from` to the tree at `to`
+    pub fn rename(&self, from: &Path, to: &Path) {
+        let mut props = self.lock();
+        props.retain(|path, _| !path.starts_with(to));
+
+        let moving: Vec<PathBuf> = props.keys()
+            .filter(|path| path.starts_with(from))
+            .cloned()
+            .collect();
+
+        for path in moving {
+            let values = props.remove(&path).unwrap();
+            let rest = path.strip_prefix(from).unwrap().to_owned();
+            props.insert(to.join(rest), values);
+        }
+    }
+
+    /// Forgets the properties of everything in the tree at `path`
+    pub fn remove(&self, path: &Path) {
+        self.lock().retain(|held, _| !held.starts_with(path));
+    }
+}
+
+/// An entity tag for a file, from its size and modification time
+pub fn etag(meta: &Metadata) -> String {
+    let modified = meta.modified().ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |since| since.as_secs() << 20 |
+                (since.subsec_nanos() / 1000) as u64);
+
+    format!("\"{:x}-{:x}\"", meta.len(), modified)
+}
+
+/// Formats a time the way HTTP headers do, like
+/// `Sun, 06 Nov 1994 08:49:37 GMT`
+pub fn http_date(time: SystemTime) -> String {
+    const DAYS: [&'static str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue",
+                                     "Wed"];
+    const MONTHS: [&'static str; 12] = ["Jan", "Feb", "Mar", "Apr", "May",
+                                        "Jun", "Jul", "Aug", "Sep", "Oct",
+                                        "Nov", "Dec"];
+
+    let secs = unix_seconds(time);
+    let days = secs / 86400;
+    let (year, month, day) = civil_from_days(days);
+
+    format!("{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+            DAYS[(days % 7) as usize], day, MONTHS[month as usize - 1], year,
+            secs % 86400 / 3600, secs % 3600 / 60, secs % 60)
+}
+
+/// Formats a time as RFC 3339 asks, like `1994-11-06T08:49:37Z`
+pub fn rfc3339(time: SystemTime) -> String {
+    let secs = unix_seconds(time);
+    let (year, month, day) = civil_from_days(secs / 86400);
+
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            year, month, day,
+            secs % 86400 / 3600, secs % 3600 / 60, secs % 60)
+}
+
+/// Seconds since the epoch, clamping earlier times to it
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or(0)
+}
+
+/// Turns days since 1970-01-01 into a year, month and day
+///
+/// This is Howard Hinnant's `civil_from_days`, cut down for dates after the
+/// epoch.
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z % 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::path::Path;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn dates_are_formatted() {
+        let time = UNIX_EPOCH + Duration::from_secs(784111777);
+
+        assert_eq!(http_date(time), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(rfc3339(time), "1994-11-06T08:49:37Z");
+        assert_eq!(http_date(UNIX_EPOCH + Duration::from_secs(951782400)),
+                   "Tue, 29 Feb 2000 00:00:00 GMT");
+    }
+
+    #[test]
+    fn dead_properties_follow_moves() {
+        let props = DeadProps::new();
+        let color = Name {
+            ns: String::from("urn:example"),
+            local: String::from("color")
+        };
+
+        props.update(Path::new("/w/a/b.txt"),
+                     vec![(color.clone(), Some(String::from("red")))]);
+        props.copy(Path::new("/w/a"), Path::new("/w/c"), true);
+        props.copy(Path::new("/w/a"), Path::new("/w/e"), false);
+        props.rename(Path::new("/w/a"), Path::new("/w/d"));
+
+        assert!(props.get(Path::new("/w/a/b.txt")).is_empty());
+        assert_eq!(props.get(Path::new("/w/c/b.txt"))[&color], "red");
+        assert_eq!(props.get(Path::new("/w/d/b.txt"))[&color], "red");
+        assert!(props.get(Path::new("/w/e/b.txt")).is_empty());
+
+        props.remove(Path::new("/w/d"));
+        assert!(props.get(Path::new("/w/d/b.txt")).is_empty());
+    }
+}
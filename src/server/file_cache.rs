@@ -0,0 +1,505 @@
+//! A cache of resolved static files
+//!
+//! Resolving a request path costs a `canonicalize`, possibly a symlink walk,
+//! an `open` and a `stat`. The cache remembers where a path led and what the
+//! file's metadata was, and for small files keeps the contents too. Entries
+//! are revalidated against the file's size and mtime once they're older than
+//! the configured interval, and the least recently used are evicted once
+//! there are too many or they hold too many bytes.
+
+use config::CacheConfig;
+use filesystem::is_within;
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, Metadata};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// What the cache knows about one request path
+#[derive(Clone)]
+pub struct Cached {
+    pub path: PathBuf,
+    pub meta: Metadata,
+    /// The whole file, if it was small enough to keep
+    pub contents: Option<Arc<Vec<u8>>>
+}
+
+impl Cached {
+    /// Whether `meta`, just read from the disk, still describes the file
+    /// this entry was made from
+    pub fn matches(&self, meta: &Metadata) -> bool {
+        meta.len() == self.meta.len() &&
+            meta.modified().ok() == self.meta.modified().ok()
+    }
+}
+
+/// Hit and miss counts, logged on SIGUSR1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: usize,
+    pub misses: usize,
+    pub entries: usize,
+    pub bytes: usize
+}
+
+pub struct FileCache {
+    config: CacheConfig,
+    inner: Mutex<Inner>,
+    hits: AtomicUsize,
+    misses: AtomicUsize
+}
+
+struct Inner {
+    entries: HashMap<Vec<u8>, Entry>,
+    /// Request paths by when they were last used, least recent first
+    recency: BTreeMap<u64, Vec<u8>>,
+    /// Total size of cached contents
+    bytes: usize,
+    /// Ticks on every insert and lookup, to order entries by recency
+    clock: u64
+}
+
+struct Entry {
+    cached: Cached,
+    checked: Instant,
+    /// The tick the entry was inserted on, which no other entry shares
+    inserted: u64,
+    last_used: u64
+}
+
+impl FileCache {
+    pub fn new(config: CacheConfig) -> FileCache {
+        FileCache {
+            config: config,
+            inner: Mutex::new(Inner {
+                entries: HashMap::new(),
+                recency: BTreeMap::new(),
+                bytes: 0,
+                clock: 0
+            }),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0)
+        }
+    }
+
+    /// Whether the cache stores anything at all
+    #[inline]
+    pub fn enabled(&self) -> bool {
+        self.config.entries > 0
+    }
+
+    /// Whether a file of `len` bytes should have its contents cached
+    #[inline]
+    pub fn wants_contents(&self, len: u64) -> bool {
+        len <= self.config.max_file_size
+    }
+
+    /// Looks up a request path, revalidating the entry if it's due
+    pub fn get(&self, uri: &[u8]) -> Option<Cached> {
+        if !self.enabled() {
+            return None;
+        }
+
+        let found = self.get_inner(uri);
+
+        if found.is_some() {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        }
+        else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+
+        found
+    }
+
+    /// Looks up `uri`, checking the file behind the entry if it's due
+    ///
+    /// The check is a `stat`, made without holding the lock, so lookups for
+    /// other paths don't wait on the disk. The entry is only updated if it's
+    /// still the one checked.
+    fn get_inner(&self, uri: &[u8]) -> Option<Cached> {
+        let revalidate = Duration::from_secs(self.config.revalidate);
+
+        let (cached, inserted) = {
+            let mut inner = match self.inner.lock() {
+                Ok(guard) => guard,
+                Err(_poison) => return None
+            };
+
+            let (cached, inserted, due) = match inner.entries.get(uri) {
+                Some(entry) => (entry.cached.clone(), entry.inserted,
+                                entry.checked.elapsed() >= revalidate),
+                None => return None
+            };
+
+            if !due {
+                inner.touch(uri);
+                return Some(cached);
+            }
+
+            (cached, inserted)
+        };
+
+        let still_same = unchanged(&cached);
+
+        let mut inner = match self.inner.lock() {
+            Ok(guard) => guard,
+            Err(_poison) => return None
+        };
+
+        // Replaced or dropped while the lock was let go
+        if inner.entries.get(uri).map_or(true, |e| e.inserted != inserted) {
+            return None;
+        }
+
+        if !still_same {
+            inner.remove(uri);
+            return None;
+        }
+
+        inner.entries.get_mut(uri).unwrap().checked = Instant::now();
+        inner.touch(uri);
+        Some(cached)
+    }
+
+    /// Remembers what `uri` resolved to
+    pub fn insert(&self, uri: &[u8], cached: Cached) {
+        if !self.enabled() {
+            return;
+        }
+
+        let mut inner = match self.inner.lock() {
+            Ok(guard) => guard,
+            Err(_poison) => return
+        };
+
+        let size = cached.contents.as_ref().map_or(0, |c| c.len());
+        if size > self.config.bytes {
+            return;
+        }
+
+        inner.remove(uri);
+        inner.clock += 1;
+        let now = inner.clock;
+
+        inner.bytes += size;
+        inner.recency.insert(now, Vec::from(uri));
+        inner.entries.insert(Vec::from(uri), Entry {
+            cached: cached,
+            checked: Instant::now(),
+            inserted: now,
+            last_used: now
+        });
+
+        while inner.entries.len() > self.config.entries ||
+            inner.bytes > self.config.bytes {
+                let oldest = inner.recency.values().next().cloned();
+
+                match oldest {
+                    Some(uri) => inner.remove(&uri),
+                    None => break
+                }
+            }
+    }
+
+    /// Drops whatever is cached for `uri`
+    pub fn invalidate(&self, uri: &[u8]) {
+        if let Ok(mut inner) = self.inner.lock() {
+            inner.remove(uri);
+        }
+    }
+
+    /// Drops whatever is cached for `uri` and any path below it
+    pub fn invalidate_tree(&self, uri: &[u8]) {
+        if let Ok(mut inner) = self.inner.lock() {
+            let doomed: Vec<Vec<u8>> = inner.entries.keys()
+                .filter(|cached| is_within(cached, uri))
+                .cloned()
+                .collect();
+
+            for cached in doomed {
+                inner.remove(&cached);
+            }
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let (entries, bytes) = match self.inner.lock() {
+            Ok(inner) => (inner.entries.len(), inner.bytes),
+            Err(_poison) => (0, 0)
+        };
+
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: entries,
+            bytes: bytes
+        }
+    }
+}
+
+impl Inner {
+    /// Marks the entry for `uri` as the most recently used
+    fn touch(&mut self, uri: &[u8]) {
+        self.clock += 1;
+        let now = self.clock;
+
+        if let Some(entry) = self.entries.get_mut(uri) {
+            if let Some(key) = self.recency.remove(&entry.last_used) {
+                self.recency.insert(now, key);
+            }
+            entry.last_used = now;
+        }
+    }
+
+    fn remove(&mut self, uri: &[u8]) {
+        if let Some(entry) = self.entries.remove(uri) {
+            self.recency.remove(&entry.last_used);
+            self.bytes -=
+                entry.cached.contents.as_ref().map_or(0, |c| c.len());
+        }
+    }
+}
+
+/// Whether the file behind a cache entry still looks the same
+fn unchanged(cached: &Cached) -> bool {
+    match fs::metadata(&cached.path) {
+        Ok(meta) => cached.matches(&meta),
+        Err(_) => false
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use config::CacheConfig;
+
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::process;
+    use std::sync::Arc;
+
+    #[test]
+    fn hits_and_misses_are_counted() {
+        let exe = env::current_exe().unwrap();
+        let cache = FileCache::new(CacheConfig {
+            entries: 4,
+            bytes: 1024,
+            max_file_size: 1024,
+            revalidate: 60
+        });
+
+        assert!(cache.get(b"/a").is_none());
+        cache.insert(b"/a", Cached {
+            meta: fs::metadata(&exe).unwrap(),
+            path: exe.clone(),
+            contents: None
+        });
+        assert!(cache.get(b"/a").is_some());
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (1, 1, 1));
+    }
+
+    #[test]
+    fn least_recently_used_is_evicted() {
+        let exe = env::current_exe().unwrap();
+        let meta = fs::metadata(&exe).unwrap();
+        let cache = FileCache::new(CacheConfig {
+            entries: 2,
+            bytes: 1024,
+            max_file_size: 1024,
+            revalidate: 60
+        });
+
+        for uri in &[&b"/a"[..], b"/b"] {
+            cache.insert(uri, Cached {
+                meta: meta.clone(),
+                path: exe.clone(),
+                contents: None
+            });
+        }
+        cache.get(b"/a");
+        cache.insert(b"/c", Cached {
+            meta: meta.clone(),
+            path: exe.clone(),
+            contents: None
+        });
+
+        assert!(cache.get(b"/a").is_some());
+        assert!(cache.get(b"/b").is_none());
+        assert!(cache.get(b"/c").is_some());
+    }
+
+    #[test]
+    fn recency_index_follows_the_entries() {
+        let exe = env::current_exe().unwrap();
+        let meta = fs::metadata(&exe).unwrap();
+        let cache = FileCache::new(CacheConfig {
+            entries: 3,
+            bytes: 1024,
+            max_file_size: 1024,
+            revalidate: 60
+        });
+
+        for uri in &[&b"/a"[..], b"/b", b"/c", b"/d", b"/a"] {
+            cache.get(uri);
+            cache.insert(uri, Cached {
+                meta: meta.clone(),
+                path: exe.clone(),
+                contents: None
+            });
+        }
+        cache.invalidate(b"/c");
+
+        let inner = cache.inner.lock().unwrap();
+        let order: Vec<&[u8]> = inner.recency.values()
+            .map(|uri| &uri[..])
+            .collect();
+        assert_eq!(order, vec![&b"/d"[..], b"/a"]);
+        assert_eq!(inner.entries.len(), 2);
+    }
+
+    #[test]
+    fn byte_limit_is_respected() {
+        let exe = env::current_exe().unwrap();
+        let meta = fs::metadata(&exe).unwrap();
+        let cache = FileCache::new(CacheConfig {
+            entries: 8,
+            bytes: 10,
+            max_file_size: 1024,
+            revalidate: 60
+        });
+
+        cache.insert(b"/a", Cached {
+            meta: meta.clone(),
+            path: exe.clone(),
+            contents: Some(Arc::new(vec![0; 6]))
+        });
+        cache.insert(b"/b", Cached {
+            meta: meta.clone(),
+            path: exe.clone(),
+            contents: Some(Arc::new(vec![0; 6]))
+        });
+        cache.insert(b"/huge", Cached {
+            meta: meta.clone(),
+            path: exe.clone(),
+            contents: Some(Arc::new(vec![0; 11]))
+        });
+
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.bytes), (1, 6));
+        assert!(cache.get(b"/b").is_some());
+        assert!(cache.get(b"/huge").is_none());
+    }
+
+    #[test]
+    fn replacing_an_entry_releases_its_bytes() {
+        let exe = env::current_exe().unwrap();
+        let meta = fs::metadata(&exe).unwrap();
+        let cache = FileCache::new(CacheConfig {
+            entries: 8,
+            bytes: 10,
+            max_file_size: 1024,
+            revalidate: 60
+        });
+
+        cache.insert(b"/a", Cached {
+            meta: meta.clone(),
+            path: exe.clone(),
+            contents: Some(Arc::new(vec![0; 8]))
+        });
+        cache.insert(b"/a", Cached {
+            meta: meta.clone(),
+            path: exe.clone(),
+            contents: Some(Arc::new(vec![0; 4]))
+        });
+
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.bytes), (1, 4));
+    }
+
+    #[test]
+    fn invalidate_tree_stops_at_segment_boundaries() {
+        let exe = env::current_exe().unwrap();
+        let meta = fs::metadata(&exe).unwrap();
+        let cache = FileCache::new(CacheConfig {
+            entries: 8,
+            bytes: 1024,
+            max_file_size: 1024,
+            revalidate: 60
+        });
+
+        for uri in &[&b"/docs"[..], b"/docs/a.txt", b"/docs/sub/b.txt",
+                     b"/docs.txt", b"/docsx/c.txt"] {
+            cache.insert(uri, Cached {
+                meta: meta.clone(),
+                path: exe.clone(),
+                contents: None
+            });
+        }
+
+        cache.invalidate_tree(b"/docs/");
+
+        assert!(cache.get(b"/docs").is_none());
+        assert!(cache.get(b"/docs/a.txt").is_none());
+        assert!(cache.get(b"/docs/sub/b.txt").is_none());
+        assert!(cache.get(b"/docs.txt").is_some());
+        assert!(cache.get(b"/docsx/c.txt").is_some());
+    }
+
+    #[test]
+    fn changed_files_are_dropped_on_revalidation() {
+        let path = env::temp_dir()
+            .join(format!("http-server-cache-{}-changed", process::id()));
+        File::create(&path).unwrap().write_all(b"first").unwrap();
+        let cache = FileCache::new(CacheConfig {
+            entries: 8,
+            bytes: 1024,
+            max_file_size: 1024,
+            revalidate: 0
+        });
+
+        cache.insert(b"/f", Cached {
+            meta: fs::metadata(&path).unwrap(),
+            path: path.clone(),
+            contents: Some(Arc::new(Vec::from(&b"first"[..])))
+        });
+        assert!(cache.get(b"/f").is_some());
+
+        File::create(&path).unwrap()
+            .write_all(b"second, longer").unwrap();
+        assert!(cache.get(b"/f").is_none());
+        assert_eq!(cache.stats().bytes, 0);
+
+        cache.insert(b"/f", Cached {
+            meta: fs::metadata(&path).unwrap(),
+            path: path.clone(),
+            contents: None
+        });
+        fs::remove_file(&path).unwrap();
+        assert!(cache.get(b"/f").is_none());
+    }
+
+    #[test]
+    fn disabled_cache_stores_nothing() {
+        let exe = env::current_exe().unwrap();
+        let cache = FileCache::new(CacheConfig {
+            entries: 0,
+            bytes: 1024,
+            max_file_size: 1024,
+            revalidate: 60
+        });
+
+        cache.insert(b"/a", Cached {
+            meta: fs::metadata(&exe).unwrap(),
+            path: exe.clone(),
+            contents: None
+        });
+        assert!(cache.get(b"/a").is_none());
+        assert_eq!(cache.stats().misses, 0);
+    }
+}
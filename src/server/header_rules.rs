@@ -0,0 +1,217 @@
+//! Applying configured header rules to responses
+
+use config::HeaderRule;
+use server::Headers;
+
+use std::ascii::AsciiExt;
+
+/// Edits `headers` according to every rule matching `path`, in order
+///
+/// Rules not marked `fastcgi` are skipped when `fastcgi` is set.
+pub fn apply(rules: &[HeaderRule], path: &[u8], headers: &mut Headers,
+             fastcgi: bool) {
+    for rule in rules {
+        if fastcgi && !rule.fastcgi {
+            continue;
+        }
+
+        if !matches(rule, path) {
+            continue;
+        }
+
+        for name in &rule.remove {
+            headers.remove(name);
+        }
+
+        for &(ref name, ref value) in &rule.set {
+            headers.set(name, value.clone());
+        }
+
+        for &(ref name, ref value) in &rule.append {
+            headers.insert(name, value.clone());
+        }
+    }
+}
+
+fn matches(rule: &HeaderRule, path: &[u8]) -> bool {
+    if let Some(ref glob) = rule.path {
+        if !glob.matches(path) {
+            return false;
+        }
+    }
+
+    if rule.extensions.is_empty() {
+        return true;
+    }
+
+    let name = path.rsplit(|&b| b == b'/').next().unwrap_or(path);
+    match name.iter().rposition(|&b| b == b'.') {
+        Some(dot) => {
+            let ext = name[dot + 1 ..].to_ascii_lowercase();
+            rule.extensions.iter().any(|e| e.as_bytes() == &ext[..])
+        },
+        None => false
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use config::HeaderRule;
+    use glob::Glob;
+    use server::Headers;
+
+    #[test]
+    fn path_rules_set_headers() {
+        let rules = vec![HeaderRule {
+            path: Some(Glob::new("/html/assets/**")),
+            extensions: Vec::new(),
+            fastcgi: false,
+            set: vec![(String::from("Cache-Control"),
+                       Vec::from(&b"public, max-age=31536000, immutable"[..]))],
+            append: Vec::new(),
+            remove: Vec::new()
+        }];
+
+        let mut headers = Headers::new();
+        apply(&rules, b"/html/assets/app.js", &mut headers, false);
+        assert_eq!(headers.get("Cache-Control").unwrap(),
+                   b"public, max-age=31536000, immutable");
+
+        let mut headers = Headers::new();
+        apply(&rules, b"/html/index.html", &mut headers, false);
+        assert!(headers.get("Cache-Control").is_none());
+    }
+
+    #[test]
+    fn path_and_extensions_must_both_match() {
+        let rules = vec![HeaderRule {
+            path: Some(Glob::new("/html/assets/**")),
+            extensions: vec![String::from("js")],
+            fastcgi: false,
+            set: vec![(String::from("Cache-Control"),
+                       Vec::from(&b"immutable"[..]))],
+            append: Vec::new(),
+            remove: Vec::new()
+        }];
+
+        let mut headers = Headers::new();
+        apply(&rules, b"/html/assets/app.css", &mut headers, false);
+        assert!(headers.get("Cache-Control").is_none());
+
+        let mut headers = Headers::new();
+        apply(&rules, b"/html/app.js", &mut headers, false);
+        assert!(headers.get("Cache-Control").is_none());
+
+        let mut headers = Headers::new();
+        apply(&rules, b"/html/assets/app.js", &mut headers, false);
+        assert_eq!(headers.get("Cache-Control").unwrap(), b"immutable");
+    }
+
+    #[test]
+    fn extensions_come_from_the_last_segment_only() {
+        let rules = vec![HeaderRule {
+            path: None,
+            extensions: vec![String::from("html")],
+            fastcgi: false,
+            set: vec![(String::from("Cache-Control"),
+                       Vec::from(&b"no-cache"[..]))],
+            append: Vec::new(),
+            remove: Vec::new()
+        }];
+
+        let mut headers = Headers::new();
+        apply(&rules, b"/docs.html/README", &mut headers, false);
+        assert!(headers.get("Cache-Control").is_none());
+
+        let mut headers = Headers::new();
+        apply(&rules, b"/docs/", &mut headers, false);
+        assert!(headers.get("Cache-Control").is_none());
+
+        let mut headers = Headers::new();
+        apply(&rules, b"/docs/INDEX.HTML", &mut headers, false);
+        assert_eq!(headers.get("Cache-Control").unwrap(), b"no-cache");
+    }
+
+    #[test]
+    fn remove_set_append_order() {
+        let rules = vec![HeaderRule {
+            path: Some(Glob::new("/**")),
+            extensions: Vec::new(),
+            fastcgi: false,
+            set: vec![(String::from("Vary"), Vec::from(&b"Accept"[..]))],
+            append: vec![(String::from("Vary"),
+                          Vec::from(&b"Accept-Encoding"[..]))],
+            remove: vec![String::from("X-Powered-By")]
+        }];
+
+        let mut headers = Headers::new();
+        headers.insert("X-Powered-By", Vec::from(&b"Go"[..]));
+        headers.insert("Vary", Vec::from(&b"Cookie"[..]));
+        apply(&rules, b"/anything", &mut headers, false);
+
+        assert!(headers.get("X-Powered-By").is_none());
+        assert_eq!(headers.get("Vary").unwrap(), b"Accept,Accept-Encoding");
+    }
+
+    #[test]
+    fn later_rules_override_earlier_ones() {
+        let rules = vec![
+            HeaderRule {
+                path: Some(Glob::new("/**")),
+                extensions: Vec::new(),
+                fastcgi: false,
+                set: vec![(String::from("Cache-Control"),
+                           Vec::from(&b"no-cache"[..]))],
+                append: Vec::new(),
+                remove: Vec::new()
+            },
+            HeaderRule {
+                path: Some(Glob::new("/static/**")),
+                extensions: Vec::new(),
+                fastcgi: false,
+                set: vec![(String::from("Cache-Control"),
+                           Vec::from(&b"max-age=3600"[..]))],
+                append: Vec::new(),
+                remove: Vec::new()
+            }
+        ];
+
+        let mut headers = Headers::new();
+        apply(&rules, b"/static/logo.png", &mut headers, false);
+        assert_eq!(headers.get("Cache-Control").unwrap(), b"max-age=3600");
+
+        let mut headers = Headers::new();
+        apply(&rules, b"/about", &mut headers, false);
+        assert_eq!(headers.get("Cache-Control").unwrap(), b"no-cache");
+    }
+
+    #[test]
+    fn fastcgi_responses_need_opt_in() {
+        let rules = vec![
+            HeaderRule {
+                path: Some(Glob::new("/**")),
+                extensions: Vec::new(),
+                fastcgi: false,
+                set: vec![(String::from("X-Frame-Options"),
+                           Vec::from(&b"DENY"[..]))],
+                append: Vec::new(),
+                remove: Vec::new()
+            },
+            HeaderRule {
+                path: Some(Glob::new("/**")),
+                extensions: Vec::new(),
+                fastcgi: true,
+                set: Vec::new(),
+                append: Vec::new(),
+                remove: vec![String::from("X-Powered-By")]
+            }
+        ];
+
+        let mut headers = Headers::new();
+        headers.insert("X-Powered-By", Vec::from(&b"PHP"[..]));
+        apply(&rules, b"/app", &mut headers, true);
+        assert!(headers.get("X-Frame-Options").is_none());
+        assert!(headers.get("X-Powered-By").is_none());
+    }
+}
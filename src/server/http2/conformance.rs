@@ -0,0 +1,562 @@
+//! Conformance tests after h2spec's, each a client on the wire doing what
+//! RFC 9113 says a server must cope with, and checking it does
+
+use config::{Limits, Timeouts};
+use server::{Handler, Request, Response, Fresh};
+use server::listener::Stream;
+use super::frame::{Frame, Frames, frame_kind, flag, setting, error_code,
+                   read_be32, PREFACE};
+use super::hpack::{self, Decoder};
+
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Mutex;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+/// A client speaking HTTP/2 to a server on its own thread
+struct Client {
+    stream: TcpStream,
+    frames: Frames,
+    decoder: Decoder
+}
+
+/// What came back on a stream
+#[derive(Debug, PartialEq)]
+enum Answer {
+    Response(String, Vec<u8>),
+    Reset(u32)
+}
+
+impl Client {
+    /// Connects to a server, without saying anything yet
+    fn connect<H>(handler: H) -> Client where H: Handler + 'static {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .unwrap();
+        let (server, _) = listener.accept().unwrap();
+        stream.set_nodelay(true).unwrap();
+        server.set_nodelay(true).unwrap();
+        thread::spawn(move || {
+            super::serve(Stream::from(server), &handler, &Timeouts::default(),
+                         &Limits::default());
+        });
+
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        Client {
+            stream: stream,
+            frames: Frames::new(),
+            decoder: Decoder::new()
+        }
+    }
+
+    /// Connects to a server, sending the preface and `settings`, and waits
+    /// for the server's settings and their acknowledgement of ours
+    fn start<H>(handler: H, settings: &[(u16, u32)]) -> Client
+        where H: Handler + 'static {
+        let mut client = Client::connect(handler);
+        client.stream.write_all(PREFACE).unwrap();
+        client.send(Frame::settings(settings));
+
+        let (mut theirs, mut acknowledged) = (false, false);
+        while !theirs || !acknowledged {
+            let frame = client.next().expect("Closed during the preface");
+            assert_eq!(frame.kind, frame_kind::SETTINGS);
+            if frame.has(flag::ACK) {
+                acknowledged = true;
+            }
+            else {
+                theirs = true;
+                client.send(Frame::new(frame_kind::SETTINGS, flag::ACK, 0,
+                                       Vec::new()));
+            }
+        }
+        client
+    }
+
+    fn send(&mut self, frame: Frame) {
+        frame.write_to(&mut self.stream).unwrap();
+    }
+
+    /// Opens stream `id` with a request for `path`
+    fn request(&mut self, id: u32, method: &str, path: &str,
+               end_stream: bool) {
+        let fields = [(":method", method), (":scheme", "http"),
+                      (":authority", "localhost"), (":path", path)];
+        self.headers(id, &fields, end_stream);
+    }
+
+    fn headers(&mut self, id: u32, fields: &[(&str, &str)],
+               end_stream: bool) {
+        let mut block = Vec::new();
+        for &(name, value) in fields {
+            hpack::encode(name.as_bytes(), value.as_bytes(), &mut block);
+        }
+        let end_stream = if end_stream { flag::END_STREAM } else { 0 };
+        self.send(Frame::new(frame_kind::HEADERS,
+                             flag::END_HEADERS | end_stream, id, block));
+    }
+
+    /// The next frame from the server, or `None` once it's closed the
+    /// connection
+    fn next(&mut self) -> Option<Frame> {
+        loop {
+            if let Some(frame) = self.frames.next().unwrap() {
+                return Some(frame);
+            }
+
+            let mut buf = [0; 4096];
+            match self.stream.read(&mut buf) {
+                Ok(0) => return None,
+                Ok(n) => self.frames.extend(&buf[.. n]),
+                Err(ref e) if e.kind() == ErrorKind::ConnectionReset =>
+                    return None,
+                Err(e) => panic!("Nothing from the server: {}", e)
+            }
+        }
+    }
+
+    /// Skips frames until one of `kind` comes
+    fn next_of(&mut self, kind: u8) -> Frame {
+        loop {
+            match self.next() {
+                Some(ref frame) if frame.kind != kind => (),
+                Some(frame) => return frame,
+                None => panic!("Closed before a frame of kind {}", kind)
+            }
+        }
+    }
+
+    /// The error code the server went away with, after `frames`
+    fn goaway_after(&mut self, frames: Vec<Frame>) -> u32 {
+        for frame in frames {
+            self.send(frame);
+        }
+        let goaway = self.next_of(frame_kind::GOAWAY);
+        assert_eq!(goaway.payload.len(), 8);
+
+        // Streams opened before it may still be answered, but then the
+        // connection has to close
+        while self.next().is_some() {}
+        read_be32(&goaway.payload[4 ..])
+    }
+
+    /// Collects what comes back on `ids`, however it's interleaved
+    fn answers(&mut self, ids: &[u32]) -> Vec<Answer> {
+        let mut statuses = vec![None; ids.len()];
+        let mut bodies = vec![Vec::new(); ids.len()];
+        let mut answers: Vec<Option<Answer>> =
+            ids.iter().map(|_| None).collect();
+
+        while answers.iter().any(|answer| answer.is_none()) {
+            let frame = self.next().expect("Closed with streams open");
+            let i = match ids.iter().position(|&id| id == frame.stream) {
+                Some(i) => i,
+                None => continue
+            };
+
+            match frame.kind {
+                frame_kind::HEADERS => {
+                    assert!(frame.has(flag::END_HEADERS));
+                    let fields = self.decoder.decode(&frame.payload, 65536)
+                        .unwrap().unwrap();
+                    assert_eq!(&fields[0].0[..], b":status");
+                    statuses[i] = Some(String::from_utf8(fields[0].1.clone())
+                                       .unwrap());
+                },
+                frame_kind::DATA => {
+                    assert!(statuses[i].is_some(), "DATA before HEADERS");
+                    bodies[i].extend_from_slice(&frame.payload);
+                },
+                frame_kind::RST_STREAM => {
+                    // A reset after the end of a response only stops the
+                    // client sending
+                    if answers[i].is_none() {
+                        answers[i] =
+                            Some(Answer::Reset(read_be32(&frame.payload)));
+                    }
+                    continue;
+                },
+                _ => continue
+            }
+
+            if frame.has(flag::END_STREAM) {
+                answers[i] = Some(Answer::Response(
+                    statuses[i].take().unwrap(),
+                    ::std::mem::replace(&mut bodies[i], Vec::new())));
+            }
+        }
+        answers.into_iter().map(|answer| answer.unwrap()).collect()
+    }
+
+    fn answer(&mut self, id: u32) -> Answer {
+        self.answers(&[id]).pop().unwrap()
+    }
+}
+
+/// Answers with the request's method, path and how long its body was
+fn echo(mut req: Request, mut res: Response<Fresh>) {
+    let mut body = Vec::new();
+    if req.read_to_end(&mut body).is_err() {
+        return;
+    }
+
+    let answer = format!("{} {} {}", req.method(),
+                         req.request_uri().to_string_lossy(), body.len());
+    res.headers_mut().insert("Content-Length",
+                             format!("{}", answer.len()).into_bytes());
+    let _ = res.of_stream(answer.as_bytes());
+}
+
+fn ok(body: &str) -> Answer {
+    Answer::Response("200".to_owned(), Vec::from(body.as_bytes()))
+}
+
+fn client() -> Client {
+    Client::start(echo, &[])
+}
+
+#[test]
+fn pings_are_answered() {
+    let mut client = client();
+    client.send(Frame::new(frame_kind::PING, 0, 0,
+                           Vec::from(&b"h2spec!!"[..])));
+
+    let pong = client.next_of(frame_kind::PING);
+    assert!(pong.has(flag::ACK));
+    assert_eq!(&pong.payload[..], b"h2spec!!");
+}
+
+#[test]
+fn bad_prefaces_are_connection_errors() {
+    let mut client = Client::connect(echo);
+    client.stream.write_all(b"PRI * HTTP/2.0\r\n\r\nXX\r\n\r\n").unwrap();
+    assert_eq!(client.goaway_after(Vec::new()), error_code::PROTOCOL_ERROR);
+
+    // The preface has to be followed by SETTINGS
+    let mut client = Client::connect(echo);
+    client.stream.write_all(PREFACE).unwrap();
+    let ping = Frame::new(frame_kind::PING, 0, 0, vec![0; 8]);
+    assert_eq!(client.goaway_after(vec![ping]), error_code::PROTOCOL_ERROR);
+}
+
+#[test]
+fn frames_of_the_wrong_size_are_connection_errors() {
+    assert_eq!(client().goaway_after(vec![
+        Frame::new(frame_kind::DATA, 0, 1, vec![0; 16385])
+    ]), error_code::FRAME_SIZE_ERROR);
+    assert_eq!(client().goaway_after(vec![
+        Frame::new(frame_kind::PING, 0, 0, vec![0; 6])
+    ]), error_code::FRAME_SIZE_ERROR);
+    assert_eq!(client().goaway_after(vec![
+        Frame::new(frame_kind::SETTINGS, 0, 0, vec![0; 5])
+    ]), error_code::FRAME_SIZE_ERROR);
+    assert_eq!(client().goaway_after(vec![
+        Frame::new(frame_kind::SETTINGS, flag::ACK, 0, vec![0; 6])
+    ]), error_code::FRAME_SIZE_ERROR);
+    assert_eq!(client().goaway_after(vec![
+        Frame::new(frame_kind::WINDOW_UPDATE, 0, 0, vec![0; 3])
+    ]), error_code::FRAME_SIZE_ERROR);
+}
+
+#[test]
+fn frames_on_the_wrong_streams_are_connection_errors() {
+    let wrong = vec![
+        Frame::new(frame_kind::DATA, 0, 0, Vec::from(&b"hi"[..])),
+        Frame::new(frame_kind::DATA, 0, 1, Vec::from(&b"hi"[..])),
+        Frame::new(frame_kind::SETTINGS, 0, 1, Vec::new()),
+        Frame::new(frame_kind::PING, 0, 1, vec![0; 8]),
+        Frame::new(frame_kind::GOAWAY, 0, 1, vec![0; 8]),
+        Frame::new(frame_kind::PRIORITY, 0, 0, vec![0; 5]),
+        Frame::rst_stream(0, error_code::NO_ERROR),
+        Frame::rst_stream(1, error_code::NO_ERROR),
+        Frame::window_update(1, 1),
+        Frame::new(frame_kind::CONTINUATION, flag::END_HEADERS, 1,
+                   Vec::new()),
+        Frame::new(frame_kind::PUSH_PROMISE, flag::END_HEADERS, 1,
+                   vec![0; 4])
+    ];
+    for frame in wrong {
+        assert_eq!(client().goaway_after(vec![frame.clone()]),
+                   error_code::PROTOCOL_ERROR, "{:?}", frame);
+    }
+
+    // Clients open odd streams, and in order
+    let mut even = client();
+    even.request(2, "GET", "/", true);
+    assert_eq!(even.goaway_after(Vec::new()), error_code::PROTOCOL_ERROR);
+
+    let mut backwards = client();
+    backwards.request(5, "GET", "/", true);
+    backwards.request(3, "GET", "/", true);
+    assert_eq!(backwards.goaway_after(Vec::new()),
+               error_code::PROTOCOL_ERROR);
+}
+
+#[test]
+fn header_blocks_cant_be_interrupted() {
+    let mut block = Vec::new();
+    hpack::encode(b":method", b"GET", &mut block);
+    let headers = Frame::new(frame_kind::HEADERS, flag::END_STREAM, 1, block);
+
+    assert_eq!(client().goaway_after(vec![
+        headers.clone(),
+        Frame::new(frame_kind::PING, 0, 0, vec![0; 8])
+    ]), error_code::PROTOCOL_ERROR);
+    assert_eq!(client().goaway_after(vec![
+        headers.clone(),
+        Frame::new(frame_kind::CONTINUATION, flag::END_HEADERS, 3, Vec::new())
+    ]), error_code::PROTOCOL_ERROR);
+
+    // But may be split up
+    let mut split = client();
+    split.send(headers);
+    let mut rest = Vec::new();
+    hpack::encode(b":scheme", b"http", &mut rest);
+    split.send(Frame::new(frame_kind::CONTINUATION, 0, 1, rest));
+    let mut rest = Vec::new();
+    hpack::encode(b":path", b"/split", &mut rest);
+    split.send(Frame::new(frame_kind::CONTINUATION, flag::END_HEADERS, 1,
+                          rest));
+    assert_eq!(split.answer(1), ok("GET /split 0"));
+}
+
+#[test]
+fn bad_settings_are_connection_errors() {
+    let bad = [
+        (setting::ENABLE_PUSH, 2, error_code::PROTOCOL_ERROR),
+        (setting::INITIAL_WINDOW_SIZE, 1 << 31,
+         error_code::FLOW_CONTROL_ERROR),
+        (setting::MAX_FRAME_SIZE, 16383, error_code::PROTOCOL_ERROR),
+        (setting::MAX_FRAME_SIZE, 1 << 24, error_code::PROTOCOL_ERROR)
+    ];
+    for &(id, value, code) in &bad {
+        assert_eq!(client().goaway_after(vec![
+            Frame::settings(&[(id, value)])
+        ]), code, "setting {} of {}", id, value);
+    }
+
+    // Settings nobody's heard of are ignored
+    let mut client = client();
+    client.send(Frame::settings(&[(0xff, 1)]));
+    assert!(client.next_of(frame_kind::SETTINGS).has(flag::ACK));
+}
+
+#[test]
+fn bad_window_updates_are_errors() {
+    assert_eq!(client().goaway_after(vec![Frame::window_update(0, 0)]),
+               error_code::PROTOCOL_ERROR);
+    assert_eq!(client().goaway_after(vec![
+        Frame::window_update(0, 1 << 30),
+        Frame::window_update(0, 1 << 30)
+    ]), error_code::FLOW_CONTROL_ERROR);
+
+    // On a stream, they only reset it
+    let mut client = client();
+    client.request(1, "POST", "/", false);
+    client.send(Frame::window_update(1, 0));
+    client.request(3, "POST", "/", false);
+    client.send(Frame::window_update(3, (1 << 31) - 1));
+    assert_eq!(client.answers(&[1, 3]), vec![
+        Answer::Reset(error_code::PROTOCOL_ERROR),
+        Answer::Reset(error_code::FLOW_CONTROL_ERROR)
+    ]);
+
+    client.request(5, "GET", "/", true);
+    assert_eq!(client.answer(5), ok("GET / 0"));
+}
+
+#[test]
+fn compression_errors_are_connection_errors() {
+    // Index 0 is never valid
+    assert_eq!(client().goaway_after(vec![
+        Frame::new(frame_kind::HEADERS, flag::END_HEADERS | flag::END_STREAM,
+                   1, vec![0x80])
+    ]), error_code::COMPRESSION_ERROR);
+}
+
+#[test]
+fn malformed_requests_are_reset() {
+    let malformed: &[&[(&str, &str)]] = &[
+        &[(":method", "GET"), (":scheme", "http")],
+        &[(":method", "GET"), (":scheme", "http"), (":path", "")],
+        &[(":method", "GET"), (":scheme", "http"), (":path", "/"),
+          (":status", "200")],
+        &[(":method", "GET"), (":method", "GET"), (":scheme", "http"),
+          (":path", "/")],
+        &[(":method", "GET"), (":scheme", "http"), ("accept", "*/*"),
+          (":path", "/")],
+        &[(":method", "GET"), (":scheme", "http"), (":path", "/"),
+          ("Accept", "*/*")],
+        &[(":method", "GET"), (":scheme", "http"), (":path", "/"),
+          ("connection", "keep-alive")],
+        &[(":method", "GET"), (":scheme", "http"), (":path", "/"),
+          ("te", "gzip")],
+        &[(":method", "GET"), (":scheme", "http"), (":path", "/"),
+          ("content-length", "1")]
+    ];
+
+    let mut client = client();
+    let mut id = 1;
+    for fields in malformed {
+        client.headers(id, fields, true);
+        assert_eq!(client.answer(id), Answer::Reset(error_code::PROTOCOL_ERROR),
+                   "{:?}", fields);
+        id += 2;
+    }
+
+    // A body longer than it was said to be
+    client.headers(id, &[(":method", "POST"), (":scheme", "http"),
+                         (":path", "/"), ("content-length", "1")], false);
+    client.send(Frame::new(frame_kind::DATA, flag::END_STREAM, id,
+                           Vec::from(&b"hi"[..])));
+    assert_eq!(client.answer(id), Answer::Reset(error_code::PROTOCOL_ERROR));
+    id += 2;
+
+    // A stream depending on itself
+    let mut block = vec![0, 0, 0, id as u8, 16];
+    for &(name, value) in &[(":method", "GET"), (":scheme", "http"),
+                            (":path", "/")] {
+        hpack::encode(name.as_bytes(), value.as_bytes(), &mut block);
+    }
+    client.send(Frame::new(frame_kind::HEADERS,
+                           flag::END_HEADERS | flag::END_STREAM |
+                           flag::PRIORITY, id, block));
+    assert_eq!(client.answer(id), Answer::Reset(error_code::PROTOCOL_ERROR));
+    id += 2;
+
+    // None of which spoils the connection
+    client.request(id, "GET", "/fine", true);
+    assert_eq!(client.answer(id), ok("GET /fine 0"));
+}
+
+#[test]
+fn streams_are_served_side_by_side() {
+    // The first request can't be answered until the second's come in
+    let (sender, receiver) = mpsc::channel();
+    let sender = Mutex::new(sender);
+    let receiver = Mutex::new(receiver);
+    let mut client = Client::start(move |req: Request, res: Response<Fresh>| {
+        if req.request_uri().to_str() == Some("/first") {
+            receiver.lock().unwrap().recv().unwrap();
+        }
+        else {
+            sender.lock().unwrap().send(()).unwrap();
+        }
+        echo(req, res);
+    }, &[]);
+
+    client.request(1, "GET", "/first", true);
+    client.request(3, "GET", "/second", true);
+    let mut padded = Vec::new();
+    hpack::encode(b":method", b"GET", &mut padded);
+    hpack::encode(b":scheme", b"https", &mut padded);
+    hpack::encode(b":path", b"/third", &mut padded);
+    padded.insert(0, 4);
+    padded.extend_from_slice(&[0; 4]);
+    client.send(Frame::new(frame_kind::HEADERS,
+                           flag::END_HEADERS | flag::END_STREAM |
+                           flag::PADDED, 5, padded));
+
+    assert_eq!(client.answers(&[1, 3, 5]), vec![
+        ok("GET /first 0"), ok("GET /second 0"), ok("GET /third 0")
+    ]);
+}
+
+#[test]
+fn streams_open_when_the_client_goes_away_are_finished() {
+    let mut client = client();
+    client.request(1, "POST", "/", false);
+    client.send(Frame::goaway(1, error_code::NO_ERROR));
+    client.request(3, "GET", "/", true);
+    client.send(Frame::new(frame_kind::DATA, flag::END_STREAM, 1,
+                           Vec::from(&b"hi"[..])));
+
+    assert_eq!(client.answer(1), ok("POST / 2"));
+    while let Some(frame) = client.next() {
+        assert!(frame.stream != 3, "{:?}", frame);
+    }
+}
+
+#[test]
+fn request_bodies_open_the_windows_they_use() {
+    let mut client = client();
+    client.request(1, "POST", "/", false);
+
+    // The first 65535 bytes fill both windows
+    for len in &[16384, 16384, 16384, 16383] {
+        client.send(Frame::new(frame_kind::DATA, 0, 1, vec![b'x'; *len]));
+    }
+
+    let (mut stream_window, mut connection_window) = (0, 0);
+    while stream_window < 30000 || connection_window < 30000 {
+        let update = client.next_of(frame_kind::WINDOW_UPDATE);
+        let increment = read_be32(&update.payload);
+        match update.stream {
+            0 => connection_window += increment,
+            1 => stream_window += increment,
+            stream => panic!("WINDOW_UPDATE on stream {}", stream)
+        }
+    }
+
+    client.send(Frame::new(frame_kind::DATA, 0, 1, vec![b'x'; 16384]));
+    client.send(Frame::new(frame_kind::DATA, flag::END_STREAM, 1,
+                           vec![b'x'; 13616]));
+    assert_eq!(client.answer(1), ok("POST / 95535"));
+}
+
+#[test]
+fn data_past_a_stream_window_resets_it() {
+    // The handler never reads, so nothing opens the stream's window again;
+    // it's let go when the test ends
+    let (_release, held) = mpsc::channel::<()>();
+    let held = Mutex::new(held);
+    let mut client = Client::start(move |_: Request, _: Response<Fresh>| {
+        let _ = held.lock().unwrap().recv();
+    }, &[]);
+
+    client.request(1, "POST", "/", false);
+    for _ in 0 .. 4 {
+        client.send(Frame::new(frame_kind::DATA, 0, 1, vec![b'x'; 16384]));
+    }
+    assert_eq!(client.answer(1),
+               Answer::Reset(error_code::FLOW_CONTROL_ERROR));
+}
+
+#[test]
+fn responses_wait_for_the_client_window() {
+    let mut client = Client::start(echo, &[(setting::INITIAL_WINDOW_SIZE,
+                                            0)]);
+    client.request(1, "GET", "/waiting", true);
+    let headers = client.next_of(frame_kind::HEADERS);
+    assert_eq!(headers.stream, 1);
+
+    // Nothing more comes until the window opens
+    client.stream.set_read_timeout(Some(Duration::from_millis(200)))
+        .unwrap();
+    let mut buf = [0; 1];
+    let waited = client.stream.read(&mut buf).unwrap_err();
+    assert!(waited.kind() == ErrorKind::WouldBlock ||
+            waited.kind() == ErrorKind::TimedOut);
+    client.stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+    client.send(Frame::window_update(1, 4));
+    let data = client.next_of(frame_kind::DATA);
+    assert_eq!(&data.payload[..], b"GET ");
+    assert!(!data.has(flag::END_STREAM));
+
+    // Changing the initial window changes open streams' windows too
+    client.send(Frame::settings(&[(setting::INITIAL_WINDOW_SIZE, 100)]));
+    let mut body = Vec::new();
+    loop {
+        let frame = client.next().expect("Closed with the stream open");
+        if frame.kind == frame_kind::DATA {
+            body.extend_from_slice(&frame.payload);
+            if frame.has(flag::END_STREAM) {
+                break;
+            }
+        }
+    }
+    assert_eq!(&body[..], b"/waiting 0");
+}
@@ -0,0 +1,263 @@
+//! HTTP/2 frames, and the codes and settings they carry (RFC 9113, sections
+//! 4 and 6)
+
+use std::io::{self, Write};
+
+/// What a client sends before anything else, ahead of its SETTINGS
+pub const PREFACE: &'static [u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// The length of a frame's header, before its payload
+pub const HEADER_LEN: usize = 9;
+
+/// The largest payload a frame may have until the receiver says otherwise,
+/// which is all we ever allow
+pub const MAX_FRAME_SIZE: usize = 16384;
+
+/// The most a flow control window can be
+pub const MAX_WINDOW: i64 = (1 << 31) - 1;
+
+/// Flow control windows start at this size
+pub const INITIAL_WINDOW: i64 = 65535;
+
+pub mod frame_kind {
+    pub const DATA: u8 = 0x0;
+    pub const HEADERS: u8 = 0x1;
+    pub const PRIORITY: u8 = 0x2;
+    pub const RST_STREAM: u8 = 0x3;
+    pub const SETTINGS: u8 = 0x4;
+    pub const PUSH_PROMISE: u8 = 0x5;
+    pub const PING: u8 = 0x6;
+    pub const GOAWAY: u8 = 0x7;
+    pub const WINDOW_UPDATE: u8 = 0x8;
+    pub const CONTINUATION: u8 = 0x9;
+}
+
+pub mod flag {
+    pub const END_STREAM: u8 = 0x1;
+    pub const ACK: u8 = 0x1;
+    pub const END_HEADERS: u8 = 0x4;
+    pub const PADDED: u8 = 0x8;
+    pub const PRIORITY: u8 = 0x20;
+}
+
+pub mod setting {
+    pub const ENABLE_PUSH: u16 = 0x2;
+    pub const MAX_CONCURRENT_STREAMS: u16 = 0x3;
+    pub const INITIAL_WINDOW_SIZE: u16 = 0x4;
+    pub const MAX_FRAME_SIZE: u16 = 0x5;
+    pub const MAX_HEADER_LIST_SIZE: u16 = 0x6;
+}
+
+pub mod error_code {
+    pub const NO_ERROR: u32 = 0x0;
+    pub const PROTOCOL_ERROR: u32 = 0x1;
+    pub const INTERNAL_ERROR: u32 = 0x2;
+    pub const FLOW_CONTROL_ERROR: u32 = 0x3;
+    pub const STREAM_CLOSED: u32 = 0x5;
+    pub const FRAME_SIZE_ERROR: u32 = 0x6;
+    pub const REFUSED_STREAM: u32 = 0x7;
+    pub const COMPRESSION_ERROR: u32 = 0x9;
+    pub const ENHANCE_YOUR_CALM: u32 = 0xb;
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Frame {
+    pub kind: u8,
+    pub flags: u8,
+    pub stream: u32,
+    pub payload: Vec<u8>
+}
+
+impl Frame {
+    pub fn new(kind: u8, flags: u8, stream: u32, payload: Vec<u8>) -> Frame {
+        Frame { kind: kind, flags: flags, stream: stream, payload: payload }
+    }
+
+    pub fn settings(values: &[(u16, u32)]) -> Frame {
+        let mut payload = Vec::with_capacity(values.len() * 6);
+        for &(id, value) in values {
+            payload.extend_from_slice(&[(id >> 8) as u8, id as u8]);
+            payload.extend_from_slice(&be32(value));
+        }
+        Frame::new(frame_kind::SETTINGS, 0, 0, payload)
+    }
+
+    pub fn rst_stream(stream: u32, code: u32) -> Frame {
+        Frame::new(frame_kind::RST_STREAM, 0, stream, be32(code).to_vec())
+    }
+
+    pub fn goaway(last_stream: u32, code: u32) -> Frame {
+        let mut payload = be32(last_stream).to_vec();
+        payload.extend_from_slice(&be32(code));
+        Frame::new(frame_kind::GOAWAY, 0, 0, payload)
+    }
+
+    pub fn window_update(stream: u32, increment: u32) -> Frame {
+        Frame::new(frame_kind::WINDOW_UPDATE, 0, stream,
+                   be32(increment).to_vec())
+    }
+
+    #[inline]
+    pub fn has(&self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+
+    /// The payload of a DATA or HEADERS frame without its padding, or of a
+    /// HEADERS frame without its priority, or `None` if the padding's longer
+    /// than the frame
+    pub fn content(&self) -> Option<&[u8]> {
+        let mut content = &self.payload[..];
+
+        if self.has(flag::PADDED) {
+            let padding = match content.first() {
+                Some(&padding) => padding as usize,
+                None => return None
+            };
+            if padding >= content.len() {
+                return None;
+            }
+            content = &content[1 .. content.len() - padding];
+        }
+
+        if self.kind == frame_kind::HEADERS && self.has(flag::PRIORITY) {
+            if content.len() < 5 {
+                return None;
+            }
+            content = &content[5 ..];
+        }
+
+        Some(content)
+    }
+
+    /// Writes the frame out in one piece
+    pub fn write_to<W: Write>(&self, sink: &mut W) -> io::Result<()> {
+        let len = self.payload.len();
+        let mut bytes = Vec::with_capacity(HEADER_LEN + len);
+        bytes.extend_from_slice(&[(len >> 16) as u8, (len >> 8) as u8,
+                                  len as u8, self.kind, self.flags]);
+        bytes.extend_from_slice(&be32(self.stream));
+        bytes.extend_from_slice(&self.payload);
+        try!(sink.write_all(&bytes));
+        sink.flush()
+    }
+}
+
+pub fn be32(n: u32) -> [u8; 4] {
+    [(n >> 24) as u8, (n >> 16) as u8, (n >> 8) as u8, n as u8]
+}
+
+pub fn read_be32(bytes: &[u8]) -> u32 {
+    (bytes[0] as u32) << 24 | (bytes[1] as u32) << 16 |
+    (bytes[2] as u32) << 8 | bytes[3] as u32
+}
+
+/// Gathers frames out of what's read from a connection, however it comes
+#[derive(Debug)]
+pub struct Frames {
+    buffer: Vec<u8>
+}
+
+impl Frames {
+    pub fn new() -> Frames {
+        Frames { buffer: Vec::new() }
+    }
+
+    pub fn extend(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Takes the client's preface, if it's all come in, or fails if the
+    /// client sent something else
+    pub fn preface(&mut self) -> Result<bool, ()> {
+        let n = ::std::cmp::min(self.buffer.len(), PREFACE.len());
+        if self.buffer[.. n] != PREFACE[.. n] {
+            return Err(());
+        }
+        if n < PREFACE.len() {
+            return Ok(false);
+        }
+
+        self.buffer.drain(.. n);
+        Ok(true)
+    }
+
+    /// Takes the next whole frame, if there is one, or fails with the length
+    /// of one too large to take
+    pub fn next(&mut self) -> Result<Option<Frame>, usize> {
+        if self.buffer.len() < HEADER_LEN {
+            return Ok(None);
+        }
+
+        let len = (self.buffer[0] as usize) << 16 |
+            (self.buffer[1] as usize) << 8 | self.buffer[2] as usize;
+        if len > MAX_FRAME_SIZE {
+            return Err(len);
+        }
+        if self.buffer.len() < HEADER_LEN + len {
+            return Ok(None);
+        }
+
+        // The stream identifier's top bit is reserved, to be ignored
+        let frame = Frame::new(self.buffer[3], self.buffer[4],
+                               read_be32(&self.buffer[5 ..]) & !(1 << 31),
+                               self.buffer[HEADER_LEN .. HEADER_LEN + len]
+                               .to_vec());
+        self.buffer.drain(.. HEADER_LEN + len);
+        Ok(Some(frame))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn frames_are_gathered_across_reads() {
+        let mut bytes = Vec::from(PREFACE);
+        Frame::settings(&[(setting::ENABLE_PUSH, 0)])
+            .write_to(&mut bytes).unwrap();
+        Frame::new(frame_kind::DATA, flag::PADDED, 0x80000003,
+                   vec![2, b'h', b'i', 0, 0])
+            .write_to(&mut bytes).unwrap();
+
+        let mut frames = Frames::new();
+        let mut got = Vec::new();
+        let mut preface = false;
+        for byte in bytes.chunks(5) {
+            frames.extend(byte);
+            if !preface {
+                preface = frames.preface().unwrap();
+                continue;
+            }
+            while let Some(frame) = frames.next().unwrap() {
+                got.push(frame);
+            }
+        }
+
+        assert_eq!(got, vec![
+            Frame::new(frame_kind::SETTINGS, 0, 0, vec![0, 2, 0, 0, 0, 0]),
+            Frame::new(frame_kind::DATA, flag::PADDED, 3,
+                       vec![2, b'h', b'i', 0, 0])
+        ]);
+        assert_eq!(got[1].content(), Some(&b"hi"[..]));
+    }
+
+    #[test]
+    fn bad_frames_are_refused() {
+        let mut frames = Frames::new();
+        frames.extend(b"GET / HTTP/1.1\r\n");
+        assert!(frames.preface().is_err());
+
+        let mut frames = Frames::new();
+        frames.extend(&[0, 0x40, 1, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(frames.next(), Err(16385));
+
+        let padded = Frame::new(frame_kind::HEADERS,
+                                flag::PADDED | flag::PRIORITY, 1,
+                                vec![1, 0, 0, 0, 0, 16, 0]);
+        assert_eq!(padded.content(), Some(&[][..]));
+        let overpadded = Frame::new(frame_kind::DATA, flag::PADDED, 1,
+                                    vec![3, 0, 0]);
+        assert_eq!(overpadded.content(), None);
+    }
+}
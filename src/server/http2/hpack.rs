@@ -0,0 +1,506 @@
+//! HPACK, the header compression HTTP/2 uses (RFC 7541)
+//!
+//! Decoding has to keep up with whatever the client does to its dynamic
+//! table. Encoding never touches ours: response headers go out as literals or
+//! static table references, which costs a few bytes but leaves the two sides
+//! nothing to disagree about.
+
+use std::collections::VecDeque;
+
+/// The size of the decoding table the client starts with, and the most we let
+/// it grow to
+pub const TABLE_SIZE: usize = 4096;
+
+/// What each field costs on top of its name and value, in the dynamic table
+/// and in a header list's size
+pub const FIELD_OVERHEAD: usize = 32;
+
+/// A header field's name and value
+pub type Field = (Vec<u8>, Vec<u8>);
+
+/// The fields every HPACK table starts with, from index 1
+const STATIC_TABLE: [(&'static str, &'static str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", "")
+];
+
+/// The length of each byte's Huffman code, and last of EOS's
+///
+/// The code is canonical, so the lengths are all it takes to rebuild it:
+/// codes go to symbols in order of length, then of symbol, counting up.
+const CODE_LENGTHS: [u8; 257] = [
+    13, 23, 28, 28, 28, 28, 28, 28, 28, 24, 30, 28, 28, 30, 28, 28,
+    28, 28, 28, 28, 28, 28, 30, 28, 28, 28, 28, 28, 28, 28, 28, 28,
+    6, 10, 10, 12, 13, 6, 8, 11, 10, 10, 8, 11, 8, 6, 6, 6,
+    5, 5, 5, 6, 6, 6, 6, 6, 6, 6, 7, 8, 15, 6, 12, 10,
+    13, 6, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7,
+    7, 7, 7, 7, 7, 7, 7, 7, 8, 7, 8, 13, 19, 13, 14, 6,
+    15, 5, 6, 5, 6, 5, 6, 6, 6, 5, 7, 7, 6, 6, 6, 5,
+    6, 7, 6, 5, 5, 6, 7, 7, 7, 7, 7, 15, 11, 14, 13, 28,
+    20, 22, 20, 20, 22, 22, 22, 23, 22, 23, 23, 23, 23, 23, 24, 23,
+    24, 24, 22, 23, 24, 23, 23, 23, 23, 21, 22, 23, 22, 23, 23, 24,
+    22, 21, 20, 22, 22, 23, 23, 21, 23, 22, 22, 24, 21, 22, 23, 23,
+    21, 21, 22, 21, 23, 22, 23, 23, 20, 22, 22, 22, 23, 22, 22, 23,
+    26, 26, 20, 19, 22, 23, 22, 25, 26, 26, 26, 27, 27, 26, 24, 25,
+    19, 21, 26, 27, 27, 26, 27, 24, 21, 21, 26, 26, 28, 27, 27, 27,
+    20, 24, 20, 21, 22, 21, 21, 23, 22, 22, 25, 25, 24, 24, 26, 23,
+    26, 27, 26, 26, 27, 27, 27, 27, 27, 28, 27, 27, 27, 27, 27, 26,
+    30
+];
+
+/// The longest Huffman code, EOS's
+const MAX_CODE_LENGTH: usize = 30;
+
+/// The symbol marking the end of a Huffman string, which mustn't appear in
+/// one
+const EOS: u16 = 256;
+
+/// Why a header block couldn't be decoded, which loses the whole connection
+/// since the client's table and ours may no longer match
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeError(pub &'static str);
+
+/// Decodes the header blocks a client sends on one connection
+pub struct Decoder {
+    /// Newest first
+    table: VecDeque<Field>,
+    size: usize,
+    /// The size the client last set for the table
+    max_size: usize,
+    huffman: Huffman
+}
+
+impl Decoder {
+    pub fn new() -> Decoder {
+        Decoder {
+            table: VecDeque::new(),
+            size: 0,
+            max_size: TABLE_SIZE,
+            huffman: Huffman::new()
+        }
+    }
+
+    /// Decodes a whole header block, or gives `None` if its fields come to
+    /// more than `max_size`
+    ///
+    /// A block that's too large is still decoded to the end, since the
+    /// client expects its changes to the table to take.
+    pub fn decode(&mut self, mut block: &[u8], max_size: usize)
+                  -> Result<Option<Vec<Field>>, DecodeError> {
+        let mut fields = Vec::new();
+        let mut size = 0;
+        let mut started = false;
+
+        while let Some(&first) = block.first() {
+            let field = if first & 0x80 != 0 {
+                let index = try!(integer(&mut block, 7));
+                try!(self.get(index))
+            }
+            else if first & 0x40 != 0 {
+                let field = try!(self.literal(&mut block, 6));
+                self.insert(field.clone());
+                field
+            }
+            else if first & 0x20 != 0 {
+                if started {
+                    return Err(DecodeError("Table size update after a field"));
+                }
+                let max = try!(integer(&mut block, 5));
+                if max > TABLE_SIZE {
+                    return Err(DecodeError("Table size over the limit"));
+                }
+                self.max_size = max;
+                self.evict(0);
+                continue;
+            }
+            // Fields never to be indexed are no different to us
+            else {
+                try!(self.literal(&mut block, 4))
+            };
+            started = true;
+
+            size += field.0.len() + field.1.len() + FIELD_OVERHEAD;
+            if size <= max_size {
+                fields.push(field);
+            }
+        }
+
+        Ok(if size <= max_size { Some(fields) } else { None })
+    }
+
+    fn get(&self, index: usize) -> Result<Field, DecodeError> {
+        if index == 0 {
+            return Err(DecodeError("Index 0"));
+        }
+        if index <= STATIC_TABLE.len() {
+            let (name, value) = STATIC_TABLE[index - 1];
+            return Ok((Vec::from(name.as_bytes()),
+                       Vec::from(value.as_bytes())));
+        }
+
+        self.table.get(index - STATIC_TABLE.len() - 1).cloned()
+            .ok_or(DecodeError("Index past the end of the table"))
+    }
+
+    /// Reads a literal field whose name's index has a `prefix`-bit prefix
+    fn literal(&self, block: &mut &[u8], prefix: u8)
+               -> Result<Field, DecodeError> {
+        let name = match try!(integer(block, prefix)) {
+            0 => try!(self.string(block)),
+            index => try!(self.get(index)).0
+        };
+        let value = try!(self.string(block));
+        Ok((name, value))
+    }
+
+    fn string(&self, block: &mut &[u8]) -> Result<Vec<u8>, DecodeError> {
+        let huffman = block.first().map_or(false, |&b| b & 0x80 != 0);
+        let len = try!(integer(block, 7));
+        if len > block.len() {
+            return Err(DecodeError("String past the end of the block"));
+        }
+
+        let (string, rest) = block.split_at(len);
+        *block = rest;
+        if huffman {
+            self.huffman.decode(string)
+        }
+        else {
+            Ok(Vec::from(string))
+        }
+    }
+
+    fn insert(&mut self, field: Field) {
+        let size = field.0.len() + field.1.len() + FIELD_OVERHEAD;
+        self.evict(size);
+
+        // A field too big for the table just empties it
+        if size <= self.max_size {
+            self.size += size;
+            self.table.push_front(field);
+        }
+    }
+
+    /// Drops the oldest fields until there's room for `size` more
+    fn evict(&mut self, size: usize) {
+        while self.size + size > self.max_size {
+            match self.table.pop_back() {
+                Some((name, value)) =>
+                    self.size -= name.len() + value.len() + FIELD_OVERHEAD,
+                None => break
+            }
+        }
+    }
+}
+
+/// Reads an integer with a `prefix`-bit prefix
+fn integer(block: &mut &[u8], prefix: u8) -> Result<usize, DecodeError> {
+    let max = (1 << prefix) - 1;
+    let mut value = match block.first() {
+        Some(&b) => b as usize & max,
+        None => return Err(DecodeError("Block ends in a field"))
+    };
+    *block = &block[1 ..];
+    if value < max {
+        return Ok(value);
+    }
+
+    let mut shift = 0;
+    loop {
+        let byte = match block.first() {
+            Some(&b) => b,
+            None => return Err(DecodeError("Block ends in an integer"))
+        };
+        *block = &block[1 ..];
+
+        // Nothing needs more than 28 bits
+        if shift > 21 {
+            return Err(DecodeError("Integer too large"));
+        }
+        value += (byte as usize & 0x7f) << shift;
+        shift += 7;
+
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+}
+
+/// The Huffman code, arranged for decoding
+struct Huffman {
+    /// How many codes there are of each length
+    counts: [u16; MAX_CODE_LENGTH + 1],
+    /// Symbols in the order of their codes
+    symbols: [u16; 257]
+}
+
+impl Huffman {
+    fn new() -> Huffman {
+        let mut huffman = Huffman {
+            counts: [0; MAX_CODE_LENGTH + 1],
+            symbols: [0; 257]
+        };
+
+        let mut next = 0;
+        for len in 1 .. MAX_CODE_LENGTH + 1 {
+            for symbol in 0 .. CODE_LENGTHS.len() {
+                if CODE_LENGTHS[symbol] as usize == len {
+                    huffman.counts[len] += 1;
+                    huffman.symbols[next] = symbol as u16;
+                    next += 1;
+                }
+            }
+        }
+
+        huffman
+    }
+
+    /// Decodes a string, which has to end in under a byte of padding made of
+    /// the start of EOS
+    fn decode(&self, input: &[u8]) -> Result<Vec<u8>, DecodeError> {
+        let mut output = Vec::with_capacity(input.len() * 8 / 5);
+
+        // The bits of the symbol so far, and the first code and symbol of
+        // their length
+        let mut code = 0u32;
+        let mut len = 0;
+        let mut first = 0u32;
+        let mut index = 0usize;
+
+        for &byte in input {
+            for bit in (0 .. 8).rev() {
+                code = code << 1 | (byte >> bit & 1) as u32;
+                len += 1;
+
+                let count = self.counts[len] as u32;
+                if code < first + count {
+                    let symbol = self.symbols[index + (code - first) as usize];
+                    if symbol == EOS {
+                        return Err(DecodeError("EOS in a Huffman string"));
+                    }
+                    output.push(symbol as u8);
+
+                    code = 0;
+                    len = 0;
+                    first = 0;
+                    index = 0;
+                }
+                else {
+                    index += count as usize;
+                    first = (first + count) << 1;
+                }
+            }
+        }
+
+        if len > 7 || code != (1 << len) - 1 {
+            return Err(DecodeError("Huffman string badly padded"));
+        }
+        Ok(output)
+    }
+}
+
+/// Encodes a field without adding it to the client's table, using the static
+/// table where that helps
+pub fn encode(name: &[u8], value: &[u8], output: &mut Vec<u8>) {
+    let mut name_index = 0;
+    for (i, &(static_name, static_value)) in STATIC_TABLE.iter().enumerate() {
+        if static_name.as_bytes() != name {
+            continue;
+        }
+        if static_value.as_bytes() == value {
+            return put_integer(i + 1, 7, 0x80, output);
+        }
+        if name_index == 0 {
+            name_index = i + 1;
+        }
+    }
+
+    put_integer(name_index, 4, 0, output);
+    if name_index == 0 {
+        put_string(name, output);
+    }
+    put_string(value, output);
+}
+
+fn put_integer(value: usize, prefix: u8, flags: u8, output: &mut Vec<u8>) {
+    let max = (1 << prefix) - 1;
+    if value < max {
+        return output.push(flags | value as u8);
+    }
+
+    output.push(flags | max as u8);
+    let mut rest = value - max;
+    while rest >= 0x80 {
+        output.push(rest as u8 & 0x7f | 0x80);
+        rest >>= 7;
+    }
+    output.push(rest as u8);
+}
+
+fn put_string(string: &[u8], output: &mut Vec<u8>) {
+    put_integer(string.len(), 7, 0, output);
+    output.extend_from_slice(string);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn field(name: &str, value: &str) -> Field {
+        (Vec::from(name.as_bytes()), Vec::from(value.as_bytes()))
+    }
+
+    fn hex(s: &str) -> Vec<u8> {
+        let digits: Vec<u8> = s.bytes().filter(|b| *b != b' ').collect();
+        digits.chunks(2)
+            .map(|pair| {
+                u8::from_str_radix(::std::str::from_utf8(pair).unwrap(), 16)
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn huffman_requests_share_a_table() {
+        // RFC 7541, appendix C.4
+        let mut decoder = Decoder::new();
+        let first = hex("8286 8441 8cf1 e3c2 e5f2 3a6b a0ab 90f4 ff");
+        assert_eq!(decoder.decode(&first, 4096).unwrap().unwrap(),
+                   vec![field(":method", "GET"), field(":scheme", "http"),
+                        field(":path", "/"),
+                        field(":authority", "www.example.com")]);
+        assert_eq!(decoder.size, 57);
+
+        let second = hex("8286 84be 5886 a8eb 1064 9cbf");
+        assert_eq!(decoder.decode(&second, 4096).unwrap().unwrap(),
+                   vec![field(":method", "GET"), field(":scheme", "http"),
+                        field(":path", "/"),
+                        field(":authority", "www.example.com"),
+                        field("cache-control", "no-cache")]);
+        assert_eq!(decoder.size, 110);
+
+        let third = hex("8287 85bf 4088 25a8 49e9 5ba9 7d7f 8925 a849 e95b \
+                         b8e8 b4bf");
+        assert_eq!(decoder.decode(&third, 4096).unwrap().unwrap(),
+                   vec![field(":method", "GET"), field(":scheme", "https"),
+                        field(":path", "/index.html"),
+                        field(":authority", "www.example.com"),
+                        field("custom-key", "custom-value")]);
+        assert_eq!(decoder.size, 164);
+        assert_eq!(decoder.table[0], field("custom-key", "custom-value"));
+    }
+
+    #[test]
+    fn tables_evict_their_oldest_fields() {
+        let mut decoder = Decoder::new();
+        // Shrink the table to fit one field, then add two
+        let mut block = hex("3f 1a");
+        for value in &["first", "second"] {
+            block.extend_from_slice(&hex("40"));
+            put_string(b"custom", &mut block);
+            put_string(value.as_bytes(), &mut block);
+        }
+        block.extend_from_slice(&hex("be"));
+
+        assert_eq!(decoder.decode(&block, 4096).unwrap().unwrap()[2],
+                   field("custom", "second"));
+        assert_eq!(decoder.table.len(), 1);
+        assert_eq!(decoder.decode(&hex("bf"), 4096),
+                   Err(DecodeError("Index past the end of the table")));
+    }
+
+    #[test]
+    fn bad_blocks_are_errors() {
+        for block in &["80", "be", "3fe2 1f", "82 20", "0f",
+                       "00 81 00 00", "0085 ffff ffff ff00",
+                       "ff ffff ffff ff"] {
+            assert!(Decoder::new().decode(&hex(block), 4096).is_err(),
+                    "{} should be an error", block);
+        }
+    }
+
+    #[test]
+    fn large_header_lists_are_decoded_but_dropped() {
+        let mut decoder = Decoder::new();
+        let block = hex("4088 25a8 49e9 5ba9 7d7f 8925 a849 e95b b8e8 b4bf");
+        assert_eq!(decoder.decode(&block, 50).unwrap(), None);
+        assert_eq!(decoder.decode(&hex("be"), 4096).unwrap().unwrap(),
+                   vec![field("custom-key", "custom-value")]);
+    }
+
+    #[test]
+    fn encoded_fields_decode() {
+        let mut block = Vec::new();
+        encode(b":status", b"200", &mut block);
+        assert_eq!(block, [0x88]);
+
+        let fields = vec![field(":status", "207"),
+                          field("content-type", "text/html"),
+                          field("x-long", &"x".repeat(300))];
+        block.clear();
+        for &(ref name, ref value) in &fields {
+            encode(name, value, &mut block);
+        }
+        assert_eq!(Decoder::new().decode(&block, 4096).unwrap().unwrap(),
+                   fields);
+    }
+}
@@ -0,0 +1,1341 @@
+//! HTTP/2, for clients that ask for it by ALPN or open with its preface
+//!
+//! A connection's frames are all read on its own thread, which starts each
+//! request on a thread of its own once its headers are in, so one slow
+//! response doesn't hold up the rest. Handlers get the same `Request` and
+//! `Response` as over HTTP/1.1: the request body is read out of what DATA
+//! frames have brought in, and the response goes out in HEADERS and DATA
+//! frames as fast as the client's flow control windows let it.
+//!
+//! The server never pushes, and ignores priorities.
+
+mod frame;
+mod hpack;
+#[cfg(test)]
+mod conformance;
+
+pub use self::frame::PREFACE;
+
+use config::{Limits, Timeouts};
+use errors::{Error, Result};
+use filesystem::{normalize_path, split_query};
+use server::{Handler, Headers, InnerRequest, Request, Response, Fresh,
+             body_length, pair_request};
+use server::connection::{self, KeepAlive, TimedStream};
+use server::listener::Stream;
+use self::frame::{Frame, Frames, frame_kind, flag, setting, error_code,
+                  read_be32, INITIAL_WINDOW, MAX_FRAME_SIZE, MAX_WINDOW};
+use self::hpack::{Decoder, DecodeError, Field};
+
+use httparse;
+
+use std::ascii::AsciiExt;
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::io::{self, BufReader, ErrorKind, Read, Write};
+use std::net::SocketAddr;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Most streams a client may have open at once
+const MAX_STREAMS: usize = 100;
+
+/// How much a window has to have been used before it's opened again, so
+/// WINDOW_UPDATEs don't go out for every read
+const WINDOW_THRESHOLD: i64 = INITIAL_WINDOW / 2;
+
+/// Headers that only mean something to an HTTP/1.1 connection, which HTTP/2
+/// does without
+const CONNECTION_HEADERS: [&'static str; 5] = [
+    "connection", "keep-alive", "proxy-connection", "transfer-encoding",
+    "upgrade"
+];
+
+/// Serves HTTP/2 on `stream` until the client's done with it, it's been idle
+/// too long, or the server's draining
+///
+/// The client's preface may still be waiting to be read.
+pub fn serve(stream: Stream, handler: &Handler, timeouts: &Timeouts,
+             limits: &Limits) {
+    let connection = match Connection::new(&stream) {
+        Ok(connection) => Arc::new(connection),
+        Err(e) => {
+            warn!("Failed connection: {}", e);
+            return;
+        }
+    };
+
+    // The server's settings can go out before the client's preface is in
+    let settings = Frame::settings(&[
+        (setting::MAX_CONCURRENT_STREAMS, MAX_STREAMS as u32),
+        (setting::MAX_HEADER_LIST_SIZE,
+         cmp::min(limits.header_bytes, u32::max_value() as u64) as u32)
+    ]);
+    if let Err(e) = connection.send(&[settings]) {
+        debug!("HTTP/2 connection failed: {}", e);
+        return;
+    }
+
+    let mut reader = Reader::new(stream, connection.clone(), timeouts,
+                                 limits);
+
+    thread::scope(|scope| {
+        loop {
+            let opened = match reader.next() {
+                Ok(Some(opened)) => opened,
+                Ok(None) => break,
+                Err(e) => {
+                    debug!("HTTP/2 connection failed: {}", e);
+                    break;
+                }
+            };
+
+            let id = opened.id;
+            let stream = H2Stream {
+                connection: connection.clone(),
+                id: id
+            };
+            let spawned = thread::Builder::new().spawn_scoped(scope, move || {
+                // Requests that can't be read have been answered already
+                if let Ok((req, res)) = request_pair(stream, opened, timeouts,
+                                                     limits) {
+                    handler.serve(req, res);
+                }
+            });
+            if let Err(e) = spawned {
+                warn!("Couldn't start a thread for a stream: {}", e);
+                connection.refuse(id);
+            }
+        }
+
+        // Whatever's still running won't get any further with the client
+        connection.close();
+    });
+}
+
+/// Builds the request a stream's headers describe, pairing it with its
+/// response
+fn request_pair(stream: H2Stream, opened: Opened, timeouts: &Timeouts,
+                limits: &Limits) -> Result<(Request, Response<Fresh>)> {
+    let peer_addr = stream.connection.peer_addr;
+    let local_port = stream.connection.local_port;
+    let secure = stream.connection.secure;
+    let stream = Stream::Http2(stream);
+
+    // Each request has a stream to itself, so there's nothing to keep alive
+    pair_request(&stream, KeepAlive::new(false), |id, keep_alive| {
+        let fields = try!(opened.fields.ok_or(Error::HeadersTooLarge));
+        let reader = TimedStream::body(try!(stream.try_clone()),
+                                       timeouts.body_min_rate);
+
+        Ok(Request {
+            inner: try!(inner_request(fields, opened.end_stream, reader,
+                                      limits)),
+            remote_addr: peer_addr,
+            local_port: local_port,
+            secure: secure,
+            id: id,
+            params: Vec::new(),
+            keep_alive: keep_alive
+        })
+    })
+}
+
+/// Turns a request's header fields into what an HTTP/1.1 request would have
+/// had
+///
+/// `:authority` stands in for any missing `Host`, and cookies, which HTTP/2
+/// lets clients split up, are put back together.
+fn inner_request(fields: Vec<Field>, end_stream: bool, reader: TimedStream,
+                 limits: &Limits) -> Result<InnerRequest<TimedStream>> {
+    if fields.len() as u64 > limits.headers {
+        return Err(Error::HeadersTooLarge);
+    }
+
+    let mut method = Vec::new();
+    let mut path = Vec::new();
+    let mut authority = None;
+    let mut headers = Headers::new();
+    let mut cookies = Vec::new();
+
+    for (name, value) in fields {
+        match &name[..] {
+            b":method" => method = value,
+            b":path" => path = value,
+            b":authority" =>
+                authority = Some(String::from_utf8_lossy(&value).into_owned()),
+            b":scheme" => (),
+            b"cookie" => cookies.push(value),
+            _ => headers.insert(&String::from_utf8_lossy(&name), value)
+        }
+    }
+
+    if path.len() as u64 > limits.request_line {
+        return Err(Error::UriTooLong);
+    }
+    let method = try!(String::from_utf8(method)
+                      .map_err(|_| Error::Parse(httparse::Error::Token)));
+
+    if !cookies.is_empty() {
+        headers.set("Cookie", cookies.join(&b"; "[..]));
+    }
+    if let Some(ref authority) = authority {
+        if headers.get("Host").is_none() {
+            headers.set("Host", Vec::from(authority.as_bytes()));
+        }
+    }
+
+    // Without a length, the body ends with the stream
+    let body_left = if end_stream {
+        Some(0)
+    }
+    else if headers.get("Content-Length").is_some() {
+        Some(try!(body_length(&headers)))
+    }
+    else {
+        None
+    };
+    if body_left.map_or(false, |len| len > limits.body) {
+        return Err(Error::TooLarge);
+    }
+
+    let (path, query) = split_query(&path);
+
+    Ok(InnerRequest {
+        method: method,
+        authority: authority,
+        path: try!(normalize_path(path)),
+        query: query.map(Vec::from),
+        version: 1,
+        headers: headers,
+        body_left: body_left,
+        body_limit: limits.body,
+        rest: BufReader::new(reader)
+    })
+}
+
+
+/// A stream the client's opened with a request
+#[derive(Debug)]
+struct Opened {
+    id: u32,
+    /// The request's header fields, or `None` if there were too many bytes
+    /// of them
+    fields: Option<Vec<Field>>,
+    /// Whether the client's sent all it will on the stream
+    end_stream: bool
+}
+
+/// Why a connection ended before the client was done with it
+#[derive(Debug)]
+enum Failure {
+    /// The client broke the protocol, and is told so with the code
+    Protocol(u32, &'static str),
+    Io(io::Error)
+}
+
+impl From<io::Error> for Failure {
+    fn from(e: io::Error) -> Failure {
+        Failure::Io(e)
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Failure::Protocol(code, why) =>
+                write!(f, "{} (error code {:#x})", why, code),
+            Failure::Io(ref e) => write!(f, "I/O error: {}", e)
+        }
+    }
+}
+
+fn protocol_error(why: &'static str) -> Failure {
+    Failure::Protocol(error_code::PROTOCOL_ERROR, why)
+}
+
+fn frame_size_error(why: &'static str) -> Failure {
+    Failure::Protocol(error_code::FRAME_SIZE_ERROR, why)
+}
+
+fn flow_control_error(why: &'static str) -> Failure {
+    Failure::Protocol(error_code::FLOW_CONTROL_ERROR, why)
+}
+
+/// A header block still coming in, over HEADERS and CONTINUATION frames
+#[derive(Debug)]
+struct Block {
+    stream: u32,
+    /// Whether the HEADERS frame ended the stream
+    end_stream: bool,
+    /// Whether the HEADERS frame made the stream depend on itself
+    self_dependent: bool,
+    fragment: Vec<u8>
+}
+
+/// The connection's frames as they're read, and what they do to its streams
+struct Reader<'a> {
+    stream: Stream,
+    connection: Arc<Connection>,
+    frames: Frames,
+    decoder: Decoder,
+    timeouts: &'a Timeouts,
+    limits: &'a Limits,
+    /// Whether the client's preface has been read, and the SETTINGS that
+    /// has to follow it
+    preface: bool,
+    settled: bool,
+    /// The highest stream the client has opened
+    last_stream: u32,
+    continuing: Option<Block>,
+    /// Streams opened and not yet handed out
+    opened: VecDeque<Opened>,
+    /// How much more DATA the client may send on the connection, and how much
+    /// has come in since the window was last opened
+    recv_window: i64,
+    unacknowledged: i64,
+    /// Set once no more streams will be taken, because either side's sent
+    /// GOAWAY
+    going_away: bool,
+    /// Since when no streams have been open, and whether any ever were
+    idle_since: Option<Instant>,
+    served: bool
+}
+
+impl<'a> Reader<'a> {
+    fn new(stream: Stream, connection: Arc<Connection>,
+           timeouts: &'a Timeouts, limits: &'a Limits) -> Reader<'a> {
+        Reader {
+            stream: stream,
+            connection: connection,
+            frames: Frames::new(),
+            decoder: Decoder::new(),
+            timeouts: timeouts,
+            limits: limits,
+            preface: false,
+            settled: false,
+            last_stream: 0,
+            continuing: None,
+            opened: VecDeque::new(),
+            recv_window: INITIAL_WINDOW,
+            unacknowledged: 0,
+            going_away: false,
+            idle_since: Some(Instant::now()),
+            served: false
+        }
+    }
+
+    /// Reads frames until a request comes in, or gives `None` once the
+    /// connection's finished
+    ///
+    /// A client that breaks the protocol is sent GOAWAY before the error's
+    /// returned.
+    fn next(&mut self) -> ::std::result::Result<Option<Opened>, Failure> {
+        match self.next_stream() {
+            Err(Failure::Protocol(code, why)) => {
+                let goaway = Frame::goaway(self.last_stream, code);
+                let _ = self.connection.send(&[goaway]);
+                Err(Failure::Protocol(code, why))
+            },
+            result => result
+        }
+    }
+
+    fn next_stream(&mut self)
+                   -> ::std::result::Result<Option<Opened>, Failure> {
+        loop {
+            if let Some(opened) = self.opened.pop_front() {
+                self.served = true;
+                return Ok(Some(opened));
+            }
+
+            if !self.preface {
+                match self.frames.preface() {
+                    Ok(done) => self.preface = done,
+                    Err(()) => return Err(protocol_error("No preface"))
+                }
+            }
+            if self.preface {
+                match self.frames.next() {
+                    Ok(Some(frame)) => {
+                        try!(self.handle(frame));
+                        continue;
+                    },
+                    Ok(None) => (),
+                    Err(_) => return Err(frame_size_error("Frame too large"))
+                }
+            }
+
+            if !try!(self.fill()) {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Reads whatever the client sends next, saying whether the connection's
+    /// still worth reading from
+    ///
+    /// Once the server's draining, or the connection's been idle for the
+    /// keep-alive timeout, the client is sent GOAWAY, and the connection's
+    /// done once its streams are.
+    fn fill(&mut self) -> ::std::result::Result<bool, Failure> {
+        loop {
+            let open = self.connection.lock_state().streams.len();
+            if open > 0 {
+                self.idle_since = None;
+            }
+            else if self.idle_since.is_none() {
+                self.idle_since = Some(Instant::now());
+            }
+
+            if connection::draining() && !self.going_away {
+                try!(self.go_away());
+            }
+            if self.going_away && open == 0 {
+                return Ok(false);
+            }
+            if let Some(since) = self.idle_since {
+                let idle = if self.served {
+                    self.timeouts.keep_alive
+                }
+                else {
+                    self.timeouts.header
+                };
+                if since.elapsed() >= Duration::from_secs(idle) {
+                    try!(self.go_away());
+                    return Ok(false);
+                }
+            }
+
+            if !try!(self.stream.readable(Duration::from_secs(1))) {
+                continue;
+            }
+
+            let mut buf = [0; MAX_FRAME_SIZE];
+            match self.stream.read(&mut buf) {
+                Ok(0) => return Ok(false),
+                Ok(n) => {
+                    self.frames.extend(&buf[.. n]);
+                    return Ok(true);
+                },
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock ||
+                    e.kind() == ErrorKind::TimedOut => continue,
+                Err(e) => return Err(Failure::Io(e))
+            }
+        }
+    }
+
+    /// Tells the client no more streams will be taken
+    fn go_away(&mut self) -> io::Result<()> {
+        self.going_away = true;
+        self.connection.send(&[Frame::goaway(self.last_stream,
+                                             error_code::NO_ERROR)])
+    }
+
+    fn handle(&mut self, frame: Frame) -> ::std::result::Result<(), Failure> {
+        if let Some(ref block) = self.continuing {
+            if frame.kind != frame_kind::CONTINUATION ||
+                frame.stream != block.stream {
+                    return Err(protocol_error("Header block interrupted"));
+                }
+        }
+        if !self.settled {
+            if frame.kind != frame_kind::SETTINGS || frame.has(flag::ACK) {
+                return Err(protocol_error("Preface without SETTINGS"));
+            }
+            self.settled = true;
+        }
+
+        match frame.kind {
+            frame_kind::DATA => self.data(frame),
+            frame_kind::HEADERS => self.headers(frame),
+            frame_kind::PRIORITY => self.priority(frame),
+            frame_kind::RST_STREAM => self.rst_stream(frame),
+            frame_kind::SETTINGS => self.settings(frame),
+            frame_kind::PUSH_PROMISE =>
+                Err(protocol_error("PUSH_PROMISE from a client")),
+            frame_kind::PING => self.ping(frame),
+            frame_kind::GOAWAY => self.goaway(frame),
+            frame_kind::WINDOW_UPDATE => self.window_update(frame),
+            frame_kind::CONTINUATION => self.continuation(frame),
+            // Frames of kinds we don't know are for extensions we don't have
+            _ => Ok(())
+        }
+    }
+
+    fn data(&mut self, frame: Frame) -> ::std::result::Result<(), Failure> {
+        if frame.stream == 0 {
+            return Err(protocol_error("DATA on stream 0"));
+        }
+        if frame.stream > self.last_stream {
+            return Err(protocol_error("DATA on an idle stream"));
+        }
+
+        // The connection's window opens as soon as anything comes in, since
+        // the streams' windows are what hold the client back
+        let len = frame.payload.len() as i64;
+        self.recv_window -= len;
+        if self.recv_window < 0 {
+            return Err(flow_control_error("DATA past the connection window"));
+        }
+        self.unacknowledged += len;
+        if self.unacknowledged >= WINDOW_THRESHOLD {
+            try!(self.connection.send(&[
+                Frame::window_update(0, self.unacknowledged as u32)
+            ]));
+            self.recv_window += self.unacknowledged;
+            self.unacknowledged = 0;
+        }
+
+        let content = match frame.content() {
+            Some(content) => content,
+            None => return Err(protocol_error("Padding past the frame"))
+        };
+        let end_stream = frame.has(flag::END_STREAM);
+
+        let problem = match self.connection.lock_state().streams
+            .get_mut(&frame.stream) {
+                None => Some(error_code::STREAM_CLOSED),
+                Some(ref stream) if stream.reset => None,
+                Some(ref stream) if stream.remote_closed =>
+                    Some(error_code::STREAM_CLOSED),
+                Some(stream) => stream.receive(content, len, end_stream)
+            };
+        self.connection.changed.notify_all();
+
+        match problem {
+            Some(code) => self.reset(frame.stream, code),
+            None => Ok(())
+        }
+    }
+
+    fn headers(&mut self, frame: Frame)
+               -> ::std::result::Result<(), Failure> {
+        if frame.stream == 0 || frame.stream % 2 == 0 {
+            return Err(protocol_error("HEADERS on a server's stream"));
+        }
+
+        let fragment = match frame.content() {
+            Some(content) => content.to_vec(),
+            None => return Err(protocol_error("Padding past the frame"))
+        };
+        let self_dependent = frame.has(flag::PRIORITY) &&
+            dependency(&frame.payload[frame.has(flag::PADDED) as usize ..]) ==
+            frame.stream;
+
+        let block = Block {
+            stream: frame.stream,
+            end_stream: frame.has(flag::END_STREAM),
+            self_dependent: self_dependent,
+            fragment: fragment
+        };
+        self.header_fragment(block, frame.has(flag::END_HEADERS))
+    }
+
+    fn continuation(&mut self, frame: Frame)
+                    -> ::std::result::Result<(), Failure> {
+        let mut block = match self.continuing.take() {
+            Some(block) => block,
+            None => return Err(protocol_error("CONTINUATION without HEADERS"))
+        };
+        block.fragment.extend_from_slice(&frame.payload);
+        self.header_fragment(block, frame.has(flag::END_HEADERS))
+    }
+
+    /// Takes a header block, whole or in part
+    fn header_fragment(&mut self, block: Block, end: bool)
+                       -> ::std::result::Result<(), Failure> {
+        // Compressed headers are smaller than the limit on them, unless
+        // someone's trying it on
+        if block.fragment.len() as u64 >
+            2 * self.limits.header_bytes + MAX_FRAME_SIZE as u64 {
+                return Err(Failure::Protocol(error_code::ENHANCE_YOUR_CALM,
+                                             "Header block too large"));
+            }
+
+        if end {
+            self.header_block(block)
+        }
+        else {
+            self.continuing = Some(block);
+            Ok(())
+        }
+    }
+
+    /// Opens a stream with a whole header block, or ends one with trailers
+    fn header_block(&mut self, block: Block)
+                    -> ::std::result::Result<(), Failure> {
+        let max_size = cmp::min(self.limits.header_bytes,
+                                usize::max_value() as u64) as usize;
+        let fields = match self.decoder.decode(&block.fragment, max_size) {
+            Ok(fields) => fields,
+            Err(DecodeError(why)) =>
+                return Err(Failure::Protocol(error_code::COMPRESSION_ERROR,
+                                             why))
+        };
+        let id = block.stream;
+
+        if id <= self.last_stream {
+            return self.trailers(block, fields);
+        }
+        self.last_stream = id;
+
+        // After GOAWAY, new streams are ignored, as the client was told
+        if self.going_away {
+            return Ok(());
+        }
+        if block.self_dependent {
+            return self.reset(id, error_code::PROTOCOL_ERROR);
+        }
+        if let Some(ref fields) = fields {
+            if let Err(why) = check_request(fields) {
+                debug!("Malformed request on stream {}: {}", id, why);
+                return self.reset(id, error_code::PROTOCOL_ERROR);
+            }
+        }
+
+        let expected_len = fields.as_ref().and_then(|fields| {
+            fields.iter()
+                .find(|&&(ref name, _)| &name[..] == b"content-length")
+                .and_then(|&(_, ref value)| parse_length(value))
+        });
+        if block.end_stream && expected_len.map_or(false, |len| len > 0) {
+            return self.reset(id, error_code::PROTOCOL_ERROR);
+        }
+
+        let refused = {
+            let mut state = self.connection.lock_state();
+            if state.streams.len() >= MAX_STREAMS {
+                true
+            }
+            else {
+                let stream = StreamState::new(state.initial_window,
+                                              block.end_stream, expected_len,
+                                              self.timeouts);
+                state.streams.insert(id, stream);
+                false
+            }
+        };
+        if refused {
+            return self.reset(id, error_code::REFUSED_STREAM);
+        }
+
+        self.opened.push_back(Opened {
+            id: id,
+            fields: fields,
+            end_stream: block.end_stream
+        });
+        Ok(())
+    }
+
+    /// Ends a stream with trailers, which are otherwise ignored
+    fn trailers(&mut self, block: Block, fields: Option<Vec<Field>>)
+                -> ::std::result::Result<(), Failure> {
+        let id = block.stream;
+        let malformed = block.self_dependent || !block.end_stream ||
+            fields.map_or(false, |fields| {
+                fields.iter().any(|&(ref name, _)| name.starts_with(b":"))
+            });
+
+        let problem = match self.connection.lock_state().streams
+            .get_mut(&id) {
+                // Closed streams can't take HEADERS, and nor can earlier
+                // streams never opened
+                None if id == self.last_stream =>
+                    return Err(Failure::Protocol(error_code::STREAM_CLOSED,
+                                                 "HEADERS on a closed stream")),
+                None =>
+                    return Err(protocol_error("Stream opened out of order")),
+                Some(ref stream) if stream.reset => None,
+                Some(ref stream) if stream.remote_closed =>
+                    Some(error_code::STREAM_CLOSED),
+                Some(_) if malformed => Some(error_code::PROTOCOL_ERROR),
+                Some(stream) => stream.receive(&[], 0, true)
+            };
+        self.connection.changed.notify_all();
+
+        match problem {
+            Some(code) => self.reset(id, code),
+            None => Ok(())
+        }
+    }
+
+    fn priority(&mut self, frame: Frame)
+                -> ::std::result::Result<(), Failure> {
+        if frame.stream == 0 {
+            return Err(protocol_error("PRIORITY on stream 0"));
+        }
+        if frame.payload.len() != 5 {
+            return self.reset(frame.stream, error_code::FRAME_SIZE_ERROR);
+        }
+        if dependency(&frame.payload) == frame.stream {
+            return self.reset(frame.stream, error_code::PROTOCOL_ERROR);
+        }
+        Ok(())
+    }
+
+    fn rst_stream(&mut self, frame: Frame)
+                  -> ::std::result::Result<(), Failure> {
+        if frame.stream == 0 {
+            return Err(protocol_error("RST_STREAM on stream 0"));
+        }
+        if frame.payload.len() != 4 {
+            return Err(frame_size_error("RST_STREAM isn't 4 bytes"));
+        }
+        if frame.stream > self.last_stream {
+            return Err(protocol_error("RST_STREAM on an idle stream"));
+        }
+
+        if let Some(stream) = self.connection.lock_state().streams
+            .get_mut(&frame.stream) {
+                stream.reset = true;
+            }
+        self.connection.changed.notify_all();
+        Ok(())
+    }
+
+    fn settings(&mut self, frame: Frame)
+                -> ::std::result::Result<(), Failure> {
+        if frame.stream != 0 {
+            return Err(protocol_error("SETTINGS on a stream"));
+        }
+        if frame.has(flag::ACK) {
+            if !frame.payload.is_empty() {
+                return Err(frame_size_error("SETTINGS ACK with a payload"));
+            }
+            return Ok(());
+        }
+        if frame.payload.len() % 6 != 0 {
+            return Err(frame_size_error("SETTINGS of a partial setting"));
+        }
+
+        {
+            let mut guard = self.connection.lock_state();
+            let state = &mut *guard;
+            for entry in frame.payload.chunks(6) {
+                let id = (entry[0] as u16) << 8 | entry[1] as u16;
+                let value = read_be32(&entry[2 ..]);
+
+                match id {
+                    setting::ENABLE_PUSH if value > 1 =>
+                        return Err(protocol_error("ENABLE_PUSH not 0 or 1")),
+                    setting::INITIAL_WINDOW_SIZE => {
+                        if value as i64 > MAX_WINDOW {
+                            return Err(flow_control_error(
+                                "INITIAL_WINDOW_SIZE too large"));
+                        }
+
+                        // Open streams' windows move by as much
+                        let change = value as i64 - state.initial_window;
+                        state.initial_window = value as i64;
+                        for stream in state.streams.values_mut() {
+                            stream.send_window += change;
+                            if stream.send_window > MAX_WINDOW {
+                                return Err(flow_control_error(
+                                    "Stream window too large"));
+                            }
+                        }
+                    },
+                    setting::MAX_FRAME_SIZE => {
+                        if value < MAX_FRAME_SIZE as u32 || value >= 1 << 24 {
+                            return Err(protocol_error(
+                                "MAX_FRAME_SIZE out of range"));
+                        }
+                        state.max_frame_size = value as usize;
+                    },
+                    // The rest are about what the server sends, and it never
+                    // pushes or uses the client's table
+                    _ => ()
+                }
+            }
+        }
+        self.connection.changed.notify_all();
+
+        try!(self.connection.send(&[
+            Frame::new(frame_kind::SETTINGS, flag::ACK, 0, Vec::new())
+        ]));
+        Ok(())
+    }
+
+    fn ping(&mut self, frame: Frame) -> ::std::result::Result<(), Failure> {
+        if frame.stream != 0 {
+            return Err(protocol_error("PING on a stream"));
+        }
+        if frame.payload.len() != 8 {
+            return Err(frame_size_error("PING isn't 8 bytes"));
+        }
+
+        if !frame.has(flag::ACK) {
+            try!(self.connection.send(&[
+                Frame::new(frame_kind::PING, flag::ACK, 0, frame.payload)
+            ]));
+        }
+        Ok(())
+    }
+
+    fn goaway(&mut self, frame: Frame) -> ::std::result::Result<(), Failure> {
+        if frame.stream != 0 {
+            return Err(protocol_error("GOAWAY on a stream"));
+        }
+        self.going_away = true;
+        Ok(())
+    }
+
+    fn window_update(&mut self, frame: Frame)
+                     -> ::std::result::Result<(), Failure> {
+        if frame.payload.len() != 4 {
+            return Err(frame_size_error("WINDOW_UPDATE isn't 4 bytes"));
+        }
+        let increment = (read_be32(&frame.payload) & !(1 << 31)) as i64;
+
+        if frame.stream == 0 {
+            if increment == 0 {
+                return Err(protocol_error("WINDOW_UPDATE of 0"));
+            }
+
+            let mut state = self.connection.lock_state();
+            state.send_window += increment;
+            if state.send_window > MAX_WINDOW {
+                return Err(flow_control_error("Connection window too large"));
+            }
+        }
+        else {
+            if frame.stream > self.last_stream {
+                return Err(protocol_error("WINDOW_UPDATE on an idle stream"));
+            }
+            if increment == 0 {
+                return self.reset(frame.stream, error_code::PROTOCOL_ERROR);
+            }
+
+            let too_large = match self.connection.lock_state().streams
+                .get_mut(&frame.stream) {
+                    Some(ref mut stream) if !stream.reset => {
+                        stream.send_window += increment;
+                        stream.send_window > MAX_WINDOW
+                    },
+                    _ => false
+                };
+            if too_large {
+                return self.reset(frame.stream,
+                                  error_code::FLOW_CONTROL_ERROR);
+            }
+        }
+
+        self.connection.changed.notify_all();
+        Ok(())
+    }
+
+    /// Resets a stream the client got wrong, leaving the rest of the
+    /// connection be
+    fn reset(&mut self, id: u32, code: u32)
+             -> ::std::result::Result<(), Failure> {
+        if let Some(stream) = self.connection.lock_state().streams
+            .get_mut(&id) {
+                stream.reset = true;
+            }
+        self.connection.changed.notify_all();
+
+        try!(self.connection.send(&[Frame::rst_stream(id, code)]));
+        Ok(())
+    }
+}
+
+/// The stream a PRIORITY frame, or the priority at the start of a HEADERS
+/// frame's payload, says a stream depends on
+fn dependency(priority: &[u8]) -> u32 {
+    if priority.len() < 4 {
+        return 0;
+    }
+    read_be32(priority) & !(1 << 31)
+}
+
+fn parse_length(value: &[u8]) -> Option<u64> {
+    ::std::str::from_utf8(value).ok().and_then(|len| len.trim().parse().ok())
+}
+
+/// Checks a request's fields are what HTTP/2 allows, saying what's wrong if
+/// they aren't
+fn check_request(fields: &[Field]) -> ::std::result::Result<(), &'static str> {
+    let mut pseudo = Vec::new();
+    let mut regular = false;
+
+    for &(ref name, ref value) in fields {
+        if name.starts_with(b":") {
+            if regular {
+                return Err("Pseudo-header after a regular one");
+            }
+            match &name[..] {
+                b":method" | b":scheme" | b":authority" | b":path" => (),
+                _ => return Err("Unknown pseudo-header")
+            }
+            if pseudo.contains(&&name[..]) {
+                return Err("Repeated pseudo-header");
+            }
+            if &name[..] == b":path" && value.is_empty() {
+                return Err("Empty :path");
+            }
+            pseudo.push(&name[..]);
+            continue;
+        }
+
+        regular = true;
+        if name.iter().any(|b| b.is_ascii_uppercase()) {
+            return Err("Uppercase in a field name");
+        }
+        if CONNECTION_HEADERS.iter()
+            .any(|header| header.as_bytes() == &name[..]) {
+            return Err("Connection-specific header");
+        }
+        if &name[..] == b"te" && &value[..] != b"trailers" {
+            return Err("TE other than trailers");
+        }
+        if &name[..] == b"content-length" && parse_length(value).is_none() {
+            return Err("Content-Length isn't a number");
+        }
+    }
+
+    for required in &[&b":method"[..], b":scheme", b":path"] {
+        if !pseudo.contains(required) {
+            return Err("Missing pseudo-header");
+        }
+    }
+    Ok(())
+}
+
+/// A connection, as shared between the thread reading its frames and those
+/// serving its streams
+struct Connection {
+    /// Where frames go, each written whole
+    writer: Mutex<Stream>,
+    state: Mutex<State>,
+    /// Signalled whenever something a stream may be waiting on changes
+    changed: Condvar,
+    peer_addr: SocketAddr,
+    local_port: u16,
+    secure: bool,
+    fd: RawFd
+}
+
+struct State {
+    streams: HashMap<u32, StreamState>,
+    /// How much more DATA the client will take on the connection as a whole
+    send_window: i64,
+    /// The window each stream starts with, by the client's settings
+    initial_window: i64,
+    /// The largest frame the client will take
+    max_frame_size: usize,
+    /// Set once the connection's over, so nothing waits on it any more
+    closed: bool
+}
+
+/// A stream being served
+struct StreamState {
+    /// Body data the client's sent, and nobody's read yet
+    received: Vec<u8>,
+    /// Whether the client's sent all it will
+    remote_closed: bool,
+    /// Whether either side has reset the stream, so nothing more goes
+    /// either way
+    reset: bool,
+    /// How much more DATA the client may send, and how much of what it sent
+    /// has been read since the window was last opened
+    recv_window: i64,
+    consumed: i64,
+    /// The body's length by `Content-Length`, and how much has come so far
+    expected_len: Option<u64>,
+    received_len: u64,
+    /// How much more DATA the client will take on this stream
+    send_window: i64,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>
+}
+
+impl Connection {
+    fn new(stream: &Stream) -> io::Result<Connection> {
+        Ok(Connection {
+            writer: Mutex::new(try!(stream.try_clone())),
+            state: Mutex::new(State {
+                streams: HashMap::new(),
+                send_window: INITIAL_WINDOW,
+                initial_window: INITIAL_WINDOW,
+                max_frame_size: MAX_FRAME_SIZE,
+                closed: false
+            }),
+            changed: Condvar::new(),
+            peer_addr: try!(stream.peer_addr()),
+            local_port: try!(stream.local_port()),
+            secure: stream.is_secure(),
+            fd: stream.as_raw_fd()
+        })
+    }
+
+    /// Writes `frames` together, with nothing in between
+    fn send(&self, frames: &[Frame]) -> io::Result<()> {
+        let mut writer = self.writer.lock()
+            .unwrap_or_else(|poison| poison.into_inner());
+        for frame in frames {
+            try!(frame.write_to(&mut *writer));
+        }
+        Ok(())
+    }
+
+    fn lock_state<'a>(&'a self) -> MutexGuard<'a, State> {
+        self.state.lock().unwrap_or_else(|poison| poison.into_inner())
+    }
+
+    /// Waits for the state to change, or for `deadline` to pass, saying
+    /// whether it has
+    fn wait<'a>(&'a self, state: MutexGuard<'a, State>,
+                deadline: Option<Instant>) -> (MutexGuard<'a, State>, bool) {
+        let deadline = match deadline {
+            Some(deadline) => deadline,
+            None => return (self.changed.wait(state)
+                            .unwrap_or_else(|poison| poison.into_inner()),
+                            false)
+        };
+
+        let now = Instant::now();
+        if deadline <= now {
+            return (state, true);
+        }
+        let state = match self.changed.wait_timeout(state, deadline - now) {
+            Ok((state, _)) => state,
+            Err(poison) => poison.into_inner().0
+        };
+        (state, Instant::now() >= deadline)
+    }
+
+    /// Turns a stream away before it's served
+    fn refuse(&self, id: u32) {
+        self.lock_state().streams.remove(&id);
+        let _ = self.send(&[Frame::rst_stream(id, error_code::REFUSED_STREAM)]);
+    }
+
+    /// Ends the connection, failing whatever its streams are waiting on
+    fn close(&self) {
+        self.lock_state().closed = true;
+        self.changed.notify_all();
+    }
+}
+
+impl StreamState {
+    fn new(initial_window: i64, remote_closed: bool,
+           expected_len: Option<u64>, timeouts: &Timeouts) -> StreamState {
+        StreamState {
+            received: Vec::new(),
+            remote_closed: remote_closed,
+            reset: false,
+            recv_window: INITIAL_WINDOW,
+            consumed: 0,
+            expected_len: expected_len,
+            received_len: 0,
+            send_window: initial_window,
+            read_timeout: None,
+            write_timeout: Some(Duration::from_secs(timeouts.write))
+        }
+    }
+
+    /// Takes body data from a DATA frame `len` bytes long, or says how the
+    /// client broke the stream
+    fn receive(&mut self, content: &[u8], len: i64, end_stream: bool)
+               -> Option<u32> {
+        self.recv_window -= len;
+        if self.recv_window < 0 {
+            return Some(error_code::FLOW_CONTROL_ERROR);
+        }
+        // Padding's never read, so it's as good as read already
+        self.consumed += len - content.len() as i64;
+
+        self.received_len += content.len() as u64;
+        if let Some(expected) = self.expected_len {
+            if self.received_len > expected ||
+                end_stream && self.received_len != expected {
+                    return Some(error_code::PROTOCOL_ERROR);
+                }
+        }
+
+        self.received.extend_from_slice(content);
+        if end_stream {
+            self.remote_closed = true;
+        }
+        None
+    }
+}
+
+/// One stream of an HTTP/2 connection, which its request is read from and
+/// its response written to
+///
+/// Writes go out as DATA frames, as the client's flow control allows; the
+/// response's headers are sent with `send_headers`, and `finish` ends the
+/// stream.
+#[derive(Clone)]
+pub struct H2Stream {
+    connection: Arc<Connection>,
+    id: u32
+}
+
+impl H2Stream {
+    pub fn set_read_timeout(&self, timeout: Option<Duration>)
+                            -> io::Result<()> {
+        if let Some(stream) = self.connection.lock_state().streams
+            .get_mut(&self.id) {
+                stream.read_timeout = timeout;
+            }
+        Ok(())
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>)
+                             -> io::Result<()> {
+        if let Some(stream) = self.connection.lock_state().streams
+            .get_mut(&self.id) {
+                stream.write_timeout = timeout;
+            }
+        Ok(())
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.connection.peer_addr
+    }
+
+    pub fn local_port(&self) -> u16 {
+        self.connection.local_port
+    }
+
+    pub fn is_secure(&self) -> bool {
+        self.connection.secure
+    }
+
+    /// Reads body data without taking it from the stream
+    pub fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.receive(buf, false)
+    }
+
+    /// Sends the response's status and headers, leaving out those HTTP/2
+    /// has no use for
+    pub fn send_headers(&self, code: u16, headers: &Headers)
+                        -> io::Result<()> {
+        let mut block = Vec::new();
+        hpack::encode(b":status", format!("{}", code).as_bytes(), &mut block);
+        for (name, value) in headers {
+            let name = name.to_ascii_lowercase();
+            if !CONNECTION_HEADERS.contains(&&name[..]) {
+                hpack::encode(name.as_bytes(), value, &mut block);
+            }
+        }
+
+        let max_frame_size = {
+            let state = self.connection.lock_state();
+            match state.streams.get(&self.id) {
+                Some(stream) if !stream.reset => state.max_frame_size,
+                _ => return Err(reset())
+            }
+        };
+
+        let mut frames: Vec<Frame> = block.chunks(max_frame_size)
+            .enumerate()
+            .map(|(i, fragment)| {
+                let kind = if i == 0 {
+                    frame_kind::HEADERS
+                }
+                else {
+                    frame_kind::CONTINUATION
+                };
+                Frame::new(kind, 0, self.id, fragment.to_vec())
+            })
+            .collect();
+        if let Some(last) = frames.last_mut() {
+            last.flags |= flag::END_HEADERS;
+        }
+        self.connection.send(&frames)
+    }
+
+    /// Ends the stream once the response is done, or resets it if there was
+    /// no response
+    ///
+    /// If the client's still sending a body nobody wants, it's told to stop.
+    pub fn finish(&self, answered: bool) {
+        let stream = self.connection.lock_state().streams.remove(&self.id);
+        self.connection.changed.notify_all();
+
+        let stream = match stream {
+            Some(ref stream) if !stream.reset => stream,
+            _ => return
+        };
+
+        let mut frames = Vec::new();
+        if answered {
+            frames.push(Frame::new(frame_kind::DATA, flag::END_STREAM, self.id,
+                                   Vec::new()));
+            if !stream.remote_closed {
+                frames.push(Frame::rst_stream(self.id, error_code::NO_ERROR));
+            }
+        }
+        else {
+            frames.push(Frame::rst_stream(self.id,
+                                          error_code::INTERNAL_ERROR));
+        }
+        let _ = self.connection.send(&frames);
+    }
+
+    /// Gives body data as it comes in, taking it from the stream if `take`
+    fn receive(&self, buf: &mut [u8], take: bool) -> io::Result<usize> {
+        let mut update = 0;
+        let n = {
+            let mut state = self.connection.lock_state();
+            let mut deadline = None;
+            let mut first = true;
+
+            loop {
+                if state.closed {
+                    return Err(closed());
+                }
+
+                let ready = match state.streams.get_mut(&self.id) {
+                    // The response is done, and the request with it
+                    None => Some(0),
+                    Some(ref stream) if stream.reset => return Err(reset()),
+                    Some(stream) => {
+                        if first {
+                            deadline = stream.read_timeout
+                                .map(|timeout| Instant::now() + timeout);
+                            first = false;
+                        }
+
+                        if !stream.received.is_empty() {
+                            let n = cmp::min(buf.len(), stream.received.len());
+                            buf[.. n].copy_from_slice(&stream.received[.. n]);
+                            if take {
+                                stream.received.drain(.. n);
+                                stream.consumed += n as i64;
+                                if !stream.remote_closed &&
+                                    stream.consumed >= WINDOW_THRESHOLD {
+                                        update = stream.consumed;
+                                        stream.recv_window += update;
+                                        stream.consumed = 0;
+                                    }
+                            }
+                            Some(n)
+                        }
+                        else if stream.remote_closed {
+                            Some(0)
+                        }
+                        else {
+                            None
+                        }
+                    }
+                };
+                if let Some(n) = ready {
+                    break n;
+                }
+
+                let (next, timed_out) = self.connection.wait(state, deadline);
+                state = next;
+                if timed_out {
+                    return Err(io::Error::new(ErrorKind::TimedOut,
+                                              "No DATA in time"));
+                }
+            }
+        };
+
+        if update > 0 {
+            try!(self.connection.send(&[
+                Frame::window_update(self.id, update as u32)
+            ]));
+        }
+        Ok(n)
+    }
+
+    /// Waits for the client's windows to take up to `len` bytes, and takes
+    /// what they will
+    fn reserve(&self, len: usize) -> io::Result<usize> {
+        let mut state = self.connection.lock_state();
+        let mut deadline = None;
+        let mut first = true;
+
+        loop {
+            if state.closed {
+                return Err(closed());
+            }
+
+            let connection_window = state.send_window;
+            let max_frame_size = state.max_frame_size as i64;
+            let n = match state.streams.get_mut(&self.id) {
+                Some(ref mut stream) if !stream.reset => {
+                    if first {
+                        deadline = stream.write_timeout
+                            .map(|timeout| Instant::now() + timeout);
+                        first = false;
+                    }
+
+                    let n = cmp::min(cmp::min(len as i64, max_frame_size),
+                                     cmp::min(connection_window,
+                                              stream.send_window));
+                    if n > 0 {
+                        stream.send_window -= n;
+                    }
+                    n
+                },
+                _ => return Err(reset())
+            };
+            if n > 0 {
+                state.send_window -= n;
+                return Ok(n as usize);
+            }
+
+            let (next, timed_out) = self.connection.wait(state, deadline);
+            state = next;
+            if timed_out {
+                return Err(io::Error::new(ErrorKind::TimedOut,
+                                          "The client's window stayed shut"));
+            }
+        }
+    }
+}
+
+fn reset() -> io::Error {
+    io::Error::new(ErrorKind::ConnectionReset, "The stream was reset")
+}
+
+fn closed() -> io::Error {
+    io::Error::new(ErrorKind::ConnectionAborted, "The connection's closed")
+}
+
+impl Read for H2Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.receive(buf, true)
+    }
+}
+
+impl Write for H2Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let n = try!(self.reserve(buf.len()));
+        try!(self.connection.send(&[
+            Frame::new(frame_kind::DATA, 0, self.id, buf[.. n].to_vec())
+        ]));
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The connection's socket, which mustn't be written to directly
+impl AsRawFd for H2Stream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.connection.fd
+    }
+}
+
+impl fmt::Debug for H2Stream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "H2Stream({} from {})", self.id, self.connection.peer_addr)
+    }
+}
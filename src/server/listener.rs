@@ -0,0 +1,612 @@
+//! Listening sockets, TCP or Unix, and the connections they accept
+//!
+//! Sockets are set up by hand rather than through `TcpListener::bind`, since
+//! options like `SO_REUSEPORT` and `IPV6_V6ONLY` have to be set before the
+//! socket's bound, and the backlog when it starts listening.
+
+use config::{ListenAddress, ListenConfig};
+use server::http2::H2Stream;
+use server::signals;
+use server::tls::{self, TlsStream};
+
+use libc::{self, c_int, c_void};
+
+use std::ffi::{CStr, CString};
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// `recv` flag to look at data without taking it, the same on Linux and the
+/// BSDs
+const MSG_PEEK: c_int = 2;
+
+/// A socket taking connections
+pub struct Listener {
+    socket: Socket,
+    /// Whether accepted connections have Nagle's algorithm turned off
+    nodelay: bool
+}
+
+enum Socket {
+    Tcp(TcpListener),
+    Unix(UnixListener)
+}
+
+impl Listener {
+    /// Opens a socket as `config` describes
+    pub fn bind(config: &ListenConfig) -> io::Result<Listener> {
+        let socket = match config.address {
+            ListenAddress::Tcp(addr) => Socket::Tcp(try!(bind_tcp(addr,
+                                                                 config))),
+            ListenAddress::Unix(ref path) => {
+                // A socket left by a server that's gone would be in the way
+                if let Ok(meta) = fs::symlink_metadata(path) {
+                    if meta.file_type().is_socket() {
+                        try!(fs::remove_file(path));
+                    }
+                }
+
+                let listener = try!(UnixListener::bind(path));
+                try!(cvt(unsafe {
+                    libc::listen(listener.as_raw_fd(), config.backlog as c_int)
+                }));
+                try!(set_permissions(config));
+                Socket::Unix(listener)
+            }
+        };
+
+        Ok(Listener { socket: socket, nodelay: config.nodelay })
+    }
+
+    /// Takes over a listening socket opened by someone else, with the
+    /// options of whichever of `configs` is for its address
+    pub fn from_fd(fd: RawFd, configs: &[ListenConfig])
+                   -> io::Result<Listener> {
+        let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let mut len = mem::size_of::<libc::sockaddr_storage>()
+            as libc::socklen_t;
+        try!(cvt(unsafe {
+            libc::getsockname(fd, &mut addr as *mut _ as *mut libc::sockaddr,
+                              &mut len)
+        }));
+
+        let socket = match addr.ss_family as c_int {
+            libc::AF_INET | libc::AF_INET6 =>
+                Socket::Tcp(unsafe { TcpListener::from_raw_fd(fd) }),
+            libc::AF_UNIX =>
+                Socket::Unix(unsafe { UnixListener::from_raw_fd(fd) }),
+            family => return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Descriptor {} is a socket of unknown family {}",
+                        fd, family)
+            ))
+        };
+
+        let mut listener = Listener { socket: socket, nodelay: false };
+        if let Some(config) = configs.iter().find(|c| listener.is_for(c)) {
+            listener.nodelay = config.nodelay;
+        }
+        Ok(listener)
+    }
+
+    /// The address the socket's bound to
+    pub fn address(&self) -> io::Result<ListenAddress> {
+        match self.socket {
+            Socket::Tcp(ref listener) =>
+                listener.local_addr().map(ListenAddress::Tcp),
+            Socket::Unix(ref listener) => {
+                let addr = try!(listener.local_addr());
+                match addr.as_pathname() {
+                    Some(path) => Ok(ListenAddress::Unix(path.to_owned())),
+                    None => Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                               "The Unix socket is unnamed"))
+                }
+            }
+        }
+    }
+
+    /// Whether this is the socket `config` asks for
+    pub fn is_for(&self, config: &ListenConfig) -> bool {
+        match (self.address(), &config.address) {
+            (Ok(ListenAddress::Tcp(ours)), &ListenAddress::Tcp(theirs)) =>
+                ours == theirs ||
+                (theirs.port() == 0 && ours.ip() == theirs.ip()),
+            (Ok(ours), theirs) => ours == *theirs,
+            (Err(_), _) => false
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self.socket {
+            Socket::Tcp(ref listener) => listener.set_nonblocking(nonblocking),
+            Socket::Unix(ref listener) =>
+                listener.set_nonblocking(nonblocking)
+        }
+    }
+
+    /// Accepts a connection, ready to be served
+    pub fn accept(&self) -> io::Result<Stream> {
+        let stream = match self.socket {
+            Socket::Tcp(ref listener) => {
+                let (stream, _) = try!(listener.accept());
+                if self.nodelay {
+                    try!(stream.set_nodelay(true));
+                }
+                Stream::Tcp(stream)
+            },
+            Socket::Unix(ref listener) =>
+                Stream::Unix(try!(listener.accept()).0)
+        };
+
+        try!(stream.set_nonblocking(false));
+        Ok(stream)
+    }
+}
+
+impl AsRawFd for Listener {
+    fn as_raw_fd(&self) -> RawFd {
+        match self.socket {
+            Socket::Tcp(ref listener) => listener.as_raw_fd(),
+            Socket::Unix(ref listener) => listener.as_raw_fd()
+        }
+    }
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.address() {
+            Ok(address) => write!(f, "{}", address),
+            Err(_) => write!(f, "descriptor {}", self.as_raw_fd())
+        }
+    }
+}
+
+fn bind_tcp(addr: SocketAddr, config: &ListenConfig)
+            -> io::Result<TcpListener> {
+    let family = match addr {
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6
+    };
+
+    let fd = try!(cvt(unsafe {
+        libc::socket(family, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0)
+    }));
+    // Owned from here, so it's closed if anything fails
+    let listener = unsafe { TcpListener::from_raw_fd(fd) };
+
+    try!(set_option(fd, libc::SOL_SOCKET, libc::SO_REUSEADDR, 1));
+    if config.reuse_port {
+        try!(set_option(fd, libc::SOL_SOCKET, libc::SO_REUSEPORT, 1));
+    }
+    if let Some(only) = config.ipv6_only {
+        try!(set_option(fd, libc::IPPROTO_IPV6, libc::IPV6_V6ONLY,
+                        only as c_int));
+    }
+    if config.fastopen > 0 {
+        try!(set_option(fd, libc::IPPROTO_TCP, libc::TCP_FASTOPEN,
+                        config.fastopen as c_int));
+    }
+
+    try!(cvt(match addr {
+        SocketAddr::V4(ref v4) => unsafe {
+            let mut raw: libc::sockaddr_in = mem::zeroed();
+            raw.sin_family = libc::AF_INET as libc::sa_family_t;
+            raw.sin_port = v4.port().to_be();
+            raw.sin_addr.s_addr = u32::from(*v4.ip()).to_be();
+            libc::bind(fd, &raw as *const _ as *const libc::sockaddr,
+                       mem::size_of_val(&raw) as libc::socklen_t)
+        },
+        SocketAddr::V6(ref v6) => unsafe {
+            let mut raw: libc::sockaddr_in6 = mem::zeroed();
+            raw.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            raw.sin6_port = v6.port().to_be();
+            raw.sin6_addr.s6_addr = v6.ip().octets();
+            raw.sin6_flowinfo = v6.flowinfo();
+            raw.sin6_scope_id = v6.scope_id();
+            libc::bind(fd, &raw as *const _ as *const libc::sockaddr,
+                       mem::size_of_val(&raw) as libc::socklen_t)
+        }
+    }));
+
+    try!(cvt(unsafe { libc::listen(fd, config.backlog as c_int) }));
+    Ok(listener)
+}
+
+fn set_option(fd: RawFd, level: c_int, option: c_int, value: c_int)
+              -> io::Result<()> {
+    try!(cvt(unsafe {
+        libc::setsockopt(fd, level, option,
+                         &value as *const c_int as *const c_void,
+                         mem::size_of::<c_int>() as libc::socklen_t)
+    }));
+    Ok(())
+}
+
+/// Gives a Unix socket's file the mode and owners `config` asks for
+fn set_permissions(config: &ListenConfig) -> io::Result<()> {
+    let path = match config.address {
+        ListenAddress::Unix(ref path) => path,
+        ListenAddress::Tcp(_) => return Ok(())
+    };
+    let path = try!(CString::new(path.as_os_str().as_bytes()));
+
+    if let Some(mode) = config.mode {
+        try!(cvt(unsafe { libc::chmod(path.as_ptr(), mode as libc::mode_t) }));
+    }
+
+    if config.owner.is_some() || config.group.is_some() {
+        // -1 leaves either as it is
+        let uid = match config.owner {
+            Some(ref owner) => try!(user_id(owner)),
+            None => !0
+        };
+        let gid = match config.group {
+            Some(ref group) => try!(group_id(group)),
+            None => !0
+        };
+        try!(cvt(unsafe { libc::chown(path.as_ptr(), uid, gid) }));
+    }
+
+    Ok(())
+}
+
+/// The fields of `struct group` before the one we need, which the libc crate
+/// doesn't have
+#[repr(C)]
+struct Group {
+    gr_name: *mut libc::c_char,
+    gr_passwd: *mut libc::c_char,
+    gr_gid: libc::gid_t
+}
+
+extern "C" {
+    fn getpwnam(name: *const libc::c_char) -> *mut libc::passwd;
+    fn getgrnam(name: *const libc::c_char) -> *mut Group;
+}
+
+fn user_id(owner: &str) -> io::Result<libc::uid_t> {
+    if let Ok(id) = owner.parse() {
+        return Ok(id);
+    }
+
+    let name = try!(CString::new(owner));
+    let entry = unsafe { getpwnam(name.as_ptr()) };
+    if entry.is_null() {
+        return Err(no_such("user", &name));
+    }
+    Ok(unsafe { (*entry).pw_uid })
+}
+
+fn group_id(group: &str) -> io::Result<libc::gid_t> {
+    if let Ok(id) = group.parse() {
+        return Ok(id);
+    }
+
+    let name = try!(CString::new(group));
+    let entry = unsafe { getgrnam(name.as_ptr()) };
+    if entry.is_null() {
+        return Err(no_such("group", &name));
+    }
+    Ok(unsafe { (*entry).gr_gid })
+}
+
+fn no_such(what: &str, name: &CStr) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound,
+                   format!("There's no {} {:?}", what, name))
+}
+
+fn cvt(result: c_int) -> io::Result<c_int> {
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    }
+    else {
+        Ok(result)
+    }
+}
+
+/// A client's connection, over TCP or a Unix socket, or TLS over TCP; or
+/// one stream of an HTTP/2 connection
+#[derive(Debug)]
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+    /// Shared between clones, since a TLS session can't be split
+    Tls(Arc<Mutex<TlsStream>>),
+    Http2(H2Stream)
+}
+
+impl Stream {
+    pub fn try_clone(&self) -> io::Result<Stream> {
+        match self {
+            &Stream::Tcp(ref s) => s.try_clone().map(Stream::Tcp),
+            &Stream::Unix(ref s) => s.try_clone().map(Stream::Unix),
+            &Stream::Tls(ref s) => Ok(Stream::Tls(s.clone())),
+            &Stream::Http2(ref s) => Ok(Stream::Http2(s.clone()))
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>)
+                            -> io::Result<()> {
+        match self {
+            &Stream::Tcp(ref s) => s.set_read_timeout(timeout),
+            &Stream::Unix(ref s) => s.set_read_timeout(timeout),
+            &Stream::Tls(ref s) => tls::lock(s).socket()
+                .set_read_timeout(timeout),
+            &Stream::Http2(ref s) => s.set_read_timeout(timeout)
+        }
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>)
+                             -> io::Result<()> {
+        match self {
+            &Stream::Tcp(ref s) => s.set_write_timeout(timeout),
+            &Stream::Unix(ref s) => s.set_write_timeout(timeout),
+            &Stream::Tls(ref s) => tls::lock(s).socket()
+                .set_write_timeout(timeout),
+            &Stream::Http2(ref s) => s.set_write_timeout(timeout)
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            &Stream::Tcp(ref s) => s.set_nonblocking(nonblocking),
+            &Stream::Unix(ref s) => s.set_nonblocking(nonblocking),
+            &Stream::Tls(ref s) => tls::lock(s).socket()
+                .set_nonblocking(nonblocking),
+            // Its connection has to block, for the frames to get through
+            &Stream::Http2(_) => Ok(())
+        }
+    }
+
+    /// Reads without taking what's read from the stream
+    pub fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            &Stream::Tls(ref s) => return tls::lock(s).peek(buf),
+            &Stream::Http2(ref s) => return s.peek(buf),
+            _ => ()
+        }
+
+        let n = unsafe {
+            libc::recv(self.as_raw_fd(), buf.as_mut_ptr() as *mut c_void,
+                       buf.len(), MSG_PEEK)
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(n as usize)
+    }
+
+    /// The client's address; Unix socket clients are local, but have no port
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            &Stream::Tcp(ref s) => s.peer_addr(),
+            &Stream::Unix(_) =>
+                Ok(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+                                   0)),
+            &Stream::Tls(ref s) => tls::lock(s).socket().peer_addr(),
+            &Stream::Http2(ref s) => Ok(s.peer_addr())
+        }
+    }
+
+    /// The port the connection came in on, or 0 for a Unix socket
+    pub fn local_port(&self) -> io::Result<u16> {
+        match self {
+            &Stream::Tcp(ref s) => s.local_addr().map(|addr| addr.port()),
+            &Stream::Unix(_) => Ok(0),
+            &Stream::Tls(ref s) =>
+                tls::lock(s).socket().local_addr().map(|addr| addr.port()),
+            &Stream::Http2(ref s) => Ok(s.local_port())
+        }
+    }
+
+    /// Whether the connection's encrypted
+    pub fn is_secure(&self) -> bool {
+        match self {
+            &Stream::Tls(_) => true,
+            &Stream::Http2(ref s) => s.is_secure(),
+            _ => false
+        }
+    }
+
+    /// Whether what's written goes to the client just as it is, with nothing
+    /// to encrypt or frame it, so files can be sent straight from the kernel
+    pub fn is_raw(&self) -> bool {
+        match self {
+            &Stream::Tcp(_) | &Stream::Unix(_) => true,
+            _ => false
+        }
+    }
+
+    pub fn is_http2(&self) -> bool {
+        match self {
+            &Stream::Http2(_) => true,
+            _ => false
+        }
+    }
+
+    /// The protocol the client and server agreed on by ALPN, if they did
+    pub fn protocol(&self) -> Option<Vec<u8>> {
+        match self {
+            &Stream::Tls(ref s) => tls::lock(s).protocol(),
+            _ => None
+        }
+    }
+
+    /// Waits up to `timeout` for something to read, saying whether it came,
+    /// without keeping anyone else from writing meanwhile
+    pub fn readable(&self, timeout: Duration) -> io::Result<bool> {
+        if let &Stream::Tls(ref s) = self {
+            if tls::lock(s).buffered() {
+                return Ok(true);
+            }
+        }
+
+        let ms = timeout.as_secs() * 1000 +
+            timeout.subsec_nanos() as u64 / 1000000;
+        signals::readable(&[self.as_raw_fd()], ms as c_int)
+            .map(|ready| !ready.is_empty())
+    }
+
+    /// Finishes the TLS handshake, if there is one
+    pub fn handshake(&self) -> io::Result<()> {
+        match self {
+            &Stream::Tls(ref s) => tls::lock(s).handshake(),
+            _ => Ok(())
+        }
+    }
+}
+
+impl From<TcpStream> for Stream {
+    fn from(stream: TcpStream) -> Stream {
+        Stream::Tcp(stream)
+    }
+}
+
+impl From<UnixStream> for Stream {
+    fn from(stream: UnixStream) -> Stream {
+        Stream::Unix(stream)
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            &mut Stream::Tcp(ref mut s) => s.read(buf),
+            &mut Stream::Unix(ref mut s) => s.read(buf),
+            &mut Stream::Tls(ref s) => tls::lock(s).read(buf),
+            &mut Stream::Http2(ref mut s) => s.read(buf)
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            &mut Stream::Tcp(ref mut s) => s.write(buf),
+            &mut Stream::Unix(ref mut s) => s.write(buf),
+            &mut Stream::Tls(ref s) => tls::lock(s).write(buf),
+            &mut Stream::Http2(ref mut s) => s.write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            &mut Stream::Tcp(ref mut s) => s.flush(),
+            &mut Stream::Unix(ref mut s) => s.flush(),
+            &mut Stream::Tls(ref s) => tls::lock(s).flush(),
+            &mut Stream::Http2(ref mut s) => s.flush()
+        }
+    }
+}
+
+/// For TLS and HTTP/2, this is the underlying socket, which mustn't be
+/// written to directly
+impl AsRawFd for Stream {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            &Stream::Tcp(ref s) => s.as_raw_fd(),
+            &Stream::Unix(ref s) => s.as_raw_fd(),
+            &Stream::Tls(ref s) => tls::lock(s).socket().as_raw_fd(),
+            &Stream::Http2(ref s) => s.as_raw_fd()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use config::{ListenAddress, ListenConfig};
+
+    use std::env;
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixStream;
+    use std::process;
+
+    #[test]
+    fn ipv6_listeners_can_take_ipv4_too() {
+        let listener = Listener::bind(&ListenConfig {
+            address: ListenAddress::Tcp("[::]:0".parse().unwrap()),
+            ipv6_only: Some(false),
+            nodelay: true,
+            ..Default::default()
+        }).unwrap();
+        let port = match listener.address().unwrap() {
+            ListenAddress::Tcp(addr) => addr.port(),
+            other => panic!("Bound {}", other)
+        };
+
+        let mut client = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        client.write_all(b"hi").unwrap();
+
+        let mut stream = listener.accept().unwrap();
+        let mut buf = [0; 2];
+        assert_eq!(stream.peek(&mut buf).unwrap(), 2);
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hi");
+        assert_eq!(stream.local_port().unwrap(), port);
+    }
+
+    #[test]
+    fn reused_ports_can_be_bound_twice() {
+        let config = ListenConfig {
+            address: ListenAddress::Tcp("127.0.0.1:0".parse().unwrap()),
+            reuse_port: true,
+            fastopen: 16,
+            backlog: 16,
+            ..Default::default()
+        };
+        let first = Listener::bind(&config).unwrap();
+        let second = Listener::bind(&ListenConfig {
+            address: first.address().unwrap(),
+            ..config.clone()
+        }).unwrap();
+
+        assert_eq!(first.address().unwrap(), second.address().unwrap());
+        assert!(first.is_for(&config));
+    }
+
+    #[test]
+    fn unix_listeners_get_their_mode() {
+        let path = env::temp_dir()
+            .join(format!("http-server-listener-{}.sock", process::id()));
+        let config = ListenConfig {
+            address: ListenAddress::Unix(path.clone()),
+            mode: Some(0o600),
+            ..Default::default()
+        };
+
+        // Binding again replaces the stale socket
+        drop(Listener::bind(&config).unwrap());
+        let listener = Listener::bind(&config).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+                   0o600);
+        assert_eq!(listener.address().unwrap(), config.address);
+
+        let mut client = UnixStream::connect(&path).unwrap();
+        client.write_all(b"hi").unwrap();
+        let mut stream = listener.accept().unwrap();
+        let mut buf = [0; 2];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hi");
+        assert_eq!(stream.local_port().unwrap(), 0);
+
+        let taken = Listener::from_fd(listener.as_raw_fd(), &[config.clone()])
+            .unwrap();
+        assert!(taken.is_for(&config));
+        ::std::mem::forget(taken);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
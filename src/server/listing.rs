@@ -0,0 +1,130 @@
+//! HTML directory listings
+
+use std::ffi::OsString;
+use std::os::unix::ffi::OsStrExt;
+
+/// One thing in a listed directory
+pub struct Entry {
+    pub name: OsString,
+    pub is_dir: bool
+}
+
+/// Renders a page listing `entries`, directories first, for the directory
+/// whose request path is `uri`
+///
+/// With `parent` set, the page links up to `../` as well.
+pub fn render(uri: &[u8], entries: &mut [Entry], parent: bool) -> Vec<u8> {
+    entries.sort_by(|a, b| {
+        b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name))
+    });
+
+    let title = html_escape(uri);
+
+    let mut page = Vec::new();
+    page.extend_from_slice(b"<!doctype html><html><head><title>Index of ");
+    page.extend_from_slice(&title);
+    page.extend_from_slice(b"</title></head><body><h1>Index of ");
+    page.extend_from_slice(&title);
+    page.extend_from_slice(b"</h1><ul>");
+
+    if parent {
+        page.extend_from_slice(b"<li><a href=\"../\">../</a></li>");
+    }
+
+    for entry in entries.iter() {
+        let name = entry.name.as_bytes();
+        let slash: &[u8] = if entry.is_dir { b"/" } else { b"" };
+
+        page.extend_from_slice(b"<li><a href=\"");
+        page.extend_from_slice(&percent_encode(name));
+        page.extend_from_slice(slash);
+        page.extend_from_slice(b"\">");
+        page.extend_from_slice(&html_escape(name));
+        page.extend_from_slice(slash);
+        page.extend_from_slice(b"</a></li>");
+    }
+
+    page.extend_from_slice(b"</ul></body></html>");
+    page
+}
+
+fn html_escape(s: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(s.len());
+
+    for &b in s {
+        match b {
+            b'&' => escaped.extend_from_slice(b"&amp;"),
+            b'<' => escaped.extend_from_slice(b"&lt;"),
+            b'>' => escaped.extend_from_slice(b"&gt;"),
+            b'"' => escaped.extend_from_slice(b"&quot;"),
+            b'\'' => escaped.extend_from_slice(b"&#39;"),
+            b => escaped.push(b)
+        }
+    }
+
+    escaped
+}
+
+/// Percent-encodes everything but RFC 3986 unreserved characters
+pub fn percent_encode(s: &[u8]) -> Vec<u8> {
+    const HEXITS: &'static [u8] = b"0123456789ABCDEF";
+
+    let mut encoded = Vec::with_capacity(s.len());
+
+    for &b in s {
+        if is_unreserved(b) {
+            encoded.push(b);
+        }
+        else {
+            encoded.push(b'%');
+            encoded.push(HEXITS[(b >> 4) as usize]);
+            encoded.push(HEXITS[(b & 0xF) as usize]);
+        }
+    }
+
+    encoded
+}
+
+pub fn is_unreserved(b: u8) -> bool {
+    (b'A' <= b && b <= b'Z') ||
+    (b'a' <= b && b <= b'z') ||
+    (b'0' <= b && b <= b'9') ||
+    b == b'-' || b == b'.' || b == b'_' || b == b'~'
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::ffi::OsString;
+
+    #[test]
+    fn listing_escapes_names() {
+        let mut entries = vec![Entry {
+            name: OsString::from("<b> & c.txt"),
+            is_dir: false
+        }];
+
+        let page = String::from_utf8(render(b"/docs/", &mut entries, true))
+            .unwrap();
+
+        assert!(page.contains(
+            "<a href=\"%3Cb%3E%20%26%20c.txt\">&lt;b&gt; &amp; c.txt</a>"
+        ));
+        assert!(page.contains("<a href=\"../\">"));
+    }
+
+    #[test]
+    fn listing_puts_directories_first() {
+        let mut entries = vec![
+            Entry { name: OsString::from("a.txt"), is_dir: false },
+            Entry { name: OsString::from("z"), is_dir: true }
+        ];
+
+        let page = String::from_utf8(render(b"/", &mut entries, false))
+            .unwrap();
+
+        assert!(page.find("z/").unwrap() < page.find("a.txt").unwrap());
+        assert!(!page.contains("../"));
+    }
+}
@@ -1,46 +1,462 @@
 //! Server functionality
 
+mod auth;
+mod connection;
+mod dav;
+mod file_cache;
+pub mod header_rules;
+mod http2;
+mod listener;
+mod listing;
+mod sendfile;
+mod signals;
 mod static_files;
+mod rewrite;
 mod router;
+mod sites;
+mod systemd;
+mod tls;
+mod upgrade;
 
-use config::Config;
+use config::{Config, Limits, ListenConfig, SiteConfig, Timeouts};
 use errors::{Result, Error};
 use fastcgi::driver as fcgi_driver;
-use filesystem::normalize_path;
+use filesystem::{normalize_path, split_query};
+use server::connection::{Active, KeepAlive, TimedStream};
+use server::dav::Dav;
+use server::error_messages::{ErrorContext, ErrorPages};
+use server::listener::{Listener, Stream};
+use server::rewrite::Rewrites;
 use server::router::Router;
+use server::signals::Signals;
+use server::sites::Sites;
 use server::static_files::Statics;
+use server::systemd::Notifier;
+use server::tls::Resumption;
+use server::upgrade::Pidfile;
 
 use httparse;
+use libc;
 use mime::Mime;
 use log::LogLevel;
+use rustls::ServerConfig;
 
 use std::ascii::AsciiExt;
+use std::cmp;
 use std::collections::HashMap;
 use std::collections::hash_map::{self, Entry};
 use std::ffi::OsStr;
-use std::fs::canonicalize;
-use std::io::{self, Read, BufRead, BufReader, Write, BufWriter, ErrorKind};
+use std::fs::{File, canonicalize};
+use std::io::{self, Read, BufRead, BufReader, Write, BufWriter, ErrorKind, Seek,
+              SeekFrom};
 use std::marker::PhantomData;
 use std::mem;
-use std::net::{TcpListener, TcpStream, SocketAddr};
+use std::net::SocketAddr;
 use std::os::unix::ffi::OsStrExt;
-use std::path::PathBuf;
-use std::time::Duration;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::process::{self, Child};
+use std::str;
+use std::thread;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// How the server stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shutdown {
+    /// Every request being served finished
+    Graceful,
+    /// Requests were cut off, by SIGQUIT or the grace period running out
+    Forced
+}
 
-/// Binds the given port and begins serving the given directory.
+/// Everything built from one configuration
+///
+/// Connections keep the generation they were accepted under, so a reload only
+/// affects those that come after it.
+struct Generation {
+    sites: Sites,
+    timeouts: Timeouts,
+    limits: Limits,
+    /// How each listener speaks HTTPS, in the order of the listeners, if it
+    /// does
+    tls: Vec<Option<Arc<ServerConfig>>>,
+    /// FastCGI connections, closed when the generation is dropped
+    backends: Vec<Arc<fcgi_driver::Connection>>,
+    /// Every site's static mounts, to report on their caches
+    statics: Vec<Arc<Statics>>
+}
+
+impl Generation {
+    fn build(mut config: Config, listeners: &[Listener],
+             resumption: &Resumption) -> Result<Generation> {
+        let mut tls = Vec::with_capacity(listeners.len());
+        for listener in listeners {
+            let listen = config.listeners.iter()
+                .find(|listen| listener.is_for(listen));
+            tls.push(match listen.and_then(|listen| listen.tls.as_ref()) {
+                Some(files) => Some(try!(tls::server_config(files,
+                                                            &config.sites,
+                                                            resumption))),
+                None => None
+            });
+        }
+
+        let mut sites = Sites::new();
+        let mut backends = Vec::new();
+        let mut statics = Vec::new();
+        for site in config.sites.iter_mut() {
+            for mount in site.statics.iter_mut() {
+                mount.webroot = try!(canonicalize(&mount.webroot));
+            }
+
+            let router = try!(site_router(site, &mut backends,
+                                          &mut statics));
+            sites.add(site.server_names.clone(), site.default, router);
+        }
+
+        Ok(Generation {
+            sites: sites,
+            timeouts: config.timeouts,
+            limits: config.limits,
+            tls: tls,
+            backends: backends,
+            statics: statics
+        })
+    }
+
+    /// Logs how each static mount's cache is doing
+    fn log_cache_stats(&self) {
+        for statics in &self.statics {
+            let stats = statics.cache_stats();
+            info!("File cache for {}: {} hits, {} misses, {} entries, {} bytes",
+                  statics.mount().webroot.display(), stats.hits,
+                  stats.misses, stats.entries, stats.bytes);
+        }
+    }
+}
+
+/// Listens on the configured addresses and begins serving the given
+/// directory, until SIGTERM, SIGINT or SIGQUIT.
+///
+/// If this process was started to replace another, it takes over the old
+/// one's listening sockets; on SIGUSR2, it starts its own replacement.
+///
+/// On SIGHUP, `reload` is asked for a fresh configuration, which new
+/// connections are served under; if it has none, or what it gives can't be
+/// set up, the old one stays. Listeners can't be changed this way, but their
+/// certificates can.
 ///
 /// This function has _no_ security. Wanna serve `/`? How about
 /// `~/.ssh`? Sure! Put those bytes on the Web.
 ///
 /// Fixing this is a project for post-`0.1`.
-pub fn serve(mut config: Config) -> Result<()> {
-    let listener = try!(TcpListener::bind(("0.0.0.0", config.port)));
-    config.stat.webroot = try!(canonicalize(config.stat.webroot));
+pub fn serve<F>(config: Config, reload: F) -> Result<Shutdown>
+    where F: Fn() -> Option<Config>
+{
+    let listen = config.listeners.clone();
+    let (listeners, inherited) = try!(listening_sockets(&listen));
+    for listener in &listeners {
+        try!(listener.set_nonblocking(true));
+        info!("Listening on {}", listener);
+    }
+    let signals = try!(Signals::catch(&[libc::SIGTERM, libc::SIGINT,
+                                        libc::SIGQUIT, libc::SIGHUP,
+                                        libc::SIGUSR1, libc::SIGUSR2,
+                                        libc::SIGCHLD]));
+
+    let pidfile_path = config.pidfile.clone();
+    let resumption = try!(Resumption::new());
+    let mut current = Arc::new(try!(Generation::build(config, &listeners,
+                                                      &resumption)));
+    let _pidfile = match pidfile_path {
+        Some(ref path) => Some(try!(Pidfile::write(path))),
+        None => None
+    };
+    if inherited {
+        upgrade::take_over();
+    }
+
+    let mut notifier = Notifier::from_env();
+    let status = format!("Serving on {}", listeners.iter()
+                         .map(|listener| format!("{}", listener))
+                         .collect::<Vec<_>>()
+                         .join(", "));
+    notifier.ready(&status);
+
+    let mut polled: Vec<RawFd> = listeners.iter()
+        .map(|listener| listener.as_raw_fd())
+        .collect();
+    polled.push(signals.fd());
+
+    let active = Active::new();
+    let mut upgrading: Option<Child> = None;
+
+    loop {
+        let ready = try!(signals::readable(&polled,
+                                           notifier.watchdog_timeout()));
+        notifier.feed_watchdog();
+
+        if ready.contains(&signals.fd()) {
+            let caught = signals.caught();
+            if caught.contains(&libc::SIGQUIT) {
+                info!("Caught SIGQUIT, stopping now");
+                return Ok(Shutdown::Forced);
+            }
+            if caught.contains(&libc::SIGTERM) ||
+                caught.contains(&libc::SIGINT) {
+                    break;
+                }
+            if caught.contains(&libc::SIGHUP) {
+                notifier.reloading();
+                match reconfigure(reload(), &listen, &listeners,
+                                  &resumption) {
+                    Some(next) => {
+                        current = Arc::new(next);
+                        notifier.ready(&status);
+                    },
+                    None => notifier.ready(&format!(
+                        "{}; the new configuration failed, see the log",
+                        status
+                    ))
+                }
+            }
+            if caught.contains(&libc::SIGUSR1) {
+                current.log_cache_stats();
+            }
+            if caught.contains(&libc::SIGCHLD) {
+                upgrading = upgrading.and_then(check_upgrade);
+            }
+            if caught.contains(&libc::SIGUSR2) {
+                if upgrading.is_some() {
+                    warn!("Already waiting on a new server to take over");
+                }
+                else {
+                    upgrading = start_upgrade(&listeners);
+                }
+            }
+        }
+
+        for (i, listener) in listeners.iter().enumerate() {
+            if !ready.contains(&listener.as_raw_fd()) {
+                continue;
+            }
+
+            let accepted = listener.accept().and_then(|stream| {
+                match current.tls[i] {
+                    Some(ref config) => tls::accept(stream, config),
+                    None => Ok(stream)
+                }
+            });
+
+            match accepted {
+                Ok(stream) => {
+                    let generation = current.clone();
+                    let serving = Active::serving(&active);
+                    let spawned = thread::Builder::new()
+                        .spawn(move || {
+                            let _serving = serving;
+                            connection::serve(stream, &generation.sites,
+                                              &generation.timeouts,
+                                              &generation.limits)
+                        });
+
+                    if let Err(e) = spawned {
+                        warn!("Couldn't start a thread for a connection: {}",
+                              e);
+                    }
+                },
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => (),
+                Err(e) => {
+                    warn!("Failed connection: {}", e);
+                }
+            };
+        }
+    }
+
+    // Stop taking connections, and let those in flight finish
+    drop(listeners);
+    connection::drain();
+    let grace = current.timeouts.shutdown;
+    info!("Shutting down; waiting up to {} seconds for requests to finish",
+          grace);
+
+    let deadline = Instant::now() + Duration::from_secs(grace);
+    let mut remaining = active.wait(Duration::from_secs(0));
+    // A new server taking over isn't the service stopping
+    let message = format!("Finishing {} connections", remaining);
+    if upgrading.is_some() {
+        notifier.notify(&format!("STATUS={}", message));
+    }
+    else {
+        notifier.stopping(&message);
+    }
+
+    while remaining > 0 {
+        notifier.feed_watchdog();
+        let now = Instant::now();
+        if deadline <= now {
+            break;
+        }
+
+        let slice = cmp::min(deadline - now, Duration::from_millis(100));
+        remaining = active.wait(slice);
+
+        if signals.caught().contains(&libc::SIGQUIT) {
+            info!("Caught SIGQUIT, stopping now");
+            return Ok(Shutdown::Forced);
+        }
+    }
+
+    if remaining > 0 {
+        warn!("Cutting off {} connections still being served", remaining);
+        return Ok(Shutdown::Forced);
+    }
+
+    for backend in &current.backends {
+        backend.close();
+    }
+    Ok(Shutdown::Graceful)
+}
+
+/// The sockets to accept on, and whether they were handed down by the
+/// server this one replaces
+///
+/// Failing that, systemd may have passed some; otherwise they're bound as
+/// `configs` say.
+fn listening_sockets(configs: &[ListenConfig])
+                     -> Result<(Vec<Listener>, bool)> {
+    let inherited = try!(upgrade::inherited_fds());
+    if !inherited.is_empty() {
+        let mut listeners = Vec::with_capacity(configs.len());
+        for fd in inherited {
+            listeners.push(try!(Listener::from_fd(fd, configs)));
+        }
+
+        // Listeners added to the configuration since are bound afresh
+        for config in configs {
+            if !listeners.iter().any(|listener| listener.is_for(config)) {
+                listeners.push(try!(Listener::bind(config)));
+            }
+        }
+
+        return Ok((listeners, true));
+    }
+
+    let passed = try!(systemd::listen_fds());
+    if !passed.is_empty() {
+        let mut listeners = Vec::with_capacity(passed.len());
+        for (fd, name) in passed {
+            let listener = try!(Listener::from_fd(fd, configs));
+            info!("Took {} from systemd, as {:?}", listener, name);
+            listeners.push(listener);
+        }
+
+        // systemd decides where to listen
+        for config in configs {
+            if !listeners.iter().any(|listener| listener.is_for(config)) {
+                warn!("Not listening on {}, since systemd didn't pass it",
+                      config.address);
+            }
+        }
+
+        return Ok((listeners, false));
+    }
+
+    let mut listeners = Vec::with_capacity(configs.len());
+    for config in configs {
+        match Listener::bind(config) {
+            Ok(listener) => listeners.push(listener),
+            Err(e) => {
+                log!(LogLevel::Error, "Couldn't listen on {}: {}",
+                     config.address, e);
+                return Err(Error::Io(e));
+            }
+        }
+    }
+
+    Ok((listeners, false))
+}
+
+/// Starts a new server to take over `listeners`, if it can be
+fn start_upgrade(listeners: &[Listener]) -> Option<Child> {
+    match upgrade::spawn(listeners) {
+        Ok(child) => {
+            info!("Started a new server, process {}, to take over",
+                  child.id());
+            Some(child)
+        },
+        Err(e) => {
+            warn!("Couldn't start a new server: {}", e);
+            None
+        }
+    }
+}
+
+/// Checks on a new server that hasn't taken over yet, giving it back if it's
+/// still running
+fn check_upgrade(mut child: Child) -> Option<Child> {
+    match child.try_wait() {
+        Ok(None) => Some(child),
+        Ok(Some(status)) => {
+            warn!("The new server exited ({}) without taking over", status);
+            None
+        },
+        Err(e) => {
+            warn!("Lost track of the new server: {}", e);
+            None
+        }
+    }
+}
+
+/// Sets up a reloaded configuration, if there is one, logging why not if it
+/// can't be
+fn reconfigure(config: Option<Config>, listening: &[ListenConfig],
+               listeners: &[Listener], resumption: &Resumption)
+               -> Option<Generation> {
+    let config = match config {
+        Some(config) => config,
+        None => {
+            warn!("Keeping the old configuration");
+            return None;
+        }
+    };
+
+    // Certificates are read afresh anyway
+    let sockets = |configs: &[ListenConfig]| -> Vec<ListenConfig> {
+        configs.iter()
+            .map(|config| ListenConfig { tls: None, ..config.clone() })
+            .collect()
+    };
+    if sockets(&config.listeners) != sockets(listening) {
+        warn!("Listeners can't change without a restart; keeping them as \
+               they were");
+    }
 
+    match Generation::build(config, listeners, resumption) {
+        Ok(generation) => {
+            info!("Reloaded the configuration");
+            Some(generation)
+        },
+        Err(e) => {
+            warn!("Keeping the old configuration: {}", e);
+            None
+        }
+    }
+}
+
+/// Builds the router for one site, connecting to its FastCGI application
+///
+/// The FastCGI connection is added to `backends`, to be closed on shutdown,
+/// and the static mounts' handlers to `mounts`.
+fn site_router(site: &SiteConfig,
+               backends: &mut Vec<Arc<fcgi_driver::Connection>>,
+               mounts: &mut Vec<Arc<Statics>>)
+               -> Result<Router> {
     let mut router = Router::new();
 
-    let fcgi_conn = match fcgi_driver::Connection::establish("127.0.0.1:9000",
-                                                             &config) {
+    let fcgi_conn = match fcgi_driver::Connection::establish(site.fcgi.address,
+                                                             site) {
         Ok(c) => c,
         Err(Error::Io(e)) => {
             match e.kind() {
@@ -54,67 +470,196 @@ pub fn serve(mut config: Config) -> Result<()> {
         Err(e) => return Err(e)
     };
 
-    router.route(config.stat.public_prefix.clone(), String::from("GET"),
-                 Statics::new(config.clone()));
-    router.route_any(PathBuf::from("/"), fcgi_conn);
-
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                try!(stream.set_read_timeout(Some(Duration::new(5, 0))));
-                try!(stream.set_write_timeout(Some(Duration::new(5, 0))));
-
-                match make_request_pair(try!(stream.try_clone())) {
-                    Ok((req, res)) => router.serve(req, res),
-                    Err(Error::Parse(_)) =>
-                        try!(error_messages::error_400(Response::new(stream))),
-                    Err(e) => warn!("{:?}", e)
-                }
-            },
-            Err(e) => {
-                warn!("Failed connection: {}", e);
+    let fcgi_conn = Arc::new(fcgi_conn);
+    backends.push(fcgi_conn.clone());
+
+    for mount in &site.statics {
+        let prefix = mount.public_prefix.to_string_lossy();
+        let pattern = format!("{}/*path", prefix.trim_right_matches('/'));
+        let statics = Arc::new(
+            Statics::new(mount.clone(), site.header_rules.clone(),
+                         Some(fcgi_conn.clone()))
+        );
+        mounts.push(statics.clone());
+
+        if let Some(ref writable) = mount.writable {
+            let dav = Arc::new(Dav::new(statics.clone(), writable));
+
+            for method in dav::METHODS {
+                let dav = dav.clone();
+                try!(router.route(&pattern, String::from(*method),
+                                  move |req: Request, res: Response<Fresh>| {
+                                      dav.serve(req, res)
+                                  }));
             }
-        };
+        }
+
+        try!(router.route(&pattern, String::from("GET"),
+                          move |req: Request, res: Response<Fresh>| {
+                              statics.serve(req, res)
+                          }));
+    }
+    // Methods a mount at `/` doesn't handle itself fall through to here
+    try!(router.route_any("/*path",
+                          move |req: Request, res: Response<Fresh>| {
+                              fcgi_conn.serve(req, res)
+                          }));
+    let pages = Arc::new(try!(ErrorPages::load(&site.error_pages)));
+
+    router.wrap(log_request);
+    router.wrap(move |req: Request, mut res: Response<Fresh>, next: &Handler| {
+        res.error_context_mut().pages = pages.clone();
+        next.serve(req, res)
+    });
+    if !site.rewrites.is_empty() {
+        router.wrap(Rewrites::new(site.rewrites.clone()));
     }
 
-    Ok(())
+    let names: Vec<String> = site.server_names.iter()
+        .map(|name| name.to_string())
+        .collect();
+    debug!("Routes for {}:\n{:?}",
+           if names.is_empty() { String::from("the default site") }
+           else { names.join(", ") },
+           router);
+
+    Ok(router)
 }
 
-fn make_request_pair(stream: TcpStream) -> Result<(Request, Response<Fresh>)>
+/// Counts requests, for their IDs
+static NEXT_REQUEST_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Reads a request from `reader`, pairing it with its response on `stream`
+fn make_request_pair(reader: TimedStream, stream: &Stream,
+                     keep_alive: KeepAlive, timeouts: &Timeouts,
+                     limits: &Limits)
+                     -> Result<(Request, Response<Fresh>)>
 {
-    let peer_addr = try!(stream.peer_addr());
-    let local_port = try!(stream.local_addr()).port();
-    let response_inner = try!(stream.try_clone());
-    let request_inner = stream;
+    pair_request(stream, keep_alive, |id, keep_alive| {
+        let mut request = try!(read_request(reader, stream, id, keep_alive,
+                                            limits));
 
-    let response = Response::new(response_inner);
+        request.inner.rest.get_mut().start_body(timeouts.body_min_rate);
+        if !request.wants_keep_alive() {
+            request.keep_alive.end();
+        }
+        Ok(request)
+    })
+}
 
-    let request = Request {
-        inner: try!(InnerRequest::parse(request_inner)),
-        remote_addr: peer_addr,
-        local_port: local_port
+/// Pairs the request `read` makes, given its ID, with its response on
+/// `stream`
+///
+/// A request that can't be read is answered with the error page for why,
+/// and logged, before the error is returned.
+fn pair_request<F>(stream: &Stream, keep_alive: KeepAlive, read: F)
+                   -> Result<(Request, Response<Fresh>)>
+    where F: FnOnce(String, KeepAlive) -> Result<Request>
+{
+    let mut response = Response::new(try!(stream.try_clone()));
+    let id = format!("{:x}-{:x}", process::id(),
+                     NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed));
+    response.error_context_mut().request_id = id.clone();
+    response.keep_alive = keep_alive.clone();
+
+    let request = match read(id, keep_alive) {
+        Ok(request) => request,
+        Err(e) => {
+            response.keep_alive.end();
+            let _ = error_messages::fail(&e, response);
+            return Err(e);
+        }
     };
 
+    if request.method() == "HEAD" {
+        response.omit_body();
+    }
+
+    response.error_context_mut().json = request.headers().get("Accept")
+        .map_or(false, |accept| error_messages::wants_json(accept));
+
     Ok((request, response))
 }
 
+fn read_request(reader: TimedStream, stream: &Stream, id: String,
+                keep_alive: KeepAlive, limits: &Limits) -> Result<Request> {
+    let peer_addr = try!(stream.peer_addr());
+    let local_port = try!(stream.local_port());
+
+    Ok(Request {
+        inner: try!(InnerRequest::parse(reader, limits)),
+        remote_addr: peer_addr,
+        local_port: local_port,
+        secure: stream.is_secure(),
+        id: id,
+        params: Vec::new(),
+        keep_alive: keep_alive
+    })
+}
+
 /// Values which can handle requests
-pub trait Handler {
+///
+/// Connections are served on threads of their own, so handlers are shared
+/// between them.
+pub trait Handler: Send + Sync {
     fn serve(&self, req: Request, res: Response<Fresh>);
 }
 
-impl<F> Handler for F where F: Fn(Request, Response<Fresh>) {
+impl<F> Handler for F where F: Fn(Request, Response<Fresh>) + Send + Sync {
     fn serve(&self, req: Request, res: Response<Fresh>) {
         self(req, res)
     }
 }
 
+/// Values which wrap handlers, adding behaviour common to many routes
+///
+/// A middleware is given the request along with the `next` handler in line.
+/// It may answer the request itself instead of calling `next`, and can change
+/// the headers of whatever response goes out with `Response::before_headers`.
+pub trait Middleware: Send + Sync {
+    fn wrap(&self, req: Request, res: Response<Fresh>, next: &Handler);
+}
+
+impl<F> Middleware for F
+    where F: Fn(Request, Response<Fresh>, &Handler) + Send + Sync
+{
+    fn wrap(&self, req: Request, res: Response<Fresh>, next: &Handler) {
+        self(req, res, next)
+    }
+}
+
+/// Logs each request along with the status it was answered with
+fn log_request(req: Request, mut res: Response<Fresh>, next: &Handler) {
+    let method = String::from(req.method());
+    let mut uri = req.request_uri().to_owned();
+    if let Some(query) = req.query() {
+        uri.push("?");
+        uri.push(query);
+    }
+    let remote_addr = req.remote_addr;
+
+    let id = String::from(req.id());
+
+    res.before_headers(move |status, _: &mut Headers| {
+        info!("{} {} {:?} from {}: {}", id, method, uri, remote_addr, status);
+    });
+
+    next.serve(req, res)
+}
+
 /// An incoming request from the client
 #[derive(Debug)]
 pub struct Request {
-    inner: InnerRequest<TcpStream>,
+    inner: InnerRequest<TimedStream>,
     pub remote_addr: SocketAddr,
-    pub local_port: u16
+    pub local_port: u16,
+    /// Whether the request came over TLS
+    pub secure: bool,
+    /// Identifies the request in logs and error pages
+    id: String,
+    /// Parameters captured by the route's pattern
+    params: Vec<(String, Vec<u8>)>,
+    keep_alive: KeepAlive
 }
 
 /// Internal, generic version of a Request
@@ -125,71 +670,273 @@ pub struct Request {
 #[derive(Debug)]
 struct InnerRequest<R> {
     method: String,
+    /// The authority from an absolute-form request target
+    authority: Option<String>,
     path: Vec<u8>,
+    /// The query string, without its `?` and still percent-encoded
+    query: Option<Vec<u8>>,
+    /// The minor version of HTTP/1.x the client speaks, or 1 for HTTP/2
+    version: u8,
     headers: Headers,
+    /// Bytes of body still to be read, or `None` if it runs to the end of
+    /// the stream
+    body_left: Option<u64>,
+    /// Bytes more that may be read of a body that runs to the end of the
+    /// stream
+    body_limit: u64,
 
     rest: BufReader<R>
 }
 
 impl<R: Read> InnerRequest<R> {
-    fn parse(stream: R) -> Result<InnerRequest<R>> {
+    fn parse(stream: R, limits: &Limits) -> Result<InnerRequest<R>> {
         let mut reader = BufReader::new(stream);
         
-        let (consumed,
-             method,
+        let (method,
              path,
-             headers) = try!(parse_inner(&mut reader));
+             version,
+             headers) = try!(parse_inner(&mut reader, limits));
+
+        let (authority, target) = split_absolute_form(path);
+        let (path, query) = split_query(target.as_bytes());
 
-        reader.consume(consumed);
+        let body_left = try!(body_length(&headers));
+        if body_left > limits.body {
+            return Err(Error::TooLarge);
+        }
 
         Ok(InnerRequest {
             method: method,
-            path: try!(normalize_path(path.as_bytes())),
+            authority: authority,
+            path: try!(normalize_path(path)),
+            query: query.map(Vec::from),
+            version: version,
             headers: headers,
+            body_left: Some(body_left),
+            body_limit: limits.body,
             rest: reader
         })
     }
 }
 
-fn parse_inner<R: BufRead>(mut source: R) -> Result<(usize,
-                                                     String,
-                                                     String,
-                                                     Headers)>
+/// Reads the request line and headers, leaving `source` at the body
+///
+/// The head is read a line at a time, so however the client's writes were
+/// split up, nothing past it is taken from `source`. It can't grow past
+/// `limits` either.
+fn parse_inner<R: BufRead>(mut source: R, limits: &Limits)
+                           -> Result<(String, String, u8, Headers)>
 {
-    let mut headers = [httparse::EMPTY_HEADER; 100];
-    let mut last_buffer_len = 0;
+    let mut head = Vec::new();
+
+    // Stray line breaks before the request line are allowed
+    loop {
+        head.clear();
+        if !try!(read_line(&mut source, limits.request_line + 2, &mut head)) {
+            return Err(Error::UriTooLong);
+        }
+        if !is_blank(&head) {
+            break;
+        }
+    }
+
+    let request_line = head.len();
+    let mut fields = 0;
 
     loop {
-        let mut req = httparse::Request::new(&mut headers);
-        let buffer = try!(source.fill_buf());
+        // The blank line ending the headers comes on top of the limit
+        let used = (head.len() - request_line) as u64;
+        let start = head.len();
+
+        if !try!(read_line(&mut source, limits.header_bytes + 2 - used,
+                           &mut head)) {
+            return Err(Error::HeadersTooLarge);
+        }
+        if is_blank(&head[start ..]) {
+            break;
+        }
 
-        let buffer_len = buffer.len();
-        if buffer_len == last_buffer_len {
-            return Err(Error::RequestIncomplete);
+        fields += 1;
+        if fields > limits.headers {
+            return Err(Error::HeadersTooLarge);
         }
-        last_buffer_len = buffer_len;
+    }
+
+    let mut headers = vec![httparse::EMPTY_HEADER; fields as usize];
+    let mut req = httparse::Request::new(&mut headers);
+
+    if let httparse::Status::Partial = try!(req.parse(&head)) {
+        return Err(Error::RequestIncomplete);
+    }
+
+    let mut headers = Headers::new();
+    for header in req.headers.iter() {
+        headers.insert(header.name, Vec::from(header.value));
+    }
 
-        if let httparse::Status::Complete(bytes) = try!(req.parse(buffer)) {
-            let mut headers = Headers::new();
-            for header in req.headers {
-                headers.insert(header.name, Vec::from(header.value));
+    Ok((String::from(req.method.unwrap()),
+        String::from(req.path.unwrap()),
+        req.version.unwrap(),
+        headers))
+}
+
+/// Reads a line of up to `max` bytes, line break included, onto `buf`,
+/// saying whether it fit
+fn read_line<R: BufRead>(source: &mut R, max: u64, buf: &mut Vec<u8>)
+                         -> Result<bool> {
+    let start = buf.len();
+    try!(source.by_ref().take(max).read_until(b'\n', buf));
+
+    if buf.len() > start && buf.last() == Some(&b'\n') {
+        Ok(true)
+    }
+    else if (buf.len() - start) as u64 == max {
+        Ok(false)
+    }
+    else {
+        Err(Error::RequestIncomplete)
+    }
+}
+
+fn is_blank(line: &[u8]) -> bool {
+    line == b"\r\n" || line == b"\n"
+}
+
+/// How long a request's body is, going by its headers
+///
+/// Chunked bodies aren't decoded, so any `Transfer-Encoding` is refused
+/// rather than read to the end of the stream. So is a `Content-Length` that
+/// isn't a single number.
+fn body_length(headers: &Headers) -> Result<u64> {
+    if headers.get("Transfer-Encoding").is_some() {
+        return Err(Error::LengthRequired);
+    }
+
+    let len = match headers.get("Content-Length") {
+        Some(len) => trim_spaces(len),
+        None => return Ok(0)
+    };
+
+    if len.is_empty() || !len.iter().all(|b| b.is_ascii_digit()) {
+        return Err(Error::Parse(httparse::Error::HeaderValue));
+    }
+
+    str::from_utf8(len).ok()
+        .and_then(|len| len.parse().ok())
+        .ok_or(Error::Parse(httparse::Error::HeaderValue))
+}
+
+#[test]
+fn bodies_are_measured_by_content_length() {
+    let mut headers = Headers::new();
+    assert_eq!(body_length(&headers).unwrap(), 0);
+
+    headers.insert("Content-Length", Vec::from(&b" 12"[..]));
+    assert_eq!(body_length(&headers).unwrap(), 12);
+}
+
+#[test]
+fn bad_content_lengths_are_400s() {
+    for len in &[&b"abc"[..], b"5, 5", b"", b"+5", b"-1",
+                 b"99999999999999999999"] {
+        let mut headers = Headers::new();
+        headers.insert("Content-Length", Vec::from(*len));
+        assert_eq!(body_length(&headers).unwrap_err().status(), 400);
+    }
+
+    let mut headers = Headers::new();
+    headers.insert("Content-Length", Vec::from(&b"5"[..]));
+    headers.insert("Content-Length", Vec::from(&b"5"[..]));
+    assert_eq!(body_length(&headers).unwrap_err().status(), 400);
+}
+
+#[test]
+fn chunked_bodies_are_refused() {
+    let mut headers = Headers::new();
+    headers.insert("Transfer-Encoding", Vec::from(&b"chunked"[..]));
+    assert_eq!(body_length(&headers).unwrap_err().status(), 411);
+
+    headers.insert("Content-Length", Vec::from(&b"12"[..]));
+    assert_eq!(body_length(&headers).unwrap_err().status(), 411);
+
+    let request: &[u8] = b"POST /a HTTP/1.1\r\n\
+                           Transfer-Encoding: chunked\r\n\r\n\
+                           4\r\nbody\r\n0\r\n\r\n";
+    match InnerRequest::parse(request, &Default::default()) {
+        Err(e) => assert_eq!(e.status(), 411),
+        Ok(_) => panic!("A chunked body was taken to run to the end")
+    }
+}
+
+/// Splits the authority off an absolute-form request target, like
+/// `http://example.com/a`, leaving the path
+fn split_absolute_form(target: String) -> (Option<String>, String) {
+    for scheme in &["http://", "https://"] {
+        let n = scheme.len();
+        if target.len() < n ||
+            !target.as_bytes()[.. n].eq_ignore_ascii_case(scheme.as_bytes()) {
+                continue;
             }
 
-            return Ok(
-                (bytes,
-                 String::from(req.method.unwrap()),
-                 String::from(req.path.unwrap()),
-                 headers)
-            );
+        let rest = &target[n ..];
+        let end = rest.find(|c| c == '/' || c == '?').unwrap_or(rest.len());
+        let path = if rest[end ..].starts_with('/') {
+            String::from(&rest[end ..])
         }
+        else {
+            format!("/{}", &rest[end ..])
+        };
+
+        return (Some(String::from(&rest[.. end])), path);
     }
+
+    (None, target)
+}
+
+/// The host named by an authority or `Host` header, lowercase and without any
+/// port or trailing `.`
+fn host_of(authority: &[u8]) -> String {
+    let after_user = authority.iter().rposition(|&b| b == b'@')
+        .map_or(authority, |at| &authority[at + 1 ..]);
+
+    let host = if after_user.starts_with(b"[") {
+        let end = after_user.iter().position(|&b| b == b']')
+            .map_or(after_user.len(), |close| close + 1);
+        &after_user[.. end]
+    }
+    else {
+        let end = after_user.iter().position(|&b| b == b':')
+            .unwrap_or(after_user.len());
+        &after_user[.. end]
+    };
+
+    let host = String::from_utf8_lossy(host).to_ascii_lowercase();
+    String::from(host.trim_right_matches('.'))
+}
+
+#[test]
+fn absolute_form_targets_are_split() {
+    assert_eq!(split_absolute_form(String::from("HTTP://Example.com:80/a?b")),
+               (Some(String::from("Example.com:80")), String::from("/a?b")));
+    assert_eq!(split_absolute_form(String::from("http://example.com?b")),
+               (Some(String::from("example.com")), String::from("/?b")));
+    assert_eq!(split_absolute_form(String::from("/http://x")),
+               (None, String::from("/http://x")));
+}
+
+#[test]
+fn hosts_lose_ports_and_case() {
+    assert_eq!(host_of(b"Example.COM.:8000"), "example.com");
+    assert_eq!(host_of(b"user@example.com"), "example.com");
+    assert_eq!(host_of(b"[::1]:8000"), "[::1]");
 }
 
 #[test]
 fn parse_request_basic() {
     let request: &[u8] = b"GET / HTTP/1.1\r\nHost: google.com\r\nUser-Agent: curl/7.47.1\r\nAccept: */*\r\n\r\n";
 
-    let (_, method, path, _) = parse_inner(request).unwrap();
+    let (method, path, _, _) = parse_inner(request, &Default::default())
+        .unwrap();
 
     assert_eq!(method, "GET");
     assert_eq!(path, "/");
@@ -199,7 +946,8 @@ fn parse_request_basic() {
 fn parse_request_does_not_percent_decode() {
     let request: &[u8] = b"GET /%20 HTTP/1.1\r\n\r\n";
 
-    let (_, _, path, _) = parse_inner(request).unwrap();
+    let (_, path, _, _) = parse_inner(request, &Default::default())
+        .unwrap();
 
     assert_eq!(path, "/%20");
 }
@@ -208,16 +956,64 @@ fn parse_request_does_not_percent_decode() {
 fn parse_request_does_not_fail_on_illegal_percent_decoding() {
     let request: &[u8] = b"GET /bogus%zz HTTP/1.1\r\n\r\n";
 
-    let (_, _, path, _) = parse_inner(request).unwrap();
+    let (_, path, _, _) = parse_inner(request, &Default::default())
+        .unwrap();
 
     assert_eq!(path, "/bogus%zz");
 }
 
+#[test]
+fn queries_are_split_off_before_decoding() {
+    let request: &[u8] = b"GET /a%3Fb?x=%20&y HTTP/1.1\r\n\r\n";
+
+    let parsed = InnerRequest::parse(request, &Default::default()).unwrap();
+
+    assert_eq!(parsed.path, b"/a?b");
+    assert_eq!(parsed.query, Some(Vec::from(&b"x=%20&y"[..])));
+}
+
+#[test]
+fn parse_request_stops_at_the_body() {
+    let mut request: &[u8] = b"\r\nPOST /a HTTP/1.0\r\n\
+                               Content-Length: 4\r\n\r\nbody";
+
+    let (method, _, version, headers) =
+        parse_inner(&mut request, &Default::default()).unwrap();
+
+    assert_eq!(method, "POST");
+    assert_eq!(version, 0);
+    assert_eq!(headers.get("Content-Length"), Some(&Vec::from(&b"4"[..])));
+    assert_eq!(request, b"body");
+}
+
 #[test]
 fn parse_request_fails_on_bad_bytes() {
     let request: &[u8] = b"GET /bogon\xff HTTP/1.1\r\n";
 
-    assert!(parse_inner(request).is_err());
+    assert!(parse_inner(request, &Default::default()).is_err());
+}
+
+#[test]
+fn parse_request_enforces_limits() {
+    let limits = Limits {
+        request_line: 16,
+        header_bytes: 32,
+        headers: 2,
+        body: 0
+    };
+    let outcome = |request: &[u8]| match parse_inner(request, &limits) {
+        Ok(_) => 200,
+        Err(e) => e.status()
+    };
+
+    assert_eq!(outcome(b"GET /a HTTP/1.1\r\nA: 1\r\nB: 2\r\n\r\n"), 200);
+    assert_eq!(outcome(b"GET /abcdefghij HTTP/1.1\r\n\r\n"), 414);
+    assert_eq!(outcome(b"GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n"),
+               431);
+    assert_eq!(outcome(b"GET / HTTP/1.1\r\nA: 0123456789012345678901234\
+                         567890\r\n\r\n"),
+               431);
+    assert_eq!(outcome(b"GET / HTTP/1.1\r\nA: 1"), 400);
 }
 
 impl Request {
@@ -225,6 +1021,11 @@ impl Request {
         OsStr::from_bytes(self.inner.path.as_slice())
     }
 
+    /// The query string, without its `?`, as the client sent it
+    pub fn query(&self) -> Option<&OsStr> {
+        self.inner.query.as_ref().map(|query| OsStr::from_bytes(query))
+    }
+
     #[inline]
     pub fn method(&self) -> &str {
         &self.inner.method
@@ -234,24 +1035,128 @@ impl Request {
     pub fn headers(&self) -> &Headers {
         &self.inner.headers
     }
+
+    /// A name for this request, unique while the server runs
+    #[inline]
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// The host the request is for, lowercase and without any port
+    ///
+    /// An absolute-form request target, like `GET http://example.com/`, takes
+    /// precedence over the `Host` header.
+    pub fn host(&self) -> Option<String> {
+        match self.inner.authority {
+            Some(ref authority) => Some(host_of(authority.as_bytes())),
+            None => self.headers().get("Host").map(|host| host_of(host))
+        }
+    }
+
+    /// Whether the client would send another request on this connection
+    ///
+    /// Only HTTP/1.1 connections are kept alive, and only when the body has a
+    /// length to find the next request after.
+    fn wants_keep_alive(&self) -> bool {
+        let close = self.headers().get("Connection").map_or(false, |value| {
+            value.split(|&b| b == b',')
+                .any(|token| trim_spaces(token).eq_ignore_ascii_case(b"close"))
+        });
+
+        self.inner.version >= 1 && !close && self.inner.body_left.is_some()
+    }
+
+    /// The value of the `:name` or `*name` in the route's pattern
+    #[allow(dead_code)] // The built-in handlers look at the whole path
+    pub fn param(&self, name: &str) -> Option<&OsStr> {
+        self.params.iter()
+            .find(|&&(ref param, _)| param == name)
+            .map(|&(_, ref value)| OsStr::from_bytes(value))
+    }
+}
+
+fn trim_spaces(bytes: &[u8]) -> &[u8] {
+    let start = bytes.iter().position(|&b| b != b' ' && b != b'\t')
+        .unwrap_or(bytes.len());
+    let end = bytes.iter().rposition(|&b| b != b' ' && b != b'\t')
+        .map_or(start, |i| i + 1);
+    &bytes[start .. end]
 }
 
+/// Reading a request gives its body, and stops at the end of it
 impl Read for Request {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        self.inner.rest.read(buf)
+        let n = {
+            let available = try!(self.fill_buf());
+            let n = cmp::min(available.len(), buf.len());
+            buf[.. n].copy_from_slice(&available[.. n]);
+            n
+        };
+
+        self.consume(n);
+        Ok(n)
     }
 }
 
 impl BufRead for Request {
     fn fill_buf(&mut self) -> io::Result<&[u8]> {
-        self.inner.rest.fill_buf()
+        let left = self.inner.body_left;
+        if left == Some(0) {
+            return Ok(&[]);
+        }
+
+        let limit = self.inner.body_limit;
+        let buffered = try!(self.inner.rest.fill_buf());
+
+        match left {
+            Some(left) if buffered.len() as u64 > left =>
+                Ok(&buffered[.. left as usize]),
+            Some(_) => Ok(buffered),
+            None if limit == 0 && !buffered.is_empty() =>
+                Err(io::Error::new(ErrorKind::Other, Error::TooLarge)),
+            None if buffered.len() as u64 > limit =>
+                Ok(&buffered[.. limit as usize]),
+            None => Ok(buffered)
+        }
     }
 
     fn consume(&mut self, amt: usize) {
+        match self.inner.body_left {
+            Some(ref mut left) => *left -= cmp::min(*left, amt as u64),
+            None => self.inner.body_limit -= cmp::min(self.inner.body_limit,
+                                                      amt as u64)
+        }
         self.inner.rest.consume(amt)
     }
 }
 
+/// Most unread body bytes that get read and thrown away so the connection
+/// can take another request; with more left, it's closed instead
+const MAX_DRAIN: u64 = 64 * 1024;
+
+impl Drop for Request {
+    fn drop(&mut self) {
+        if !self.keep_alive.reusable() {
+            return;
+        }
+
+        match self.inner.body_left {
+            Some(left) if left <= MAX_DRAIN => (),
+            _ => return self.keep_alive.end()
+        }
+        if io::copy(self, &mut io::sink()).is_err() {
+            return self.keep_alive.end();
+        }
+
+        // Whatever's buffered now is the start of the next request
+        self.inner.rest.get_mut().stop();
+        match self.inner.rest.fill_buf() {
+            Ok(next) => self.keep_alive.keep_leftover(next),
+            Err(_) => self.keep_alive.end()
+        }
+    }
+}
+
 /// The response being constructed by a `Handler`
 ///
 /// The type parameter represents where in the cycle this response is. When
@@ -262,11 +1167,28 @@ impl BufRead for Request {
 /// When `Status = Streaming`, headers have already been sent, and use of the
 /// `io::Write` interface will buffer chunks (as in Transfer-Encoding: Chunked)
 /// to be sent to the client as they become available.
+///
+/// Responses to `HEAD` requests never send a body, whatever the handler does.
+/// A streaming `HEAD` response holds its headers back and counts what's
+/// written, so the client still gets an accurate Content-Length.
 pub struct Response<Status> {
-    writer: BufWriter<TcpStream>,
+    writer: BufWriter<Stream>,
     buffer: Vec<u8>,
     status: ResponseStatus,
     headers: Headers,
+    /// Whether this is a response to `HEAD`
+    omit_body: bool,
+    /// Body bytes swallowed by a streaming `HEAD` response
+    omitted_len: u64,
+    /// Whether headers are still to be sent when a streaming response ends
+    headers_deferred: bool,
+    /// Called on the status and headers just before they're sent
+    header_hooks: Vec<Box<FnMut(u16, &mut Headers) + Send>>,
+    /// How to answer, should it come to an error page
+    error_context: ErrorContext,
+    keep_alive: KeepAlive,
+    /// Whether the status line and headers have gone out
+    headers_sent: bool,
     _status: PhantomData<Status>
 }
 
@@ -282,6 +1204,45 @@ struct ResponseStatus {
     reason: String
 }
 
+/// The standard reason phrase for a status
+pub fn reason(code: u16) -> &'static str {
+    match code {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        207 => "Multi-Status",
+        301 => "Moved Permanently",
+        302 => "Found",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        410 => "Gone",
+        411 => "Length Required",
+        412 => "Precondition Failed",
+        413 => "Payload Too Large",
+        414 => "URI Too Long",
+        415 => "Unsupported Media Type",
+        416 => "Range Not Satisfiable",
+        423 => "Locked",
+        424 => "Failed Dependency",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "Unknown"
+    }
+}
+
 /// A map of HTTP headers
 ///
 /// This is just a newtype wrapper around a `HashMap<String, String>`, but the
@@ -353,6 +1314,15 @@ impl Headers {
     pub fn get(&self, key: &str) -> Option<&Vec<u8>> {
         self.map.get(&normalize_header_name(key))
     }
+
+    /// Sets a header, replacing any value it already had
+    pub fn set(&mut self, key: &str, value: Vec<u8>) {
+        self.map.insert(normalize_header_name(key), value);
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<Vec<u8>> {
+        self.map.remove(&normalize_header_name(key))
+    }
 }
 
 impl IntoIterator for Headers {
@@ -382,13 +1352,54 @@ impl<'a> IntoIterator for &'a mut Headers {
     }
 }    
 
-/*
 impl<Status> Response<Status> {
+    fn write_headers(&mut self) -> io::Result<()> {
+        let hooks = mem::replace(&mut self.header_hooks, Vec::new());
+        for mut hook in hooks.into_iter().rev() {
+            hook(self.status.code, &mut self.headers);
+        }
 
+        // HTTP/2 has frames for the status and headers, and its connections
+        // stay open whatever the response
+        if let Stream::Http2(ref stream) = *self.writer.get_ref() {
+            self.headers_sent = true;
+            return stream.send_headers(self.status.code, &self.headers);
+        }
+
+        // Without a length, the body ends when the connection does
+        let code = self.status.code;
+        let bodiless = self.omit_body || code < 200 || code == 204 ||
+            code == 304;
+        if !bodiless && self.headers.get("Content-Length").is_none() &&
+            self.headers.get("Transfer-Encoding").is_none() {
+                self.keep_alive.end();
+            }
+        if connection::draining() {
+            self.keep_alive.end();
+        }
+        if !self.keep_alive.reusable() {
+            self.headers.set("Connection", Vec::from(&b"close"[..]));
+        }
+        self.headers_sent = true;
+
+        // Status line
+        try!(write!(self.writer, "HTTP/1.1 {} {}\r\n",
+                    self.status.code, self.status.reason));
+
+        for (header, content) in &self.headers {
+            try!(write!(self.writer, "{}: ", header));
+            try!(self.writer.write_all(content));
+            try!(self.writer.write_all(b"\r\n"));
+        }
+
+        try!(self.writer.write_all(b"\r\n"));
+
+        Ok(())
+    }
 }
-*/
+
 impl Response<Fresh> {
-    pub fn new(stream: TcpStream) -> Self {
+    pub fn new(stream: Stream) -> Self {
         Response {
             writer: BufWriter::new(stream),
             buffer: Vec::new(),
@@ -397,13 +1408,92 @@ impl Response<Fresh> {
                 reason: String::from("Ok")
             },
             headers: Headers::new(),
+            omit_body: false,
+            omitted_len: 0,
+            headers_deferred: false,
+            header_hooks: Vec::new(),
+            error_context: Default::default(),
+            keep_alive: KeepAlive::new(false),
+            headers_sent: false,
             _status: PhantomData
         }
     }
 
+    /// Makes this a response to `HEAD`: headers only
+    pub fn omit_body(&mut self) {
+        self.omit_body = true;
+    }
+
     pub fn of_stream<R: Read>(mut self, mut stream: R) -> io::Result<()> {
         try!(self.write_headers());
-        io::copy(&mut stream, &mut self.writer).map(|_| ())
+        if self.omit_body {
+            return Ok(());
+        }
+
+        let copied = io::copy(&mut stream, &mut self.writer);
+        if copied.is_err() {
+            self.keep_alive.end();
+        }
+        copied.map(|_| ())
+    }
+
+    /// Sends the headers, then the first `len` bytes of `file` as the body.
+    ///
+    /// Where the platform allows it the body goes out with `sendfile(2)`,
+    /// never being copied into userspace. Whatever the kernel won't send for
+    /// us is copied the ordinary way, as in `of_stream`.
+    pub fn of_file(mut self, file: File, len: u64) -> io::Result<()> {
+        try!(self.write_headers());
+        if self.omit_body {
+            return Ok(());
+        }
+
+        // A file that shrank leaves the client waiting for the rest
+        match self.send_body(file, len) {
+            Ok(sent) if sent == len => Ok(()),
+            result => {
+                self.keep_alive.end();
+                result.map(|_| ())
+            }
+        }
+    }
+
+    /// Sends up to `len` bytes of `file`, returning how many there were
+    fn send_body(&mut self, mut file: File, len: u64) -> io::Result<u64> {
+        try!(self.writer.flush());
+
+        // TLS has to encrypt the file on its way through, and HTTP/2 to
+        // frame it
+        let sent = if !self.writer.get_ref().is_raw() {
+            0
+        }
+        else {
+            try!(sendfile::send_file(self.writer.get_ref(), &file, len))
+        };
+        if sent == len {
+            return Ok(sent);
+        }
+
+        try!(file.seek(SeekFrom::Start(sent)));
+        let copied = try!(io::copy(&mut file.take(len - sent),
+                                   &mut self.writer));
+        Ok(sent + copied)
+    }
+
+    /// Arranges for `hook` to see the status and headers just before they're
+    /// sent, whichever way the response goes out
+    ///
+    /// Hooks run in the reverse of the order they were added, so the
+    /// outermost middleware has the last word.
+    pub fn before_headers<F>(&mut self, hook: F)
+        where F: FnMut(u16, &mut Headers) + Send + 'static
+    {
+        self.header_hooks.push(Box::new(hook));
+    }
+
+    #[inline]
+    pub fn error_context_mut(&mut self) -> &mut ErrorContext {
+        &mut self.error_context
     }
 
     #[inline]
@@ -419,30 +1509,25 @@ impl Response<Fresh> {
     }
 
     pub fn start(mut self) -> io::Result<Response<Streaming>> {
-        self.headers.insert("Transfer-Encoding",
-                            Vec::from(&b"Chunked"[..]));
-                            
-        try!(self.write_headers());
-        self.buffer = Vec::with_capacity(4096);
-
-        // Transmute to ourselves with a different phantom type
-        Ok(unsafe { mem::transmute(self) })
-    }
-
-    fn write_headers(&mut self) -> io::Result<()> {
-        // Status line
-        try!(write!(self.writer, "HTTP/1.1 {} {}\r\n",
-                    self.status.code, self.status.reason));
-
-        for (header, content) in &self.headers {
-            try!(write!(self.writer, "{}: ", header));
-            try!(self.writer.write_all(content));
-            try!(self.writer.write_all(b"\r\n"));
+        if self.omit_body {
+            // Wait until we know how long the body would have been
+            self.headers_deferred = true;
+        }
+        // HTTP/2's DATA frames mark out the body already
+        else if self.writer.get_ref().is_http2() {
+            try!(self.write_headers());
+            self.buffer = Vec::with_capacity(4096);
         }
+        else {
+            self.headers.insert("Transfer-Encoding",
+                                Vec::from(&b"Chunked"[..]));
 
-        try!(self.writer.write_all(b"\r\n"));
+            try!(self.write_headers());
+            self.buffer = Vec::with_capacity(4096);
+        }
 
-        Ok(())
+        // Transmute to ourselves with a different phantom type
+        Ok(unsafe { mem::transmute(self) })
     }
 }
 
@@ -460,9 +1545,14 @@ impl Response<Streaming> {
     }
 }
 
-fn write_chunk_raw<W: Write>(sink: &mut W, chunk_content: &[u8])
-                             -> io::Result<()>
+fn write_chunk_raw(sink: &mut BufWriter<Stream>, chunk_content: &[u8])
+                   -> io::Result<()>
 {
+    if sink.get_ref().is_http2() {
+        try!(sink.write_all(chunk_content));
+        return sink.flush();
+    }
+
     try!(write!(sink, "{:x}\r\n", chunk_content.len()));
     try!(sink.write_all(chunk_content));
     try!(sink.write_all(b"\r\n"));
@@ -475,6 +1565,11 @@ impl Write for Response<Streaming> {
             return Ok(0);
         }
 
+        if self.omit_body {
+            self.omitted_len += buf.len() as u64;
+            return Ok(buf.len());
+        }
+
         let buffer_cap_remaining = self.buffer.capacity() - self.buffer.len();
 
         if buf.len() > buffer_cap_remaining {
@@ -508,10 +1603,35 @@ impl Write for Response<Streaming> {
 
 impl<T> Drop for Response<T> {
     fn drop(&mut self) {
+        if self.headers_deferred {
+            if self.headers.get("Content-Length").is_none() &&
+                self.omitted_len > 0 {
+                    let len = format!("{}", self.omitted_len).into_bytes();
+                    self.headers.set("Content-Length", len);
+                }
+            let _ = self.write_headers();
+        }
         // A non-trivial buffer implies the Response is streaming
-        if self.buffer.capacity() > 0 {
-            let _ = write_chunk_raw(&mut self.writer, self.buffer.as_slice());
-            let _ = self.writer.write_all(b"0\r\n"); // last chunk
+        else if self.buffer.capacity() > 0 {
+            if !self.buffer.is_empty() {
+                let _ = write_chunk_raw(&mut self.writer,
+                                        self.buffer.as_slice());
+            }
+            if !self.writer.get_ref().is_http2() {
+                let _ = self.writer.write_all(b"0\r\n\r\n"); // last chunk
+            }
+        }
+        // The client's still waiting for an answer it won't get
+        else if !self.headers_sent {
+            self.keep_alive.end();
+        }
+
+        // An HTTP/2 response ends with its stream, however it went
+        if self.writer.get_ref().is_http2() {
+            let _ = self.writer.flush();
+            if let Stream::Http2(ref stream) = *self.writer.get_ref() {
+                stream.finish(self.headers_sent);
+            }
         }
     }
 }
@@ -520,12 +1640,19 @@ impl<T> Drop for Response<T> {
 pub fn mime_as_string(mime: Mime) -> String {
     let mut s = String::new();
 
-    let Mime(toplevel, sublevel, _) = mime;
+    let Mime(toplevel, sublevel, params) = mime;
 
     s.push_str(toplevel.as_str());
     s.push_str("/");
     s.push_str(sublevel.as_str());
-    
+
+    for (attr, value) in params {
+        s.push_str("; ");
+        s.push_str(attr.as_str());
+        s.push_str("=");
+        s.push_str(value.as_str());
+    }
+
     s
 }
 
@@ -544,74 +1671,204 @@ fn mime_as_string_javascript() {
     assert_eq!(mime_as_string(mime!(Text/Javascript)), "text/javascript");
 }
 
+#[test]
+fn mime_as_string_keeps_parameters() {
+    assert_eq!(mime_as_string(mime!(Text/Html; Charset=Utf8)),
+               "text/html; charset=utf-8");
+}
+
 pub mod error_messages {
-    use super::Response;
-    use super::Fresh;
+    use config::ErrorPage;
+    use errors::Error;
+    use super::{Response, Fresh, reason};
 
-    use std::io;
+    use log::LogLevel;
 
-    pub fn error_500(mut res: Response<Fresh>) -> io::Result<()> {
-        res.set_status(500, String::from("Internal Error"));
-        {
-            let headers = res.headers_mut();
-            headers.insert("Content-Type", Vec::from(&b"text/html"[..]));
-            headers.insert("Content-Length", Vec::from(&b"192"[..]));
-        }
+    use std::collections::HashMap;
+    use std::fs::File;
+    use std::io::{self, Read};
+    use std::sync::Arc;
 
-        res.of_stream(ERROR_500)
+    /// A site's own error pages, by status
+    #[derive(Debug, Default)]
+    pub struct ErrorPages {
+        templates: HashMap<u16, String>
     }
 
-    const ERROR_500: &'static [u8] = b"<!doctype html><html><head><title>Error</title></head><body><h1>Internal Error</h1><p>Something went wrong on my side.</p><p>There's nothing you can do; maybe come back later.</p></body></html>";
+    impl ErrorPages {
+        /// Reads any pages kept in files
+        pub fn load(pages: &HashMap<u16, ErrorPage>) -> io::Result<ErrorPages> {
+            let mut templates = HashMap::with_capacity(pages.len());
+
+            for (&code, page) in pages {
+                let template = match page {
+                    &ErrorPage::Template(ref template) => template.clone(),
+                    &ErrorPage::File(ref path) => {
+                        let mut template = String::new();
+                        try!(try!(File::open(path))
+                             .read_to_string(&mut template));
+                        template
+                    }
+                };
+
+                templates.insert(code, template);
+            }
 
-    pub fn error_405(mut res: Response<Fresh>) -> io::Result<()> {
-        res.set_status(405, String::from("Method not allowed"));
-        {
-            let headers = res.headers_mut();
-            headers.insert("Content-Type", Vec::from(&b"text/html"[..]));
-            headers.insert("Content-Length", Vec::from(&b"181"[..]));
+            Ok(ErrorPages { templates: templates })
         }
+    }
+
+    /// What an error response needs to know about its request and site
+    #[derive(Debug, Clone, Default)]
+    pub struct ErrorContext {
+        pub pages: Arc<ErrorPages>,
+        /// Whether the client would rather have `application/problem+json`
+        pub json: bool,
+        pub request_id: String
+    }
 
-        res.of_stream(ERROR_405)
+    /// Whether an `Accept` header asks for JSON rather than HTML
+    pub fn wants_json(accept: &[u8]) -> bool {
+        let accept = String::from_utf8_lossy(accept).to_lowercase();
+        accept.contains("json") && !accept.contains("text/html")
     }
 
-    const ERROR_405: &'static [u8] = b"<!doctype html><html><head><title>Error</title></head><body><h1>Method Not Allowed</h1><p>This server only understands <code>GET</code> requests. Sorry about that.</p></body></html>";
+    /// Sends the error response for `code`
+    ///
+    /// Clients that asked for JSON get an RFC 7807 problem document. Everyone
+    /// else gets the site's own page for the status, if it has one, or the
+    /// built-in page.
+    pub fn error_page(code: u16, mut res: Response<Fresh>) -> io::Result<()> {
+        let (content_type, body) = render(code, &res.error_context);
 
-    pub fn error_404(mut res: Response<Fresh>) -> io::Result<()> {
-        res.set_status(404, String::from("Not Found"));
+        res.set_status(code, String::from(reason(code)));
         {
             let headers = res.headers_mut();
-            headers.insert("Content-Type", Vec::from(&b"text/html"[..]));
-            headers.insert("Content-Length", Vec::from(&b"132"[..]));
+            headers.set("Content-Type", Vec::from(content_type.as_bytes()));
+            headers.set("Content-Length",
+                        format!("{}", body.len()).into_bytes());
         }
 
-        res.of_stream(ERROR_404)
+        res.of_stream(&body[..])
     }
 
-    const ERROR_404: &'static [u8] = b"<!doctype html><html><head><title>Error</title></head><body><h1>Not Found</h1><p>I couldn't find that file. Sorry.</p></body></html>";
+    fn render(code: u16, context: &ErrorContext) -> (&'static str, Vec<u8>) {
+        if context.json {
+            let problem = format!("{{\"type\":\"about:blank\",\"title\":\"{}\",\
+                                   \"status\":{},\"request_id\":\"{}\"}}",
+                                  reason(code), code, context.request_id);
+            return ("application/problem+json", problem.into_bytes());
+        }
 
-    pub fn error_403(mut res: Response<Fresh>) -> io::Result<()> {
-        res.set_status(403, String::from("Forbidden"));
-        {
-            let headers = res.headers_mut();
-            headers.insert("Content-type", Vec::from(&b"text/html"[..]));
-            headers.insert("Content-Length", Vec::from(&b"150"[..]));
+        let page = match context.pages.templates.get(&code) {
+            Some(template) => template
+                .replace("{status}", &format!("{}", code))
+                .replace("{reason}", reason(code))
+                .replace("{request_id}", &context.request_id),
+            None => format!("<!doctype html><html><head><title>Error</title>\
+                             </head><body><h1>{}</h1>{}</body></html>",
+                            reason(code), explanation(code))
+        };
+
+        ("text/html; charset=utf-8", page.into_bytes())
+    }
+
+    /// The built-in pages' words for some statuses
+    fn explanation(code: u16) -> &'static str {
+        match code {
+            400 => "<p>Your request had some kind of bad syntax. Are you \
+                    using netcat?</p>",
+            403 => "<p>You don't have permission to view that file. \
+                    Sorry.</p>",
+            404 => "<p>I couldn't find that file. Sorry.</p>",
+            405 => "<p>That method doesn't work here. The <code>Allow</code> \
+                    header lists the ones that do.</p>",
+            500 => "<p>Something went wrong on my side.</p><p>There's \
+                    nothing you can do; maybe come back later.</p>",
+            _ => ""
         }
+    }
 
-        res.of_stream(ERROR_403)
+    /// Sends the error response `e` calls for, and logs why
+    pub fn fail(e: &Error, res: Response<Fresh>) -> io::Result<()> {
+        log_failure(e, &res.error_context.request_id);
+        error_page(e.status(), res)
     }
 
-    const ERROR_403: &'static [u8] = b"<!doctype html><html><head><title>Error</title></head><body><h1>Forbidden</h1><p>You don't have permission to view that file. Sorry.</p></body></html>";
+    /// Logs a request failing with `e`
+    ///
+    /// Use `fail` instead while the response can still be an error page; this
+    /// is for errors that come after it's started.
+    pub fn log_failure(e: &Error, request_id: &str) {
+        let status = e.status();
+        let level = if status >= 500 { LogLevel::Warn } else { LogLevel::Info };
 
-    pub fn error_400(mut res: Response<Fresh>) -> io::Result<()> {
-        res.set_status(400, String::from("Bad Request"));
-        {
-            let headers = res.headers_mut();
-            headers.insert("Content-type", Vec::from(&b"text/html"[..]));
-            headers.insert("Content-length", Vec::from(&b"164"[..]));
-        }
+        log!(level, "Request {} failed with {} {}: {}", request_id, status,
+             reason(status), e);
+    }
+
+    pub fn error_500(res: Response<Fresh>) -> io::Result<()> {
+        error_page(500, res)
+    }
+
+    /// Sends a 405, with `allow` listing the methods that would have worked
+    pub fn error_405(mut res: Response<Fresh>, allow: &str)
+                     -> io::Result<()> {
+        res.headers_mut().set("Allow", Vec::from(allow.as_bytes()));
+        error_page(405, res)
+    }
+
+    pub fn error_404(res: Response<Fresh>) -> io::Result<()> {
+        error_page(404, res)
+    }
 
-        res.of_stream(ERROR_400)
+    pub fn error_403(res: Response<Fresh>) -> io::Result<()> {
+        error_page(403, res)
     }
 
-    const ERROR_400: &'static [u8] = b"<!doctype html><html><head><title>Error</title></head><body><h1>Bad Request</h1><p>Your request had some kind of bad syntax. Are you using netcat?</p></body></html>";
+    #[cfg(test)]
+    mod test {
+        use super::*;
+        use super::render;
+
+        use std::collections::HashMap;
+        use std::sync::Arc;
+
+        use config::ErrorPage;
+
+        #[test]
+        fn custom_pages_are_filled_in() {
+            let mut pages = HashMap::new();
+            pages.insert(404, ErrorPage::Template(
+                String::from("{status} {reason} ({request_id})")
+            ));
+
+            let context = ErrorContext {
+                pages: Arc::new(ErrorPages::load(&pages).unwrap()),
+                json: false,
+                request_id: String::from("1-a")
+            };
+
+            assert_eq!(render(404, &context).1, b"404 Not Found (1-a)");
+            assert!(render(403, &context).1.starts_with(b"<!doctype html>"));
+        }
+
+        #[test]
+        fn json_clients_get_problem_documents() {
+            assert!(wants_json(b"application/problem+json"));
+            assert!(!wants_json(b"text/html,application/json;q=0.9"));
+
+            let context = ErrorContext {
+                json: true,
+                request_id: String::from("1-a"),
+                .. Default::default()
+            };
+
+            assert_eq!(render(403, &context),
+                       ("application/problem+json",
+                        Vec::from(&b"{\"type\":\"about:blank\",\
+                                     \"title\":\"Forbidden\",\"status\":403,\
+                                     \"request_id\":\"1-a\"}"[..])));
+        }
+    }
 }
@@ -0,0 +1,278 @@
+//! Redirects and internal rewrites, applied before routing
+//!
+//! Rules are tried in order against the request path, without its query
+//! string, and the first that matches decides. A redirect answers the request
+//! there and then. An internal rewrite changes the path, and that's the path
+//! routed, unless the rule chains: then it starts over from the first rule.
+//! Once chained rewrites pass `MAX_REWRITES`, or come back to a path already
+//! seen, the request fails with a 500.
+//!
+//! Unless the target has a query string of its own, the request's is kept.
+
+use config::RewriteRule;
+use filesystem::split_query;
+use server::{Handler, Middleware, Request, Response, Fresh, reason};
+use server::error_messages::error_500;
+use server::listing::is_unreserved;
+
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+
+/// Most internal rewrites one request may go through
+const MAX_REWRITES: usize = 10;
+
+pub struct Rewrites {
+    rules: Vec<RewriteRule>
+}
+
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    Unchanged,
+    /// The new path, and query string
+    Rewritten(Vec<u8>, Option<Vec<u8>>),
+    /// The status, and the `Location` to send
+    Redirect(u16, Vec<u8>),
+    Loop
+}
+
+impl Rewrites {
+    pub fn new(rules: Vec<RewriteRule>) -> Rewrites {
+        Rewrites { rules: rules }
+    }
+
+    /// Decides what becomes of a request for `path`, with `query`
+    fn apply(&self, path: &[u8], query: Option<&[u8]>) -> Outcome {
+        let mut seen = vec![path.to_owned()];
+        let mut query = query.map(Vec::from);
+
+        loop {
+            let (rule, (target, own_query)) = {
+                let current = seen.last().unwrap();
+
+                match self.rules.iter()
+                    .filter_map(|rule| substitute(rule, current)
+                                .map(|target| (rule, target)))
+                    .next()
+                {
+                    Some(found) => found,
+                    None if seen.len() == 1 => return Outcome::Unchanged,
+                    None => return Outcome::Rewritten(current.clone(), query)
+                }
+            };
+
+            if own_query.is_some() {
+                query = own_query;
+            }
+
+            if let Some(status) = rule.redirect {
+                let mut location = encode(&target, PATH_DELIMITERS);
+                if let Some(query) = query {
+                    location.push(b'?');
+                    location.extend(query);
+                }
+                return Outcome::Redirect(status, location);
+            }
+            if !rule.chain {
+                return Outcome::Rewritten(target, query);
+            }
+            if seen.len() > MAX_REWRITES || seen.contains(&target) {
+                return Outcome::Loop;
+            }
+
+            seen.push(target);
+        }
+    }
+}
+
+/// What `path` becomes under `rule`, if the rule matches it, along with the
+/// target's own query string, if it has one
+///
+/// The query string is split off the rule's target before captures are
+/// substituted, so a `?` decoded from the request path stays in the path.
+fn substitute(rule: &RewriteRule, path: &[u8])
+              -> Option<(Vec<u8>, Option<Vec<u8>>)> {
+    rule.pattern.captures(path).map(|captures| {
+        let (to_path, to_query) = split_query(rule.to.as_bytes());
+
+        let mut target = Vec::new();
+        captures.expand(to_path, &mut target);
+
+        let query = to_query.map(|to_query| {
+            let mut query = Vec::new();
+            captures.expand(to_query, &mut query);
+            encode(&query, QUERY_DELIMITERS)
+        });
+
+        (target, query)
+    })
+}
+
+impl Middleware for Rewrites {
+    fn wrap(&self, mut req: Request, res: Response<Fresh>, next: &Handler) {
+        let outcome = self.apply(req.request_uri().as_bytes(),
+                                 req.query().map(OsStrExt::as_bytes));
+
+        let sent = match outcome {
+            Outcome::Unchanged => return next.serve(req, res),
+            Outcome::Rewritten(path, query) => {
+                req.inner.path = path;
+                req.inner.query = query;
+                return next.serve(req, res);
+            },
+            Outcome::Redirect(status, location) =>
+                redirect(res, status, location),
+            Outcome::Loop => {
+                warn!("Rewriting {:?} went round in circles",
+                      req.request_uri());
+                error_500(res)
+            }
+        };
+
+        if let Err(e) = sent {
+            warn!("Error sending a redirect: {:?}", e);
+        }
+    }
+}
+
+fn redirect(mut res: Response<Fresh>, status: u16, location: Vec<u8>)
+            -> io::Result<()> {
+    res.set_status(status, String::from(reason(status)));
+    res.headers_mut().insert("Location", location);
+    res.headers_mut().insert("Content-Length", Vec::from(&b"0"[..]));
+    res.of_stream(&b""[..])
+}
+
+/// Delimiters left as they are in a target's path, or the whole URL for an
+/// absolute one
+const PATH_DELIMITERS: &'static [u8] = b":/[]@!$&'()*+,;=";
+
+/// Delimiters left as they are in a target's query string
+const QUERY_DELIMITERS: &'static [u8] = b":/?[]@!$&'()*+,;=";
+
+/// Percent-encodes whatever can't appear in a URI as it is, except for the
+/// delimiters in `keep`
+///
+/// Request paths are decoded by the time they're matched, so captures are
+/// too, and even a literal `%` has to be encoded again.
+fn encode(target: &[u8], keep: &[u8]) -> Vec<u8> {
+    const HEXITS: &'static [u8] = b"0123456789ABCDEF";
+
+    let mut encoded = Vec::with_capacity(target.len());
+
+    for &b in target {
+        if is_unreserved(b) || keep.contains(&b) {
+            encoded.push(b);
+        }
+        else {
+            encoded.push(b'%');
+            encoded.push(HEXITS[(b >> 4) as usize]);
+            encoded.push(HEXITS[(b & 0xF) as usize]);
+        }
+    }
+
+    encoded
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::Outcome;
+    use config::RewriteRule;
+    use glob::Glob;
+
+    use regex::bytes::Regex;
+
+    fn rule(pattern: &str, to: &str, redirect: Option<u16>) -> RewriteRule {
+        RewriteRule {
+            pattern: Regex::new(pattern).unwrap(),
+            to: String::from(to),
+            redirect: redirect,
+            chain: false
+        }
+    }
+
+    #[test]
+    fn redirects_substitute_captures() {
+        let rewrites = Rewrites::new(vec![
+            rule("^/old/(?P<id>[0-9]+)\\.html$", "/posts/$id", Some(301)),
+            rule(&Glob::new("/blog/**").to_regex(), "https://blog.example/$1",
+                 Some(308))
+        ]);
+
+        assert_eq!(rewrites.apply(b"/old/12.html", Some(b"x=1")),
+                   Outcome::Redirect(301, Vec::from(&b"/posts/12?x=1"[..])));
+        assert_eq!(rewrites.apply(b"/blog/a/b", None),
+                   Outcome::Redirect(308, Vec::from(&b"https://blog.example/\
+                                                       a/b"[..])));
+        assert_eq!(rewrites.apply(b"/new", None), Outcome::Unchanged);
+    }
+
+    #[test]
+    fn rewrites_stop_at_their_target() {
+        let rewrites = Rewrites::new(vec![
+            rule("^/app/.*$", "/app/index.html", None),
+            rule("^/(.*)$", "/index.php?q=$1", None)
+        ]);
+
+        assert_eq!(rewrites.apply(b"/app/settings", Some(b"tab=2")),
+                   Outcome::Rewritten(Vec::from(&b"/app/index.html"[..]),
+                                      Some(Vec::from(&b"tab=2"[..]))));
+        assert_eq!(rewrites.apply(b"/app/index.html", None),
+                   Outcome::Rewritten(Vec::from(&b"/app/index.html"[..]),
+                                      None));
+        assert_eq!(rewrites.apply(b"/about", Some(b"x=1")),
+                   Outcome::Rewritten(Vec::from(&b"/index.php"[..]),
+                                      Some(Vec::from(&b"q=about"[..]))));
+        assert_eq!(rewrites.apply(b"/index.php", None),
+                   Outcome::Rewritten(Vec::from(&b"/index.php"[..]),
+                                      Some(Vec::from(&b"q=index.php"[..]))));
+    }
+
+    #[test]
+    fn rewrites_chain_until_nothing_matches() {
+        let rewrites = Rewrites::new(vec![
+            RewriteRule { chain: true, .. rule("^/a$", "/b", None) },
+            rule("^/b$", "/c?from=b", None)
+        ]);
+
+        assert_eq!(rewrites.apply(b"/a", Some(b"q")),
+                   Outcome::Rewritten(Vec::from(&b"/c"[..]),
+                                      Some(Vec::from(&b"from=b"[..]))));
+    }
+
+    #[test]
+    fn rewrite_loops_are_caught() {
+        let cycle = Rewrites::new(vec![
+            RewriteRule { chain: true, .. rule("^/a$", "/b", None) },
+            RewriteRule { chain: true, .. rule("^/b$", "/a", None) }
+        ]);
+        let growing = Rewrites::new(vec![
+            RewriteRule { chain: true, .. rule("^/(.*)$", "/x$1", None) }
+        ]);
+
+        assert_eq!(cycle.apply(b"/a", None), Outcome::Loop);
+        assert_eq!(growing.apply(b"/a", None), Outcome::Loop);
+    }
+
+    #[test]
+    fn queries_are_matched_or_encoded_only_once() {
+        let rewrites = Rewrites::new(vec![
+            rule("^/old/(.*)$", "/new/$1", Some(301)),
+            rule("^/(.*)$", "/index.php?q=$1", None)
+        ]);
+
+        assert_eq!(rewrites.apply(b"/old/a?b", Some(b"x=%2F")),
+                   Outcome::Redirect(301, Vec::from(&b"/new/a%3Fb?x=%2F"[..])));
+        assert_eq!(rewrites.apply(b"/a b", None),
+                   Outcome::Rewritten(Vec::from(&b"/index.php"[..]),
+                                      Some(Vec::from(&b"q=a%20b"[..]))));
+    }
+
+    #[test]
+    fn locations_are_encoded() {
+        assert_eq!(encode(b"/a b/100%?#", PATH_DELIMITERS),
+                   Vec::from(&b"/a%20b/100%25%3F%23"[..]));
+        assert_eq!(encode(b"q=\xc3\xa9?&", QUERY_DELIMITERS),
+                   Vec::from(&b"q=%C3%A9?&"[..]));
+    }
+}
@@ -1,105 +1,676 @@
-//! A dead-simple router implementation
+//! A pattern-matching router
 //!
-//! A `Router` simply matches a request-uri against installed routes, in the
-//! order they have been added, dispatching to the first handler that matches.
+//! Routes are patterns of `/`-separated segments. A segment is either literal,
+//! a `:name` parameter matching any one segment, or a final `*name` wildcard
+//! matching whatever is left, including nothing. So `/users/:id/files/*rest`
+//! matches `/users/7/files/a/b.txt` with `id` as `7` and `rest` as `a/b.txt`;
+//! handlers read them back with `Request::param`. Only the path is matched;
+//! the query string is left for handlers, through `Request::query`.
+//!
+//! Patterns are kept in a trie of segments. When several match, the most
+//! specific wins, whatever order they were added in: a literal segment beats a
+//! parameter, which beats a wildcard, deciding from the left.
+//!
+//! A pattern may have handlers for particular methods and one for any other
+//! method too, so a static mount at `/` can sit in front of the FastCGI
+//! application. Two handlers for the same pattern and method conflict.
+//!
+//! Middleware can wrap every request, or just those taking one route. Global
+//! middleware sees requests before they're routed, so it sees those that end
+//! in a 404 too, but not the route's parameters.
 
 use errors::*;
-use server::{Handler, Request, Response, Fresh};
+use server::{Handler, Middleware, Request, Response, Fresh};
 use server::error_messages::*;
 
-use std::collections::HashMap;
-use std::path::{Path, PathBuf};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::os::unix::ffi::OsStrExt;
 
 pub struct Router {
-    routes: Vec<Route>
+    root: Node,
+    /// Wrapping every request, outermost first
+    middleware: Vec<Box<Middleware>>
+}
+
+/// One segment position in the trie
+#[derive(Default)]
+struct Node {
+    /// The route whose pattern ends here
+    route: Option<Route>,
+    literals: BTreeMap<Vec<u8>, Node>,
+    /// A `:name` parameter here, and what follows it
+    param: Option<(String, Box<Node>)>,
+    /// A `*name` wildcard here, ending its route
+    wildcard: Option<(String, Route)>
 }
 
 struct Route {
-    path: PathBuf,
-    handlers: MethodDispatch
+    pattern: String,
+    handlers: MethodDispatch,
+    /// Wrapping the handlers, outermost first
+    middleware: Vec<Box<Middleware>>
 }
 
-enum MethodDispatch {
-    Any(Box<Handler>),
-    Specific(HashMap<String, Box<Handler>>)
+/// A handler with the middleware wrapping it
+struct Chain<'a> {
+    middleware: &'a [Box<Middleware>],
+    handler: &'a Handler
 }
 
+struct MethodDispatch {
+    /// Handlers for particular methods
+    specific: HashMap<String, Box<Handler>>,
+    /// The handler for every method without one of its own
+    any: Option<Box<Handler>>
+}
+
+enum Segment {
+    Literal(Vec<u8>),
+    Param(String),
+    Wildcard(String)
+}
+
+/// Parameters captured while matching, by name
+type Params = Vec<(String, Vec<u8>)>;
+
 impl Router {
-    fn serve_inner(&self, req: Request, res: Response<Fresh>) -> Result<()> {
-        let request_path = Path::new(req.request_uri()).to_owned();
+    fn serve_inner(&self, mut req: Request, res: Response<Fresh>)
+                   -> Result<()> {
+        let found = {
+            let uri = req.request_uri().as_bytes();
+            let mut params = Vec::new();
 
-        for route in &self.routes {
-            if request_path.starts_with(&route.path) {
-                route.handlers.serve(req, res);
-                return Ok(());
-            }
+            self.root.find(uri, &segments(uri), &mut params)
+                .map(|route| (route, params))
+        };
+
+        match found {
+            Some((route, params)) => {
+                req.params = params;
+
+                let chain = Chain {
+                    middleware: &route.middleware,
+                    handler: &route.handlers
+                };
+                chain.serve(req, res);
+            },
+            None => try!(error_404(res))
         }
 
-        try!(error_404(res));
         Ok(())
     }
 
     /// Initialize a new, empty router
     pub fn new() -> Router {
-        Router { routes: Vec::new() }
+        Router { root: Default::default(), middleware: Vec::new() }
     }
 
     /// Create a route that will invoke the given `handler` for all methods
-    pub fn route_any<H: Handler + 'static>(&mut self, path: PathBuf, handler: H)
-    {
-        self.routes.push(Route {
-            path: path,
-            handlers: MethodDispatch::Any(Box::new(handler))
-        });
+    /// without a handler of their own
+    pub fn route_any<H: Handler + 'static>(&mut self, pattern: &str, handler: H)
+                                           -> Result<()> {
+        self.add(pattern, MethodDispatch {
+            specific: HashMap::new(),
+            any: Some(Box::new(handler))
+        })
     }
 
     /// Create a route that will invoke the given `handler`, but only for the
     /// particular `method`.
-    pub fn route<H: Handler + 'static>(&mut self, path: PathBuf, method: String,
-                                       handler: H) {
-        for route in self.routes.iter_mut() {
-            if route.path == path {
-                match &mut route.handlers {
-                    &mut MethodDispatch::Specific(ref mut map) =>
-                    {map.insert(method, Box::new(handler));},
-                    &mut MethodDispatch::Any(_) =>
-                    {panic!("Tried to add a universal and method-specific route for the same prefix");}
+    pub fn route<H: Handler + 'static>(&mut self, pattern: &str, method: String,
+                                       handler: H) -> Result<()> {
+        let mut handlers: HashMap<_, Box<Handler>> = HashMap::new();
+        handlers.insert(method, Box::new(handler));
+
+        self.add(pattern, MethodDispatch { specific: handlers, any: None })
+    }
+
+    /// Wraps every request in `middleware`, inside any added before
+    pub fn wrap<M: Middleware + 'static>(&mut self, middleware: M) {
+        self.middleware.push(Box::new(middleware));
+    }
+
+    /// Wraps requests taking the route added for `pattern` in `middleware`,
+    /// inside any added before
+    ///
+    /// Panics if there's no such route yet.
+    #[allow(dead_code)] // Nothing built in needs it yet
+    pub fn wrap_route<M: Middleware + 'static>(&mut self, pattern: &str,
+                                               middleware: M) {
+        let (node, wildcard) = match self.root.descend(pattern) {
+            Ok(found) => found,
+            Err(e) => panic!("There's no route {} to wrap: {}", pattern, e)
+        };
+        let route = match wildcard {
+            Some(_) => node.wildcard.as_mut()
+                .map(|&mut (_, ref mut route)| route),
+            None => node.route.as_mut()
+        };
+
+        match route {
+            Some(route) => route.middleware.push(Box::new(middleware)),
+            None => panic!("There's no route {} to wrap", pattern)
+        }
+    }
+
+    fn add(&mut self, pattern: &str, handlers: MethodDispatch) -> Result<()> {
+        let (node, wildcard) = try!(self.root.descend(pattern));
+
+        let route = match wildcard {
+            Some(name) => match node.wildcard {
+                Some((_, ref mut route)) => route,
+                ref mut empty => {
+                    *empty = Some((name, Route::new(pattern, handlers)));
+                    return Ok(());
+                }
+            },
+            None => match node.route {
+                Some(ref mut route) => route,
+                ref mut empty => {
+                    *empty = Some(Route::new(pattern, handlers));
+                    return Ok(());
                 }
-                return;
             }
+        };
+
+        route.merge(handlers)
+    }
+}
+
+impl Route {
+    fn new(pattern: &str, handlers: MethodDispatch) -> Route {
+        Route {
+            pattern: String::from(pattern),
+            handlers: handlers,
+            middleware: Vec::new()
         }
+    }
 
-        let mut handlers: HashMap<_, Box<Handler>> = HashMap::new();
-        handlers.insert(method, Box::new(handler));
+    /// Adds more method handlers to this route, unless it already has a
+    /// handler for one of their methods
+    fn merge(&mut self, handlers: MethodDispatch) -> Result<()> {
+        if handlers.any.is_some() && self.handlers.any.is_some() {
+            return Err(Error::RouteConflict(
+                format!("{} has two handlers for any method", self.pattern)));
+        }
+        if let Some(method) = handlers.specific.keys()
+            .find(|method| self.handlers.specific.contains_key(*method)) {
+            return Err(Error::RouteConflict(
+                format!("{} has two {} handlers", self.pattern, method)));
+        }
 
-        self.routes.push(Route {
-            path: path,
-            handlers: MethodDispatch::Specific(handlers)
-        });
+        self.handlers.specific.extend(handlers.specific);
+        if handlers.any.is_some() {
+            self.handlers.any = handlers.any;
+        }
+        Ok(())
+    }
+}
+
+impl Node {
+    /// Walks down to where `pattern` ends, making nodes as needed
+    ///
+    /// Along with the node comes the name of the wildcard ending the pattern,
+    /// if it has one. Two patterns naming the parameter in the same place
+    /// differently conflict.
+    fn descend(&mut self, pattern: &str)
+               -> Result<(&mut Node, Option<String>)> {
+        let mut node = self;
+        let mut wildcard = None;
+
+        for segment in parse_pattern(pattern) {
+            node = match segment {
+                Segment::Literal(literal) => node.literals.entry(literal)
+                    .or_insert_with(Default::default),
+                Segment::Param(name) => {
+                    let &mut (ref existing, ref mut next) = node.param
+                        .get_or_insert_with(|| {
+                            (name.clone(), Box::new(Default::default()))
+                        });
+
+                    if *existing != name {
+                        return Err(Error::RouteConflict(
+                            format!("{} names a parameter :{}, but another \
+                                     route has :{} in the same place",
+                                    pattern, name, existing)));
+                    }
+
+                    next
+                },
+                Segment::Wildcard(name) => {
+                    wildcard = Some(name);
+                    break;
+                }
+            };
+        }
+
+        Ok((node, wildcard))
+    }
+
+    /// Finds the most specific route matching the rest of a request path
+    ///
+    /// `segments` are the start offsets and contents of the segments of `uri`
+    /// still to match. Parameters are pushed onto `params` as they're
+    /// captured, and left there if a route is found.
+    fn find<'a>(&'a self, uri: &[u8], segments: &[(usize, &[u8])],
+                params: &mut Params) -> Option<&'a Route> {
+        let (&(_, segment), rest) = match segments.split_first() {
+            Some(split) => split,
+            None => {
+                return self.route.as_ref().or_else(|| {
+                    self.wildcard.as_ref().map(|&(ref name, ref route)| {
+                        params.push((name.clone(), Vec::new()));
+                        route
+                    })
+                });
+            }
+        };
+
+        if let Some(child) = self.literals.get(segment) {
+            if let Some(route) = child.find(uri, rest, params) {
+                return Some(route);
+            }
+        }
+
+        if let Some((ref name, ref child)) = self.param {
+            params.push((name.clone(), segment.to_owned()));
+
+            if let Some(route) = child.find(uri, rest, params) {
+                return Some(route);
+            }
+
+            params.pop();
+        }
+
+        self.wildcard.as_ref().map(|&(ref name, ref route)| {
+            params.push((name.clone(), uri[segments[0].0 ..].to_owned()));
+            route
+        })
+    }
+
+    /// Lists the routes at and below this node, most specific first
+    fn routes<'a>(&'a self, routes: &mut Vec<&'a Route>) {
+        routes.extend(self.route.as_ref());
+
+        for child in self.literals.values() {
+            child.routes(routes);
+        }
+
+        if let Some((_, ref child)) = self.param {
+            child.routes(routes);
+        }
+
+        routes.extend(self.wildcard.as_ref().map(|&(_, ref route)| route));
     }
 }
 
+/// Splits a pattern into segments
+///
+/// Panics if a wildcard isn't last, since that's a mistake in the code adding
+/// the route.
+fn parse_pattern(pattern: &str) -> Vec<Segment> {
+    let parts: Vec<&str> = pattern.split('/')
+        .filter(|part| !part.is_empty())
+        .collect();
+
+    parts.iter().enumerate()
+        .map(|(i, part)| {
+            if part.starts_with(':') {
+                Segment::Param(String::from(&part[1..]))
+            }
+            else if part.starts_with('*') {
+                if i != parts.len() - 1 {
+                    panic!("The wildcard in route {} must come last", pattern);
+                }
+                Segment::Wildcard(String::from(&part[1..]))
+            }
+            else {
+                Segment::Literal(Vec::from(part.as_bytes()))
+            }
+        })
+        .collect()
+}
+
+/// Splits a request path into its non-empty segments, with where each starts
+fn segments(uri: &[u8]) -> Vec<(usize, &[u8])> {
+    let mut segments = Vec::new();
+    let mut start = 0;
+
+    for part in uri.split(|&b| b == b'/') {
+        if !part.is_empty() {
+            segments.push((start, part));
+        }
+        start += part.len() + 1;
+    }
+
+    segments
+}
+
 impl Handler for Router {
     fn serve(&self, req: Request, res: Response<Fresh>) {
-        match self.serve_inner(req, res) {
-            Ok(_) => (),
-            Err(e) => warn!("Error serving a request: {:?}", e)
+        let route = |req: Request, res: Response<Fresh>| {
+            let id = req.id().to_owned();
+
+            if let Err(e) = self.serve_inner(req, res) {
+                log_failure(&e, &id);
+            }
+        };
+
+        Chain { middleware: &self.middleware, handler: &route }.serve(req, res);
+    }
+}
+
+impl<'a> Handler for Chain<'a> {
+    fn serve(&self, req: Request, res: Response<Fresh>) {
+        match self.middleware.split_first() {
+            Some((outer, inner)) => {
+                let next = Chain { middleware: inner, handler: self.handler };
+                outer.wrap(req, res, &next);
+            },
+            None => self.handler.serve(req, res)
         }
     }
 }
 
+/// Dumps the route table, one route to a line, most specific first
+impl fmt::Debug for Router {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut routes = Vec::new();
+        self.root.routes(&mut routes);
+
+        let width = routes.iter().map(|route| route.pattern.len()).max()
+            .unwrap_or(0);
+
+        for route in routes {
+            let handlers = &route.handlers;
+            let methods = match (handlers.specific.is_empty(),
+                                 handlers.any.is_some()) {
+                (true, _) => String::from("*"),
+                (false, false) => allowed_methods(&handlers.specific),
+                (false, true) =>
+                    format!("{}, *", allowed_methods(&handlers.specific))
+            };
+
+            try!(writeln!(f, "{:width$}  {}", route.pattern, methods,
+                          width = width));
+        }
+
+        Ok(())
+    }
+}
+
 impl Handler for MethodDispatch {
-    fn serve(&self, req: Request, res: Response<Fresh>) {
-        match self {
-            &MethodDispatch::Any(ref handler) => handler.serve(req, res),
-            &MethodDispatch::Specific(ref map) => {
-                if let Some(handler) = map.get(req.method()) {
-                    handler.serve(req, res);
-                }
-                else {
-                    let _ = error_405(res);
-                }
-            }
+    fn serve(&self, req: Request, mut res: Response<Fresh>) {
+        let map = &self.specific;
+
+        if let Some(handler) = map.get(req.method()) {
+            handler.serve(req, res);
+        }
+        else if req.method() == "HEAD" && map.contains_key("GET") {
+            // The response knows to drop the body
+            map["GET"].serve(req, res);
+        }
+        else if let Some(ref handler) = self.any {
+            handler.serve(req, res);
+        }
+        else if req.method() == "OPTIONS" {
+            res.headers_mut().insert("Allow",
+                                     allowed_methods(map).into_bytes());
+            res.headers_mut().insert("Content-Length", Vec::from(&b"0"[..]));
+            let _ = res.of_stream(&b""[..]);
+        }
+        else {
+            let _ = error_405(res, &allowed_methods(map));
+        }
+    }
+}
+
+/// Computes the Allow header for a method-specific route
+///
+/// `HEAD` comes free with `GET`, and the router answers `OPTIONS` itself.
+fn allowed_methods(map: &HashMap<String, Box<Handler>>) -> String {
+    let mut methods: Vec<&str> = map.keys().map(|m| &m[..]).collect();
+
+    if map.contains_key("GET") && !map.contains_key("HEAD") {
+        methods.push("HEAD");
+    }
+    if !map.contains_key("OPTIONS") {
+        methods.push("OPTIONS");
+    }
+
+    methods.sort();
+    methods.join(", ")
+}
+
+#[test]
+fn allowed_methods_for_get() {
+    let mut map: HashMap<String, Box<Handler>> = HashMap::new();
+    map.insert(String::from("GET"),
+               Box::new(|_: Request, _: Response<Fresh>| ()));
+
+    assert_eq!(allowed_methods(&map), "GET, HEAD, OPTIONS");
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use config::Timeouts;
+    use server::{Handler, Headers, Request, Response, Fresh};
+    use server::connection;
+    use server::error_messages::error_403;
+
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::os::unix::ffi::OsStrExt;
+    use std::thread;
+
+    fn router(patterns: &[&str]) -> Router {
+        let mut router = Router::new();
+        for pattern in patterns {
+            router.route_any(pattern, |_: Request, _: Response<Fresh>| ())
+                .unwrap();
+        }
+        router
+    }
+
+    /// The pattern that `uri` is routed to, and what it captured
+    fn lookup(router: &Router, uri: &str) -> Option<(String, Vec<String>)> {
+        let uri = uri.as_bytes();
+        let mut params = Vec::new();
+
+        router.root.find(uri, &segments(uri), &mut params).map(|route| {
+            (route.pattern.clone(),
+             params.into_iter()
+                 .map(|(name, value)| {
+                     format!("{}={}", name, String::from_utf8(value).unwrap())
+                 })
+                 .collect())
+        })
+    }
+
+    #[test]
+    fn parameters_are_captured() {
+        let router = router(&["/users/:id/files/*rest"]);
+
+        assert_eq!(lookup(&router, "/users/7/files/a/b.txt"),
+                   Some((String::from("/users/:id/files/*rest"),
+                         vec![String::from("id=7"),
+                              String::from("rest=a/b.txt")])));
+        assert_eq!(lookup(&router, "/users/7"), None);
+    }
+
+    #[test]
+    fn most_specific_route_wins() {
+        let router = router(&["/*path", "/users/:id", "/users/me",
+                              "/users/*rest"]);
+
+        assert_eq!(lookup(&router, "/users/me").unwrap().0, "/users/me");
+        assert_eq!(lookup(&router, "/users/7").unwrap().0, "/users/:id");
+        assert_eq!(lookup(&router, "/users/7/x").unwrap().0, "/users/*rest");
+        assert_eq!(lookup(&router, "/other").unwrap().0, "/*path");
+    }
+
+    #[test]
+    fn wildcards_match_nothing_too() {
+        let router = router(&["/html/*path"]);
+
+        assert_eq!(lookup(&router, "/html"),
+                   Some((String::from("/html/*path"),
+                         vec![String::from("path=")])));
+        assert_eq!(lookup(&router, "/html/").unwrap().1,
+                   vec![String::from("path=")]);
+        assert_eq!(lookup(&router, "/htmlx"), None);
+    }
+
+    #[test]
+    fn failed_branches_give_their_parameters_back() {
+        let router = router(&["/a/:x/c", "/a/*rest"]);
+
+        assert_eq!(lookup(&router, "/a/b/d"),
+                   Some((String::from("/a/*rest"),
+                         vec![String::from("rest=b/d")])));
+        assert_eq!(lookup(&router, "/a/b/c").unwrap().1,
+                   vec![String::from("x=b")]);
+    }
+
+    #[test]
+    fn parameters_named_differently_conflict() {
+        let mut router = router(&["/users/:id"]);
+
+        match router.route_any("/users/:name/files",
+                               |_: Request, _: Response<Fresh>| ()) {
+            Err(Error::RouteConflict(_)) => (),
+            _ => panic!("Two names for one parameter were let in")
+        }
+    }
+
+    /// Routes a request for `uri` through `router`, returning the raw response
+    fn send(router: &Router, method: &str, uri: &str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let raw = format!("{} {} HTTP/1.1\r\n\r\n", method, uri);
+
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(raw.as_bytes()).unwrap();
+
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        connection::serve(stream.into(), router, &Timeouts {
+            keep_alive: 0,
+            ..Default::default()
+        }, &Default::default());
+
+        client.join().unwrap()
+    }
+
+    #[test]
+    fn queries_are_left_out_of_matching() {
+        let mut router = Router::new();
+        router.route("/users/me", String::from("GET"),
+                     |req: Request, res: Response<Fresh>| {
+                         let query = req.query().unwrap().to_owned();
+                         let _ = res.of_stream(query.as_bytes());
+                     }).unwrap();
+        router.route("/files/*path", String::from("GET"),
+                     |req: Request, res: Response<Fresh>| {
+                         let path = req.param("path").unwrap().to_owned();
+                         let _ = res.of_stream(path.as_bytes());
+                     }).unwrap();
+
+        assert!(send(&router, "GET", "/users/me?x=1").ends_with("\r\n\r\nx=1"));
+        assert!(send(&router, "GET", "/files/app.js?v=3")
+                .ends_with("\r\n\r\napp.js"));
+    }
+
+    #[test]
+    fn handlers_see_parameters() {
+        let mut router = Router::new();
+        router.route("/users/:id/files/*rest", String::from("GET"),
+                     |req: Request, res: Response<Fresh>| {
+                         let body = format!(
+                             "id={} rest={}",
+                             req.param("id").unwrap().to_string_lossy(),
+                             req.param("rest").unwrap().to_string_lossy());
+                         let _ = res.of_stream(body.as_bytes());
+                     }).unwrap();
+
+        let answer = send(&router, "GET", "/users/7/files/a/b.txt");
+        assert!(answer.ends_with("\r\n\r\nid=7 rest=a/b.txt"));
+    }
+
+    #[test]
+    fn middleware_wraps_handlers() {
+        let mut router = Router::new();
+        router.route_any("/open", |_: Request, res: Response<Fresh>| {
+            let _ = res.of_stream(&b"open"[..]);
+        }).unwrap();
+        router.route_any("/closed", |_: Request, res: Response<Fresh>| {
+            let _ = res.of_stream(&b"closed"[..]);
+        }).unwrap();
+
+        router.wrap(|req: Request, mut res: Response<Fresh>, next: &Handler| {
+            res.before_headers(|status, headers: &mut Headers| {
+                headers.set("X-Status", format!("{}", status).into_bytes());
+            });
+            next.serve(req, res)
+        });
+        router.wrap_route("/closed",
+                          |_: Request, res: Response<Fresh>, _: &Handler| {
+                              let _ = error_403(res);
+                          });
+
+        let open = send(&router, "GET", "/open");
+        assert!(open.contains("X-Status: 200\r\n"));
+        assert!(open.ends_with("open"));
+
+        let closed = send(&router, "GET", "/closed");
+        assert!(closed.starts_with("HTTP/1.1 403 "));
+        assert!(closed.contains("X-Status: 403\r\n"));
+
+        assert!(send(&router, "GET", "/missing").contains("X-Status: 404\r\n"));
+    }
+
+    #[test]
+    fn route_table_dumps_most_specific_first() {
+        let mut router = router(&["/*path", "/users/:id"]);
+        router.route("/users/me", String::from("GET"),
+                     |_: Request, _: Response<Fresh>| ()).unwrap();
+
+        assert_eq!(format!("{:?}", router),
+                   "/users/me   GET, HEAD, OPTIONS\n\
+                    /users/:id  *\n\
+                    /*path      *\n");
+    }
+
+    #[test]
+    fn a_root_mount_sits_in_front_of_the_fallback() {
+        let mut router = Router::new();
+        router.route("/*path", String::from("GET"),
+                     |_: Request, res: Response<Fresh>| {
+                         let _ = res.of_stream(&b"static"[..]);
+                     }).unwrap();
+        router.route_any("/*path", |_: Request, res: Response<Fresh>| {
+            let _ = res.of_stream(&b"fastcgi"[..]);
+        }).unwrap();
+
+        assert!(send(&router, "GET", "/a.css").ends_with("static"));
+        assert!(send(&router, "HEAD", "/a.css").starts_with("HTTP/1.1 200 "));
+        assert!(send(&router, "POST", "/index.php").ends_with("fastcgi"));
+        assert_eq!(format!("{:?}", router), "/*path  GET, HEAD, OPTIONS, *\n");
+
+        let again = router.route("/*path", String::from("GET"),
+                                 |_: Request, _: Response<Fresh>| ());
+        match again {
+            Err(Error::RouteConflict(_)) => (),
+            _ => panic!("A second GET handler for /*path was let in")
         }
+        assert!(router.route_any("/*path", |_: Request, _: Response<Fresh>| ())
+                .is_err());
     }
 }
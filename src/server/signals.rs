@@ -0,0 +1,138 @@
+//! Signals, caught and queued for the accept loop
+//!
+//! A signal handler can't safely do much, so each caught signal is written
+//! down a pipe as a byte. The accept loop waits on the pipe alongside its
+//! listener, and deals with signals in its own time.
+
+use libc::{self, c_int, c_void};
+
+use std::io;
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// The write end of the pipe, for the handler
+static PIPE: AtomicUsize = AtomicUsize::new(0);
+
+/// The read end of the pipe caught signals go down
+pub struct Signals {
+    read: RawFd
+}
+
+impl Signals {
+    /// Starts catching `signals`
+    ///
+    /// There's one pipe per process, so this should only be done once.
+    pub fn catch(signals: &[c_int]) -> io::Result<Signals> {
+        let mut fds = [0; 2];
+        try!(cvt(unsafe { libc::pipe(fds.as_mut_ptr()) }));
+
+        for &fd in &fds {
+            unsafe {
+                try!(cvt(libc::fcntl(fd, libc::F_SETFL, libc::O_NONBLOCK)));
+                try!(cvt(libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC)));
+            }
+        }
+        PIPE.store(fds[1] as usize, Ordering::SeqCst);
+
+        for &signal in signals {
+            let handler = on_signal as extern "C" fn(c_int);
+            let old = unsafe {
+                libc::signal(signal, handler as libc::sighandler_t)
+            };
+            if old == libc::SIG_ERR {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        Ok(Signals { read: fds[0] })
+    }
+
+    /// Becomes readable when a signal's been caught
+    pub fn fd(&self) -> RawFd {
+        self.read
+    }
+
+    /// The signals caught since last asked, oldest first
+    pub fn caught(&self) -> Vec<c_int> {
+        let mut caught = Vec::new();
+        let mut buf = [0u8; 16];
+
+        loop {
+            let n = unsafe {
+                libc::read(self.read, buf.as_mut_ptr() as *mut c_void,
+                           buf.len())
+            };
+            if n <= 0 {
+                break;
+            }
+
+            caught.extend(buf[.. n as usize].iter().map(|&b| b as c_int));
+        }
+
+        caught
+    }
+}
+
+extern "C" fn on_signal(signal: c_int) {
+    let byte = signal as u8;
+    let fd = PIPE.load(Ordering::Relaxed) as c_int;
+
+    unsafe {
+        libc::write(fd, &byte as *const u8 as *const c_void, 1);
+    }
+}
+
+/// Waits up to `timeout` milliseconds, or for ever if it's negative, for any
+/// of `fds` to become readable, returning those that have
+///
+/// A signal arriving cuts the wait short, with nothing readable.
+pub fn readable(fds: &[RawFd], timeout: c_int) -> io::Result<Vec<RawFd>> {
+    let mut polled: Vec<libc::pollfd> = fds.iter()
+        .map(|&fd| libc::pollfd { fd: fd, events: libc::POLLIN, revents: 0 })
+        .collect();
+
+    let n = unsafe {
+        libc::poll(polled.as_mut_ptr(), polled.len() as libc::nfds_t, timeout)
+    };
+    if n < 0 {
+        let e = io::Error::last_os_error();
+        return match e.kind() {
+            io::ErrorKind::Interrupted => Ok(Vec::new()),
+            _ => Err(e)
+        };
+    }
+
+    Ok(polled.iter()
+       .filter(|polled| polled.revents != 0)
+       .map(|polled| polled.fd)
+       .collect())
+}
+
+fn cvt(result: c_int) -> io::Result<c_int> {
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    }
+    else {
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use libc;
+
+    #[test]
+    fn caught_signals_come_down_the_pipe() {
+        let signals = Signals::catch(&[libc::SIGUSR1]).unwrap();
+        assert!(signals.caught().is_empty());
+
+        unsafe { libc::raise(libc::SIGUSR1) };
+
+        assert_eq!(readable(&[signals.fd()], 1000).unwrap(),
+                   vec![signals.fd()]);
+        assert_eq!(signals.caught(), vec![libc::SIGUSR1]);
+        assert!(readable(&[signals.fd()], 0).unwrap().is_empty());
+    }
+}
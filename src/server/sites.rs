@@ -0,0 +1,130 @@
+//! Name-based virtual hosting
+//!
+//! Each site has its own handler, usually a `Router`. A request goes to the
+//! site named for its host: by exact name first, then the longest `*.` suffix,
+//! then the longest `.*` prefix, and otherwise to the default site.
+
+use config::ServerName;
+use server::{Handler, Request, Response, Fresh};
+
+pub struct Sites {
+    sites: Vec<Site>,
+    /// Which site takes hosts no site is named for
+    default: usize
+}
+
+struct Site {
+    names: Vec<ServerName>,
+    handler: Box<Handler>
+}
+
+impl Sites {
+    pub fn new() -> Sites {
+        Sites { sites: Vec::new(), default: 0 }
+    }
+
+    /// Adds a site answering to `names`, and if it's the `default`, to hosts
+    /// no site is named for
+    pub fn add<H: Handler + 'static>(&mut self, names: Vec<ServerName>,
+                                     default: bool, handler: H) {
+        if default {
+            self.default = self.sites.len();
+        }
+
+        self.sites.push(Site { names: names, handler: Box::new(handler) });
+    }
+
+    /// Picks the site for a request to `host`
+    fn choose(&self, host: Option<&str>) -> usize {
+        let host = match host {
+            Some(host) => host,
+            None => return self.default
+        };
+
+        best_match(self.sites.iter().map(|site| &site.names[..]), host)
+            .unwrap_or(self.default)
+    }
+}
+
+/// Which of `sites`, given by the names each answers to, is named for `host`,
+/// if any is
+pub fn best_match<'a, I>(sites: I, host: &str) -> Option<usize>
+    where I: IntoIterator<Item = &'a [ServerName]>
+{
+    sites.into_iter().enumerate()
+        .flat_map(|(i, names)| {
+            names.iter()
+                .filter_map(move |name| rank(name, host).map(|r| (r, i)))
+        })
+        .max_by_key(|&(rank, i)| (rank, -(i as isize)))
+        .map(|(_, i)| i)
+}
+
+/// How well `name` matches `host`, if it does at all; better matches are
+/// greater
+fn rank(name: &ServerName, host: &str) -> Option<(u8, usize)> {
+    match name {
+        &ServerName::Exact(ref exact) if host == exact =>
+            Some((2, exact.len())),
+        &ServerName::Suffix(ref suffix) if host.ends_with(&suffix[..]) =>
+            Some((1, suffix.len())),
+        &ServerName::Prefix(ref prefix) if host.starts_with(&prefix[..]) =>
+            Some((0, prefix.len())),
+        _ => None
+    }
+}
+
+impl Handler for Sites {
+    fn serve(&self, req: Request, res: Response<Fresh>) {
+        let site = {
+            let host = req.host();
+            self.choose(host.as_ref().map(|host| &host[..]))
+        };
+
+        self.sites[site].handler.serve(req, res)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use config::ServerName;
+    use server::{Request, Response, Fresh};
+
+    fn sites(names: &[&[ServerName]]) -> Sites {
+        let mut sites = Sites::new();
+        for (i, names) in names.iter().enumerate() {
+            sites.add(names.to_vec(), i == 1,
+                      |_: Request, _: Response<Fresh>| ());
+        }
+        sites
+    }
+
+    fn exact(name: &str) -> ServerName {
+        ServerName::Exact(String::from(name))
+    }
+
+    #[test]
+    fn more_exact_names_win() {
+        let sites = sites(&[
+            &[ServerName::Prefix(String::from("www.example."))],
+            &[],
+            &[ServerName::Suffix(String::from(".example.com"))],
+            &[ServerName::Suffix(String::from(".static.example.com"))],
+            &[exact("www.example.com")]
+        ]);
+
+        assert_eq!(sites.choose(Some("www.example.com")), 4);
+        assert_eq!(sites.choose(Some("img.static.example.com")), 3);
+        assert_eq!(sites.choose(Some("api.example.com")), 2);
+        assert_eq!(sites.choose(Some("www.example.org")), 0);
+    }
+
+    #[test]
+    fn unknown_hosts_go_to_the_default() {
+        let sites = sites(&[&[exact("a.example")], &[exact("b.example")]]);
+
+        assert_eq!(sites.choose(Some("c.example")), 1);
+        assert_eq!(sites.choose(None), 1);
+    }
+}
@@ -2,92 +2,553 @@
 
 use super::{Handler, Request, Response, Fresh, mime_as_string};
 use super::error_messages::*;
-use config::Config;
+use super::file_cache::{CacheStats, Cached, FileCache};
+use super::header_rules;
+use super::listing;
+use config::{Fallback, HeaderRule, StaticFilesConfig, SymlinkPolicy};
 use errors::*;
+use filesystem::is_within;
 
+use libc;
+use mime::{Mime, TopLevel, SubLevel, Attr, Value};
 use mime_guess::guess_mime_type_opt;
 
+use std::ascii::AsciiExt;
 use std::ffi::OsStr;
-use std::fs::{File, canonicalize};
-use std::io::ErrorKind;
+use std::fs::{self, File, Metadata, canonicalize};
+use std::io::{ErrorKind, Read};
 use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-/// A handler for static files
+/// A handler for one static mount
 pub struct Statics {
-    conf: Config
+    mount: StaticFilesConfig,
+    header_rules: Vec<HeaderRule>,
+    fastcgi: Option<Arc<Handler>>,
+    cache: FileCache
+}
+
+/// What a request path turned out to name
+enum Found {
+    File(PathBuf, File, Metadata),
+    /// A small file, already read
+    Memory(PathBuf, Arc<Vec<u8>>),
+    /// A directory with no index file, to be listed
+    Listing(PathBuf)
 }
 
 impl Statics {
-    pub fn new(conf: Config) -> Statics {
-        Statics { conf: conf }
-    }
-
-    fn serve_file(&self, req: Request, mut res: Response<Fresh>) -> Result<()> {
-        let request_uri_relative = OsStr::from_bytes(
-            &req.request_uri().as_bytes()[1..]
-        );
-
-        let requested_file =
-            match canonicalize(self.conf.stat.webroot
-                               .join(request_uri_relative)) {
-                Ok(f) => f,
-                Err(e) => {
-                    match e.kind() {
-                        ErrorKind::NotFound => try!(error_404(res)),
-                        _ => try!(error_500(res))
-                    };
-
-                    return Err(Error::from(e));
+    /// Creates a handler for a mount
+    ///
+    /// `header_rules` are the server-wide rules; the mount's own are applied
+    /// after them. `fastcgi` is where requests go if the `try_files` fallback
+    /// is `@fastcgi`.
+    pub fn new(mount: StaticFilesConfig, mut header_rules: Vec<HeaderRule>,
+               fastcgi: Option<Arc<Handler>>) -> Statics {
+        header_rules.extend(mount.header_rules.iter().cloned());
+
+        Statics {
+            cache: FileCache::new(mount.cache.clone()),
+            mount: mount,
+            header_rules: header_rules,
+            fastcgi: fastcgi
+        }
+    }
+
+    fn serve_file(&self, req: Request, res: Response<Fresh>) -> Result<()> {
+        let uri = req.request_uri().as_bytes().to_owned();
+
+        for candidate in &self.mount.try_files {
+            match self.resolve(&expand_uri(candidate, &uri)) {
+                Ok(Some(found)) => return self.send(&req, res, found),
+                Ok(None) => (),
+                Err(e) => return fail_lookup(res, e)
+            }
+        }
+
+        match self.mount.fallback {
+            Fallback::Status(code) => Ok(try!(error_page(code, res))),
+            Fallback::Uri(ref template) => {
+                match self.resolve(&expand_uri(template, &uri)) {
+                    Ok(Some(found)) => self.send(&req, res, found),
+                    Ok(None) => Ok(try!(error_404(res))),
+                    Err(e) => fail_lookup(res, e)
                 }
-            };
+            },
+            Fallback::FastCgi => match self.fastcgi {
+                Some(ref handler) => Ok(handler.serve(req, res)),
+                None => Ok(try!(error_404(res)))
+            }
+        }
+    }
 
-        if !requested_file.starts_with(&self.conf.stat.webroot) {
-            let _ = error_403(res);
-            return Err(Error::PermissionDenied);
+    /// Finds what a request path names, if it exists and may be served
+    ///
+    /// Paths ending in `'/'` name a directory: its first index file that
+    /// exists, or else a listing if those are turned on. Missing files, and
+    /// directories named without the `'/'`, come back as `Ok(None)`.
+    fn resolve(&self, uri: &[u8]) -> Result<Option<Found>> {
+        if uri.last() != Some(&b'/') {
+            return self.resolve_file(uri);
         }
 
-        let file = match File::open(&requested_file) {
-            Ok(f) => f,
-            Err(e) => {
-                match e.kind() {
-                    ErrorKind::NotFound => try!(error_404(res)),
-                    _ => try!(error_500(res))
-                };
+        for index in &self.mount.index {
+            let mut index_uri = uri.to_owned();
+            index_uri.extend_from_slice(index.as_bytes());
 
-                return Err(Error::from(e));
+            if let Some(found) = try!(self.resolve_file(&index_uri)) {
+                return Ok(Some(found));
             }
-        };
+        }
 
-        let meta = match file.metadata() {
-            Ok(m) => m,
-            Err(e) => {
-                try!(error_500(res));
-                return Err(Error::from(e));
+        if !self.mount.listing {
+            return Ok(None);
+        }
+
+        match try!(self.locate(uri)) {
+            Some(ref dir) if try!(fs::metadata(dir)).is_dir() =>
+                Ok(Some(Found::Listing(dir.clone()))),
+            _ => Ok(None)
+        }
+    }
+
+    fn resolve_file(&self, uri: &[u8]) -> Result<Option<Found>> {
+        if let Some(cached) = self.cache.get(uri) {
+            if let Some(contents) = cached.contents {
+                return Ok(Some(Found::Memory(cached.path, contents)));
             }
+
+            // The length sent has to be the length of the file opened, so
+            // its metadata comes fresh off the descriptor
+            let opened = File::open(&cached.path)
+                .and_then(|file| file.metadata().map(|meta| (file, meta)));
+
+            if let Ok((file, meta)) = opened {
+                if cached.matches(&meta) {
+                    return Ok(Some(Found::File(cached.path, file, meta)));
+                }
+            }
+
+            // Gone or changed since we last looked; start over
+            self.cache.invalidate(uri);
+        }
+
+        let path = match try!(self.locate(uri)) {
+            Some(path) => path,
+            None => return Ok(None)
+        };
+
+        let mut file = match File::open(&path) {
+            Ok(f) => f,
+            Err(e) => return not_found_as_none(Error::from(e))
         };
 
+        let meta = try!(file.metadata());
         if meta.is_dir() {
-            try!(error_403(res));
+            return Ok(None);
+        }
+
+        if !self.cache.enabled() {
+            return Ok(Some(Found::File(path, file, meta)));
+        }
+
+        let contents = if self.cache.wants_contents(meta.len()) {
+            let mut contents = Vec::with_capacity(meta.len() as usize);
+            try!(file.read_to_end(&mut contents));
+            Some(Arc::new(contents))
+        }
+        else {
+            None
+        };
+
+        self.cache.insert(uri, Cached {
+            path: path.clone(),
+            meta: meta.clone(),
+            contents: contents.clone()
+        });
+
+        match contents {
+            Some(contents) => Ok(Some(Found::Memory(path, contents))),
+            None => Ok(Some(Found::File(path, file, meta)))
+        }
+    }
+
+    /// Maps a request path to a canonical path inside the webroot, enforcing
+    /// the deny and symlink policies along the way
+    ///
+    /// The mount's `public_prefix` is taken off `uri` first, so with a prefix
+    /// of `/assets`, `/assets/site.css` is `site.css` in the webroot. Paths
+    /// that don't exist, or aren't under the prefix at all, come back as
+    /// `Ok(None)`; ones that may not be served as
+    /// `Err(Error::PermissionDenied)`.
+    pub fn locate(&self, uri: &[u8]) -> Result<Option<PathBuf>> {
+        if self.is_denied(uri) {
+            return Err(Error::PermissionDenied);
+        }
+
+        let prefix = self.mount.public_prefix.as_os_str().as_bytes();
+        if !is_within(uri, prefix) {
+            return Ok(None);
+        }
+
+        let rest = if uri.len() > prefix.len() { &uri[prefix.len()..] }
+                   else { &b""[..] };
+        let start = rest.iter().position(|&b| b != b'/')
+            .unwrap_or(rest.len());
+        let relative = Path::new(OsStr::from_bytes(&rest[start..]));
+
+        if let Err(e) = self.check_symlinks(relative) {
+            return not_found_as_none(e);
+        }
+
+        let located = match canonicalize(self.mount.webroot.join(relative)) {
+            Ok(f) => f,
+            Err(e) => return not_found_as_none(Error::from(e))
+        };
+
+        // Links may have led somewhere the request path alone didn't reveal
+        let within_webroot = located
+            .strip_prefix(&self.mount.webroot)
+            .map(|rest| {
+                !self.is_denied(self.mount.public_prefix.join(rest)
+                                .as_os_str().as_bytes())
+            })
+            .unwrap_or(false);
+
+        if !within_webroot {
             return Err(Error::PermissionDenied);
         }
 
-        let mime = guess_mime_type_opt(&requested_file)
-            .map(mime_as_string)
-            .unwrap_or(String::from("application/octet-stream"));
+        Ok(Some(located))
+    }
+
+    /// Sends whatever `resolve` found
+    fn send(&self, req: &Request, mut res: Response<Fresh>, found: Found)
+            -> Result<()>
+    {
+        match found {
+            Found::File(path, file, meta) => {
+                self.set_headers(req, &mut res, self.content_type(&path),
+                                 meta.len());
+                Ok(try!(res.of_file(file, meta.len())))
+            },
+            Found::Memory(path, contents) => {
+                self.set_headers(req, &mut res, self.content_type(&path),
+                                 contents.len() as u64);
+                Ok(try!(res.of_stream(&contents[..])))
+            },
+            Found::Listing(dir) => {
+                let body = try!(self.listing(req.request_uri().as_bytes(),
+                                             &dir));
+
+                self.set_headers(req, &mut res, String::from("text/html"),
+                                 body.len() as u64);
+                Ok(try!(res.of_stream(&body[..])))
+            }
+        }
+    }
 
-        res.headers_mut().insert("Content-type", mime.into_bytes());
+    fn set_headers(&self, req: &Request, res: &mut Response<Fresh>,
+                   content_type: String, len: u64) {
+        res.headers_mut().insert("Content-type", content_type.into_bytes());
         res.headers_mut().insert("Content-length",
-                                 format!("{}", meta.len()).into_bytes());
+                                 format!("{}", len).into_bytes());
+        header_rules::apply(&self.header_rules, req.request_uri().as_bytes(),
+                            res.headers_mut(), false);
+    }
+
+    /// Renders a listing of `dir`, reached through the request path `uri`
+    ///
+    /// Entries the deny settings would refuse aren't shown.
+    fn listing(&self, uri: &[u8], dir: &Path) -> Result<Vec<u8>> {
+        let mut entries = Vec::new();
+
+        for entry in try!(fs::read_dir(dir)) {
+            let entry = try!(entry);
+            let name = entry.file_name();
+
+            let mut entry_uri = uri.to_owned();
+            entry_uri.extend_from_slice(name.as_bytes());
+            if self.is_denied(&entry_uri) {
+                continue;
+            }
+
+            entries.push(listing::Entry {
+                name: name,
+                is_dir: try!(fs::metadata(entry.path())).is_dir()
+            });
+        }
+
+        let at_mount_root = Path::new(OsStr::from_bytes(uri)) ==
+            self.mount.public_prefix.as_path();
 
-        Ok(try!(res.of_stream(file)))
+        Ok(listing::render(uri, &mut entries, !at_mount_root))
     }
+
+    /// Drops anything cached about `uri` and the paths below it, after
+    /// they've been changed
+    pub fn forget(&self, uri: &[u8]) {
+        self.cache.invalidate_tree(uri);
+    }
+
+    #[inline]
+    pub fn mount(&self) -> &StaticFilesConfig {
+        &self.mount
+    }
+
+    #[inline]
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
+    /// Whether the dotfile and deny-pattern settings forbid serving `path`
+    pub fn is_denied(&self, path: &[u8]) -> bool {
+        (self.mount.deny_dotfiles && has_hidden_segment(path)) ||
+            self.mount.deny.iter().any(|glob| glob.matches(path))
+    }
+
+    /// Walks `relative` down from the webroot, applying the symlink policy to
+    /// every link met along the way
+    fn check_symlinks(&self, relative: &Path) -> Result<()> {
+        if self.mount.symlinks == SymlinkPolicy::Follow {
+            return Ok(());
+        }
+
+        let mut current = self.mount.webroot.clone();
+        for component in relative.components() {
+            current.push(component.as_os_str());
+
+            let link = try!(fs::symlink_metadata(&current));
+            if !link.file_type().is_symlink() {
+                continue;
+            }
+
+            match self.mount.symlinks {
+                SymlinkPolicy::Never => return Err(Error::PermissionDenied),
+                SymlinkPolicy::FollowIfOwnerMatches => {
+                    let target = try!(fs::metadata(&current));
+                    if target.uid() != link.uid() {
+                        return Err(Error::PermissionDenied);
+                    }
+                },
+                SymlinkPolicy::Follow => ()
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Works out the Content-Type to send for the file at `path`
+    ///
+    /// Configured extensions win over `mime_guess`, and anything still unknown
+    /// is `application/octet-stream`.
+    pub fn content_type(&self, path: &Path) -> String {
+        let configured = path.extension()
+            .and_then(OsStr::to_str)
+            .and_then(|ext| {
+                self.mount.mime_types.get(&ext.to_ascii_lowercase())
+            })
+            .cloned();
+
+        match configured.or_else(|| guess_mime_type_opt(path)) {
+            Some(mime) => mime_as_string(
+                with_default_charset(mime, &self.mount.default_charset)
+            ),
+            None => String::from("application/octet-stream")
+        }
+    }
+}
+
+/// Sends the error page for a failed lookup
+fn fail_lookup(res: Response<Fresh>, e: Error) -> Result<()> {
+    Ok(try!(fail(&e, res)))
+}
+
+/// Turns "no such file" errors into `Ok(None)`, passing others through
+fn not_found_as_none<T>(e: Error) -> Result<Option<T>> {
+    match e {
+        Error::Io(ref e) if e.kind() == ErrorKind::NotFound ||
+            e.raw_os_error() == Some(libc::ENOTDIR) => Ok(None),
+        e => Err(e)
+    }
+}
+
+/// Substitutes the request path for `$uri` in a `try_files` entry
+fn expand_uri(template: &str, uri: &[u8]) -> Vec<u8> {
+    let mut expanded = Vec::with_capacity(template.len() + uri.len());
+
+    let mut pieces = template.split("$uri");
+    if let Some(first) = pieces.next() {
+        expanded.extend_from_slice(first.as_bytes());
+    }
+    for piece in pieces {
+        expanded.extend_from_slice(uri);
+        expanded.extend_from_slice(piece.as_bytes());
+    }
+
+    expanded
+}
+
+#[test]
+fn expand_uri_substitutes() {
+    assert_eq!(expand_uri("$uri", b"/html/a"), b"/html/a");
+    assert_eq!(expand_uri("$uri.html", b"/html/a"), b"/html/a.html");
+    assert_eq!(expand_uri("$uri/", b"/html/a"), b"/html/a/");
+    assert_eq!(expand_uri("/html/index.html", b"/html/a"),
+               b"/html/index.html");
+}
+
+#[test]
+fn locate_takes_the_prefix_off() {
+    use std::env;
+    use std::process;
+
+    let webroot = env::temp_dir()
+        .join(format!("http-server-locate-{}", process::id()));
+    let _ = fs::remove_dir_all(&webroot);
+    fs::create_dir_all(webroot.join("css")).unwrap();
+    File::create(webroot.join("css/site.css")).unwrap();
+    let webroot = canonicalize(&webroot).unwrap();
+
+    let statics = Statics::new(StaticFilesConfig {
+        webroot: webroot.clone(),
+        public_prefix: PathBuf::from("/assets"),
+        ..Default::default()
+    }, Vec::new(), None);
+
+    assert_eq!(statics.locate(b"/assets/css/site.css").unwrap(),
+               Some(webroot.join("css/site.css")));
+    assert_eq!(statics.locate(b"/assets/").unwrap(), Some(webroot.clone()));
+    assert_eq!(statics.locate(b"/assets").unwrap(), Some(webroot.clone()));
+    assert_eq!(statics.locate(b"/assets/assets/css/site.css").unwrap(), None);
+    assert_eq!(statics.locate(b"/css/site.css").unwrap(), None);
+    assert_eq!(statics.locate(b"/assetsx/css/site.css").unwrap(), None);
+
+    fs::remove_dir_all(&webroot).unwrap();
+}
+
+#[test]
+fn cache_hits_see_files_replaced_since() {
+    use config::CacheConfig;
+    use std::env;
+    use std::io::Write;
+    use std::process;
+
+    let webroot = env::temp_dir()
+        .join(format!("http-server-replaced-{}", process::id()));
+    let _ = fs::remove_dir_all(&webroot);
+    fs::create_dir_all(&webroot).unwrap();
+    File::create(webroot.join("big.bin")).unwrap()
+        .write_all(&[0; 64]).unwrap();
+    let webroot = canonicalize(&webroot).unwrap();
+
+    let statics = Statics::new(StaticFilesConfig {
+        webroot: webroot.clone(),
+        public_prefix: PathBuf::from("/"),
+        cache: CacheConfig {
+            entries: 8,
+            bytes: 1024,
+            max_file_size: 16,
+            revalidate: 3600
+        },
+        ..Default::default()
+    }, Vec::new(), None);
+
+    let length = |statics: &Statics| {
+        match statics.resolve_file(b"/big.bin").unwrap() {
+            Some(Found::File(_, _, meta)) => meta.len(),
+            _ => panic!("/big.bin wasn't found as a file")
+        }
+    };
+
+    assert_eq!(length(&statics), 64);
+    assert_eq!(length(&statics), 64);
+    assert_eq!(statics.cache_stats().hits, 1);
+
+    File::create(webroot.join("big.bin")).unwrap()
+        .write_all(&[0; 32]).unwrap();
+    assert_eq!(length(&statics), 32);
+
+    fs::remove_dir_all(&webroot).unwrap();
+}
+
+/// Returns `true` if any segment of `path` is a dotfile, other than
+/// `.well-known`
+fn has_hidden_segment(path: &[u8]) -> bool {
+    path.split(|&b| b == b'/')
+        .any(|segment| segment.first() == Some(&b'.') &&
+             segment != b".well-known")
+}
+
+#[test]
+fn hidden_segments() {
+    assert!(has_hidden_segment(b"/html/.git/config"));
+    assert!(has_hidden_segment(b"/html/.env"));
+    assert!(has_hidden_segment(b"/html/../etc/passwd"));
+    assert!(!has_hidden_segment(b"/html/.well-known/acme-challenge/x"));
+    assert!(!has_hidden_segment(b"/html/site.css"));
+}
+
+/// Adds a `charset` parameter to textual types that lack one
+fn with_default_charset(mime: Mime, charset: &Option<String>) -> Mime {
+    let charset = match *charset {
+        Some(ref charset) => charset,
+        None => return mime
+    };
+
+    let textual = match mime {
+        Mime(TopLevel::Text, _, _) => true,
+        Mime(TopLevel::Application, SubLevel::Javascript, _) |
+        Mime(TopLevel::Application, SubLevel::Json, _) => true,
+        _ => false
+    };
+
+    if !textual || mime.get_param(Attr::Charset).is_some() {
+        return mime;
+    }
+
+    let Mime(toplevel, sublevel, mut params) = mime;
+    let value = if charset.eq_ignore_ascii_case("utf-8") {
+        Value::Utf8
+    }
+    else {
+        Value::Ext(charset.clone())
+    };
+    params.push((Attr::Charset, value));
+
+    Mime(toplevel, sublevel, params)
+}
+
+#[test]
+fn default_charset_applies_to_text() {
+    let utf8 = Some(String::from("utf-8"));
+
+    assert_eq!(with_default_charset(mime!(Text/Css), &utf8),
+               mime!(Text/Css; Charset=Utf8));
+    assert_eq!(with_default_charset(mime!(Application/Json), &utf8),
+               mime!(Application/Json; Charset=Utf8));
+}
+
+#[test]
+fn default_charset_leaves_others_alone() {
+    let latin1 = Some(String::from("iso-8859-1"));
+
+    assert_eq!(with_default_charset(mime!(Image/Png), &latin1),
+               mime!(Image/Png));
+    assert_eq!(with_default_charset(mime!(Text/Plain; Charset=Utf8), &latin1),
+               mime!(Text/Plain; Charset=Utf8));
+    assert_eq!(with_default_charset(mime!(Text/Plain), &None),
+               mime!(Text/Plain));
 }
 
 impl Handler for Statics {
     fn serve(&self, req: Request, res: Response<Fresh>) {
-        match self.serve_file(req, res) {
-            Ok(_) => (),
-            Err(e) => warn!("Error serving a file: {:?}", e)
+        let id = req.id().to_owned();
+
+        if let Err(e) = self.serve_file(req, res) {
+            log_failure(&e, &id);
         }
     }
 }
@@ -0,0 +1,244 @@
+//! Running under systemd: socket activation, and telling it how we're doing
+//!
+//! With socket activation, systemd binds the listening sockets itself and
+//! passes them in from descriptor 3 on, saying how many in `LISTEN_FDS`, and
+//! naming them in `LISTEN_FDNAMES`. For `Type=notify` services, it listens on
+//! the datagram socket in `NOTIFY_SOCKET` for state changes, and if
+//! `WATCHDOG_USEC` is set, expects to hear from us at least that often.
+
+use libc;
+
+use std::env;
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+/// The first descriptor systemd passes
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Takes the sockets systemd passed, with their names
+pub fn listen_fds() -> io::Result<Vec<(RawFd, String)>> {
+    let fds = try!(passed_fds(env::var("LISTEN_PID").ok(),
+                              env::var("LISTEN_FDS").ok(),
+                              env::var("LISTEN_FDNAMES").ok(),
+                              unsafe { libc::getpid() }));
+
+    // They're ours alone now, not for anything we start
+    for var in &["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        env::remove_var(var);
+    }
+
+    for &(fd, _) in &fds {
+        if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(fds)
+}
+
+/// Works out which descriptors were passed to process `pid`, from the
+/// environment variables systemd sets, and what they're called
+fn passed_fds(listen_pid: Option<String>, listen_fds: Option<String>,
+              names: Option<String>, pid: libc::pid_t)
+              -> io::Result<Vec<(RawFd, String)>> {
+    // Without a matching PID, they were meant for someone else
+    match listen_pid.map(|listen_pid| listen_pid.parse::<libc::pid_t>()) {
+        Some(Ok(listen_pid)) if listen_pid == pid => (),
+        _ => return Ok(Vec::new())
+    }
+
+    let count: RawFd = match listen_fds.map(|count| count.parse()) {
+        Some(Ok(count)) if count >= 0 => count,
+        Some(_) => return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "LISTEN_FDS should be a count of descriptors"
+        )),
+        None => return Ok(Vec::new())
+    };
+
+    let mut names: Vec<String> = match names {
+        Some(names) => names.split(':').map(String::from).collect(),
+        None => Vec::new()
+    };
+    names.resize(count as usize, String::from("unknown"));
+
+    Ok((LISTEN_FDS_START .. LISTEN_FDS_START + count).zip(names).collect())
+}
+
+/// Sends systemd notifications, if it wants them
+#[derive(Debug)]
+pub struct Notifier {
+    socket: Option<(UnixDatagram, PathBuf)>,
+    /// How often to send the watchdog keepalive, and when it was last sent
+    watchdog: Option<(Duration, Instant)>
+}
+
+impl Notifier {
+    /// Sets up notifications as the environment asks
+    pub fn from_env() -> Notifier {
+        let socket = match env::var_os("NOTIFY_SOCKET") {
+            Some(ref path) if path.to_string_lossy().starts_with('@') => {
+                warn!("Can't notify systemd on abstract socket {:?}", path);
+                None
+            },
+            Some(path) => match UnixDatagram::unbound() {
+                Ok(socket) => Some((socket, PathBuf::from(path))),
+                Err(e) => {
+                    warn!("Can't notify systemd: {}", e);
+                    None
+                }
+            },
+            None => None
+        };
+
+        // The watchdog is ours unless it's meant for some other process
+        let pid = format!("{}", unsafe { libc::getpid() });
+        let ours = env::var("WATCHDOG_PID").map_or(true, |p| p == pid);
+        let usec = env::var("WATCHDOG_USEC").map(|u| u.parse::<u64>());
+        let watchdog = match usec {
+            Ok(Ok(usec)) if ours && usec > 0 => {
+                // Keep well inside the limit
+                let interval = Duration::from_millis(usec / 2000);
+                Some((interval, Instant::now()))
+            },
+            _ => None
+        };
+
+        Notifier { socket: socket, watchdog: watchdog }
+    }
+
+    /// Sends `state`, as newline-separated `KEY=value` assignments
+    pub fn notify(&self, state: &str) {
+        if let Some((ref socket, ref path)) = self.socket {
+            if let Err(e) = socket.send_to(state.as_bytes(), path) {
+                warn!("Couldn't notify systemd: {}", e);
+            }
+        }
+    }
+
+    pub fn ready(&self, status: &str) {
+        self.notify(&format!("READY=1\nSTATUS={}\nMAINPID={}", status,
+                             unsafe { libc::getpid() }));
+    }
+
+    pub fn reloading(&self) {
+        self.notify(&format!("RELOADING=1\nMONOTONIC_USEC={}",
+                             monotonic_usec()));
+    }
+
+    pub fn stopping(&self, status: &str) {
+        self.notify(&format!("STOPPING=1\nSTATUS={}", status));
+    }
+
+    /// Milliseconds until the watchdog next needs feeding, or -1 if it never
+    /// does, for `poll`
+    pub fn watchdog_timeout(&self) -> libc::c_int {
+        match self.watchdog {
+            Some((interval, last)) => {
+                let elapsed = last.elapsed();
+                if elapsed >= interval {
+                    return 0;
+                }
+
+                let left = interval - elapsed;
+                (left.as_secs() * 1000 + left.subsec_nanos() as u64 / 1000000)
+                    as libc::c_int + 1
+            },
+            None => -1
+        }
+    }
+
+    /// Tells the watchdog we're still alive, if it's time to
+    pub fn feed_watchdog(&mut self) {
+        let due = match self.watchdog {
+            Some((interval, ref mut last)) if last.elapsed() >= interval => {
+                *last = Instant::now();
+                true
+            },
+            _ => false
+        };
+
+        if due {
+            self.notify("WATCHDOG=1");
+        }
+    }
+}
+
+fn monotonic_usec() -> u64 {
+    let mut now: libc::timespec = unsafe { mem::zeroed() };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) };
+    now.tv_sec as u64 * 1000000 + now.tv_nsec as u64 / 1000
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::passed_fds;
+
+    use std::env;
+    use std::fs;
+    use std::os::unix::net::UnixDatagram;
+    use std::process;
+    use std::time::{Duration, Instant};
+
+    fn some(s: &str) -> Option<String> {
+        Some(String::from(s))
+    }
+
+    #[test]
+    fn passed_fds_are_named() {
+        assert_eq!(passed_fds(some("42"), some("2"), some("http:admin"), 42)
+                   .unwrap(),
+                   vec![(3, String::from("http")), (4, String::from("admin"))]);
+        assert_eq!(passed_fds(some("42"), some("2"), None, 42).unwrap(),
+                   vec![(3, String::from("unknown")),
+                        (4, String::from("unknown"))]);
+    }
+
+    #[test]
+    fn passed_fds_are_only_for_their_process() {
+        assert!(passed_fds(some("41"), some("1"), None, 42).unwrap()
+                .is_empty());
+        assert!(passed_fds(None, some("1"), None, 42).unwrap().is_empty());
+        assert!(passed_fds(some("42"), some("many"), None, 42).is_err());
+    }
+
+    #[test]
+    fn notifications_reach_the_socket() {
+        let path = env::temp_dir()
+            .join(format!("http-server-notify-{}", process::id()));
+        let _ = fs::remove_file(&path);
+        let systemd = UnixDatagram::bind(&path).unwrap();
+        systemd.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        let mut notifier = Notifier {
+            socket: Some((UnixDatagram::unbound().unwrap(), path.clone())),
+            watchdog: Some((Duration::from_millis(0), Instant::now()))
+        };
+        let mut buf = [0; 256];
+
+        notifier.ready("Serving");
+        let n = systemd.recv(&mut buf).unwrap();
+        let ready = String::from_utf8_lossy(&buf[.. n]).into_owned();
+        assert!(ready.starts_with("READY=1\nSTATUS=Serving\nMAINPID="));
+
+        notifier.reloading();
+        let n = systemd.recv(&mut buf).unwrap();
+        assert!(buf[.. n].starts_with(b"RELOADING=1\nMONOTONIC_USEC="));
+
+        assert_eq!(notifier.watchdog_timeout(), 0);
+        notifier.feed_watchdog();
+        let n = systemd.recv(&mut buf).unwrap();
+        assert_eq!(&buf[.. n], b"WATCHDOG=1");
+
+        notifier.stopping("Draining");
+        let n = systemd.recv(&mut buf).unwrap();
+        assert_eq!(&buf[.. n], b"STOPPING=1\nSTATUS=Draining");
+
+        fs::remove_file(&path).unwrap();
+    }
+}
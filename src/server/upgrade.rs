@@ -0,0 +1,180 @@
+//! Replacing the running binary without dropping connections
+//!
+//! On SIGUSR2 the server starts its binary afresh, handing the new process
+//! its listening sockets through `LISTEN_FD_VAR`. The new process accepts on
+//! the same sockets as soon as it's ready, then sends the old one SIGTERM to
+//! have it drain and exit. If the new process dies first, the old one carries
+//! on as if nothing happened.
+
+use server::listener::Listener;
+
+use libc;
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+
+/// The environment variable the listening sockets' descriptors are passed in,
+/// separated by commas
+pub const LISTEN_FD_VAR: &'static str = "HTTP_SERVER_LISTEN_FD";
+
+/// Takes the listening sockets handed down by the process this one replaces,
+/// if there are any
+pub fn inherited_fds() -> io::Result<Vec<RawFd>> {
+    let fds = match env::var(LISTEN_FD_VAR) {
+        Ok(fds) => fds,
+        Err(_) => return Ok(Vec::new())
+    };
+    env::remove_var(LISTEN_FD_VAR);
+
+    let mut inherited = Vec::new();
+    for fd in fds.split(',') {
+        let fd: RawFd = match fd.parse() {
+            Ok(fd) if fd >= 0 => fd,
+            _ => return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} should list file descriptors, but it's {:?}",
+                        LISTEN_FD_VAR, fds)
+            ))
+        };
+        try!(close_on_exec(fd, true));
+        inherited.push(fd);
+    }
+
+    Ok(inherited)
+}
+
+/// Starts this binary again, with the same arguments, to take over
+/// `listeners`
+pub fn spawn(listeners: &[Listener]) -> io::Result<Child> {
+    let fds: Vec<RawFd> = listeners.iter().map(|l| l.as_raw_fd()).collect();
+    let exe = try!(env::current_exe());
+
+    for &fd in &fds {
+        try!(close_on_exec(fd, false));
+    }
+    let child = Command::new(exe)
+        .args(env::args_os().skip(1))
+        .env(LISTEN_FD_VAR, fds.iter()
+             .map(|fd| format!("{}", fd))
+             .collect::<Vec<_>>()
+             .join(","))
+        // The watchdog is the new server's to feed, once it's running
+        .env_remove("WATCHDOG_PID")
+        .spawn();
+    for &fd in &fds {
+        try!(close_on_exec(fd, true));
+    }
+
+    child
+}
+
+/// Tells the process that handed down the listening socket to drain and exit,
+/// now that this one's accepting on it
+pub fn take_over() {
+    let parent = unsafe { libc::getppid() };
+    if parent > 1 && unsafe { libc::kill(parent, libc::SIGTERM) } < 0 {
+        warn!("Couldn't stop the old server: {}", io::Error::last_os_error());
+    }
+}
+
+fn close_on_exec(fd: RawFd, close: bool) -> io::Result<()> {
+    let flags = if close { libc::FD_CLOEXEC } else { 0 };
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, flags) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// A pidfile holding this process's ID, removed when dropped unless another
+/// process has written its own ID there since
+#[derive(Debug)]
+pub struct Pidfile {
+    path: PathBuf,
+    pid: String
+}
+
+impl Pidfile {
+    pub fn write(path: &Path) -> io::Result<Pidfile> {
+        let pid = format!("{}", unsafe { libc::getpid() });
+
+        // Written aside and renamed into place, so nobody reads half of it
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".new");
+        {
+            let mut file = try!(File::create(&partial));
+            try!(writeln!(file, "{}", pid));
+        }
+        try!(fs::rename(&partial, path));
+
+        Ok(Pidfile { path: path.to_owned(), pid: pid })
+    }
+
+    fn ours(&self) -> bool {
+        let mut contents = String::new();
+        match File::open(&self.path)
+            .and_then(|mut file| file.read_to_string(&mut contents)) {
+                Ok(_) => contents.trim() == self.pid,
+                Err(_) => false
+            }
+    }
+}
+
+impl Drop for Pidfile {
+    fn drop(&mut self) {
+        if self.ours() {
+            if let Err(e) = fs::remove_file(&self.path) {
+                warn!("Couldn't remove the pidfile {:?}: {}", self.path, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::os::unix::io::AsRawFd;
+    use std::process;
+
+    #[test]
+    fn pidfiles_are_only_removed_by_their_owner() {
+        let path = env::temp_dir()
+            .join(format!("http-server-test-{}.pid", process::id()));
+
+        {
+            let pidfile = Pidfile::write(&path).unwrap();
+            let mut contents = String::new();
+            File::open(&path).unwrap().read_to_string(&mut contents).unwrap();
+            assert_eq!(contents, format!("{}\n", process::id()));
+            drop(pidfile);
+        }
+        assert!(!path.exists());
+
+        let pidfile = Pidfile::write(&path).unwrap();
+        File::create(&path).unwrap().write_all(b"1\n").unwrap();
+        drop(pidfile);
+        assert!(path.exists());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn listeners_are_inherited_from_the_environment() {
+        let listeners: Vec<_> = (0 .. 2)
+            .map(|_| TcpListener::bind("127.0.0.1:0").unwrap())
+            .collect();
+        let fds: Vec<_> = listeners.iter().map(|l| l.as_raw_fd()).collect();
+
+        env::set_var(LISTEN_FD_VAR, format!("{},{}", fds[0], fds[1]));
+        assert_eq!(inherited_fds().unwrap(), fds);
+        assert!(env::var_os(LISTEN_FD_VAR).is_none());
+        assert!(inherited_fds().unwrap().is_empty());
+    }
+}